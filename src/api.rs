@@ -0,0 +1,477 @@
+use crate::core::manager::{PeerStats, TorrentStats};
+use crate::core::session::Session;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, Instant, timeout};
+use tracing::{debug, info};
+
+/// Largest request head (request line + headers) we accept.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Largest request body we accept; bodies only ever carry a small JSON object.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Time a client gets to send its whole request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest byte range one `GET /data` returns; players read in smaller chunks anyway.
+const MAX_DATA_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// How long `GET /data` waits for the pieces covering the range to download.
+const DATA_WAIT: Duration = Duration::from_secs(60);
+
+/// Per-torrent entry of `GET /status`.
+#[derive(Serialize)]
+struct TorrentStatus {
+    #[serde(flatten)]
+    stats: TorrentStats,
+    /// Average payload rates in bytes per second since the previous `GET /status`
+    /// (0 on the first request).
+    download_rate: u64,
+    upload_rate: u64,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    torrents: Vec<TorrentStatus>,
+}
+
+/// Per-torrent entry of `GET /peers`.
+#[derive(Serialize)]
+struct TorrentPeers {
+    name: String,
+    info_hash: String,
+    peers: Vec<PeerStats>,
+}
+
+/// Body of `POST /torrents`.
+#[derive(Deserialize)]
+struct AddTorrentRequest {
+    path: Option<String>,
+    magnet: Option<String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap_or_else(|_| b"{}".to_vec()),
+        }
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, &serde_json::json!({ "error": message.into() }))
+    }
+}
+
+/// Counter values seen by the previous `GET /status`, used to derive rates.
+struct Sample {
+    at: Instant,
+    downloaded: u64,
+    uploaded: u64,
+}
+
+/// Embedded HTTP control API (`--api-addr`).
+///
+/// Routes:
+/// - `GET /status`: progress, transfer totals and rates, and peer count per torrent.
+/// - `GET /peers`: per-peer transfer totals, rates and choke state, for every torrent or
+///   just `?info_hash=<hex>`.
+/// - `POST /pause`, `POST /resume`: every torrent, or just `?info_hash=<hex>`.
+/// - `POST /torrents`: adds a torrent from `{"path": "file.torrent"}`.
+/// - `GET /data?info_hash=<hex>&offset=<n>&length=<n>`: raw content bytes, waiting for
+///   (and prioritizing) the pieces that cover them.
+///
+/// Connections carry one request each (`Connection: close`); this is a local control
+/// channel, not a web server.
+pub struct Api {
+    session: Arc<Session>,
+    samples: Mutex<HashMap<String, Sample>>,
+}
+
+impl Api {
+    pub fn new(session: Arc<Session>) -> Self {
+        Self {
+            session,
+            samples: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serves requests on `addr` until the task is dropped.
+    pub async fn serve(self: Arc<Self>, addr: &str) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("Failed to bind API address {}", addr))?;
+        info!("HTTP API listening on {}", addr);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let api = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = api.handle_connection(stream).await {
+                    debug!("API request failed: {:#}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let response = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => self.route(request).await,
+            Ok(Err(e)) => Response::error(400, format!("{:#}", e)),
+            Err(_) => Response::error(408, "Request timed out"),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            reason_phrase(response.status),
+            response.content_type,
+            response.body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&response.body).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn route(&self, request: Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/status") => self.status(),
+            ("GET", "/peers") => self.peers(&request),
+            ("POST", "/pause") => self.set_paused(&request, true),
+            ("POST", "/resume") => self.set_paused(&request, false),
+            ("POST", "/torrents") => self.add_torrent(&request).await,
+            ("GET", "/data") => self.data(&request).await,
+            (_, "/status" | "/peers" | "/pause" | "/resume" | "/torrents" | "/data") => {
+                Response::error(405, "Method not allowed")
+            }
+            _ => Response::error(404, "Not found"),
+        }
+    }
+
+    fn status(&self) -> Response {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        let mut torrents = Vec::new();
+
+        for handle in self.session.torrents() {
+            let stats = match handle.manager.stats() {
+                Ok(stats) => stats,
+                Err(e) => return Response::error(500, format!("{:#}", e)),
+            };
+            let (download_rate, upload_rate) = match samples.get(&stats.info_hash) {
+                Some(prev) => {
+                    let secs = now.duration_since(prev.at).as_secs_f64().max(0.001);
+                    (
+                        (stats.downloaded.saturating_sub(prev.downloaded) as f64 / secs) as u64,
+                        (stats.uploaded.saturating_sub(prev.uploaded) as f64 / secs) as u64,
+                    )
+                }
+                None => (0, 0),
+            };
+            samples.insert(
+                stats.info_hash.clone(),
+                Sample {
+                    at: now,
+                    downloaded: stats.downloaded,
+                    uploaded: stats.uploaded,
+                },
+            );
+            torrents.push(TorrentStatus {
+                stats,
+                download_rate,
+                upload_rate,
+            });
+        }
+
+        torrents.sort_by(|a, b| a.stats.name.cmp(&b.stats.name));
+        Response::json(200, &StatusResponse { torrents })
+    }
+
+    fn peers(&self, request: &Request) -> Response {
+        let handles = match request.query.get("info_hash") {
+            Some(hex_hash) => match parse_info_hash(hex_hash) {
+                Some(info_hash) => match self.session.torrent(&info_hash) {
+                    Some(handle) => vec![handle],
+                    None => return Response::error(404, "Unknown torrent"),
+                },
+                None => return Response::error(400, "info_hash must be 40 hex characters"),
+            },
+            None => self.session.torrents(),
+        };
+
+        let mut torrents: Vec<TorrentPeers> = handles
+            .iter()
+            .map(|handle| {
+                let mut peers = handle.manager.peers();
+                peers.sort_by_key(|peer| std::cmp::Reverse(peer.download_rate));
+                TorrentPeers {
+                    name: handle.manager.torrent.info().name.clone(),
+                    info_hash: hex::encode(handle.info_hash),
+                    peers,
+                }
+            })
+            .collect();
+        torrents.sort_by(|a, b| a.name.cmp(&b.name));
+        Response::json(200, &serde_json::json!({ "torrents": torrents }))
+    }
+
+    fn set_paused(&self, request: &Request, paused: bool) -> Response {
+        let targets = match request.query.get("info_hash") {
+            Some(hex_hash) => match parse_info_hash(hex_hash) {
+                Some(info_hash) => vec![info_hash],
+                None => return Response::error(400, "info_hash must be 40 hex characters"),
+            },
+            None => self
+                .session
+                .torrents()
+                .iter()
+                .map(|handle| handle.info_hash)
+                .collect(),
+        };
+
+        for info_hash in &targets {
+            let found = if paused {
+                self.session.pause_torrent(info_hash)
+            } else {
+                self.session.resume_torrent(info_hash)
+            };
+            if !found {
+                return Response::error(404, "Unknown torrent");
+            }
+        }
+        Response::json(
+            200,
+            &serde_json::json!({ "paused": paused, "torrents": targets.len() }),
+        )
+    }
+
+    async fn add_torrent(&self, request: &Request) -> Response {
+        let body: AddTorrentRequest = match serde_json::from_slice(&request.body) {
+            Ok(body) => body,
+            Err(e) => return Response::error(400, format!("Invalid JSON body: {}", e)),
+        };
+        if body.magnet.is_some() {
+            return Response::error(400, "Magnet links are not supported");
+        }
+        let Some(path) = body.path else {
+            return Response::error(400, "Expected {\"path\": \"<file.torrent>\"}");
+        };
+
+        // Adding verifies existing data on disk, which can take a while
+        let session = self.session.clone();
+        match tokio::task::spawn_blocking(move || session.add_torrent(&path)).await {
+            Ok(Ok(handle)) => {
+                info!(
+                    "Added torrent {} via API",
+                    handle.manager.torrent.info().name
+                );
+                Response::json(
+                    201,
+                    &serde_json::json!({ "info_hash": hex::encode(handle.info_hash) }),
+                )
+            }
+            Ok(Err(e)) => Response::error(400, format!("{:#}", e)),
+            Err(e) => Response::error(500, e.to_string()),
+        }
+    }
+
+    async fn data(&self, request: &Request) -> Response {
+        let Some(info_hash) = request
+            .query
+            .get("info_hash")
+            .and_then(|h| parse_info_hash(h))
+        else {
+            return Response::error(400, "info_hash must be 40 hex characters");
+        };
+        let number = |name: &str| request.query.get(name).and_then(|v| v.parse::<u64>().ok());
+        let (Some(offset), Some(length)) = (number("offset"), number("length")) else {
+            return Response::error(400, "Expected numeric offset and length");
+        };
+        if length > MAX_DATA_LENGTH {
+            return Response::error(
+                400,
+                format!("length must be at most {} bytes", MAX_DATA_LENGTH),
+            );
+        }
+        let Some(handle) = self.session.torrent(&info_hash) else {
+            return Response::error(404, "Unknown torrent");
+        };
+
+        match handle.manager.read_range(offset, length, DATA_WAIT).await {
+            Ok(data) => Response::bytes(data),
+            Err(e) => Response::error(400, format!("{:#}", e)),
+        }
+    }
+}
+
+/// Reads one HTTP/1.1 request: the head up to the blank line, then `Content-Length` bytes.
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            anyhow::bail!("Request head too large");
+        }
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed mid-request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end]).context("Request head is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        anyhow::bail!("Malformed request line");
+    };
+
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().context("Invalid Content-Length")?;
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        anyhow::bail!("Request body too large");
+    }
+
+    let mut body = buf[head_end + 4..].to_vec();
+    if body.len() < content_length {
+        let already = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[already..]).await?;
+    }
+    body.truncate(content_length);
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        body,
+    })
+}
+
+fn parse_info_hash(value: &str) -> Option<[u8; 20]> {
+    hex::decode(value).ok()?.try_into().ok()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::session::SessionOptions;
+    use crate::core::storage::StorageOptions;
+    use crate::core::test_util::torrent;
+    use tempfile::TempDir;
+
+    /// Sends `request` to the API over a loopback connection and returns the status
+    /// line and body of the response.
+    async fn call(api: &Api, request: &str) -> (String, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        api.handle_connection(server).await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..head_end]).into_owned();
+        let status = head.lines().next().unwrap().to_string();
+        (status, response[head_end + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn status_reports_each_torrent() {
+        let dir = TempDir::new().unwrap();
+        let session = Arc::new(Session::new(SessionOptions {
+            storage: StorageOptions {
+                output_dir: dir.path().to_string_lossy().into_owned(),
+                ..StorageOptions::default()
+            },
+            ..SessionOptions::default()
+        }));
+        let handle = session.add(torrent("data", &[1; 32768], 16384)).unwrap();
+        handle.manager.record_download(16384);
+        handle.manager.mark_piece_complete(0);
+        let api = Api::new(session);
+
+        let (status, body) = call(&api, "GET /status HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "torrents": [{
+                    "name": "data",
+                    "info_hash": hex::encode(handle.info_hash),
+                    "pieces_complete": 1,
+                    "piece_count": 2,
+                    "downloaded": 16384,
+                    "uploaded": 0,
+                    "peers": 0,
+                    "swarm": null,
+                    "paused": false,
+                    "complete": false,
+                    "download_rate": 0,
+                    "upload_rate": 0,
+                }]
+            })
+        );
+
+        let (status, _) = call(&api, "POST /status HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,152 @@
+/// A fixed-length set of bits, one per piece, packed 64 to a word.
+///
+/// Used for per-peer piece availability and for our own piece states, where a
+/// `Vec<bool>` would cost 8x the memory on torrents with many pieces.
+#[derive(Debug, Clone)]
+pub struct Bitfield {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// Creates a bitfield of `len` bits, all unset.
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the bit at `index`; out-of-range indices read as unset.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Sets or clears the bit at `index`. Out-of-range indices are ignored.
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        let mask = 1 << (index % 64);
+        if value {
+            self.words[index / 64] |= mask;
+        } else {
+            self.words[index / 64] &= !mask;
+        }
+    }
+
+    /// Encodes the bits in wire format (first piece in the high bit of the first byte),
+    /// with spare bits in the last byte cleared.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.len.div_ceil(8)];
+        for index in (0..self.len).filter(|&i| self.get(i)) {
+            bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+        bytes
+    }
+
+    /// Returns true if no bit is set.
+    pub fn none(&self) -> bool {
+        self.words.iter().all(|&word| word == 0)
+    }
+
+    /// Decodes a wire-format bitfield of `len` bits (the payload of a `Bitfield`
+    /// message: first piece in the high bit of the first byte).
+    ///
+    /// Fails unless it is exactly `ceil(len / 8)` bytes with the spare bits in the last
+    /// byte cleared, as the spec requires.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> anyhow::Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            anyhow::bail!(
+                "Bitfield is {} bytes, expected {} for {} pieces",
+                bytes.len(),
+                len.div_ceil(8),
+                len
+            );
+        }
+        let mut bitfield = Self::new(len);
+        for (i, byte) in bytes.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    if i * 8 + bit >= len {
+                        anyhow::bail!("Bitfield has spare bits set past piece {}", len - 1);
+                    }
+                    bitfield.set(i * 8 + bit, true);
+                }
+            }
+        }
+        Ok(bitfield)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_first_piece_in_the_high_bit() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0, true);
+        bitfield.set(7, true);
+        bitfield.set(9, true);
+        assert_eq!(bitfield.to_bytes(), vec![0b1000_0001, 0b0100_0000]);
+    }
+
+    #[test]
+    fn round_trips_across_word_boundaries() {
+        for len in [1, 7, 8, 9, 63, 64, 65, 130] {
+            let mut bitfield = Bitfield::new(len);
+            for index in (0..len).filter(|i| i % 3 == 0 || i % 64 == 63) {
+                bitfield.set(index, true);
+            }
+            let decoded = Bitfield::from_bytes(&bitfield.to_bytes(), len).unwrap();
+            assert_eq!(decoded.len(), len);
+            for index in 0..len {
+                assert_eq!(
+                    decoded.get(index),
+                    bitfield.get(index),
+                    "bit {} of {}",
+                    index,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spare_bits_stay_clear() {
+        let mut bitfield = Bitfield::new(9);
+        for index in 0..20 {
+            bitfield.set(index, true);
+        }
+        assert_eq!(bitfield.to_bytes(), vec![0xff, 0x80]);
+        assert!(!bitfield.get(9));
+    }
+
+    #[test]
+    fn rejects_spare_bits_set() {
+        assert!(Bitfield::from_bytes(&[0xff, 0x80], 9).is_ok());
+        assert!(Bitfield::from_bytes(&[0xff, 0xc0], 9).is_err());
+        assert!(Bitfield::from_bytes(&[0x01], 7).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        assert!(Bitfield::from_bytes(&[0xff], 9).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0, 0], 9).is_err());
+        assert!(Bitfield::from_bytes(&[], 0).unwrap().none());
+    }
+
+    #[test]
+    fn clearing_bits() {
+        let mut bitfield = Bitfield::new(70);
+        bitfield.set(69, true);
+        assert!(!bitfield.none());
+        bitfield.set(69, false);
+        assert!(bitfield.none());
+    }
+}
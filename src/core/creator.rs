@@ -1,8 +1,13 @@
 use crate::core::torrent_info::{FileNode, Info, Torrent};
+use crate::utils;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{info, warn};
 use walkdir::WalkDir;
 
 /// Standard piece size for most torrents (256 KB).
@@ -10,77 +15,168 @@ use walkdir::WalkDir;
 /// and efficiency (larger pieces = more wasted data on corruption).
 const PIECE_LENGTH: usize = 262144;
 
+/// Read-ahead buffer per input file. Several pieces' worth, so each read syscall
+/// fills multiple pieces and small files are usually read in a single call.
+const READ_BUFFER_SIZE: usize = 4 * PIECE_LENGTH;
+
+/// How often the hashes so far are saved to the checkpoint next to the output.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Controls which files under the input directory end up in the torrent.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Include dotfiles and dot-directories (`.git`, `.DS_Store`, ...). Off by default,
+    /// since they are rarely meant to be shared and can leak private data.
+    pub include_hidden: bool,
+    /// Glob patterns (see `utils::glob_match`) matched against each file's path
+    /// relative to the input directory, with `/` separators. Matching files are skipped.
+    pub exclude: Vec<String>,
+    /// The torrent's `name`, instead of the input's file or directory name. Required
+    /// when reading from stdin.
+    pub name: Option<String>,
+    /// Also record each file's MD5 (`md5sum`), computed from the same reads as the
+    /// piece hashes.
+    pub md5: bool,
+    /// Also record each file's modification time (`mtime`), which downloaders restore.
+    pub mtime: bool,
+}
+
+/// What `create_torrent_file` would produce, worked out without reading file contents.
+#[derive(Debug)]
+pub struct CreatePlan {
+    pub name: String,
+    /// Each file's path relative to the input (empty for a single-file torrent) and
+    /// size, in the order they are hashed.
+    pub files: Vec<(String, u64)>,
+    pub total_length: u64,
+    pub piece_length: usize,
+    pub piece_count: u64,
+}
+
+/// The input files of a torrent, filtered and sorted, with their sizes.
+struct Scan {
+    name: String,
+    is_single_file: bool,
+    /// Each entry pairs the on-disk path with its path components relative to the root,
+    /// which are what gets written into the torrent.
+    files: Vec<(PathBuf, Vec<String>)>,
+    file_lengths: Vec<i64>,
+}
+
+/// Walks the input like `create_torrent_file` (same exclusions, same order) and
+/// reports the files, total size and piece count, without hashing anything.
+pub fn plan_torrent(path_str: &str, options: &CreateOptions) -> anyhow::Result<CreatePlan> {
+    if path_str == "-" {
+        anyhow::bail!("Stdin can't be inspected without reading it");
+    }
+    let scan = scan_input(Path::new(path_str), path_str, options)?;
+    let total_length = scan.file_lengths.iter().sum::<i64>() as u64;
+    Ok(CreatePlan {
+        name: scan.name,
+        files: scan
+            .files
+            .into_iter()
+            .zip(scan.file_lengths)
+            .map(|((_, components), length)| (components.join("/"), length as u64))
+            .collect(),
+        total_length,
+        piece_length: PIECE_LENGTH,
+        piece_count: total_length.div_ceil(PIECE_LENGTH as u64),
+    })
+}
+
 /// Generates a valid .torrent metainfo file from a given file or directory.
 ///
 /// This function performs the following steps:
-/// 1. Scans the input path (recursively if a directory).
+/// 1. Scans the input path (recursively if a directory), dropping hidden and excluded files.
 /// 2. Sorts files to ensure deterministic hashing (producing the same Info Hash every time).
 /// 3. Reads all files as a single continuous stream of bytes.
 /// 4. Chunks the stream into 256KB pieces and calculates SHA-1 hashes.
 /// 5. Serializes the metadata into Bencode format.
+///
+/// `progress` is called with `(bytes_hashed, total_bytes)` after every piece and once
+/// more when hashing finishes. The byte count only ever grows and ends at the total.
+///
+/// Every 30 seconds the piece hashes so far are saved to `<output_path>.checkpoint`,
+/// which a later run over the same input resumes from (see `resume_checkpoint`) and
+/// which is removed once the torrent is written. Runs with `md5` don't checkpoint,
+/// since a file's MD5 can't be carried over from an interrupted run.
+///
+/// A `path_str` of `-` reads a single file's data from stdin (see `create_from_stdin`).
 pub fn create_torrent_file(
     path_str: &str,
-    announce_url: &str,
+    trackers: &[String],
     output_path: &str,
+    options: &CreateOptions,
+    mut progress: impl FnMut(u64, u64),
 ) -> anyhow::Result<()> {
-    let path = Path::new(path_str);
-    if !path.exists() {
-        anyhow::bail!("Path does not exist: {}", path_str);
+    if path_str == "-" {
+        return create_from_stdin(trackers, output_path, options, progress);
     }
 
-    println!("Hashing files from: {:?}", path);
+    let path = Path::new(path_str);
+    info!("Hashing files from: {:?}", path);
 
     // --- 1. Identify Files ---
-    let mut files = Vec::new();
-    let is_single_file = path.is_file();
+    let Scan {
+        name,
+        is_single_file,
+        files,
+        file_lengths,
+    } = scan_input(path, path_str, options)?;
 
-    // The 'name' field in the Info dictionary is either the filename
-    // or the name of the root directory.
-    let name = path
-        .file_name()
-        .ok_or_else(|| anyhow::anyhow!("Invalid path name"))?
-        .to_str()
-        .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in path"))?
-        .to_string();
-
-    if is_single_file {
-        files.push(path.to_path_buf());
+    // --- 2. Hash Pieces ---
+    // Pieces an interrupted run already hashed are taken from its checkpoint
+    let checkpoint_path = PathBuf::from(format!("{}.checkpoint", output_path));
+    let input = if options.md5 {
+        None
     } else {
-        // Recursively find all files in the folder
-        for entry in WalkDir::new(path) {
-            let entry = entry?;
-            if entry.file_type().is_file() {
-                files.push(entry.path().to_path_buf());
-            }
-        }
-    }
-
-    // Critical: Sort files to ensure the Info Hash is deterministic.
-    // If we process files in random order, the resulting hash will change,
-    // creating a different torrent swarm for the same data.
-    files.sort();
+        Some(CheckpointInput::describe(&files, &file_lengths)?)
+    };
+    let mut pieces = match &input {
+        Some(input) => resume_checkpoint(&checkpoint_path, input, &files, &file_lengths),
+        None => Vec::new(),
+    };
+    let resume_offset = (pieces.len() / 20 * PIECE_LENGTH) as u64;
 
-    // --- 2. Hash Pieces ---
     let mut hasher = Sha1::new();
-    let mut pieces = Vec::new();
     let mut buffer = vec![0u8; PIECE_LENGTH];
     let mut buf_idx = 0;
-    let mut total_length = 0i64;
+    let total_length: i64 = file_lengths.iter().sum();
+    let mut bytes_hashed = resume_offset;
+    let mut md5sums = Vec::new();
+    let mut last_checkpoint = Instant::now();
+    progress(bytes_hashed, total_length as u64);
 
     // Simulate a continuous stream across multiple files.
     // BitTorrent treats a multi-file torrent as one long string of bytes.
-    for file_path in &files {
-        let mut f = File::open(file_path)?;
-        let file_len = f.metadata()?.len() as i64;
-        total_length += file_len;
+    let mut file_start = 0u64;
+    for ((file_path, _), &file_len) in files.iter().zip(&file_lengths) {
+        // Bytes of this file already covered by the checkpoint
+        let skip = resume_offset
+            .saturating_sub(file_start)
+            .min(file_len as u64);
+        file_start += file_len as u64;
+        if skip > 0 && skip == file_len as u64 {
+            md5sums.push(None);
+            continue;
+        }
+        let mut file = File::open(file_path)?;
+        file.seek(SeekFrom::Start(skip))?;
 
-        let mut bytes_left = file_len;
+        // Empty files add nothing to the stream but still get a 0-length entry in `files`
+        let mut f = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+        let mut md5 = options.md5.then(Md5::new);
+        let mut bytes_left = file_len - skip as i64;
         while bytes_left > 0 {
             // Fill the buffer until it hits 256KB or the file ends
             let space_in_buf = PIECE_LENGTH - buf_idx;
             let read_len = std::cmp::min(space_in_buf as i64, bytes_left) as usize;
 
             f.read_exact(&mut buffer[buf_idx..buf_idx + read_len])?;
+            if let Some(md5) = &mut md5 {
+                md5.update(&buffer[buf_idx..buf_idx + read_len]);
+            }
 
             buf_idx += read_len;
             bytes_left -= read_len as i64;
@@ -90,15 +186,39 @@ pub fn create_torrent_file(
                 hasher.update(&buffer);
                 pieces.extend_from_slice(&hasher.finalize_reset());
                 buf_idx = 0;
+
+                bytes_hashed += PIECE_LENGTH as u64;
+                progress(bytes_hashed, total_length as u64);
+
+                if let Some(input) = &input
+                    && last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL
+                {
+                    if let Err(e) = save_checkpoint(&checkpoint_path, input, &pieces) {
+                        warn!("Failed to save checkpoint: {}", e);
+                    }
+                    last_checkpoint = Instant::now();
+                }
             }
         }
+        md5sums.push(md5.map(|md5| hex::encode(md5.finalize())));
     }
 
-    // Hash remaining bytes (the final partial piece)
+    // Hash remaining bytes (the final partial piece).
+    // If every file is empty there is no data and therefore no pieces at all.
     if buf_idx > 0 {
         hasher.update(&buffer[..buf_idx]);
         pieces.extend_from_slice(&hasher.finalize_reset());
     }
+    progress(total_length as u64, total_length as u64);
+
+    let mut mtimes = Vec::with_capacity(files.len());
+    for (file_path, _) in &files {
+        mtimes.push(if options.mtime {
+            Some(file_mtime(file_path)?)
+        } else {
+            None
+        });
+    }
 
     // --- 3. Build Info Structure ---
     let info = if is_single_file {
@@ -107,23 +227,22 @@ pub fn create_torrent_file(
             piece_length: PIECE_LENGTH,
             pieces: serde_bytes::ByteBuf::from(pieces),
             length: Some(total_length),
-            files: None,
+            md5sum: md5sums.pop().flatten(),
+            mtime: mtimes.pop().flatten(),
+            ..Info::default()
         }
     } else {
-        // For multi-file torrents, we calculate paths relative to the root folder
+        // For multi-file torrents, paths are relative to the root folder
         let file_nodes: Vec<FileNode> = files
-            .iter()
-            .map(|f| {
-                let relative = f.strip_prefix(path).unwrap();
-                let path_parts = relative
-                    .iter()
-                    .map(|s| s.to_str().unwrap().to_string())
-                    .collect();
-
-                FileNode {
-                    length: f.metadata().unwrap().len() as i64,
-                    path: path_parts,
-                }
+            .into_iter()
+            .zip(file_lengths)
+            .zip(md5sums)
+            .zip(mtimes)
+            .map(|((((_, path_parts), length), md5sum), mtime)| FileNode {
+                length,
+                path: path_parts,
+                md5sum,
+                mtime,
             })
             .collect();
 
@@ -131,22 +250,633 @@ pub fn create_torrent_file(
             name,
             piece_length: PIECE_LENGTH,
             pieces: serde_bytes::ByteBuf::from(pieces),
-            length: None,
             files: Some(file_nodes),
+            ..Info::default()
         }
     };
 
     // --- 4. Build & Save Torrent ---
-    let torrent = Torrent {
-        announce: announce_url.to_string(),
-        announce_list: None,
-        info,
+    save_torrent(trackers, info, output_path)?;
+    if checkpoint_path.exists() {
+        fs::remove_file(&checkpoint_path)?;
+    }
+    Ok(())
+}
+
+/// A file's modification time in whole seconds since the Unix epoch (negative before).
+fn file_mtime(path: &Path) -> anyhow::Result<i64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(match modified.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    })
+}
+
+/// What a checkpoint was taken over. Hashes are only reused if this is unchanged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CheckpointInput {
+    piece_length: usize,
+    /// Relative path, size and modification time (seconds, nanoseconds since the
+    /// Unix epoch) of each file, in hashing order.
+    files: Vec<(String, i64, u64, u32)>,
+}
+
+impl CheckpointInput {
+    fn describe(files: &[(PathBuf, Vec<String>)], file_lengths: &[i64]) -> anyhow::Result<Self> {
+        let mut described = Vec::with_capacity(files.len());
+        for ((path, components), &length) in files.iter().zip(file_lengths) {
+            let modified = fs::metadata(path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            described.push((
+                components.join("/"),
+                length,
+                modified.as_secs(),
+                modified.subsec_nanos(),
+            ));
+        }
+        Ok(Self {
+            piece_length: PIECE_LENGTH,
+            files: described,
+        })
+    }
+}
+
+/// The creator's checkpoint file: whole pieces hashed so far, from the start.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    input: CheckpointInput,
+    /// Concatenated SHA-1 hashes, hex encoded.
+    pieces: String,
+}
+
+/// Writes the checkpoint atomically, so an interruption mid-write leaves the previous one.
+fn save_checkpoint(path: &Path, input: &CheckpointInput, pieces: &[u8]) -> anyhow::Result<()> {
+    let checkpoint = Checkpoint {
+        input: input.clone(),
+        pieces: hex::encode(pieces),
+    };
+    let temp = path.with_extension("checkpoint.tmp");
+    fs::write(&temp, serde_json::to_vec(&checkpoint)?)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Piece hashes from an interrupted run over the same input, or none.
+///
+/// The file list, sizes and mtimes must match exactly, and the last recorded piece is
+/// read and hashed again as a spot check that the data didn't change underneath them.
+fn resume_checkpoint(
+    path: &Path,
+    input: &CheckpointInput,
+    files: &[(PathBuf, Vec<String>)],
+    file_lengths: &[i64],
+) -> Vec<u8> {
+    let Ok(bytes) = fs::read(path) else {
+        return Vec::new();
+    };
+    let checkpoint: Checkpoint = match serde_json::from_slice(&bytes) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            warn!("Ignoring unreadable checkpoint {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    if checkpoint.input != *input {
+        info!("Input changed since the checkpoint was taken; hashing from the start");
+        return Vec::new();
+    }
+    let pieces = match hex::decode(&checkpoint.pieces) {
+        Ok(pieces) if !pieces.is_empty() && pieces.len().is_multiple_of(20) => pieces,
+        _ => return Vec::new(),
+    };
+
+    let last = pieces.len() / 20 - 1;
+    let mut buffer = vec![0u8; PIECE_LENGTH];
+    let unchanged = read_stream(
+        files,
+        file_lengths,
+        (last * PIECE_LENGTH) as u64,
+        &mut buffer,
+    )
+    .is_ok()
+        && Sha1::digest(&buffer)[..] == pieces[last * 20..];
+    if !unchanged {
+        warn!("Data changed since the checkpoint was taken; hashing from the start");
+        return Vec::new();
+    }
+    info!(
+        "Resuming from checkpoint: {} pieces already hashed",
+        pieces.len() / 20
+    );
+    pieces
+}
+
+/// Fills `buffer` from the files' concatenated contents, starting at `offset`.
+fn read_stream(
+    files: &[(PathBuf, Vec<String>)],
+    file_lengths: &[i64],
+    offset: u64,
+    buffer: &mut [u8],
+) -> anyhow::Result<()> {
+    let mut filled = 0;
+    let mut file_start = 0u64;
+    for ((path, _), &length) in files.iter().zip(file_lengths) {
+        let file_end = file_start + length as u64;
+        let position = offset + filled as u64;
+        if filled < buffer.len() && position < file_end {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(position - file_start))?;
+            let count = ((file_end - position) as usize).min(buffer.len() - filled);
+            file.read_exact(&mut buffer[filled..filled + count])?;
+            filled += count;
+        }
+        file_start = file_end;
+    }
+    if filled < buffer.len() {
+        anyhow::bail!("Input ends before the checkpoint");
+    }
+    Ok(())
+}
+
+/// Lists the files under `path` that go into the torrent, sorted into hashing order.
+fn scan_input(path: &Path, path_str: &str, options: &CreateOptions) -> anyhow::Result<Scan> {
+    if !path.exists() {
+        anyhow::bail!("Path does not exist: {}", path_str);
+    }
+
+    let mut files: Vec<(PathBuf, Vec<String>)> = Vec::new();
+    let is_single_file = path.is_file();
+
+    // The 'name' field in the Info dictionary is either the filename
+    // or the name of the root directory.
+    let name = match &options.name {
+        Some(name) => name.clone(),
+        None => path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid path name"))?
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in path"))?
+            .to_string(),
     };
 
-    let bencoded = serde_bencode::to_bytes(&torrent)?;
+    if is_single_file {
+        files.push((path.to_path_buf(), Vec::new()));
+    } else {
+        // Recursively find all files in the folder.
+        // Hidden directories are pruned whole rather than walked and filtered.
+        let walker = WalkDir::new(path).into_iter().filter_entry(|e| {
+            e.depth() == 0
+                || options.include_hidden
+                || !e.file_name().to_string_lossy().starts_with('.')
+        });
+        for entry in walker {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let components = entry
+                .path()
+                .strip_prefix(path)?
+                .iter()
+                .map(|c| {
+                    c.to_str()
+                        .map(String::from)
+                        .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in path: {:?}", entry.path()))
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            let relative = components.join("/");
+            if options
+                .exclude
+                .iter()
+                .any(|pattern| utils::glob_match(pattern, &relative))
+            {
+                continue;
+            }
+            files.push((entry.path().to_path_buf(), components));
+        }
+    }
+
+    // Critical: Sort files to ensure the Info Hash is deterministic.
+    // If we process files in random order, the resulting hash will change,
+    // creating a different torrent swarm for the same data.
+    // Sort by the relative components written into the torrent, not the absolute path,
+    // so the order (and hash) doesn't depend on where the input happens to be mounted.
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+
+    if files.is_empty() {
+        anyhow::bail!("No files found in: {}", path_str);
+    }
+
+    // Sizes are taken once up front: they drive progress reporting and are exactly
+    // what gets hashed and written into the metadata
+    let file_lengths = files
+        .iter()
+        .map(|(file_path, _)| Ok(std::fs::metadata(file_path)?.len() as i64))
+        .collect::<anyhow::Result<Vec<i64>>>()?;
+
+    Ok(Scan {
+        name,
+        is_single_file,
+        files,
+        file_lengths,
+    })
+}
+
+/// Creates a single-file torrent from data piped on stdin.
+///
+/// The length isn't known until the stream ends, so `progress` is only called once,
+/// at the end. The name must come from `options.name`.
+fn create_from_stdin(
+    trackers: &[String],
+    output_path: &str,
+    options: &CreateOptions,
+    progress: impl FnMut(u64, u64),
+) -> anyhow::Result<()> {
+    info!("Hashing data from stdin");
+    create_from_reader(
+        std::io::stdin().lock(),
+        trackers,
+        output_path,
+        options,
+        progress,
+    )
+}
+
+/// Does the work of `create_from_stdin` on any byte stream.
+fn create_from_reader(
+    input: impl Read,
+    trackers: &[String],
+    output_path: &str,
+    options: &CreateOptions,
+    mut progress: impl FnMut(u64, u64),
+) -> anyhow::Result<()> {
+    let name = options
+        .name
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("--name is required when reading from stdin"))?;
+
+    let mut stdin = BufReader::with_capacity(READ_BUFFER_SIZE, input);
+    let mut hasher = Sha1::new();
+    let mut pieces = Vec::new();
+    let mut buffer = Vec::with_capacity(PIECE_LENGTH);
+    let mut md5 = options.md5.then(Md5::new);
+    let mut total_length = 0u64;
+    loop {
+        // Pipes deliver data in arbitrary chunks; `take` keeps reading until a whole
+        // piece is buffered or the stream ends
+        buffer.clear();
+        (&mut stdin)
+            .take(PIECE_LENGTH as u64)
+            .read_to_end(&mut buffer)?;
+        if buffer.is_empty() {
+            break;
+        }
+        hasher.update(&buffer);
+        pieces.extend_from_slice(&hasher.finalize_reset());
+        if let Some(md5) = &mut md5 {
+            md5.update(&buffer);
+        }
+        total_length += buffer.len() as u64;
+        if buffer.len() < PIECE_LENGTH {
+            break;
+        }
+    }
+    progress(total_length, total_length);
+
+    let info = Info {
+        name,
+        piece_length: PIECE_LENGTH,
+        pieces: serde_bytes::ByteBuf::from(pieces),
+        length: Some(total_length as i64),
+        md5sum: md5.map(|md5| hex::encode(md5.finalize())),
+        ..Info::default()
+    };
+    save_torrent(trackers, info, output_path)
+}
+
+/// Wraps `info` into a torrent, validates it and writes it to `output_path`.
+///
+/// The first tracker becomes `announce`. With more than one, all of them also go into
+/// `announce-list`, each in its own tier, in the order given.
+fn save_torrent(trackers: &[String], info: Info, output_path: &str) -> anyhow::Result<()> {
+    let announce = trackers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("At least one tracker is required"))?;
+    let announce_list =
+        (trackers.len() > 1).then(|| trackers.iter().map(|url| vec![url.clone()]).collect());
+    let torrent = Torrent::new(announce.clone(), announce_list, info);
+    torrent.validate()?;
+
     let mut out = File::create(output_path)?;
-    out.write_all(&bencoded)?;
+    out.write_all(&torrent.to_bytes()?)?;
 
-    println!("Torrent created successfully: {}", output_path);
+    info!("Torrent created successfully: {}", output_path);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::TempDir;
+
+    /// Writes `files` (relative path, contents) under a fresh input directory.
+    fn input(files: &[(&str, &[u8])]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for (path, contents) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    /// Creates a torrent from `path` and reads it back.
+    fn create(path: &Path, options: &CreateOptions) -> anyhow::Result<Torrent> {
+        let out = TempDir::new().unwrap();
+        let output = out.path().join("out.torrent");
+        let output = output.to_str().unwrap();
+        create_torrent_file(
+            path.to_str().unwrap(),
+            &["http://t/announce".to_string()],
+            output,
+            options,
+            |_, _| {},
+        )?;
+        Torrent::read(output)
+    }
+
+    /// The torrent's file paths and lengths, in order.
+    fn listed(torrent: &Torrent) -> Vec<(String, i64)> {
+        torrent
+            .info()
+            .files
+            .iter()
+            .flatten()
+            .map(|f| (f.path.join("/"), f.length))
+            .collect()
+    }
+
+    #[test]
+    fn plan_lists_what_create_hashes() {
+        let big = vec![1; PIECE_LENGTH + 10];
+        let dir = input(&[
+            ("b.bin", &big),
+            ("a/x.txt", b"hello"),
+            (".hidden", b"secret"),
+            ("skip.log", b"log"),
+        ]);
+        let options = CreateOptions {
+            exclude: vec!["*.log".to_string()],
+            ..CreateOptions::default()
+        };
+        let plan = plan_torrent(dir.path().to_str().unwrap(), &options).unwrap();
+        let expected = vec![
+            ("a/x.txt".to_string(), 5),
+            ("b.bin".to_string(), PIECE_LENGTH as u64 + 10),
+        ];
+        assert_eq!(plan.files, expected);
+        assert_eq!(plan.total_length, PIECE_LENGTH as u64 + 15);
+        assert_eq!(plan.piece_count, 2);
+
+        let torrent = create(dir.path(), &options).unwrap();
+        let listed: Vec<(String, u64)> = listed(&torrent)
+            .into_iter()
+            .map(|(path, length)| (path, length as u64))
+            .collect();
+        assert_eq!(listed, plan.files);
+        assert_eq!(torrent.info().pieces.len() as u64, plan.piece_count * 20);
+    }
+
+    #[test]
+    fn empty_files_are_kept() {
+        let dir = input(&[("a", b""), ("b", b"data"), ("c/d", b"")]);
+        let torrent = create(dir.path(), &CreateOptions::default()).unwrap();
+        assert_eq!(
+            listed(&torrent),
+            vec![("a".into(), 0), ("b".into(), 4), ("c/d".into(), 0)]
+        );
+        assert_eq!(torrent.info().pieces.len(), 20);
+    }
+
+    #[test]
+    fn directory_without_files_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        let error = create(dir.path(), &CreateOptions::default()).unwrap_err();
+        assert!(error.to_string().starts_with("No files found"), "{}", error);
+    }
+
+    #[test]
+    fn hidden_files_are_skipped_unless_included() {
+        let dir = input(&[
+            ("a", b"1"),
+            (".env", b"2"),
+            (".git/config", b"3"),
+            ("b/.x", b"4"),
+        ]);
+        let torrent = create(dir.path(), &CreateOptions::default()).unwrap();
+        assert_eq!(listed(&torrent), vec![("a".into(), 1)]);
+
+        let options = CreateOptions {
+            include_hidden: true,
+            ..CreateOptions::default()
+        };
+        let torrent = create(dir.path(), &options).unwrap();
+        assert_eq!(
+            listed(&torrent),
+            vec![
+                (".env".into(), 1),
+                (".git/config".into(), 1),
+                ("a".into(), 1),
+                ("b/.x".into(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn excluded_files_are_skipped() {
+        let dir = input(&[
+            ("a.log", b"1"),
+            ("b.txt", b"2"),
+            ("logs/c.log", b"3"),
+            ("tmp/d", b"4"),
+        ]);
+        let options = CreateOptions {
+            exclude: vec!["*.log".to_string(), "tmp/*".to_string()],
+            ..CreateOptions::default()
+        };
+        let torrent = create(dir.path(), &options).unwrap();
+        assert_eq!(listed(&torrent), vec![("b.txt".into(), 1)]);
+    }
+
+    #[test]
+    fn nested_files_are_ordered_by_path_components() {
+        let dir = input(&[
+            ("b/a", b"1"),
+            ("a.txt", b"2"),
+            ("a/z", b"3"),
+            ("a/b/c", b"4"),
+        ]);
+        let torrent = create(dir.path(), &CreateOptions::default()).unwrap();
+        // Component by component, so "a/..." sorts before "a.txt" even though '.' < '/'
+        let paths: Vec<String> = listed(&torrent).into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["a/b/c", "a/z", "a.txt", "b/a"]);
+    }
+
+    #[test]
+    fn buffered_hashes_match_hashing_everything_at_once() {
+        let mut rng = StdRng::seed_from_u64(563);
+        let contents: Vec<Vec<u8>> = [300_000, 1, 0, PIECE_LENGTH, 123_457]
+            .iter()
+            .map(|&length| {
+                let mut data = vec![0; length];
+                rng.fill_bytes(&mut data);
+                data
+            })
+            .collect();
+        let names = ["a", "b", "c", "d", "e"];
+        let files: Vec<(&str, &[u8])> = names
+            .iter()
+            .zip(&contents)
+            .map(|(name, data)| (*name, data.as_slice()))
+            .collect();
+        let dir = input(&files);
+        let torrent = create(dir.path(), &CreateOptions::default()).unwrap();
+
+        // What the unbuffered creator did: the whole stream in memory, cut into pieces
+        let stream = contents.concat();
+        let expected: Vec<u8> = stream
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        assert_eq!(torrent.info().pieces.as_ref(), expected.as_slice());
+    }
+
+    #[test]
+    fn progress_only_grows_and_ends_at_the_total() {
+        let dir = input(&[
+            ("a", &vec![1; PIECE_LENGTH + 10]),
+            ("b", b""),
+            ("c", &vec![2; 2 * PIECE_LENGTH - 3]),
+        ]);
+        let total = (3 * PIECE_LENGTH + 7) as u64;
+        let out = TempDir::new().unwrap();
+        let output = out.path().join("out.torrent");
+        let mut reports = Vec::new();
+        create_torrent_file(
+            dir.path().to_str().unwrap(),
+            &["http://t/announce".to_string()],
+            output.to_str().unwrap(),
+            &CreateOptions::default(),
+            |done, of| reports.push((done, of)),
+        )
+        .unwrap();
+
+        assert!(reports.iter().all(|&(_, of)| of == total));
+        assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert_eq!(reports.first().unwrap().0, 0);
+        assert_eq!(reports.last().unwrap().0, total);
+        // One report per whole piece in between
+        let whole: Vec<u64> = reports
+            .iter()
+            .map(|&(done, _)| done)
+            .filter(|&done| done > 0 && done < total)
+            .collect();
+        let piece = PIECE_LENGTH as u64;
+        assert_eq!(whole, vec![piece, 2 * piece, 3 * piece]);
+    }
+
+    /// Hands out at most 1000 bytes per read, like a pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn stdin_and_file_inputs_produce_the_same_torrent() {
+        let mut data = vec![0u8; 2 * PIECE_LENGTH + 12345];
+        StdRng::seed_from_u64(7).fill_bytes(&mut data);
+        let dir = input(&[("data.bin", &data)]);
+        let trackers = ["http://t/announce".to_string()];
+
+        for md5 in [false, true] {
+            let options = CreateOptions {
+                md5,
+                ..CreateOptions::default()
+            };
+            let from_file = dir.path().join("file.torrent");
+            create_torrent_file(
+                dir.path().join("data.bin").to_str().unwrap(),
+                &trackers,
+                from_file.to_str().unwrap(),
+                &options,
+                |_, _| {},
+            )
+            .unwrap();
+
+            let from_stdin = dir.path().join("stdin.torrent");
+            let options = CreateOptions {
+                name: Some("data.bin".to_string()),
+                ..options
+            };
+            create_from_reader(
+                Trickle(&data),
+                &trackers,
+                from_stdin.to_str().unwrap(),
+                &options,
+                |_, _| {},
+            )
+            .unwrap();
+            assert_eq!(
+                fs::read(&from_stdin).unwrap(),
+                fs::read(&from_file).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn resumed_run_gives_the_same_pieces() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut data = vec![0u8; 5 * PIECE_LENGTH + 1000];
+        rng.fill_bytes(&mut data);
+        let (head, tail) = data.split_at(2 * PIECE_LENGTH + 123);
+        let dir = input(&[("a.bin", head), ("b.bin", tail)]);
+        let options = CreateOptions::default();
+        let expected = create(dir.path(), &options).unwrap().info().pieces.clone();
+
+        // An interrupted run that got through three pieces
+        let out = TempDir::new().unwrap();
+        let output = out.path().join("out.torrent");
+        let checkpoint = out.path().join("out.torrent.checkpoint");
+        let path_str = dir.path().to_str().unwrap();
+        let scan = scan_input(dir.path(), path_str, &options).unwrap();
+        let described = CheckpointInput::describe(&scan.files, &scan.file_lengths).unwrap();
+        save_checkpoint(&checkpoint, &described, &expected[..3 * 20]).unwrap();
+
+        let mut first_progress = None;
+        create_torrent_file(
+            path_str,
+            &["http://t/announce".to_string()],
+            output.to_str().unwrap(),
+            &options,
+            |hashed, _| {
+                first_progress.get_or_insert(hashed);
+            },
+        )
+        .unwrap();
+        assert_eq!(first_progress, Some(3 * PIECE_LENGTH as u64));
+        let resumed = Torrent::read(output.to_str().unwrap()).unwrap();
+        assert_eq!(resumed.info().pieces, expected);
+        assert!(!checkpoint.exists());
+    }
+}
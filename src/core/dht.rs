@@ -0,0 +1,551 @@
+use anyhow::Context;
+use serde_bencode::value::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant, timeout_at};
+use tracing::{debug, info, trace};
+
+/// Well-known routers used to join the DHT when the routing table is empty.
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Bucket size from the Kademlia paper: a lookup converges on the K closest nodes.
+const K: usize = 8;
+
+/// Queries sent in parallel per lookup round.
+const ALPHA: usize = 3;
+
+/// Rounds per lookup, bounding how long a `get_peers` call can take.
+const MAX_LOOKUP_ROUNDS: usize = 6;
+
+/// Time a node gets to answer one query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Responsive nodes remembered between lookups.
+const MAX_KNOWN_NODES: usize = 512;
+
+/// Compact node info: 20-byte ID followed by a compact IPv4 address.
+const COMPACT_NODE_LEN: usize = 26;
+
+/// How often each torrent is looked up in the DHT. A lookup sends up to
+/// `MAX_LOOKUP_ROUNDS * ALPHA` queries, far too many to repeat on every tracker round.
+pub const LOOKUP_INTERVAL: Duration = Duration::from_secs(20 * 60);
+
+/// KRPC error code for queries we don't implement (BEP 5).
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+pub type NodeId = [u8; 20];
+
+/// Identifies an outstanding query: its transaction ID and the address it was sent to.
+type QueryKey = (Vec<u8>, SocketAddr);
+
+/// Kademlia distance between two IDs: their XOR, compared as a big-endian number.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut out = [0u8; 20];
+    for (o, (x, y)) in out.iter_mut().zip(a.iter().zip(b)) {
+        *o = x ^ y;
+    }
+    out
+}
+
+/// A minimal mainline DHT node (BEP 5), used to find peers without a tracker.
+///
+/// Lookups are iterative `get_peers` queries that walk towards the info hash,
+/// collecting peers from every node that has them. Incoming `ping`, `find_node` and
+/// `get_peers` queries are answered from the nodes we know; we don't store peers for
+/// others, so `announce_peer` is refused.
+pub struct Dht {
+    id: NodeId,
+    socket: UdpSocket,
+    /// Outstanding queries by random transaction ID and the address queried, completed
+    /// by the receive loop. A reply only counts if it comes from that address, so other
+    /// hosts can't inject nodes or peers by guessing IDs.
+    pending: Mutex<HashMap<QueryKey, oneshot::Sender<Value>>>,
+    /// Nodes that have answered us, used to start the next lookup closer to the target.
+    nodes: Mutex<HashMap<NodeId, SocketAddr>>,
+}
+
+impl Dht {
+    /// Binds the DHT socket on `port` (UDP) of `address` and starts answering queries.
+    pub async fn bind(address: Ipv4Addr, port: u16) -> anyhow::Result<Arc<Self>> {
+        let socket = UdpSocket::bind((address, port))
+            .await
+            .context(format!("Failed to bind DHT port {}", port))?;
+        let dht = Arc::new(Self {
+            id: rand::random(),
+            socket,
+            pending: Mutex::new(HashMap::new()),
+            nodes: Mutex::new(HashMap::new()),
+        });
+        info!("DHT node listening on UDP port {}", port);
+
+        let receiver = dht.clone();
+        tokio::spawn(async move { receiver.receive_loop().await });
+        Ok(dht)
+    }
+
+    /// Finds peers for `info_hash`, returned as `ip:port` strings like tracker peers.
+    ///
+    /// `extra_nodes` (e.g. the torrent's `nodes` key) are queried alongside the
+    /// bootstrap routers when we don't yet know anyone close to the target.
+    pub async fn get_peers(&self, info_hash: &[u8; 20], extra_nodes: &[String]) -> Vec<String> {
+        // Candidates sorted by distance to the target
+        let mut candidates: BTreeMap<NodeId, SocketAddr> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, &addr)| (distance(id, info_hash), addr))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+
+        // Nodes without a known ID are queried first; their replies seed the candidates
+        let mut unsorted = Vec::new();
+        if candidates.len() < K {
+            for host in BOOTSTRAP_NODES
+                .iter()
+                .copied()
+                .chain(extra_nodes.iter().map(String::as_str))
+            {
+                match tokio::net::lookup_host(host).await {
+                    Ok(addrs) => unsorted.extend(addrs.filter(SocketAddr::is_ipv4)),
+                    Err(e) => debug!("DHT: failed to resolve {}: {}", host, e),
+                }
+            }
+        }
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            let mut batch: Vec<SocketAddr> = std::mem::take(&mut unsorted);
+            batch.extend(
+                candidates
+                    .values()
+                    .filter(|addr| !queried.contains(*addr))
+                    .take(ALPHA)
+                    .copied(),
+            );
+            batch.retain(|addr| queried.insert(*addr));
+            if batch.is_empty() {
+                break;
+            }
+
+            let args = vec![("id", bytes(&self.id)), ("info_hash", bytes(info_hash))];
+            for reply in self.query_all(&batch, "get_peers", args).await {
+                for peer in get_list(&reply, "values").unwrap_or_default() {
+                    if let Value::Bytes(compact) = peer
+                        && let Some(addr) = parse_compact_addr(compact)
+                    {
+                        peers.insert(addr.to_string());
+                    }
+                }
+                if let Some(compact) = get_bytes(&reply, "nodes") {
+                    for (id, addr) in parse_compact_nodes(compact) {
+                        candidates.insert(distance(&id, info_hash), addr);
+                    }
+                }
+            }
+
+            // Converged: the K closest nodes we know of have all been asked
+            if candidates
+                .values()
+                .take(K)
+                .all(|addr| queried.contains(addr))
+            {
+                break;
+            }
+        }
+
+        debug!(
+            "DHT lookup queried {} nodes, found {} peers",
+            queried.len(),
+            peers.len()
+        );
+        peers.into_iter().collect()
+    }
+
+    /// Sends `method` to every address and returns the `r` dictionaries of the replies
+    /// that arrive within `QUERY_TIMEOUT`.
+    async fn query_all(
+        &self,
+        addrs: &[SocketAddr],
+        method: &str,
+        args: Vec<(&str, Value)>,
+    ) -> Vec<Value> {
+        let mut waiting = Vec::new();
+        for &addr in addrs {
+            let transaction = rand::random::<[u8; 4]>().to_vec();
+            let message = dict(vec![
+                ("t", bytes(&transaction)),
+                ("y", bytes(b"q")),
+                ("q", bytes(method.as_bytes())),
+                ("a", dict(args.clone())),
+            ]);
+            let Ok(encoded) = serde_bencode::to_bytes(&message) else {
+                continue;
+            };
+
+            let key = (transaction, addr);
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().unwrap().insert(key.clone(), tx);
+            if let Err(e) = self.socket.send_to(&encoded, addr).await {
+                trace!("DHT: send to {} failed: {}", addr, e);
+                self.pending.lock().unwrap().remove(&key);
+                continue;
+            }
+            waiting.push((key, rx));
+        }
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut replies = Vec::new();
+        for (key, rx) in waiting {
+            let addr = key.1;
+            match timeout_at(deadline, rx).await {
+                Ok(Ok(reply)) => {
+                    if let Some(id) = get_bytes(&reply, "id").and_then(|id| id.try_into().ok()) {
+                        self.remember(id, addr);
+                    }
+                    replies.push(reply);
+                }
+                _ => {
+                    self.pending.lock().unwrap().remove(&key);
+                }
+            }
+        }
+        replies
+    }
+
+    fn remember(&self, id: NodeId, addr: SocketAddr) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.len() < MAX_KNOWN_NODES || nodes.contains_key(&id) {
+            nodes.insert(id, addr);
+        }
+    }
+
+    /// Dispatches replies to waiting queries and answers queries from other nodes.
+    async fn receive_loop(&self) {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    trace!("DHT: receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Ok(message) = serde_bencode::from_bytes::<Value>(&buf[..len]) else {
+                trace!("DHT: undecodable message from {}", from);
+                continue;
+            };
+            let Some(transaction) = get_bytes(&message, "t").map(<[u8]>::to_vec) else {
+                continue;
+            };
+
+            match get_bytes(&message, "y") {
+                Some(b"r") => {
+                    let waiter = self.pending.lock().unwrap().remove(&(transaction, from));
+                    if let (Some(tx), Some(reply)) = (waiter, get(&message, "r")) {
+                        let _ = tx.send(reply.clone());
+                    }
+                }
+                Some(b"e") => {
+                    // Dropping the sender fails the query right away
+                    self.pending.lock().unwrap().remove(&(transaction, from));
+                }
+                Some(b"q") => {
+                    let reply = self.answer(&message, &transaction);
+                    if let Ok(encoded) = serde_bencode::to_bytes(&reply) {
+                        let _ = self.socket.send_to(&encoded, from).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Builds the reply to an incoming query.
+    fn answer(&self, message: &Value, transaction: &[u8]) -> Value {
+        let method = get_bytes(message, "q").unwrap_or_default();
+        let args = get(message, "a");
+        let target = match method {
+            b"find_node" => args.and_then(|a| get_bytes(a, "target")),
+            b"get_peers" => args.and_then(|a| get_bytes(a, "info_hash")),
+            _ => None,
+        };
+
+        let mut response = vec![("id", bytes(&self.id))];
+        match method {
+            b"ping" => {}
+            b"find_node" | b"get_peers" => {
+                let target: NodeId = target.and_then(|t| t.try_into().ok()).unwrap_or(self.id);
+                response.push(("nodes", bytes(&self.closest_compact(&target))));
+                if method == b"get_peers" {
+                    response.push(("token", bytes(&rand::random::<[u8; 4]>())));
+                }
+            }
+            _ => {
+                return dict(vec![
+                    ("t", bytes(transaction)),
+                    ("y", bytes(b"e")),
+                    (
+                        "e",
+                        Value::List(vec![
+                            Value::Int(ERROR_METHOD_UNKNOWN),
+                            bytes(b"Method Unknown"),
+                        ]),
+                    ),
+                ]);
+            }
+        }
+        dict(vec![
+            ("t", bytes(transaction)),
+            ("y", bytes(b"r")),
+            ("r", dict(response)),
+        ])
+    }
+
+    /// The K known nodes closest to `target`, in compact node info form.
+    fn closest_compact(&self, target: &NodeId) -> Vec<u8> {
+        let nodes = self.nodes.lock().unwrap();
+        let mut sorted: Vec<(&NodeId, &SocketAddr)> = nodes.iter().collect();
+        sorted.sort_by_key(|(id, _)| distance(id, target));
+
+        let mut compact = Vec::with_capacity(K * COMPACT_NODE_LEN);
+        for (id, addr) in sorted.into_iter().take(K) {
+            if let SocketAddr::V4(v4) = addr {
+                compact.extend_from_slice(id);
+                compact.extend_from_slice(&v4.ip().octets());
+                compact.extend_from_slice(&v4.port().to_be_bytes());
+            }
+        }
+        compact
+    }
+}
+
+fn bytes(value: &[u8]) -> Value {
+    Value::Bytes(value.to_vec())
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Dict(map) => map.get(key.as_bytes()),
+        _ => None,
+    }
+}
+
+fn get_bytes<'a>(value: &'a Value, key: &str) -> Option<&'a [u8]> {
+    match get(value, key)? {
+        Value::Bytes(b) => Some(b),
+        _ => None,
+    }
+}
+
+fn get_list<'a>(value: &'a Value, key: &str) -> Option<&'a [Value]> {
+    match get(value, key)? {
+        Value::List(list) => Some(list),
+        _ => None,
+    }
+}
+
+/// Parses a 6-byte compact IPv4 address (4 byte IP + 2 byte port).
+fn parse_compact_addr(data: &[u8]) -> Option<SocketAddr> {
+    let data: [u8; 6] = data.try_into().ok()?;
+    let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+    let port = u16::from_be_bytes([data[4], data[5]]);
+    (port != 0).then(|| SocketAddr::V4(SocketAddrV4::new(ip, port)))
+}
+
+/// Parses concatenated 26-byte compact node infos.
+fn parse_compact_nodes(data: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    data.chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            let id: NodeId = chunk[..20].try_into().ok()?;
+            Some((id, parse_compact_addr(&chunk[20..])?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first: u8, last: u8) -> NodeId {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        id[19] = last;
+        id
+    }
+
+    /// Sends one raw KRPC message to `dht` and decodes the reply.
+    async fn exchange(dht: &Dht, message: &[u8]) -> Value {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(message, dht.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 2048];
+        let (len, _) = tokio::time::timeout(QUERY_TIMEOUT, client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        serde_bencode::from_bytes(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn distance_is_xor_ordered_big_endian() {
+        let a = id(0x0f, 0x01);
+        assert_eq!(distance(&a, &a), [0; 20]);
+        assert_eq!(distance(&a, &id(0xf0, 0x01)), id(0xff, 0));
+        assert_eq!(distance(&a, &id(0, 0)), distance(&id(0, 0), &a));
+        // A difference in the first byte outweighs any in the last
+        assert!(distance(&a, &id(0x0e, 0x01)) > distance(&a, &id(0x0f, 0xfe)));
+    }
+
+    #[test]
+    fn compact_nodes_skip_partial_entries_and_port_zero() {
+        let mut data = id(1, 1).to_vec();
+        data.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        data.extend_from_slice(&id(2, 2));
+        data.extend_from_slice(&[10, 0, 0, 2, 0, 0]);
+        data.extend_from_slice(&[0; 10]);
+        assert_eq!(
+            parse_compact_nodes(&data),
+            vec![(id(1, 1), "10.0.0.1:6881".parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_ping_and_find_node() {
+        let dht = Dht::bind(Ipv4Addr::LOCALHOST, 0).await.unwrap();
+        dht.remember(id(0x80, 0), "10.0.0.1:6881".parse().unwrap());
+        dht.remember(id(0x01, 0), "10.0.0.2:6881".parse().unwrap());
+
+        let reply = exchange(
+            &dht,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+        )
+        .await;
+        assert_eq!(get_bytes(&reply, "t"), Some(&b"aa"[..]));
+        assert_eq!(get_bytes(&reply, "y"), Some(&b"r"[..]));
+        assert_eq!(
+            get_bytes(get(&reply, "r").unwrap(), "id"),
+            Some(&dht.id[..])
+        );
+
+        let mut query = b"d1:ad2:id20:abcdefghij01234567896:target20:".to_vec();
+        query.extend_from_slice(&id(0x02, 0));
+        query.extend_from_slice(b"e1:q9:find_node1:t2:bb1:y1:qe");
+        let reply = exchange(&dht, &query).await;
+        let nodes = get_bytes(get(&reply, "r").unwrap(), "nodes").unwrap();
+        // Closest to the target first
+        assert_eq!(
+            parse_compact_nodes(nodes),
+            vec![
+                (id(0x01, 0), "10.0.0.2:6881".parse().unwrap()),
+                (id(0x80, 0), "10.0.0.1:6881".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_methods() {
+        let dht = Dht::bind(Ipv4Addr::LOCALHOST, 0).await.unwrap();
+        let reply = exchange(
+            &dht,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q13:announce_peer1:t2:cc1:y1:qe",
+        )
+        .await;
+        assert_eq!(get_bytes(&reply, "y"), Some(&b"e"[..]));
+        assert_eq!(
+            get_list(&reply, "e").unwrap()[0],
+            Value::Int(ERROR_METHOD_UNKNOWN)
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_decode_replies_and_remember_the_node() {
+        let dht = Dht::bind(Ipv4Addr::LOCALHOST, 0).await.unwrap();
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let (len, from) = node.recv_from(&mut buf).await.unwrap();
+            let query: Value = serde_bencode::from_bytes(&buf[..len]).unwrap();
+            let mut compact_peer = vec![10, 0, 0, 9];
+            compact_peer.extend_from_slice(&6881u16.to_be_bytes());
+            let reply = dict(vec![
+                ("t", bytes(get_bytes(&query, "t").unwrap())),
+                ("y", bytes(b"r")),
+                (
+                    "r",
+                    dict(vec![
+                        ("id", bytes(&id(7, 7))),
+                        ("values", Value::List(vec![bytes(&compact_peer)])),
+                    ]),
+                ),
+            ]);
+            node.send_to(&serde_bencode::to_bytes(&reply).unwrap(), from)
+                .await
+                .unwrap();
+            query
+        });
+
+        let args = vec![("id", bytes(&dht.id)), ("info_hash", bytes(&[5; 20]))];
+        let replies = dht.query_all(&[node_addr], "get_peers", args).await;
+        assert_eq!(replies.len(), 1);
+        let values = get_list(&replies[0], "values").unwrap();
+        assert_eq!(values, [bytes(&[10, 0, 0, 9, 0x1a, 0xe1])]);
+        assert_eq!(dht.nodes.lock().unwrap().get(&id(7, 7)), Some(&node_addr));
+
+        let query = responder.await.unwrap();
+        assert_eq!(get_bytes(&query, "y"), Some(&b"q"[..]));
+        assert_eq!(get_bytes(&query, "q"), Some(&b"get_peers"[..]));
+        let args = get(&query, "a").unwrap();
+        assert_eq!(get_bytes(args, "info_hash"), Some(&[5; 20][..]));
+        assert_eq!(get_bytes(args, "id"), Some(&dht.id[..]));
+    }
+
+    #[tokio::test]
+    async fn replies_from_other_addresses_are_ignored() {
+        let dht = Dht::bind(Ipv4Addr::LOCALHOST, 0).await.unwrap();
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let (len, from) = node.recv_from(&mut buf).await.unwrap();
+            let query: Value = serde_bencode::from_bytes(&buf[..len]).unwrap();
+            // Someone else answers with the right transaction ID; the node stays quiet
+            let reply = dict(vec![
+                ("t", bytes(get_bytes(&query, "t").unwrap())),
+                ("y", bytes(b"r")),
+                ("r", dict(vec![("id", bytes(&id(6, 6)))])),
+            ]);
+            spoofer
+                .send_to(&serde_bencode::to_bytes(&reply).unwrap(), from)
+                .await
+                .unwrap();
+            node
+        });
+
+        let args = vec![("id", bytes(&dht.id)), ("info_hash", bytes(&[5; 20]))];
+        let replies = dht.query_all(&[node_addr], "get_peers", args).await;
+        assert!(replies.is_empty());
+        assert!(dht.nodes.lock().unwrap().is_empty());
+        assert!(dht.pending.lock().unwrap().is_empty());
+    }
+}
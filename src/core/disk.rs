@@ -0,0 +1,151 @@
+use crate::core::manager::TorrentManager;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Number of verified pieces that may be queued before senders wait on the writer.
+const WRITE_QUEUE_DEPTH: usize = 32;
+
+/// A verified piece waiting to be written to disk.
+pub struct WriteRequest {
+    pub piece_index: usize,
+    pub data: Vec<u8>,
+}
+
+/// Handle used by peer sessions to hand verified pieces to the disk-writer task.
+///
+/// Sessions send the piece and return to the network immediately; the writer performs
+/// the (possibly multi-file) scatter write without touching piece state, and only
+/// then marks the piece complete. This keeps slow disk I/O off the hot path.
+#[derive(Clone)]
+pub struct DiskWriter {
+    tx: mpsc::Sender<WriteRequest>,
+}
+
+impl DiskWriter {
+    /// Spawns the dedicated writer task for a torrent and returns a handle to it.
+    pub fn spawn(manager: Arc<TorrentManager>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<WriteRequest>(WRITE_QUEUE_DEPTH);
+
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let index = request.piece_index;
+
+                // Report back: the piece only counts as complete once it is on disk
+                match Self::perform_write(&manager, request).await {
+                    Ok(()) => {
+                        manager.mark_piece_complete(index);
+                        if manager.is_complete() {
+                            let storage = manager.storage.clone();
+                            match tokio::task::spawn_blocking(move || {
+                                storage.move_to_complete_dir()
+                            })
+                            .await
+                            {
+                                Ok(Ok(_)) => {}
+                                Ok(Err(e)) => warn!("{:#}", e),
+                                Err(e) => warn!("Moving completed files failed: {}", e),
+                            }
+                        }
+                    }
+                    Err(e) => manager.report_disk_error(index, &e),
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// Queues a verified piece for writing.
+    pub async fn write(&self, piece_index: usize, data: Vec<u8>) -> anyhow::Result<()> {
+        self.tx
+            .send(WriteRequest { piece_index, data })
+            .await
+            .map_err(|_| anyhow::anyhow!("Disk writer has shut down"))
+    }
+
+    /// Validates and performs a single write on the blocking thread pool.
+    async fn perform_write(manager: &TorrentManager, request: WriteRequest) -> anyhow::Result<()> {
+        let piece_len = manager.torrent.calculate_piece_size(request.piece_index)? as usize;
+
+        // Safety check to ensure network logic delivered the correct amount of data
+        if request.data.len() != piece_len {
+            anyhow::bail!(
+                "Data length mismatch. Expected {}, got {}",
+                piece_len,
+                request.data.len()
+            );
+        }
+
+        let storage = manager.storage.clone();
+        tokio::task::spawn_blocking(move || storage.write_piece(request.piece_index, &request.data))
+            .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::bitfield::Bitfield;
+    use crate::core::manager::TorrentEvent;
+    use crate::core::test_util::manager_for;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use tokio::time::{Duration, timeout};
+
+    /// A two-piece, 24-byte torrent downloading into `output_dir`.
+    fn manager_in(output_dir: &Path) -> Arc<TorrentManager> {
+        manager_for(output_dir, &[1; 24], 16)
+    }
+
+    #[tokio::test]
+    async fn written_piece_lands_on_disk_and_completes() {
+        let dir = TempDir::new().unwrap();
+        let manager = manager_in(dir.path());
+        let mut events = manager.subscribe();
+        let writer = DiskWriter::spawn(manager.clone());
+
+        writer.write(1, vec![7; 8]).await.unwrap();
+        let event = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            event,
+            Ok(TorrentEvent::PieceCompleted { index: 1 })
+        ));
+
+        assert!(manager.has_piece(1));
+        assert!(!manager.has_piece(0));
+        // Still staged under `.part` until the whole file is there
+        let on_disk = std::fs::read(dir.path().join("data.part")).unwrap();
+        assert_eq!(&on_disk[16..], &[7; 8]);
+    }
+
+    #[tokio::test]
+    async fn failed_write_leaves_the_piece_pending() {
+        let dir = TempDir::new().unwrap();
+        // A regular file where the output directory should be: every open fails
+        let blocker = dir.path().join("blocker");
+        std::fs::write(&blocker, b"").unwrap();
+        let manager = manager_in(&blocker.join("out"));
+        let mut events = manager.subscribe();
+        let writer = DiskWriter::spawn(manager.clone());
+
+        let mut peer = Bitfield::new(2);
+        peer.set(0, true);
+        assert_eq!(manager.pick_next_piece(&peer), Some(0));
+        writer.write(0, vec![7; 16]).await.unwrap();
+        let event = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap();
+        assert!(
+            matches!(&event, Ok(TorrentEvent::DiskError { message }) if message.contains("piece 0")),
+            "{:?}",
+            event
+        );
+
+        assert!(!manager.has_piece(0));
+        // Back in the pool rather than stuck in progress
+        assert_eq!(manager.pick_next_piece(&peer), Some(0));
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Default cap on simultaneously open file handles per torrent.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// A small LRU cache of open file handles keyed by path.
+///
+/// Piece reads and writes repeatedly touch the same handful of files. Reopening the
+/// file for every piece costs several syscalls each time, so handles are kept open and
+/// reused. Once `capacity` is reached, the least recently used handle is closed.
+///
+/// Handles written since their last `sync_all()` are tracked as dirty so the
+/// sync policy can flush them in one batch (and before eviction closes them).
+///
+/// Handles are shared (`Arc<File>`), so callers only need the cache for the lookup and
+/// can do the actual I/O on their clone without holding whatever lock guards the cache.
+pub struct FileCache {
+    capacity: usize,
+    /// Open handles, each with the generation of its last use.
+    handles: HashMap<PathBuf, (Arc<File>, u64)>,
+    /// Paths by the generation of their last use, least recently used first.
+    order: BTreeMap<u64, PathBuf>,
+    generation: u64,
+    dirty: HashSet<PathBuf>,
+    /// Files opened so far, including reopens after eviction.
+    opens: u64,
+    /// `sync_all()` calls made so far.
+    syncs: u64,
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            handles: HashMap::new(),
+            order: BTreeMap::new(),
+            generation: 0,
+            dirty: HashSet::new(),
+            opens: 0,
+            syncs: 0,
+        }
+    }
+
+    /// Number of times a file has been opened.
+    #[cfg(test)]
+    pub fn opens(&self) -> u64 {
+        self.opens
+    }
+
+    /// Number of times a file has been synced.
+    #[cfg(test)]
+    pub fn syncs(&self) -> u64 {
+        self.syncs
+    }
+
+    /// Returns an open read/write handle for `path`, opening (and creating) it if needed.
+    pub fn get(&mut self, path: &Path) -> std::io::Result<Arc<File>> {
+        self.generation += 1;
+        let generation = self.generation;
+        if let Some((_, used)) = self.handles.get_mut(path) {
+            // Mark as most recently used
+            let p = self.order.remove(used).unwrap();
+            self.order.insert(generation, p);
+            *used = generation;
+        } else {
+            if self.handles.len() >= self.capacity
+                && let Some((_, oldest)) = self.order.pop_first()
+                && let Some((file, _)) = self.handles.remove(&oldest)
+                && self.dirty.remove(&oldest)
+            {
+                // Don't lose unsynced writes just because the handle is being closed
+                file.sync_all()?;
+                self.syncs += 1;
+            }
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            self.opens += 1;
+            self.handles
+                .insert(path.to_path_buf(), (Arc::new(file), generation));
+            self.order.insert(generation, path.to_path_buf());
+        }
+
+        Ok(Arc::clone(&self.handles[path].0))
+    }
+
+    /// Records that `path` has unsynced writes. Returns false if its handle is no
+    /// longer cached (evicted or closed while the write was in flight); the caller
+    /// must then sync its own clone.
+    pub fn mark_dirty(&mut self, path: &Path) -> bool {
+        if !self.handles.contains_key(path) {
+            return false;
+        }
+        self.dirty.insert(path.to_path_buf());
+        true
+    }
+
+    /// Takes the dirty handles, counting them as synced. The caller runs `sync_all()`
+    /// on them, typically after releasing the lock guarding the cache.
+    pub fn take_dirty(&mut self) -> Vec<Arc<File>> {
+        let files: Vec<Arc<File>> = std::mem::take(&mut self.dirty)
+            .iter()
+            .filter_map(|path| self.handles.get(path))
+            .map(|(file, _)| Arc::clone(file))
+            .collect();
+        self.syncs += files.len() as u64;
+        files
+    }
+
+    /// Closes the handle for `path` (syncing it first if dirty), e.g. once the file has moved.
+    pub fn close(&mut self, path: &Path) -> std::io::Result<()> {
+        if let Some((file, used)) = self.handles.remove(path) {
+            if self.dirty.remove(path) {
+                file.sync_all()?;
+                self.syncs += 1;
+            }
+            self.order.remove(&used);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn reuses_the_handle_for_repeated_writes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a");
+        let mut cache = FileCache::new(4);
+        for _ in 0..100 {
+            cache.get(&path).unwrap();
+        }
+        assert_eq!(cache.opens(), 1);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_handle() {
+        let dir = TempDir::new().unwrap();
+        let paths: Vec<PathBuf> = (0..3).map(|i| dir.path().join(i.to_string())).collect();
+        let mut cache = FileCache::new(2);
+        cache.get(&paths[0]).unwrap();
+        cache.get(&paths[1]).unwrap();
+        // Touching 0 makes 1 the oldest, so 2 takes its place
+        cache.get(&paths[0]).unwrap();
+        cache.get(&paths[2]).unwrap();
+        assert_eq!(cache.opens(), 3);
+        cache.get(&paths[0]).unwrap();
+        assert_eq!(cache.opens(), 3);
+        cache.get(&paths[1]).unwrap();
+        assert_eq!(cache.opens(), 4);
+        assert_eq!(cache.handles.len(), 2);
+        assert_eq!(cache.order.len(), 2);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::core::session::Session;
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, sleep};
+use tracing::{debug, info, trace};
+
+/// BEP 14 IPv4 multicast group and port.
+const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_PORT: u16 = 6771;
+
+/// How often every active torrent is announced on the LAN. BEP 14 asks clients not
+/// to announce a torrent more than once a minute.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A parsed `BT-SEARCH` announcement.
+#[derive(Debug)]
+struct Announcement {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl Announcement {
+    /// Address to connect to: the sender's IP with the port it announced.
+    fn peer(&self, from: SocketAddr) -> String {
+        SocketAddr::new(from.ip(), self.port).to_string()
+    }
+}
+
+/// Local Service Discovery (BEP 14): finds peers on the LAN via multicast.
+///
+/// Every torrent in the session is announced periodically, and announcements from
+/// other clients for a torrent we have turn into outbound connections right away.
+pub struct Lsd {
+    socket: UdpSocket,
+    session: Arc<Session>,
+    /// Sent with our announcements so we can recognise (and ignore) our own echoes.
+    cookie: String,
+}
+
+impl Lsd {
+    /// Joins the multicast group and starts the announce and receive tasks, which run
+    /// for the rest of the process.
+    pub fn start(session: Arc<Session>) -> anyhow::Result<()> {
+        // Other clients on this host listen on the same port, hence SO_REUSEADDR
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())
+            .context(format!("Failed to bind LSD port {}", LSD_PORT))?;
+        socket
+            .join_multicast_v4(&LSD_GROUP, &Ipv4Addr::UNSPECIFIED)
+            .context("Failed to join the LSD multicast group")?;
+
+        let lsd = Arc::new(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            session,
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+        });
+        info!(
+            "Local Service Discovery enabled on {}:{}",
+            LSD_GROUP, LSD_PORT
+        );
+
+        let announcer = lsd.clone();
+        tokio::spawn(async move {
+            loop {
+                announcer.announce().await;
+                sleep(ANNOUNCE_INTERVAL).await;
+            }
+        });
+        tokio::spawn(async move { lsd.receive_loop().await });
+        Ok(())
+    }
+
+    /// Announces every torrent that isn't paused, in one message.
+    async fn announce(&self) {
+        let info_hashes: Vec<[u8; 20]> = self
+            .session
+            .torrents()
+            .iter()
+            .filter(|handle| !handle.manager.is_paused())
+            .map(|handle| handle.info_hash)
+            .collect();
+        if info_hashes.is_empty() {
+            return;
+        }
+
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n",
+            LSD_GROUP, LSD_PORT, self.session.options.listen_port
+        );
+        for info_hash in &info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        message.push_str(&format!("cookie: {}\r\n\r\n\r\n", self.cookie));
+
+        if let Err(e) = self
+            .socket
+            .send_to(message.as_bytes(), (LSD_GROUP, LSD_PORT))
+            .await
+        {
+            debug!("LSD announce failed: {}", e);
+        }
+    }
+
+    async fn receive_loop(&self) {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    trace!("LSD: receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Some(announcement) = parse_announcement(&buf[..len]) else {
+                trace!("LSD: ignoring malformed packet from {}", from);
+                continue;
+            };
+            if announcement.cookie.as_deref() == Some(self.cookie.as_str())
+                || self.session.is_blocked(from.ip())
+            {
+                continue;
+            }
+
+            let peer = announcement.peer(from);
+            for info_hash in &announcement.info_hashes {
+                if let Some(handle) = self.session.torrent(info_hash) {
+                    info!(
+                        "LSD: local peer {} for {}",
+                        peer,
+                        handle.manager.torrent.info().name
+                    );
+                    self.session.connect(&handle, peer.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Parses a BEP 14 announcement:
+///
+/// ```text
+/// BT-SEARCH * HTTP/1.1
+/// Host: 239.192.152.143:6771
+/// Port: <port>
+/// Infohash: <40 hex chars>      (one or more)
+/// cookie: <opaque>              (optional)
+/// ```
+///
+/// Header names are case-insensitive; unknown headers and malformed info hashes are
+/// ignored. Returns `None` unless there is a valid port and at least one info hash.
+fn parse_announcement(packet: &[u8]) -> Option<Announcement> {
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.starts_with("BT-SEARCH * HTTP/1.1") {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse::<u16>().ok().filter(|&p| p != 0),
+            "infohash" => {
+                if let Some(info_hash) = hex::decode(value)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                {
+                    info_hashes.push(info_hash);
+                }
+            }
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return None;
+    }
+    Some(Announcement {
+        port: port?,
+        info_hashes,
+        cookie,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcement_yields_the_peer_address() {
+        let info_hash = [0xab; 20];
+        let packet = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPORT: 51413\r\n\
+             Infohash: {}\r\ninfohash: not-hex\r\ncookie: c00k1e\r\n\r\n\r\n",
+            hex::encode(info_hash)
+        );
+        let announcement = parse_announcement(packet.as_bytes()).unwrap();
+        assert_eq!(announcement.info_hashes, vec![info_hash]);
+        assert_eq!(announcement.cookie.as_deref(), Some("c00k1e"));
+        let from = "192.168.1.20:6771".parse().unwrap();
+        assert_eq!(announcement.peer(from), "192.168.1.20:51413");
+    }
+
+    #[test]
+    fn rejects_announcements_without_a_port_or_info_hash() {
+        let hash = hex::encode([1; 20]);
+        let missing_port = format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\n", hash);
+        assert!(parse_announcement(missing_port.as_bytes()).is_none());
+        let zero_port = format!(
+            "BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: {}\r\n\r\n",
+            hash
+        );
+        assert!(parse_announcement(zero_port.as_bytes()).is_none());
+        assert!(parse_announcement(b"BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_none());
+        let other = format!(
+            "M-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: {}\r\n",
+            hash
+        );
+        assert!(parse_announcement(other.as_bytes()).is_none());
+    }
+}
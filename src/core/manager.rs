@@ -1,47 +1,693 @@
+use crate::core::bitfield::Bitfield;
+use crate::core::quota::Quota;
+use crate::core::storage::{Storage, StorageOptions};
 use crate::core::torrent_info::Torrent;
+use crate::core::tracker::SwarmSize;
+use md5::Md5;
+use serde::Serialize;
 use sha1::{Digest, Sha1};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::Read;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
 
-#[derive(Debug, Clone, PartialEq)]
+/// Capacity of the event channel; slow subscribers lag rather than block the manager.
+/// Roomy enough for a burst of piece completions.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// How long shutdown waits for closing peer sessions to hand back their pieces.
+const SESSION_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PieceStatus {
     Pending,
     InProgress,
     Complete,
 }
 
+/// The `PieceStatus` of every piece, packed into two bits per piece.
+///
+/// A piece is never both in progress and complete; a piece in neither set is pending.
+struct PieceStates {
+    in_progress: Bitfield,
+    complete: Bitfield,
+    /// Number of pieces set in `in_progress`.
+    in_progress_count: usize,
+}
+
+impl PieceStates {
+    fn new(piece_count: usize) -> Self {
+        Self {
+            in_progress: Bitfield::new(piece_count),
+            complete: Bitfield::new(piece_count),
+            in_progress_count: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.complete.len()
+    }
+
+    fn get(&self, index: usize) -> PieceStatus {
+        if self.complete.get(index) {
+            PieceStatus::Complete
+        } else if self.in_progress.get(index) {
+            PieceStatus::InProgress
+        } else {
+            PieceStatus::Pending
+        }
+    }
+
+    fn set(&mut self, index: usize, status: PieceStatus) {
+        let was_in_progress = self.in_progress.get(index);
+        let in_progress = status == PieceStatus::InProgress;
+        if was_in_progress != in_progress {
+            if in_progress {
+                self.in_progress_count += 1;
+            } else {
+                self.in_progress_count -= 1;
+            }
+        }
+        self.in_progress.set(index, in_progress);
+        self.complete.set(index, status == PieceStatus::Complete);
+    }
+}
+
+/// Download priority of a file within a multi-file torrent.
+///
+/// Ordered so that `Skip < Normal < High`; a piece takes the highest priority of
+/// any file it overlaps, so a piece straddling a wanted and a skipped file still downloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Normal,
+    High,
+}
+
+/// How `pick_next_piece` orders candidate pieces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickStrategy {
+    /// High-priority pieces first, then normal ones.
+    Default,
+    /// Strictly ascending piece order so media can be played while downloading.
+    /// With `first_last`, the first and last piece of each wanted file are fetched
+    /// before anything else, since players read container headers/footers first.
+    Sequential { first_last: bool },
+}
+
+/// Notifications published by the manager to anyone who called `subscribe`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentEvent {
+    /// A piece was verified and written to disk.
+    PieceCompleted { index: usize },
+    /// Every piece overlapping this file is verified and it has been renamed out of
+    /// `.part` staging to `path`. Sent at most once per file.
+    FileCompleted { path: PathBuf },
+    /// Every wanted piece is verified. Sent at most once per torrent.
+    Completed,
+    /// A verified piece couldn't be written (e.g. the disk is full). The piece was put
+    /// back to Pending; the download can't make progress until the cause is fixed.
+    DiskError { message: String },
+    /// A peer session found the metadata can't describe a piece (see
+    /// `InvalidMetadata`). Nothing can be downloaded until the torrent file is fixed.
+    InvalidMetadata { message: String },
+    /// A peer session was established (see `register_peer`).
+    PeerConnected { addr: String, client: String },
+    /// A registered peer session ended.
+    PeerDisconnected { addr: String },
+    /// An announce round returned this many unique peers, across all trackers.
+    TrackerAnnounced { peers: usize },
+}
+
+/// Outcome of checking a single piece against the data on disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PieceCheck {
+    Valid,
+    /// Data is present but its hash doesn't match.
+    Corrupt,
+    /// The backing file is missing or too short to hold the piece.
+    Missing,
+}
+
+/// Point-in-time transfer statistics for one torrent, produced by `TorrentManager::stats()`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStats {
+    pub name: String,
+    /// Hex-encoded Info Hash.
+    pub info_hash: String,
+    pub pieces_complete: usize,
+    pub piece_count: usize,
+    /// Payload bytes received from and sent to peers since the torrent was added.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub peers: usize,
+    /// Swarm size from the latest tracker announce that reported one.
+    pub swarm: Option<SwarmSize>,
+    pub paused: bool,
+    pub complete: bool,
+}
+
+/// Snapshot of one connected peer, published by its session (see `TorrentManager::peers()`).
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStats {
+    pub addr: String,
+    /// Client decoded from the peer ID, e.g. `qBittorrent 4.6.2`.
+    pub client: String,
+    /// Payload bytes received from and sent to this peer.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Average payload rates in bytes per second over the last publish interval.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Whether we refuse the peer's requests, and whether we want its data.
+    pub am_choking: bool,
+    pub am_interested: bool,
+    /// Whether the peer refuses our requests, and whether it wants our data.
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    pub connected_secs: u64,
+}
+
+/// Conditions under which seeding stops (`--seed-ratio`, `--seed-time`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeedLimits {
+    /// Stop once uploaded bytes reach this multiple of the torrent size.
+    pub ratio: Option<f64>,
+    /// Stop after seeding for this long.
+    pub time: Option<Duration>,
+}
+
+impl SeedLimits {
+    /// Returns why seeding should stop, if any limit has been reached.
+    ///
+    /// The ratio is measured against the total torrent size rather than bytes
+    /// downloaded, so it also works for the initial seeder (who downloaded nothing).
+    pub fn reached(
+        &self,
+        uploaded: u64,
+        total_length: u64,
+        seeded_for: Duration,
+    ) -> Option<String> {
+        if let Some(target) = self.ratio {
+            let ratio = uploaded as f64 / total_length.max(1) as f64;
+            if ratio >= target {
+                return Some(format!(
+                    "Seed ratio {:.2} reached (target {:.2})",
+                    ratio, target
+                ));
+            }
+        }
+        if let Some(limit) = self.time
+            && seeded_for >= limit
+        {
+            return Some(format!("Seed time of {}s reached", limit.as_secs()));
+        }
+        None
+    }
+}
+
+/// Watches a seeding torrent against its `SeedLimits`.
+#[derive(Debug, Default)]
+pub struct SeedWatch {
+    pub limits: SeedLimits,
+    /// When seeding started, set by the first check.
+    seeding_since: Option<Instant>,
+    stopped: bool,
+}
+
+impl SeedWatch {
+    pub fn new(limits: SeedLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Returns why seeding should stop the first time a limit is reached, and `None`
+    /// on every check after that, so the stop is only acted on once.
+    pub fn check(&mut self, uploaded: u64, total_length: u64) -> Option<String> {
+        if self.stopped {
+            return None;
+        }
+        let seeded_for = self
+            .seeding_since
+            .get_or_insert_with(Instant::now)
+            .elapsed();
+        let reason = self.limits.reached(uploaded, total_length, seeded_for)?;
+        self.stopped = true;
+        Some(reason)
+    }
+}
+
+/// Outcome of checking a file against the `md5sum` recorded in the torrent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Md5Check {
+    Match,
+    Mismatch,
+    /// The file is missing or shorter than its recorded length.
+    Missing,
+}
+
+/// Summary produced by `TorrentManager::verify_pieces`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub complete: usize,
+    pub corrupt: usize,
+    pub missing: usize,
+}
+
+impl VerifyReport {
+    pub fn is_valid(&self) -> bool {
+        self.corrupt == 0 && self.missing == 0
+    }
+}
+
 /// Manages the state of the torrent download, including piece tracking,
 /// file I/O, and data verification.
 ///
 /// This struct acts as the "Single Source of Truth" for the download progress.
 /// It coordinates multiple concurrent workers to ensure pieces are downloaded
 /// only once and written to the correct location on disk.
+///
+/// It is shared as `Arc<TorrentManager>` and synchronizes internally, so workers
+/// don't serialize through one global lock:
+/// - Piece status (and piece priority) sit behind `RwLock`s. Status checks (e.g. serving uploads) take
+///   the read side concurrently; assignment and completion take the write side
+///   only for a short, non-blocking critical section.
+/// - The progress counter is an atomic, readable without any lock.
+/// - Disk I/O goes through `Storage`, which never touches the piece-status lock.
 pub struct TorrentManager {
     pub torrent: Torrent,
-    pub piece_status: Vec<PieceStatus>,
-    pub downloaded_pieces: usize,
+    piece_status: RwLock<PieceStates>,
+    /// Per-piece priority derived from file priorities. `Skip` pieces are never requested.
+    /// Lock order: always taken after `piece_status`.
+    piece_priority: RwLock<Vec<FilePriority>>,
+    downloaded_pieces: AtomicUsize,
+    /// Piece selection order. Set before the manager is shared.
+    pub strategy: PickStrategy,
+    /// Cap on pieces in progress at once across all peer sessions
+    /// (`--max-pending-pieces`); unlimited when `None`. Set before the manager is shared.
+    pub max_in_progress: Option<usize>,
+    /// Session-wide transfer quotas the byte counters feed. Set before the manager is
+    /// shared.
+    pub quota: Arc<Quota>,
+    /// Flattened file layout, computed once and shared by every read and write.
+    /// Shared with the disk-writer task so writes don't need the manager lock.
+    pub storage: Arc<Storage>,
+    events: broadcast::Sender<TorrentEvent>,
+    /// Set once `TorrentEvent::Completed` has been sent, so it fires only once.
+    completion_announced: AtomicBool,
+    /// Per file: set once its `FileCompleted` event has been sent.
+    file_announced: Vec<AtomicBool>,
+    /// Peers with an established session, keyed by peer ID (which also rejects duplicate
+    /// connections), with the stats their session last published.
+    connected_peers: Mutex<HashMap<[u8; 20], PeerStats>>,
+    /// Payload byte counters, fed by peer sessions.
+    downloaded_bytes: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    /// While set, no peers are connected and the torrent isn't announced.
+    paused: AtomicBool,
+    /// Super-seeding: how many peers each piece has been offered to. Empty until the
+    /// first offer.
+    super_seed_offers: Mutex<Vec<u32>>,
+    /// Pieces a closed peer session had partly downloaded: the bytes received so far,
+    /// always a prefix of the piece. Whoever picks the piece next carries on from there,
+    /// and they are kept across restarts (see `save_partial_pieces`).
+    partial_pieces: Mutex<HashMap<usize, Vec<u8>>>,
+    /// Swarm size from the latest announce that reported one.
+    swarm: Mutex<Option<SwarmSize>>,
+    /// Pieces a `read_range` caller is waiting for, with how many callers wait on each.
+    /// Picked ahead of everything else.
+    urgent_pieces: Mutex<HashMap<usize, usize>>,
+}
+
+/// Marks pieces urgent for as long as a `read_range` call waits on them, including
+/// when the caller gives up and drops the future.
+struct UrgentPieces<'a> {
+    manager: &'a TorrentManager,
+    pieces: RangeInclusive<usize>,
+}
+
+impl<'a> UrgentPieces<'a> {
+    fn new(manager: &'a TorrentManager, pieces: RangeInclusive<usize>) -> Self {
+        let mut urgent = manager.urgent_pieces.lock().unwrap();
+        for index in pieces.clone() {
+            *urgent.entry(index).or_insert(0) += 1;
+        }
+        drop(urgent);
+        Self { manager, pieces }
+    }
+}
+
+impl Drop for UrgentPieces<'_> {
+    fn drop(&mut self) {
+        let mut urgent = self.manager.urgent_pieces.lock().unwrap();
+        for index in self.pieces.clone() {
+            if let Entry::Occupied(mut entry) = urgent.entry(index) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
 }
 
 impl TorrentManager {
-    pub fn new(torrent: Torrent) -> Self {
+    pub fn new(torrent: Torrent, storage_options: &StorageOptions) -> Self {
         // Calculate total pieces based on the piece length (usually 20 bytes per hash)
-        let piece_count = torrent.info.pieces.len() / 20;
+        let piece_count = torrent.info().pieces.len() / 20;
+        let storage = Arc::new(Storage::new(&torrent, storage_options));
+        let file_announced = storage
+            .files
+            .iter()
+            .map(|_| AtomicBool::new(false))
+            .collect();
         Self {
             torrent,
-            piece_status: vec![PieceStatus::Pending; piece_count],
-            downloaded_pieces: 0,
+            piece_status: RwLock::new(PieceStates::new(piece_count)),
+            piece_priority: RwLock::new(vec![FilePriority::Normal; piece_count]),
+            downloaded_pieces: AtomicUsize::new(0),
+            strategy: PickStrategy::Default,
+            max_in_progress: None,
+            quota: Arc::new(Quota::default()),
+            storage,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            completion_announced: AtomicBool::new(false),
+            file_announced,
+            connected_peers: Mutex::new(HashMap::new()),
+            downloaded_bytes: AtomicU64::new(0),
+            uploaded_bytes: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            super_seed_offers: Mutex::new(Vec::new()),
+            partial_pieces: Mutex::new(HashMap::new()),
+            swarm: Mutex::new(None),
+            urgent_pieces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a session with `peer_id`. Returns false if one is already connected, in
+    /// which case the caller should drop the new connection.
+    pub fn register_peer(&self, peer_id: [u8; 20], stats: PeerStats) -> bool {
+        match self.connected_peers.lock().unwrap().entry(peer_id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                let _ = self.events.send(TorrentEvent::PeerConnected {
+                    addr: stats.addr.clone(),
+                    client: stats.client.clone(),
+                });
+                entry.insert(stats);
+                true
+            }
+        }
+    }
+
+    /// Replaces the stats of a registered peer.
+    pub fn update_peer(&self, peer_id: &[u8; 20], stats: PeerStats) {
+        if let Some(entry) = self.connected_peers.lock().unwrap().get_mut(peer_id) {
+            *entry = stats;
+        }
+    }
+
+    /// Stats of every connected peer, as last published by their sessions.
+    pub fn peers(&self) -> Vec<PeerStats> {
+        self.connected_peers
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets a peer registered with `register_peer` once its session ends.
+    pub fn unregister_peer(&self, peer_id: &[u8; 20]) {
+        if let Some(stats) = self.connected_peers.lock().unwrap().remove(peer_id) {
+            let _ = self
+                .events
+                .send(TorrentEvent::PeerDisconnected { addr: stats.addr });
+        }
+    }
+
+    /// Number of peers with an established session.
+    pub fn peer_count(&self) -> usize {
+        self.connected_peers.lock().unwrap().len()
+    }
+
+    /// Payload bytes received from peers since the torrent was added.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Counts payload bytes received in `Piece` messages.
+    pub fn record_download(&self, bytes: usize) {
+        self.downloaded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.quota.record_download(bytes);
+    }
+
+    /// Payload bytes sent to peers since the torrent was added.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Counts payload bytes sent in `Piece` messages.
+    pub fn record_upload(&self, bytes: usize) {
+        self.uploaded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.quota.record_upload(bytes);
+    }
+
+    /// Stops piece assignment and releases every in-progress piece, so whatever peers
+    /// were fetching can be picked again after `resume`.
+    ///
+    /// Closing the peer sessions is up to the caller (see `Session::pause_torrent`).
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+        let mut piece_status = self.piece_status.write().unwrap();
+        for index in 0..piece_status.len() {
+            if piece_status.get(index) == PieceStatus::InProgress {
+                piece_status.set(index, PieceStatus::Pending);
+            }
+        }
+    }
+
+    /// Re-enables piece assignment after `pause`.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Snapshot of progress and transfer counters.
+    pub fn stats(&self) -> anyhow::Result<TorrentStats> {
+        Ok(TorrentStats {
+            name: self.torrent.info().name.clone(),
+            info_hash: hex::encode(self.torrent.info_hash()?),
+            pieces_complete: self.downloaded_pieces(),
+            piece_count: self.piece_count(),
+            downloaded: self.downloaded(),
+            uploaded: self.uploaded(),
+            peers: self.peer_count(),
+            swarm: *self.swarm.lock().unwrap(),
+            paused: self.is_paused(),
+            complete: self.is_complete(),
+        })
+    }
+
+    /// Returns a receiver for torrent events (completion, etc.).
+    pub fn subscribe(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Records the swarm size a tracker reported; `None` keeps the last known one.
+    pub fn set_swarm(&self, swarm: Option<SwarmSize>) {
+        if swarm.is_some() {
+            *self.swarm.lock().unwrap() = swarm;
+        }
+    }
+
+    /// Sends an event that happened outside the manager (e.g. a tracker announce).
+    pub fn publish(&self, event: TorrentEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Total number of pieces in the torrent.
+    pub fn piece_count(&self) -> usize {
+        self.piece_status.read().unwrap().len()
+    }
+
+    /// Number of pieces verified and written so far.
+    pub fn downloaded_pieces(&self) -> usize {
+        self.downloaded_pieces.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the piece has been fully downloaded and verified.
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.piece_status.read().unwrap().get(index) == PieceStatus::Complete
+    }
+
+    /// Whether we lack piece `index` and want it (it isn't only in skipped files).
+    pub fn wants_piece(&self, index: usize) -> bool {
+        index < self.piece_count()
+            && !self.has_piece(index)
+            && self.piece_priority.read().unwrap()[index] != FilePriority::Skip
+    }
+
+    /// Whether a peer with these pieces has any we want: the test for telling it we
+    /// are interested. Never true once we're complete.
+    pub fn wants_any(&self, peer_bitfield: &Bitfield) -> bool {
+        if self.is_complete() {
+            return false;
+        }
+        let piece_status = self.piece_status.read().unwrap();
+        let piece_priority = self.piece_priority.read().unwrap();
+        (0..piece_status.len()).any(|index| {
+            peer_bitfield.get(index)
+                && piece_status.get(index) != PieceStatus::Complete
+                && piece_priority[index] != FilePriority::Skip
+        })
+    }
+
+    /// Applies per-file priorities (one entry per file, in metainfo order).
+    ///
+    /// Each piece gets the highest priority among the files it overlaps. Pieces that
+    /// belong exclusively to skipped files are never requested and don't count
+    /// towards completion.
+    pub fn set_file_priorities(&self, priorities: &[FilePriority]) -> anyhow::Result<()> {
+        let files = &self.storage.files;
+        if priorities.len() != files.len() {
+            anyhow::bail!(
+                "Expected {} file priorities, got {}",
+                files.len(),
+                priorities.len()
+            );
+        }
+
+        let piece_len = self.torrent.info().piece_length as u64;
+        let mut piece_priority = self.piece_priority.write().unwrap();
+        piece_priority.fill(FilePriority::Skip);
+
+        for (file, &priority) in files.iter().zip(priorities) {
+            if file.length == 0 {
+                continue;
+            }
+            let first = (file.offset / piece_len) as usize;
+            let last = ((file.offset + file.length - 1) / piece_len) as usize;
+            for p in piece_priority.iter_mut().take(last + 1).skip(first) {
+                *p = (*p).max(priority);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restricts the download to `pieces` (`--pieces`): every other piece is treated
+    /// like one of a skipped file, so the torrent counts as complete once these are.
+    /// Call after `set_file_priorities`, which would undo it; pieces it skipped stay
+    /// skipped. Indices past the last piece are ignored.
+    pub fn restrict_to_pieces(&self, pieces: &[usize]) {
+        let mut piece_priority = self.piece_priority.write().unwrap();
+        let mut wanted = vec![false; piece_priority.len()];
+        for &index in pieces {
+            if let Some(wanted) = wanted.get_mut(index) {
+                *wanted = true;
+            }
+        }
+        for (priority, wanted) in piece_priority.iter_mut().zip(wanted) {
+            if !wanted {
+                *priority = FilePriority::Skip;
+            }
         }
     }
 
     /// Selects the next available piece to download based on the connected peer's availability.
     ///
-    /// Implements a simple "Rarest First" or sequential strategy (currently sequential).
-    /// Returns `Some(index)` if a pending piece is found that the peer possesses.
-    pub fn pick_next_piece(&mut self, peer_bitfield: &[bool]) -> Option<usize> {
-        for (index, status) in self.piece_status.iter_mut().enumerate() {
-            if *status == PieceStatus::Pending {
-                // Only assign if the peer actually has this piece
-                if index < peer_bitfield.len() && peer_bitfield[index] {
-                    *status = PieceStatus::InProgress;
+    /// Pieces a `read_range` call is waiting for come first, lowest index first. After
+    /// that it follows the configured `PickStrategy`. In the default mode high-priority
+    /// pieces are preferred; skipped pieces are never picked in any mode.
+    /// Returns `Some(index)` if a pending piece is found that the peer possesses, and
+    /// never while the torrent is paused, `max_in_progress` pieces are already being
+    /// downloaded, or the download quota is used up.
+    pub fn pick_next_piece(&self, peer_bitfield: &Bitfield) -> Option<usize> {
+        self.pick_next_piece_for(peer_bitfield, 0, None)
+    }
+
+    /// Like `pick_next_piece`, for a peer session that already holds `held` pieces in
+    /// progress: nothing is picked once that reaches `max_per_peer`
+    /// (`--max-pieces-per-peer`), so one fast peer can't take the whole piece pool.
+    pub fn pick_next_piece_for(
+        &self,
+        peer_bitfield: &Bitfield,
+        held: usize,
+        max_per_peer: Option<usize>,
+    ) -> Option<usize> {
+        let mut piece_status = self.piece_status.write().unwrap();
+        // Checked under the lock: `pause` sets the flag before releasing pieces, so a
+        // piece can't be handed out after the release pass
+        if self.is_paused() {
+            return None;
+        }
+        if self
+            .max_in_progress
+            .is_some_and(|max| piece_status.in_progress_count >= max)
+            || max_per_peer.is_some_and(|max| held >= max)
+            || self.quota.download_exhausted()
+        {
+            return None;
+        }
+        let piece_priority = self.piece_priority.read().unwrap();
+
+        // Only assign pending, wanted pieces that this specific peer actually has
+        let available = |index: usize, status: PieceStatus| {
+            status == PieceStatus::Pending
+                && piece_priority[index] != FilePriority::Skip
+                && peer_bitfield.get(index)
+        };
+
+        let urgent = self
+            .urgent_pieces
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .filter(|&index| available(index, piece_status.get(index)))
+            .min();
+        if let Some(index) = urgent {
+            piece_status.set(index, PieceStatus::InProgress);
+            return Some(index);
+        }
+
+        if let PickStrategy::Sequential { first_last } = self.strategy {
+            if first_last {
+                for index in self.file_edge_pieces() {
+                    if available(index, piece_status.get(index)) {
+                        piece_status.set(index, PieceStatus::InProgress);
+                        return Some(index);
+                    }
+                }
+            }
+            // Strict in-order: lowest pending index the peer has
+            for index in 0..piece_status.len() {
+                if available(index, piece_status.get(index)) {
+                    piece_status.set(index, PieceStatus::InProgress);
+                    return Some(index);
+                }
+            }
+            return None;
+        }
+
+        for wanted in [FilePriority::High, FilePriority::Normal] {
+            for index in 0..piece_status.len() {
+                if piece_priority[index] == wanted && available(index, piece_status.get(index)) {
+                    piece_status.set(index, PieceStatus::InProgress);
                     return Some(index);
                 }
             }
@@ -49,18 +695,152 @@ impl TorrentManager {
         None
     }
 
+    /// Our verified pieces, as advertised to peers in a `Bitfield` message.
+    pub fn bitfield(&self) -> Bitfield {
+        self.piece_status.read().unwrap().complete.clone()
+    }
+
+    /// Super-seeding: picks the next piece to reveal to a peer.
+    ///
+    /// Chooses the complete piece the peer lacks that has been offered to the fewest
+    /// peers so far (lowest index on ties), so each peer gets a different piece and the
+    /// swarm fills in the torrent between themselves.
+    pub fn next_super_seed_piece(&self, peer_has: &Bitfield) -> Option<usize> {
+        let piece_status = self.piece_status.read().unwrap();
+        let mut offers = self.super_seed_offers.lock().unwrap();
+        offers.resize(piece_status.len(), 0);
+
+        let index = (0..piece_status.len())
+            .filter(|&i| piece_status.get(i) == PieceStatus::Complete && !peer_has.get(i))
+            .min_by_key(|&i| offers[i])?;
+        offers[index] += 1;
+        Some(index)
+    }
+
+    /// Indices of the first and last piece of every non-empty file.
+    fn file_edge_pieces(&self) -> Vec<usize> {
+        let piece_len = self.torrent.info().piece_length as u64;
+        let mut edges = Vec::new();
+        for file in self.storage.files.iter().filter(|f| f.length > 0) {
+            edges.push((file.offset / piece_len) as usize);
+            edges.push(((file.offset + file.length - 1) / piece_len) as usize);
+        }
+        edges
+    }
+
     /// Marks a piece as fully downloaded and verified.
     /// Updates the global progress counter.
-    pub fn mark_piece_complete(&mut self, index: usize) {
-        if self.piece_status[index] != PieceStatus::Complete {
-            self.piece_status[index] = PieceStatus::Complete;
-            self.downloaded_pieces += 1;
-            println!(
-                "Piece {} finished. Progress: {}/{}",
-                index,
-                self.downloaded_pieces,
-                self.piece_status.len()
+    pub fn mark_piece_complete(&self, index: usize) {
+        let mut piece_status = self.piece_status.write().unwrap();
+        if piece_status.get(index) != PieceStatus::Complete {
+            piece_status.set(index, PieceStatus::Complete);
+            let downloaded = self.downloaded_pieces.fetch_add(1, Ordering::AcqRel) + 1;
+            info!(
+                piece = index,
+                "Piece finished. Progress: {}/{}",
+                downloaded,
+                piece_status.len()
             );
+            let _ = self.events.send(TorrentEvent::PieceCompleted { index });
+        }
+        drop(piece_status);
+
+        // A piece straddling a boundary can finish either file; each file is only
+        // complete once every piece it overlaps is. The layout is sorted by offset, so
+        // the overlapping files are found by binary search, as in `Storage::spans`.
+        let piece_len = self.torrent.info().piece_length as u64;
+        let piece_start = index as u64 * piece_len;
+        let piece_end = piece_start + piece_len;
+        let files = &self.storage.files;
+        let first = files.partition_point(|f| f.offset + f.length <= piece_start);
+        let last = files.partition_point(|f| f.offset < piece_end);
+        for (file_index, file) in files.iter().enumerate().take(last).skip(first) {
+            if file.length > 0 && self.file_complete(file_index) {
+                self.finish_file(file_index);
+            }
+        }
+        self.check_completion();
+    }
+
+    /// Handles a failed write of a verified piece: the piece goes back to Pending (it is
+    /// NOT on disk) and a `DiskError` event is published for the supervision loop.
+    pub fn report_disk_error(&self, index: usize, error: &anyhow::Error) {
+        self.reset_piece(index);
+
+        let out_of_space = error.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+        });
+        let message = if out_of_space {
+            format!("Out of disk space while writing piece {}", index)
+        } else {
+            format!("Disk write failed for piece {}: {}", index, error)
+        };
+        error!("{}", message);
+        let _ = self.events.send(TorrentEvent::DiskError { message });
+    }
+
+    /// Handles an error a peer session can't recover from because the torrent's own
+    /// metadata is broken: an `InvalidMetadata` event is published so the supervision
+    /// loop stops the torrent instead of trying other peers forever.
+    pub fn report_invalid_metadata(&self, error: &anyhow::Error) {
+        let message = format!("{:#}", error);
+        error!("{}", message);
+        let _ = self.events.send(TorrentEvent::InvalidMetadata { message });
+    }
+
+    /// Bytes still to download: the size of every wanted (non-skipped) piece that
+    /// isn't complete.
+    pub fn bytes_left(&self) -> u64 {
+        let piece_status = self.piece_status.read().unwrap();
+        let piece_priority = self.piece_priority.read().unwrap();
+        (0..piece_status.len())
+            .filter(|&index| {
+                piece_status.get(index) != PieceStatus::Complete
+                    && piece_priority[index] != FilePriority::Skip
+            })
+            .filter_map(|index| self.torrent.calculate_piece_size(index).ok())
+            .map(u64::from)
+            .sum()
+    }
+
+    /// Returns true if every piece overlapping the given file is complete.
+    fn file_complete(&self, file_index: usize) -> bool {
+        let file = &self.storage.files[file_index];
+        if file.length == 0 {
+            return true;
+        }
+        let piece_len = self.torrent.info().piece_length as u64;
+        let first = (file.offset / piece_len) as usize;
+        let last = ((file.offset + file.length - 1) / piece_len) as usize;
+        let piece_status = self.piece_status.read().unwrap();
+        (first..=last).all(|index| piece_status.get(index) == PieceStatus::Complete)
+    }
+
+    /// Moves a finished file out of `.part` staging and publishes `FileCompleted`, once.
+    fn finish_file(&self, file_index: usize) {
+        if self.file_announced[file_index].swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let path = self.storage.files[file_index].path.clone();
+        match self.storage.finalize_file(file_index) {
+            Ok(_) => {
+                info!("File complete: {:?}", path);
+                // No subscribers is fine; events are purely informational
+                let _ = self.events.send(TorrentEvent::FileCompleted { path });
+            }
+            Err(e) => warn!("Failed to finalize {:?}: {}", path, e),
+        }
+    }
+
+    /// Publishes `TorrentEvent::Completed` the first time every wanted piece is done.
+    ///
+    /// Files that were skipped (and so aren't fully downloaded) keep their `.part` name.
+    fn check_completion(&self) {
+        if self.is_complete() && !self.completion_announced.swap(true, Ordering::AcqRel) {
+            let _ = self.events.send(TorrentEvent::Completed);
         }
     }
 
@@ -68,287 +848,895 @@ impl TorrentManager {
     ///
     /// This is typically called when a worker disconnects or when a downloaded piece
     /// fails the SHA-1 hash verification.
-    pub fn reset_piece(&mut self, index: usize) {
-        if self.piece_status[index] != PieceStatus::Complete {
-            self.piece_status[index] = PieceStatus::Pending;
+    pub fn reset_piece(&self, index: usize) {
+        let mut piece_status = self.piece_status.write().unwrap();
+        if piece_status.get(index) != PieceStatus::Complete {
+            piece_status.set(index, PieceStatus::Pending);
         }
     }
 
+    /// Releases a piece whose peer session closed mid-download, keeping the `received`
+    /// prefix for whoever picks it next.
+    pub fn release_piece(&self, index: usize, received: Vec<u8>) {
+        if !received.is_empty() {
+            self.partial_pieces.lock().unwrap().insert(index, received);
+        }
+        self.reset_piece(index);
+    }
+
+    /// Takes the prefix of a piece received by an earlier session (or run), if any.
+    pub fn take_partial_piece(&self, index: usize) -> Option<Vec<u8>> {
+        self.partial_pieces.lock().unwrap().remove(&index)
+    }
+
+    /// Saves partly downloaded pieces to the storage sidecar so the next run resumes
+    /// them mid-piece. Called on shutdown, after the peer sessions were cancelled; they
+    /// get a moment to close and hand back their pieces first.
+    pub async fn save_partial_pieces(&self) {
+        let deadline = Instant::now() + SESSION_DRAIN_TIMEOUT;
+        while self.peer_count() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let partial_pieces = self.partial_pieces.lock().unwrap();
+        if let Err(e) = self.storage.save_partial_pieces(&partial_pieces) {
+            warn!("Failed to save partly downloaded pieces: {}", e);
+        }
+    }
+
+    /// Demotes a complete piece whose data on disk no longer matches its hash (bit rot,
+    /// an outside edit) back to Pending, so it is downloaded again.
+    pub fn invalidate_piece(&self, index: usize) {
+        let mut piece_status = self.piece_status.write().unwrap();
+        if piece_status.get(index) == PieceStatus::Complete {
+            piece_status.set(index, PieceStatus::Pending);
+            self.downloaded_pieces.fetch_sub(1, Ordering::AcqRel);
+            self.completion_announced.store(false, Ordering::Release);
+            warn!(
+                piece = index,
+                "Piece on disk is corrupt; downloading it again"
+            );
+        }
+    }
+
+    /// Returns true once every wanted (non-skipped) piece is complete.
     pub fn is_complete(&self) -> bool {
-        self.downloaded_pieces == self.piece_status.len()
+        if self.downloaded_pieces() == self.piece_count() {
+            return true;
+        }
+        let piece_status = self.piece_status.read().unwrap();
+        let piece_priority = self.piece_priority.read().unwrap();
+        piece_priority.iter().enumerate().all(|(index, priority)| {
+            piece_status.get(index) == PieceStatus::Complete || *priority == FilePriority::Skip
+        })
     }
 
     /// Scans the disk on startup to identify existing files and verify their integrity.
     ///
     /// This function performs two critical tasks:
-    /// 1. **Pre-allocation:** Creates empty files of the correct size to prevent
-    ///    sparse file read errors and reduce disk fragmentation.
+    /// 1. **Pre-allocation:** Applies the `--if-exists` policy to files already at
+    ///    their final path, then creates the files, sized according to the storage
+    ///    `AllocationPolicy` (full length by default).
     /// 2. **Resume:** Reads existing data, hashes it, and updates the `piece_status`
     ///    to skip re-downloading valid pieces.
-    pub fn verify_existing_data(&mut self) {
-        println!("Checking existing files for resume...");
-        let output_dir = "downloads";
+    ///
+    /// Fails only when `--if-exists skip` finds a file that doesn't match the torrent.
+    pub fn verify_existing_data(&self) -> anyhow::Result<()> {
+        info!("Checking existing files for resume...");
 
         // --- PHASE 0: PRE-ALLOCATE FILES ---
-        let files_list = if let Some(files) = &self.torrent.info.files {
-            files
-                .iter()
-                .map(|f| {
-                    let mut path = std::path::PathBuf::from(output_dir);
-                    path.push(&self.torrent.info.name);
-                    for part in &f.path {
-                        path.push(part);
-                    }
-                    (path, f.length)
-                })
-                .collect::<Vec<_>>()
-        } else {
-            let mut path = std::path::PathBuf::from(output_dir);
-            path.push(&self.torrent.info.name);
-            vec![(path, self.torrent.total_length())]
-        };
-
-        for (path, length) in &files_list {
-            if let Some(parent) = path.parent() {
-                std::fs::create_dir_all(parent).ok();
+        // Incomplete files live under their `.part` staging name; the storage
+        // allocation policy decides whether they're sized up front.
+        let mut must_match = Vec::new();
+        for index in 0..self.storage.files.len() {
+            if self.storage.check_existing(index)? {
+                must_match.push(index);
             }
-
-            match std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .read(true)
-                .open(path)
-            {
-                Ok(file) => {
-                    let current_len = file.metadata().map(|m| m.len()).unwrap_or(0);
-
-                    // If file is missing or truncated, extend it.
-                    // Important: We assume the OS fills the gap with zeros.
-                    if current_len < *length as u64 {
-                        println!("Pre-allocating file: {:?} ({} bytes)", path, length);
-                        if let Err(e) = file.set_len(*length as u64) {
-                            println!("Failed to pre-allocate file: {}", e);
-                        }
-                        // CRITICAL: Force OS to flush metadata changes to disk immediately.
-                        // This prevents race conditions where the reader sees a 0-byte file.
-                        let _ = file.sync_all();
-                    }
-                }
-                Err(e) => println!("Failed to open file for pre-allocation: {}", e),
+            if let Err(e) = self.storage.allocate(index) {
+                warn!(
+                    "Failed to pre-allocate {:?}: {}",
+                    self.storage.files[index].path, e
+                );
             }
         }
 
         // --- PHASE 1: VERIFY PIECES ---
-        println!("Verifying piece hashes...");
-        for index in 0..self.piece_status.len() {
-            let piece_index = index;
-            let expected_hash = match self.torrent.get_piece_hash(piece_index) {
-                Ok(h) => h,
-                Err(_) => continue,
-            };
-
-            let expected_size = self.torrent.calculate_piece_size(piece_index) as u64;
-
-            // Reuse the robust read logic to check the disk
-            match self.read_piece_from_disk(piece_index, expected_size, output_dir) {
-                Ok(buffer) => {
-                    let mut hasher = Sha1::new();
-                    hasher.update(&buffer);
-                    let actual_hash: [u8; 20] = hasher.finalize().into();
-
-                    if actual_hash == expected_hash {
-                        self.piece_status[piece_index] = PieceStatus::Complete;
-                        self.downloaded_pieces += 1;
-                    }
-                }
-                Err(_) => {
-                    // Fail silently; piece remains 'Pending' and will be downloaded.
-                }
+        info!("Verifying piece hashes...");
+        for index in 0..self.piece_count() {
+            // Fail silently on missing/corrupt data; the piece remains 'Pending' and will be downloaded.
+            if self.check_piece(index) == PieceCheck::Valid {
+                self.piece_status
+                    .write()
+                    .unwrap()
+                    .set(index, PieceStatus::Complete);
+                self.downloaded_pieces.fetch_add(1, Ordering::AcqRel);
             }
         }
 
-        println!(
+        info!(
             "Resume: Found {}/{} complete pieces.",
-            self.downloaded_pieces,
-            self.piece_status.len()
+            self.downloaded_pieces(),
+            self.piece_count()
         );
+
+        // Under `--if-exists skip`, a kept file may not have any piece of its own
+        // overwritten. Pieces it shares with a neighbouring file can't tell it apart.
+        for file_index in must_match {
+            let file = &self.storage.files[file_index];
+            let piece_len = self.torrent.info().piece_length as u64;
+            let total_length = self.torrent.total_length() as u64;
+            let first = file.offset.div_ceil(piece_len) as usize;
+            let mut own_pieces = (first..self.piece_count()).take_while(|&index| {
+                ((index as u64 + 1) * piece_len).min(total_length) <= file.offset + file.length
+            });
+            if own_pieces.any(|index| !self.has_piece(index)) {
+                anyhow::bail!(
+                    "{:?} already exists and doesn't match the torrent; leaving it alone (--if-exists skip)",
+                    file.path
+                );
+            }
+        }
+
+        // --- PHASE 2: PARTLY DOWNLOADED PIECES ---
+        // Pieces left mid-download by the last run continue from the bytes it received
+        match self.storage.load_partial_pieces() {
+            Ok(mut partial_pieces) => {
+                let piece_status = self.piece_status.read().unwrap();
+                partial_pieces.retain(|&index, received| {
+                    index < self.piece_count()
+                        && piece_status.get(index) != PieceStatus::Complete
+                        && self
+                            .torrent
+                            .calculate_piece_size(index)
+                            .is_ok_and(|size| received.len() < size as usize)
+                });
+                if !partial_pieces.is_empty() {
+                    info!(
+                        "Resume: continuing {} partly downloaded pieces.",
+                        partial_pieces.len()
+                    );
+                }
+                *self.partial_pieces.lock().unwrap() = partial_pieces;
+            }
+            Err(e) => warn!("Ignoring unreadable partial pieces file: {}", e),
+        }
+
+        // Data left over from an earlier run may already cover whole files (or the torrent)
+        for file_index in 0..self.storage.files.len() {
+            if self.file_complete(file_index) {
+                self.finish_file(file_index);
+            }
+        }
+        self.check_completion();
+        Ok(())
+    }
+
+    /// Re-hashes every piece on disk and reports integrity, without creating,
+    /// extending, or otherwise modifying any file.
+    ///
+    /// Used by the `verify` command. Does not change the in-memory piece status.
+    pub fn verify_pieces(&self) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        for index in 0..self.piece_count() {
+            match self.check_piece(index) {
+                PieceCheck::Valid => report.complete += 1,
+                PieceCheck::Corrupt => report.corrupt += 1,
+                PieceCheck::Missing => report.missing += 1,
+            }
+        }
+        report
+    }
+
+    /// Hashes every file that has an `md5sum` in the torrent and compares the two.
+    ///
+    /// Used by `verify --md5`. Files without a recorded MD5 are left out of the result.
+    pub fn verify_md5sums(&self) -> Vec<(PathBuf, Md5Check)> {
+        let recorded: Vec<Option<&String>> = match &self.torrent.info().files {
+            Some(files) => files.iter().map(|file| file.md5sum.as_ref()).collect(),
+            None => vec![self.torrent.info().md5sum.as_ref()],
+        };
+        recorded
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, expected)| {
+                let expected = expected?;
+                let path = self.storage.disk_path(index);
+                let check = match file_md5(&path, self.storage.files[index].length) {
+                    Ok(actual) if actual.eq_ignore_ascii_case(expected) => Md5Check::Match,
+                    Ok(_) => Md5Check::Mismatch,
+                    Err(_) => Md5Check::Missing,
+                };
+                Some((path, check))
+            })
+            .collect()
+    }
+
+    /// Reads one piece from disk and compares it against its expected SHA-1 hash.
+    fn check_piece(&self, index: usize) -> PieceCheck {
+        let expected_hash = match self.torrent.get_piece_hash(index) {
+            Ok(h) => h,
+            Err(_) => return PieceCheck::Missing,
+        };
+        let expected_size = match self.torrent.calculate_piece_size(index) {
+            Ok(size) => size as u64,
+            Err(_) => return PieceCheck::Missing,
+        };
+
+        // Reuse the robust read logic to check the disk
+        match self.read_piece_from_disk(index, expected_size) {
+            Ok(buffer) => {
+                let mut hasher = Sha1::new();
+                hasher.update(&buffer);
+                let actual_hash: [u8; 20] = hasher.finalize().into();
+
+                if actual_hash == expected_hash {
+                    PieceCheck::Valid
+                } else {
+                    PieceCheck::Corrupt
+                }
+            }
+            // Missing file or too short to contain the piece
+            Err(_) => PieceCheck::Missing,
+        }
     }
 
     /// Reads a specific piece from the disk, handling logic for pieces that span
     /// across multiple files.
     ///
     /// This function is public to support the seeding functionality (uploading to peers).
-    pub fn read_piece_from_disk(
-        &self,
-        index: usize,
-        piece_size: u64,
-        output_dir: &str,
+    pub fn read_piece_from_disk(&self, index: usize, piece_size: u64) -> anyhow::Result<Vec<u8>> {
+        self.storage.read_piece(index, piece_size)
+    }
+
+    /// Reads `length` bytes starting at `offset` into the torrent's content (all files
+    /// back to back, in metainfo order), for streaming playback.
+    ///
+    /// Waits up to `wait` for the pieces covering the range, picking them ahead of
+    /// every other piece meanwhile. Fails if the range is out of bounds, touches a
+    /// skipped file, or isn't downloaded in time.
+    pub async fn read_range(
+        self: &Arc<Self>,
+        offset: u64,
+        length: u64,
+        wait: Duration,
     ) -> anyhow::Result<Vec<u8>> {
-        let mut buffer = vec![0u8; piece_size as usize];
-        let standard_len = self.torrent.info.piece_length as u64;
-
-        // Calculate global byte offsets for this piece
-        let piece_global_start = (index as u64) * standard_len;
-        let piece_global_end = piece_global_start + piece_size;
-
-        // Flatten the multi-file structure into a linear list of (Path, Length)
-        let files_list = if let Some(files) = &self.torrent.info.files {
-            files
-                .iter()
-                .map(|f| {
-                    let mut path = std::path::PathBuf::from(output_dir);
-                    path.push(&self.torrent.info.name);
-                    for part in &f.path {
-                        path.push(part);
-                    }
-                    (path, f.length)
-                })
-                .collect::<Vec<_>>()
-        } else {
-            let mut path = std::path::PathBuf::from(output_dir);
-            path.push(&self.torrent.info.name);
-            vec![(path, self.torrent.total_length())]
+        let total_length = self.torrent.total_length() as u64;
+        if length == 0
+            || offset
+                .checked_add(length)
+                .is_none_or(|end| end > total_length)
+        {
+            anyhow::bail!(
+                "Range {}+{} is outside the torrent ({} bytes)",
+                offset,
+                length,
+                total_length
+            );
+        }
+        let piece_len = self.torrent.info().piece_length as u64;
+        let pieces = (offset / piece_len) as usize..=((offset + length - 1) / piece_len) as usize;
+        if self.piece_priority.read().unwrap()[pieces.clone()].contains(&FilePriority::Skip) {
+            anyhow::bail!("Range covers a skipped file");
+        }
+
+        // Subscribed before checking, so a piece finishing in between isn't missed
+        let mut events = self.subscribe();
+        let urgent = UrgentPieces::new(self, pieces.clone());
+        let covered = || pieces.clone().all(|index| self.has_piece(index));
+        let waited = tokio::time::timeout(wait, async {
+            while !covered() {
+                if let Err(RecvError::Closed) = events.recv().await {
+                    break;
+                }
+            }
+        })
+        .await;
+        drop(urgent);
+        if waited.is_err() || !covered() {
+            anyhow::bail!(
+                "Pieces {}-{} not downloaded within {}s",
+                pieces.start(),
+                pieces.end(),
+                wait.as_secs()
+            );
+        }
+
+        // Read off the runtime: a multi-MiB range would otherwise stall peer sessions
+        let manager = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let end = offset + length;
+            let mut data = Vec::with_capacity(length as usize);
+            for index in pieces {
+                let piece_size = manager.torrent.calculate_piece_size(index)? as u64;
+                let piece = manager.storage.read_piece(index, piece_size)?;
+                let piece_start = index as u64 * piece_len;
+                let from = offset.saturating_sub(piece_start) as usize;
+                let to = (end - piece_start).min(piece_size) as usize;
+                data.extend_from_slice(&piece[from..to]);
+            }
+            Ok(data)
+        })
+        .await?
+    }
+
+    /// Flushes all unsynced writes to disk. Called on completion and on shutdown.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.storage.flush()
+    }
+}
+
+/// MD5 of the first `length` bytes of a file, failing if it's missing or shorter.
+fn file_md5(path: &Path, length: u64) -> std::io::Result<String> {
+    let file = std::fs::File::open(path)?;
+    let mut reader = std::io::BufReader::new(file).take(length);
+    let mut md5 = Md5::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut read = 0u64;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        md5.update(&buffer[..n]);
+        read += n as u64;
+    }
+    if read < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(hex::encode(md5.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::storage::ExistingFilePolicy;
+    use crate::core::torrent_info::{FileNode, Info};
+    use serde_bytes::ByteBuf;
+    use tempfile::TempDir;
+
+    /// A multi-file torrent whose piece hashes match `files`, which are written to
+    /// their final paths under a fresh output directory before the manager loads them.
+    fn fixture(
+        files: &[(&str, &[u8])],
+        piece_length: usize,
+        options: StorageOptions,
+    ) -> (TempDir, TorrentManager) {
+        let dir = TempDir::new().unwrap();
+        let data: Vec<u8> = files.iter().flat_map(|(_, bytes)| bytes.to_vec()).collect();
+        let pieces: Vec<u8> = data
+            .chunks(piece_length)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let info = Info {
+            name: "fixture".to_string(),
+            piece_length,
+            pieces: ByteBuf::from(pieces),
+            files: Some(
+                files
+                    .iter()
+                    .map(|(name, bytes)| FileNode {
+                        length: bytes.len() as i64,
+                        path: vec![name.to_string()],
+                        md5sum: None,
+                        mtime: None,
+                    })
+                    .collect(),
+            ),
+            ..Info::default()
         };
 
-        let mut file_global_start = 0u64;
-        let mut bytes_read = 0;
+        let root = dir.path().join("fixture");
+        std::fs::create_dir_all(&root).unwrap();
+        for (name, bytes) in files {
+            std::fs::write(root.join(name), bytes).unwrap();
+        }
+        let options = StorageOptions {
+            output_dir: dir.path().to_string_lossy().into_owned(),
+            ..options
+        };
+        let torrent = Torrent::new(String::new(), None, info);
+        (dir, TorrentManager::new(torrent, &options))
+    }
 
-        for (path, file_len) in files_list {
-            let file_global_end = file_global_start + (file_len as u64);
+    fn content(length: usize, seed: u8) -> Vec<u8> {
+        (0..length)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
 
-            // Check if this file contains any part of the requested piece
-            if file_global_end > piece_global_start && file_global_start < piece_global_end {
-                // Calculate the byte range relative to the PIECE
-                let read_start_in_piece = if file_global_start > piece_global_start {
-                    file_global_start - piece_global_start
-                } else {
-                    0
-                };
+    fn counts(report: VerifyReport) -> (usize, usize, usize) {
+        (report.complete, report.corrupt, report.missing)
+    }
 
-                let read_end_in_piece = if file_global_end < piece_global_end {
-                    file_global_end - piece_global_start
-                } else {
-                    piece_size
-                };
+    #[test]
+    fn verify_counts_intact_data() {
+        let (a, b) = (content(40, 1), content(25, 2));
+        let (_dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        // 65 bytes: four full pieces and one of a single byte
+        assert_eq!(counts(manager.verify_pieces()), (5, 0, 0));
+        assert!(manager.verify_pieces().is_valid());
+    }
 
-                // Calculate the byte offset relative to the FILE
-                let seek_pos_in_file = if piece_global_start > file_global_start {
-                    piece_global_start - file_global_start
-                } else {
-                    0
-                };
+    #[test]
+    fn verify_counts_corrupt_and_missing_pieces() {
+        let (a, b) = (content(40, 1), content(25, 2));
+        let (dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        let root = dir.path().join("fixture");
 
-                if path.exists() {
-                    let mut file = std::fs::File::open(&path)?;
-                    file.seek(SeekFrom::Start(seek_pos_in_file))?;
+        // Flip a byte of piece 1, and cut `b` to end at byte 60 of 65, which leaves the
+        // last two pieces unreadable
+        let mut corrupted = a.clone();
+        corrupted[20] ^= 0xff;
+        std::fs::write(root.join("a"), &corrupted).unwrap();
+        std::fs::write(root.join("b"), &b[..20]).unwrap();
 
-                    let slice_len = (read_end_in_piece - read_start_in_piece) as usize;
-                    let mut chunk_buf = vec![0u8; slice_len];
-                    file.read_exact(&mut chunk_buf)?;
+        let report = manager.verify_pieces();
+        assert!(!report.is_valid());
+        assert_eq!(counts(report), (2, 1, 2));
+    }
 
-                    // Copy read data into the main buffer
-                    let start = read_start_in_piece as usize;
-                    buffer[start..start + slice_len].copy_from_slice(&chunk_buf);
-                    bytes_read += slice_len;
-                } else {
-                    anyhow::bail!("File missing during read operation");
-                }
-            }
-            file_global_start += file_len as u64;
+    #[test]
+    fn verify_counts_pieces_of_a_missing_file() {
+        let (a, b) = (content(32, 1), content(32, 2));
+        let (dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        std::fs::remove_file(dir.path().join("fixture").join("b")).unwrap();
+        assert_eq!(counts(manager.verify_pieces()), (2, 0, 2));
+    }
+
+    #[test]
+    fn verify_leaves_files_untouched() {
+        let a = content(30, 1);
+        let (dir, manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        let path = dir.path().join("fixture").join("a");
+        std::fs::write(&path, &a[..10]).unwrap();
+        manager.verify_pieces();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn verify_finds_files_moved_to_the_complete_dir() {
+        let a = content(40, 1);
+        let (dir, manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        let done = dir.path().join("done");
+        std::fs::create_dir(&done).unwrap();
+        std::fs::rename(dir.path().join("fixture"), done.join("fixture")).unwrap();
+
+        let output_dir = dir.path().to_string_lossy().into_owned();
+        let without = StorageOptions {
+            output_dir: output_dir.clone(),
+            ..StorageOptions::default()
+        };
+        let with = StorageOptions {
+            output_dir,
+            complete_dir: Some(done.to_string_lossy().into_owned()),
+            ..StorageOptions::default()
+        };
+        let torrent = &manager.torrent;
+        let report = TorrentManager::new(torrent.clone(), &without).verify_pieces();
+        assert_eq!(counts(report), (0, 0, 3));
+        let report = TorrentManager::new(torrent.clone(), &with).verify_pieces();
+        assert_eq!(counts(report), (3, 0, 0));
+    }
+
+    #[test]
+    fn completed_files_move_once_and_are_read_from_the_complete_dir() {
+        let (a, b) = (content(40, 1), content(25, 2));
+        let done = TempDir::new().unwrap();
+        let options = StorageOptions {
+            complete_dir: Some(done.path().to_string_lossy().into_owned()),
+            ..StorageOptions::default()
+        };
+        let (dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, options);
+        assert_eq!(counts(manager.verify_pieces()), (5, 0, 0));
+
+        assert!(manager.storage.move_to_complete_dir().unwrap());
+        assert!(!manager.storage.move_to_complete_dir().unwrap());
+        assert!(!dir.path().join("fixture").exists());
+        assert_eq!(std::fs::read(done.path().join("fixture/a")).unwrap(), a);
+        assert_eq!(std::fs::read(done.path().join("fixture/b")).unwrap(), b);
+
+        let data = [a, b].concat();
+        for (index, piece) in data.chunks(16).enumerate() {
+            let read = manager.read_piece_from_disk(index, piece.len() as u64);
+            assert_eq!(read.unwrap(), piece);
         }
+    }
 
-        if bytes_read == piece_size as usize {
-            Ok(buffer)
-        } else {
-            anyhow::bail!(
-                "Incomplete read: expected {} bytes, got {}",
-                piece_size,
-                bytes_read
-            )
+    fn all_pieces(manager: &TorrentManager) -> Bitfield {
+        let mut bitfield = Bitfield::new(manager.piece_count());
+        for index in 0..manager.piece_count() {
+            bitfield.set(index, true);
         }
+        bitfield
     }
 
-    /// Writes a downloaded piece to disk.
-    ///
-    /// This mirrors `read_piece_from_disk` but performs writes. It ensures data is
-    /// correctly distributed across file boundaries if a piece spans multiple files.
-    /// Includes `sync_all()` calls to enforce data durability.
-    pub fn write_piece_to_disk(&self, index: usize, data: &[u8]) -> anyhow::Result<()> {
-        let output_dir = "downloads";
-        let piece_len = self.torrent.calculate_piece_size(index) as u64;
-
-        // Safety check to ensure network logic delivered the correct amount of data
-        if data.len() as u64 != piece_len {
-            anyhow::bail!(
-                "Data length mismatch. Expected {}, got {}",
-                piece_len,
-                data.len()
-            );
+    fn picks(manager: &TorrentManager) -> Vec<usize> {
+        let peer = all_pieces(manager);
+        std::iter::from_fn(|| manager.pick_next_piece(&peer)).collect()
+    }
+
+    #[test]
+    fn restricted_download_picks_only_the_given_pieces() {
+        let a = content(100, 1);
+        let (_dir, manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        let pieces = crate::utils::parse_index_list("1,3-4").unwrap();
+        manager.restrict_to_pieces(&pieces);
+        assert_eq!(picks(&manager), vec![1, 3, 4]);
+
+        assert!(!manager.is_complete());
+        for index in pieces {
+            manager.mark_piece_complete(index);
         }
+        assert!(manager.is_complete());
+    }
+
+    #[test]
+    fn restriction_keeps_skipped_files_skipped() {
+        let (a, b) = (content(32, 1), content(32, 2));
+        let (_dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        manager
+            .set_file_priorities(&[FilePriority::Skip, FilePriority::Normal])
+            .unwrap();
+        // Pieces 0-1 belong to the skipped file; 9 is past the end
+        manager.restrict_to_pieces(&[1, 2, 9]);
+        assert_eq!(picks(&manager), vec![2]);
+    }
+
+    #[test]
+    fn exhausted_download_quota_stops_picking() {
+        let a = content(64, 1);
+        let (dir, mut manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        let output_dir = dir.path().to_string_lossy().into_owned();
+        manager.quota = Arc::new(Quota::load(&output_dir, Some(20), None).unwrap());
+        let peer = all_pieces(&manager);
+
+        assert_eq!(manager.pick_next_piece(&peer), Some(0));
+        manager.record_download(16);
+        assert_eq!(manager.pick_next_piece(&peer), Some(1));
+        manager.record_download(16);
+        assert_eq!(manager.pick_next_piece(&peer), None);
+    }
+
+    #[test]
+    fn concurrent_picks_hand_each_piece_out_once() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
 
-        let piece_global_start = (index as u64) * (self.torrent.info.piece_length as u64);
-        let piece_global_end = piece_global_start + piece_len;
+        let a = content(16 * 300, 1);
+        let (_dir, manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        let manager = Arc::new(manager);
+        let piece_count = manager.piece_count();
+        let peer = Arc::new(all_pieces(&manager));
+        // Set while a worker holds the piece; a second pick of it would find it set
+        let held: Arc<Vec<AtomicBool>> =
+            Arc::new((0..piece_count).map(|_| AtomicBool::new(false)).collect());
+        let done: Arc<Vec<AtomicBool>> =
+            Arc::new((0..piece_count).map(|_| AtomicBool::new(false)).collect());
 
-        let files_list = if let Some(files) = &self.torrent.info.files {
-            files
-                .iter()
-                .map(|f| {
-                    let mut path = std::path::PathBuf::from(output_dir);
-                    path.push(&self.torrent.info.name);
-                    for part in &f.path {
-                        path.push(part);
+        let workers: Vec<_> = (0..8)
+            .map(|seed| {
+                let (manager, peer, held, done) =
+                    (manager.clone(), peer.clone(), held.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    while manager.downloaded_pieces() < manager.piece_count() {
+                        let Some(index) = manager.pick_next_piece(&peer) else {
+                            std::thread::yield_now();
+                            continue;
+                        };
+                        assert!(
+                            !held[index].swap(true, Ordering::SeqCst),
+                            "{} picked twice",
+                            index
+                        );
+                        assert!(
+                            !done[index].load(Ordering::SeqCst),
+                            "{} picked after completion",
+                            index
+                        );
+                        // Some downloads fail and hand the piece back
+                        if rng.gen_bool(0.3) {
+                            held[index].store(false, Ordering::SeqCst);
+                            manager.reset_piece(index);
+                        } else {
+                            done[index].store(true, Ordering::SeqCst);
+                            held[index].store(false, Ordering::SeqCst);
+                            manager.mark_piece_complete(index);
+                            // A late reset (e.g. a closing session) mustn't undo it
+                            manager.reset_piece(index);
+                        }
                     }
-                    (path, f.length)
                 })
-                .collect::<Vec<_>>()
-        } else {
-            let mut path = std::path::PathBuf::from(output_dir);
-            path.push(&self.torrent.info.name);
-            vec![(path, self.torrent.total_length())]
-        };
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
 
-        let mut file_global_start = 0u64;
+        assert_eq!(manager.downloaded_pieces(), manager.piece_count());
+        assert!(manager.is_complete());
+        assert!((0..piece_count).all(|index| manager.has_piece(index)));
+        assert_eq!(manager.pick_next_piece(&peer), None);
+    }
 
-        for (path, file_len) in files_list {
-            let file_global_end = file_global_start + (file_len as u64);
+    #[test]
+    fn skipped_file_pieces_are_not_needed() {
+        // Piece 2 straddles `a` and `b`, so it stays wanted; piece 3 is `b`'s alone
+        let (a, b, c) = (content(40, 1), content(24, 2), content(32, 3));
+        let (_dir, manager) = fixture(
+            &[("a", &a), ("b", &b), ("c", &c)],
+            16,
+            StorageOptions::default(),
+        );
+        manager
+            .set_file_priorities(&[
+                FilePriority::Normal,
+                FilePriority::Skip,
+                FilePriority::Normal,
+            ])
+            .unwrap();
+        assert!(!manager.wants_piece(3));
+        assert!(manager.wants_piece(2));
 
-            // Check overlap
-            if file_global_end > piece_global_start && file_global_start < piece_global_end {
-                let write_start_in_piece = if file_global_start > piece_global_start {
-                    file_global_start - piece_global_start
-                } else {
-                    0
-                };
-                let write_end_in_piece = if file_global_end < piece_global_end {
-                    file_global_end - piece_global_start
-                } else {
-                    piece_len
-                };
-                let seek_pos_in_file = if piece_global_start > file_global_start {
-                    piece_global_start - file_global_start
-                } else {
-                    0
-                };
+        let picked = picks(&manager);
+        assert_eq!(picked, vec![0, 1, 2, 4, 5]);
+        for index in picked {
+            manager.mark_piece_complete(index);
+        }
+        assert!(manager.is_complete());
+        assert_eq!(manager.bytes_left(), 0);
+        assert!(!manager.has_piece(3));
+    }
+
+    #[test]
+    fn high_priority_pieces_come_first() {
+        let (a, b) = (content(32, 1), content(32, 2));
+        let (_dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        manager
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::High])
+            .unwrap();
+        assert_eq!(picks(&manager), vec![2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn sequential_mode_picks_in_ascending_order() {
+        let (a, b) = (content(50, 1), content(70, 2));
+        let (_dir, mut manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        manager.strategy = PickStrategy::Sequential { first_last: false };
+        // Priorities don't reorder a sequential download
+        manager
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::High])
+            .unwrap();
+        assert_eq!(picks(&manager), (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sequential_first_last_fetches_file_edges_first() {
+        let (a, b) = (content(50, 1), content(70, 2));
+        let (_dir, mut manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        manager.strategy = PickStrategy::Sequential { first_last: true };
+        // `a` spans pieces 0-3 and `b` pieces 3-7
+        assert_eq!(picks(&manager), vec![0, 3, 7, 1, 2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn pause_halts_assignment_until_resumed() {
+        let a = content(64, 1);
+        let (_dir, manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        let peer = all_pieces(&manager);
+        assert_eq!(manager.pick_next_piece(&peer), Some(0));
+
+        manager.pause();
+        assert!(manager.is_paused());
+        assert_eq!(manager.pick_next_piece(&peer), None);
+
+        // The piece in progress was released, so it is handed out again
+        manager.resume();
+        assert_eq!(manager.pick_next_piece(&peer), Some(0));
+        assert_eq!(manager.pick_next_piece(&peer), Some(1));
+    }
+
+    #[test]
+    fn max_in_progress_caps_pieces_being_downloaded() {
+        let a = content(80, 1);
+        let (_dir, mut manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        manager.max_in_progress = Some(2);
+        assert_eq!(picks(&manager), vec![0, 1]);
+
+        // Finishing or releasing a piece makes room for one more
+        manager.mark_piece_complete(0);
+        assert_eq!(picks(&manager), vec![2]);
+        manager.reset_piece(1);
+        assert_eq!(picks(&manager), vec![1]);
+    }
+
+    #[test]
+    fn max_per_peer_refuses_a_session_holding_its_share() {
+        let a = content(80, 1);
+        let (_dir, mut manager) = fixture(&[("a", &a)], 16, StorageOptions::default());
+        manager.max_in_progress = Some(4);
+        let all = all_pieces(&manager);
+
+        assert_eq!(manager.pick_next_piece_for(&all, 0, Some(2)), Some(0));
+        assert_eq!(manager.pick_next_piece_for(&all, 1, Some(2)), Some(1));
+        // The torrent-wide cap still has room, but this session is at its own
+        assert_eq!(manager.pick_next_piece_for(&all, 2, Some(2)), None);
+        assert_eq!(manager.pick_next_piece_for(&all, 3, Some(2)), None);
+        // Other sessions carry on
+        assert_eq!(manager.pick_next_piece_for(&all, 0, Some(2)), Some(2));
+    }
+
+    #[tokio::test]
+    async fn read_range_prioritizes_and_returns_its_pieces() {
+        let data = content(100, 1);
+        let (_dir, manager) = fixture(&[("a", &data)], 16, StorageOptions::default());
+        let manager = Arc::new(manager);
+        let reader = manager.clone();
+        let read =
+            tokio::spawn(async move { reader.read_range(20, 30, Duration::from_secs(5)).await });
+        while manager.urgent_pieces.lock().unwrap().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        // Bytes 20-49 live in pieces 1-3, which are picked ahead of piece 0
+        let peer = all_pieces(&manager);
+        let mut picked = Vec::new();
+        for _ in 0..4 {
+            let index = manager.pick_next_piece(&peer).unwrap();
+            let start = index * 16;
+            let piece = &data[start..(start + 16).min(data.len())];
+            manager.storage.write_piece(index, piece).unwrap();
+            manager.mark_piece_complete(index);
+            picked.push(index);
+        }
+        assert_eq!(picked, vec![1, 2, 3, 0]);
+        assert_eq!(read.await.unwrap().unwrap(), data[20..50]);
+
+        // Out-of-range reads fail without waiting
+        assert!(
+            manager
+                .read_range(90, 20, Duration::from_secs(5))
+                .await
+                .is_err()
+        );
+    }
 
-                if let Some(parent) = path.parent() {
-                    std::fs::create_dir_all(parent).ok();
+    #[test]
+    fn seed_ratio_stops_seeding_once() {
+        let mut watch = SeedWatch::new(SeedLimits {
+            ratio: Some(2.0),
+            time: None,
+        });
+        assert_eq!(watch.check(150, 100), None);
+        let reason = watch.check(200, 100).unwrap();
+        assert!(reason.starts_with("Seed ratio 2.00 reached"), "{}", reason);
+        // Uploads keep coming in while the session winds down
+        assert_eq!(watch.check(250, 100), None);
+        assert_eq!(watch.check(400, 100), None);
+    }
+
+    /// The `FileCompleted` paths published so far, by file name.
+    fn completed_files(events: &mut broadcast::Receiver<TorrentEvent>) -> Vec<String> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                TorrentEvent::FileCompleted { path } => {
+                    Some(path.file_name()?.to_string_lossy().into_owned())
                 }
+                _ => None,
+            })
+            .collect()
+    }
 
-                let mut file = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&path)?;
-                file.seek(std::io::SeekFrom::Start(seek_pos_in_file))?;
+    #[test]
+    fn file_completes_once_its_last_piece_does() {
+        // Piece 2 (bytes 32-47) straddles both files
+        let (a, b) = (content(40, 1), content(25, 2));
+        let (_dir, manager) = fixture(&[("a", &a), ("b", &b)], 16, StorageOptions::default());
+        let mut events = manager.subscribe();
 
-                let buffer_slice =
-                    &data[write_start_in_piece as usize..write_end_in_piece as usize];
+        manager.mark_piece_complete(0);
+        manager.mark_piece_complete(1);
+        assert!(completed_files(&mut events).is_empty());
+        manager.mark_piece_complete(2);
+        assert_eq!(completed_files(&mut events), vec!["a"]);
 
-                file.write_all(buffer_slice)?;
-                // Critical for data integrity on crash/restart
-                file.sync_all()?;
-            }
-            file_global_start += file_len as u64;
+        // Completing a piece again, or the rest of `b`, doesn't announce `a` twice
+        manager.mark_piece_complete(2);
+        manager.mark_piece_complete(3);
+        assert!(completed_files(&mut events).is_empty());
+        manager.mark_piece_complete(4);
+        assert_eq!(completed_files(&mut events), vec!["b"]);
+    }
+
+    #[test]
+    fn straddling_piece_completes_both_files() {
+        let (a, b, c) = (content(24, 1), content(8, 2), content(16, 3));
+        let (_dir, manager) = fixture(
+            &[("a", &a), ("b", &b), ("c", &c)],
+            16,
+            StorageOptions::default(),
+        );
+        let mut events = manager.subscribe();
+        manager.mark_piece_complete(0);
+        assert!(completed_files(&mut events).is_empty());
+        // Piece 1 holds the end of `a` and all of `b`
+        manager.mark_piece_complete(1);
+        assert_eq!(completed_files(&mut events), vec!["a", "b"]);
+        manager.mark_piece_complete(2);
+        assert_eq!(completed_files(&mut events), vec!["c"]);
+    }
+
+    fn if_exists(policy: ExistingFilePolicy) -> StorageOptions {
+        StorageOptions {
+            if_exists: policy,
+            ..StorageOptions::default()
         }
-        Ok(())
+    }
+
+    #[test]
+    fn if_exists_verify_keeps_the_pieces_that_match() {
+        let mut a = content(64, 1);
+        let (dir, manager) = fixture(&[("a", &a)], 16, if_exists(ExistingFilePolicy::Verify));
+        let path = dir.path().join("fixture").join("a");
+        a[40] ^= 0xff;
+        std::fs::write(&path, &a).unwrap();
+
+        manager.verify_existing_data().unwrap();
+        assert_eq!(manager.downloaded_pieces(), 3);
+        assert!(!manager.has_piece(2));
+        assert_eq!(std::fs::read(&path).unwrap(), a);
+    }
+
+    #[test]
+    fn if_exists_skip_accepts_a_matching_file() {
+        let a = content(64, 1);
+        let (_dir, manager) = fixture(&[("a", &a)], 16, if_exists(ExistingFilePolicy::Skip));
+        manager.verify_existing_data().unwrap();
+        assert!(manager.is_complete());
+    }
+
+    #[test]
+    fn if_exists_skip_refuses_a_different_file() {
+        let mut a = content(64, 1);
+        let (dir, manager) = fixture(&[("a", &a)], 16, if_exists(ExistingFilePolicy::Skip));
+        let path = dir.path().join("fixture").join("a");
+        a[40] ^= 0xff;
+        std::fs::write(&path, &a).unwrap();
+        assert!(manager.verify_existing_data().is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), a);
+
+        // A size mismatch is refused before anything is read
+        std::fs::write(&path, &a[..50]).unwrap();
+        let error = manager.verify_existing_data().unwrap_err().to_string();
+        assert!(error.contains("different size"), "{}", error);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 50);
+    }
+
+    #[test]
+    fn if_exists_overwrite_starts_afresh() {
+        let a = content(64, 1);
+        let (dir, manager) = fixture(&[("a", &a)], 16, if_exists(ExistingFilePolicy::Overwrite));
+        let path = dir.path().join("fixture").join("a");
+        manager.verify_existing_data().unwrap();
+        assert_eq!(manager.downloaded_pieces(), 0);
+        assert!(!path.exists());
+        assert!(dir.path().join("fixture").join("a.part").exists());
+    }
+
+    #[test]
+    fn file_md5_hashes_the_first_length_bytes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("f");
+        std::fs::write(&path, b"message digest and more").unwrap();
+        // RFC 1321 test suite value for "message digest"
+        assert_eq!(
+            file_md5(&path, 14).unwrap(),
+            "f96b697d7cb7938d525a2f31aaf161d0"
+        );
+        assert_eq!(
+            file_md5(&path, 0).unwrap(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert!(file_md5(&path, 100).is_err());
+        assert!(file_md5(&dir.path().join("missing"), 1).is_err());
     }
 }
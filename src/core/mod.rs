@@ -1,4 +1,14 @@
 pub mod torrent_info;
 pub mod tracker;
 pub mod manager;
-pub mod creator;
\ No newline at end of file
+pub mod creator;
+pub mod storage;
+pub mod file_cache;
+pub mod disk;
+pub mod session;
+pub mod bitfield;
+pub mod dht;
+pub mod lsd;
+pub mod quota;
+#[cfg(test)]
+pub mod test_util;
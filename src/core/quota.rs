@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tracing::warn;
+
+/// Usage file kept in the output directory while a quota is set.
+const USAGE_FILE: &str = ".quota.json";
+
+/// Bytes counted against the quotas, as saved in the usage file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Usage {
+    downloaded: u64,
+    uploaded: u64,
+}
+
+/// Caps on payload bytes transferred (`--download-quota`, `--upload-quota`), counted
+/// across every torrent of the session and across runs.
+///
+/// Once the download quota is used up no new pieces are picked (pieces in flight
+/// still finish); once the upload quota is, peers are choked and no more blocks are
+/// served. The running totals are saved to `<output_dir>/.quota.json` so a restart
+/// carries on where the last run stopped; deleting the file starts afresh.
+#[derive(Debug, Default)]
+pub struct Quota {
+    download: Option<u64>,
+    upload: Option<u64>,
+    /// Usage file; `None` when no quota is set, in which case nothing is saved.
+    path: Option<PathBuf>,
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    /// Set once each quota has been reported as used up, so it is logged once.
+    download_reported: AtomicBool,
+    upload_reported: AtomicBool,
+}
+
+impl Quota {
+    /// Sets up the given quotas (in bytes), resuming the totals saved in `output_dir`
+    /// by earlier runs.
+    pub fn load(
+        output_dir: &str,
+        download: Option<u64>,
+        upload: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        if download.is_none() && upload.is_none() {
+            return Ok(Self::default());
+        }
+        let path = Path::new(output_dir).join(USAGE_FILE);
+        let usage: Usage = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| anyhow::anyhow!("Invalid quota usage file {:?}: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Usage::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            download,
+            upload,
+            path: Some(path),
+            downloaded: AtomicU64::new(usage.downloaded),
+            uploaded: AtomicU64::new(usage.uploaded),
+            ..Self::default()
+        })
+    }
+
+    pub fn record_download(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Whether the download quota is used up; logs it the first time.
+    pub fn download_exhausted(&self) -> bool {
+        Self::exhausted(
+            "downloading",
+            self.download,
+            &self.downloaded,
+            &self.download_reported,
+        )
+    }
+
+    /// Whether the upload quota is used up; logs it the first time.
+    pub fn upload_exhausted(&self) -> bool {
+        Self::exhausted(
+            "uploading",
+            self.upload,
+            &self.uploaded,
+            &self.upload_reported,
+        )
+    }
+
+    fn exhausted(
+        activity: &str,
+        quota: Option<u64>,
+        used: &AtomicU64,
+        reported: &AtomicBool,
+    ) -> bool {
+        let Some(quota) = quota else {
+            return false;
+        };
+        if used.load(Ordering::Relaxed) < quota {
+            return false;
+        }
+        if !reported.swap(true, Ordering::Relaxed) {
+            warn!("Quota of {} bytes reached; {} stopped", quota, activity);
+        }
+        true
+    }
+
+    /// Writes the running totals to the usage file, replacing it atomically. Does
+    /// nothing when no quota is set.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let usage = Usage {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+        };
+        let mut staging = path.clone().into_os_string();
+        staging.push(".tmp");
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&staging, serde_json::to_vec(&usage)?)?;
+        std::fs::rename(&staging, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn output_dir(dir: &TempDir) -> String {
+        dir.path().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn no_quota_is_never_exhausted_and_saves_nothing() {
+        let dir = TempDir::new().unwrap();
+        let quota = Quota::load(&output_dir(&dir), None, None).unwrap();
+        quota.record_download(usize::MAX);
+        quota.record_upload(usize::MAX);
+        assert!(!quota.download_exhausted());
+        assert!(!quota.upload_exhausted());
+        quota.save().unwrap();
+        assert!(!dir.path().join(USAGE_FILE).exists());
+    }
+
+    #[test]
+    fn exhausted_once_the_quota_is_reached() {
+        let dir = TempDir::new().unwrap();
+        let quota = Quota::load(&output_dir(&dir), Some(100), Some(50)).unwrap();
+        quota.record_download(99);
+        assert!(!quota.download_exhausted());
+        quota.record_download(1);
+        assert!(quota.download_exhausted());
+        // Stays exhausted when asked again
+        assert!(quota.download_exhausted());
+
+        assert!(!quota.upload_exhausted());
+        quota.record_upload(80);
+        assert!(quota.upload_exhausted());
+    }
+
+    #[test]
+    fn quotas_are_independent() {
+        let dir = TempDir::new().unwrap();
+        let quota = Quota::load(&output_dir(&dir), Some(10), None).unwrap();
+        quota.record_upload(1000);
+        assert!(!quota.download_exhausted());
+        assert!(!quota.upload_exhausted());
+    }
+
+    #[test]
+    fn usage_carries_over_between_runs() {
+        let dir = TempDir::new().unwrap();
+        let quota = Quota::load(&output_dir(&dir), Some(100), Some(100)).unwrap();
+        quota.record_download(60);
+        quota.record_upload(30);
+        quota.save().unwrap();
+
+        let resumed = Quota::load(&output_dir(&dir), Some(100), Some(100)).unwrap();
+        assert!(!resumed.download_exhausted());
+        resumed.record_download(40);
+        assert!(resumed.download_exhausted());
+        assert!(!resumed.upload_exhausted());
+
+        // A smaller quota on restart is already used up
+        let lowered = Quota::load(&output_dir(&dir), Some(50), None).unwrap();
+        assert!(lowered.download_exhausted());
+    }
+
+    #[test]
+    fn rejects_a_corrupt_usage_file() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join(USAGE_FILE), b"not json").unwrap();
+        assert!(Quota::load(&output_dir(&dir), Some(1), None).is_err());
+        // Without a quota the file isn't read at all
+        assert!(Quota::load(&output_dir(&dir), None, None).is_ok());
+    }
+}
@@ -0,0 +1,682 @@
+use crate::core::disk::DiskWriter;
+use crate::core::manager::{PickStrategy, TorrentManager};
+use crate::core::quota::Quota;
+use crate::core::storage::StorageOptions;
+use crate::core::torrent_info::{MetadataLimits, Torrent};
+use crate::core::tracker;
+use crate::events::EventLog;
+use crate::network::backoff::PeerBackoff;
+use crate::network::blocklist::Blocklist;
+use crate::network::choker::Choker;
+use crate::network::connect_timeout::ConnectTimeout;
+use crate::network::mse::EncryptionMode;
+use crate::network::rate_limiter::RateLimiter;
+use crate::network::socks5::ProxyConfig;
+use crate::network::webseed::WebSeed;
+use crate::network::{self, ConnectFailed, HandshakeClosed, PeerContext};
+use crate::utils;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, info, info_span, trace, warn};
+
+/// Port we listen on for inbound peers (and announce to trackers) by default.
+pub const DEFAULT_LISTEN_PORT: u16 = 8888;
+
+/// Default cap on simultaneous peer connections across all torrents.
+pub const DEFAULT_MAX_PEERS: usize = 50;
+
+/// A torrent refused a connection slot this recently still counts as wanting its
+/// fair share, so busier torrents leave room for it.
+const SLOT_WAIT_MEMORY: Duration = Duration::from_secs(60);
+
+/// Settings shared by every torrent in a session.
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    pub listen_port: u16,
+    /// Maximum peer connections (inbound and outbound) across all torrents.
+    pub max_peers: usize,
+    /// Upload and download caps in bytes per second across all peers (0 = unlimited).
+    pub upload_limit: u64,
+    pub download_limit: u64,
+    /// Peers unchoked at once across all torrents (0 = every interested peer).
+    pub upload_slots: usize,
+    /// Byte quotas on everything the session downloads and uploads.
+    pub quota: Arc<Quota>,
+    /// How piece data is stored for each torrent added.
+    pub storage: StorageOptions,
+    /// Piece selection order for each torrent added.
+    pub strategy: PickStrategy,
+    /// Pieces each torrent downloads at once, across its peers (`None` = no cap).
+    pub max_pending_pieces: Option<usize>,
+    /// Pieces a single peer session downloads at once (`None` = no cap).
+    pub max_pieces_per_peer: Option<usize>,
+    /// SOCKS5 proxy for outbound peer connections. Inbound peers still connect directly.
+    pub proxy: Option<ProxyConfig>,
+    /// Bytes per block request, and blocks kept in flight per peer (`None` adapts it).
+    pub block_size: u32,
+    pub pipeline_depth: Option<u32>,
+    /// Reveal pieces to peers one at a time once complete (see `PeerContext::super_seed`).
+    pub super_seed: bool,
+    /// Re-hash pieces before serving them (see `PeerContext::verify_on_seed`).
+    pub verify_on_seed: bool,
+    /// Client names (lowercase) whose peers are refused after the handshake.
+    pub blocked_clients: Vec<String>,
+    /// Peers in these ranges are never connected to or accepted.
+    pub blocklist: Arc<Blocklist>,
+    /// MSE/PE policy for outbound and inbound connections.
+    pub encryption: EncryptionMode,
+    /// First 8 bytes of our peer ID (`--peer-id-prefix`).
+    pub peer_id_prefix: [u8; 8],
+    /// `User-Agent` header for HTTP tracker requests (`--user-agent`).
+    pub user_agent: String,
+    /// Fixed outbound connect timeout; adaptive when `None`.
+    pub connect_timeout: Option<Duration>,
+    /// Silence after which a peer connection is dropped.
+    pub read_timeout: Duration,
+    /// Local address for peer connections, tracker requests and the listen socket
+    /// (`--bind`); the OS picks when `None`.
+    pub bind: Option<IpAddr>,
+    /// Caps on what an added torrent's metadata may declare.
+    pub metadata_limits: MetadataLimits,
+    /// JSON Lines event feed (`--events`) every torrent's events are written to.
+    pub event_log: Option<Arc<EventLog>>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            listen_port: DEFAULT_LISTEN_PORT,
+            max_peers: DEFAULT_MAX_PEERS,
+            upload_limit: 0,
+            download_limit: 0,
+            upload_slots: 0,
+            quota: Arc::new(Quota::default()),
+            storage: StorageOptions::default(),
+            strategy: PickStrategy::Default,
+            max_pending_pieces: None,
+            max_pieces_per_peer: None,
+            proxy: None,
+            super_seed: false,
+            verify_on_seed: false,
+            blocked_clients: Vec::new(),
+            block_size: network::DEFAULT_BLOCK_SIZE,
+            pipeline_depth: None,
+            blocklist: Arc::new(Blocklist::default()),
+            encryption: EncryptionMode::default(),
+            peer_id_prefix: utils::DEFAULT_PEER_ID_PREFIX,
+            user_agent: tracker::DEFAULT_USER_AGENT.to_string(),
+            connect_timeout: None,
+            read_timeout: network::DEFAULT_READ_TIMEOUT,
+            bind: None,
+            metadata_limits: MetadataLimits::default(),
+            event_log: None,
+        }
+    }
+}
+
+/// A torrent registered with a session, plus the per-torrent tasks serving it.
+pub struct TorrentHandle {
+    pub info_hash: [u8; 20],
+    pub manager: Arc<TorrentManager>,
+    pub disk: DiskWriter,
+    /// Cancels every peer session of this torrent (child of the session's token).
+    /// Replaced with a fresh token when the torrent is resumed after a pause.
+    cancel: Mutex<CancellationToken>,
+    /// Fires when the torrent leaves the session; stops its web seeds.
+    removed: CancellationToken,
+    /// Connection slots this torrent's peer sessions hold.
+    connections: Arc<AtomicUsize>,
+    /// When this torrent last wanted a connection slot and got none.
+    refused_at: Mutex<Option<Instant>>,
+}
+
+/// Counts a peer session against its torrent's connection slots until dropped.
+struct HeldConnection(Arc<AtomicUsize>);
+
+impl HeldConnection {
+    fn new(handle: &TorrentHandle) -> Self {
+        handle.connections.fetch_add(1, Ordering::Relaxed);
+        Self(handle.connections.clone())
+    }
+}
+
+impl Drop for HeldConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs any number of torrents in one process with shared limits.
+///
+/// Torrents are keyed by info hash. A single accept loop serves all of them: the
+/// info hash in an inbound handshake picks the torrent. Outbound and inbound peers
+/// draw from one connection semaphore, and all payload traffic goes through the
+/// same upload/download rate limiters, so adding a torrent never raises the totals.
+/// A torrent may use slots others leave idle, but not those a starved torrent needs
+/// to reach its fair share (see `within_fair_share`).
+pub struct Session {
+    pub peer_id: [u8; 20],
+    /// Tracker `key`, random and unchanged for the life of the process.
+    pub announce_key: u32,
+    pub options: SessionOptions,
+    torrents: RwLock<HashMap<[u8; 20], Arc<TorrentHandle>>>,
+    connection_slots: Arc<Semaphore>,
+    upload_limit: Arc<RateLimiter>,
+    download_limit: Arc<RateLimiter>,
+    choker: Arc<Choker>,
+    shutdown: CancellationToken,
+    /// Signalled by pause/resume; see `announce_requested`.
+    announce_wake: Notify,
+    /// Outbound peers that recently failed to connect, and when to try them again.
+    backoff: Arc<PeerBackoff>,
+    connect_timeout: Arc<ConnectTimeout>,
+}
+
+impl Session {
+    pub fn new(options: SessionOptions) -> Self {
+        Self {
+            peer_id: utils::generate_peer_id(&options.peer_id_prefix),
+            announce_key: rand::random(),
+            torrents: RwLock::new(HashMap::new()),
+            connection_slots: Arc::new(Semaphore::new(options.max_peers)),
+            upload_limit: Arc::new(RateLimiter::new(options.upload_limit)),
+            download_limit: Arc::new(RateLimiter::new(options.download_limit)),
+            choker: Arc::new(Choker::new(options.upload_slots)),
+            shutdown: CancellationToken::new(),
+            announce_wake: Notify::new(),
+            backoff: Arc::new(PeerBackoff::default()),
+            connect_timeout: Arc::new(ConnectTimeout::new(options.connect_timeout)),
+            options,
+        }
+    }
+
+    /// Loads a .torrent file, checks existing data on disk and starts its disk writer.
+    ///
+    /// Fails if a torrent with the same info hash is already in the session.
+    pub fn add_torrent(&self, path: &str) -> anyhow::Result<Arc<TorrentHandle>> {
+        let torrent = Torrent::read_with_limits(path, &self.options.metadata_limits)?;
+        self.add(torrent)
+    }
+
+    /// Adds an already loaded torrent, as `add_torrent` does after reading the file.
+    pub fn add(&self, torrent: Torrent) -> anyhow::Result<Arc<TorrentHandle>> {
+        let info_hash = torrent.info_hash()?;
+        if self.torrent(&info_hash).is_some() {
+            anyhow::bail!("Torrent {} is already added", hex::encode(info_hash));
+        }
+
+        let mut manager = TorrentManager::new(torrent, &self.options.storage);
+        manager.strategy = self.options.strategy;
+        manager.max_in_progress = self.options.max_pending_pieces;
+        manager.quota = self.options.quota.clone();
+        let manager = Arc::new(manager);
+        // Subscribed before the resume check, which can already complete files
+        if let Some(event_log) = &self.options.event_log {
+            event_log.follow(&manager)?;
+        }
+        // Pre-allocates files and marks already downloaded pieces
+        manager.verify_existing_data()?;
+        // Already complete from an earlier run, but not moved yet
+        if manager.is_complete()
+            && let Err(e) = manager.storage.move_to_complete_dir()
+        {
+            warn!("{:#}", e);
+        }
+
+        let handle = Arc::new(TorrentHandle {
+            info_hash,
+            disk: DiskWriter::spawn(manager.clone()),
+            manager,
+            cancel: Mutex::new(self.shutdown.child_token()),
+            removed: self.shutdown.child_token(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            refused_at: Mutex::new(None),
+        });
+
+        for url in handle.manager.torrent.web_seeds() {
+            if let Err(e) = WebSeed::spawn(
+                url.clone(),
+                handle.manager.clone(),
+                handle.disk.clone(),
+                self.options.proxy.as_ref(),
+                self.options.bind,
+                self.download_limit.clone(),
+                handle.removed.clone(),
+            ) {
+                warn!("Skipping web seed {}: {:#}", url, e);
+            }
+        }
+        self.torrents
+            .write()
+            .unwrap()
+            .insert(info_hash, handle.clone());
+        Ok(handle)
+    }
+
+    /// Removes a torrent: its peer sessions are cancelled and pending writes flushed.
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentHandle>> {
+        let handle = self.torrents.write().unwrap().remove(info_hash)?;
+        handle.cancel.lock().unwrap().cancel();
+        handle.removed.cancel();
+        if let Err(e) = handle.manager.flush() {
+            warn!(
+                "Failed to flush {}: {}",
+                handle.manager.torrent.info().name,
+                e
+            );
+        }
+        Some(handle)
+    }
+
+    /// Looks up a torrent by info hash.
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentHandle>> {
+        self.torrents.read().unwrap().get(info_hash).cloned()
+    }
+
+    /// Every torrent in the session, in no particular order.
+    pub fn torrents(&self) -> Vec<Arc<TorrentHandle>> {
+        self.torrents.read().unwrap().values().cloned().collect()
+    }
+
+    /// Pauses a torrent: its peer sessions are closed (releasing their pieces) and no
+    /// new ones are started until `resume_torrent`. Returns false if it isn't in the session.
+    ///
+    /// Wakes `announce_requested` so the supervision loop can send the Stopped announce.
+    pub fn pause_torrent(&self, info_hash: &[u8; 20]) -> bool {
+        let Some(handle) = self.torrent(info_hash) else {
+            return false;
+        };
+        handle.manager.pause();
+        handle.cancel.lock().unwrap().cancel();
+        self.announce_wake.notify_one();
+        true
+    }
+
+    /// Resumes a paused torrent and wakes `announce_requested` to re-announce it.
+    pub fn resume_torrent(&self, info_hash: &[u8; 20]) -> bool {
+        let Some(handle) = self.torrent(info_hash) else {
+            return false;
+        };
+        *handle.cancel.lock().unwrap() = self.shutdown.child_token();
+        handle.manager.resume();
+        self.announce_wake.notify_one();
+        true
+    }
+
+    /// Completes when a torrent was paused or resumed, i.e. trackers should be told now
+    /// rather than at the next regular announce.
+    pub async fn announce_requested(&self) {
+        self.announce_wake.notified().await;
+    }
+
+    /// Whether the blocklist forbids talking to `ip`.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.options.blocklist.contains(ip)
+    }
+
+    /// Removes peers the blocklist forbids from a list about to be connected to, and
+    /// returns how many were dropped.
+    ///
+    /// Peers are "ip:port" strings; anything unparseable is left to fail at connect time.
+    pub fn drop_blocked_peers(&self, peers: &mut Vec<String>) -> usize {
+        let found = peers.len();
+        peers.retain(|peer| {
+            !peer
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| self.is_blocked(addr.ip()))
+        });
+        found - peers.len()
+    }
+
+    fn peer_context(&self, handle: &TorrentHandle) -> PeerContext {
+        PeerContext {
+            info_hash: handle.info_hash,
+            peer_id: self.peer_id,
+            manager: handle.manager.clone(),
+            disk: handle.disk.clone(),
+            cancel: handle.cancel.lock().unwrap().clone(),
+            upload_limit: self.upload_limit.clone(),
+            download_limit: self.download_limit.clone(),
+            choker: self.choker.clone(),
+            proxy: self.options.proxy.clone(),
+            block_size: self.options.block_size,
+            pipeline_depth: self.options.pipeline_depth,
+            max_pieces_per_peer: self.options.max_pieces_per_peer,
+            super_seed: self.options.super_seed,
+            verify_on_seed: self.options.verify_on_seed,
+            blocked_clients: self.options.blocked_clients.clone(),
+            encryption: self.options.encryption,
+            connect_timeout: self.connect_timeout.clone(),
+            read_timeout: self.options.read_timeout,
+            bind: self.options.bind,
+        }
+    }
+
+    /// Starts an outbound session to `peer_addr` for the given torrent.
+    ///
+    /// Returns false (without connecting) when every connection slot is taken or the
+    /// torrent is paused. Peers still backing off from a failed attempt are skipped,
+    /// but leave the slot for the next one, so true is returned.
+    pub fn connect(&self, handle: &TorrentHandle, peer_addr: String) -> bool {
+        if handle.manager.is_paused() {
+            return false;
+        }
+        if !self.backoff.allows(&peer_addr) {
+            trace!(peer = %peer_addr, "Backing off");
+            return true;
+        }
+        let permit = match self.connection_slots.clone().try_acquire_owned() {
+            Ok(permit)
+                if self
+                    .within_fair_share(handle, self.connection_slots.available_permits() + 1) =>
+            {
+                permit
+            }
+            _ => {
+                *handle.refused_at.lock().unwrap() = Some(Instant::now());
+                return false;
+            }
+        };
+        let held = HeldConnection::new(handle);
+        let ctx = self.peer_context(handle);
+        let backoff = self.backoff.clone();
+        let span = info_span!("peer", addr = %peer_addr);
+        tokio::spawn(
+            async move {
+                // The slot is held for as long as the session runs
+                let _slot = (permit, held);
+                let result = network::run_peer_session(peer_addr.clone(), ctx).await;
+                match &result {
+                    Err(e) if e.downcast_ref::<ConnectFailed>().is_some() => {
+                        backoff.record_failure(&peer_addr)
+                    }
+                    _ => backoff.record_success(&peer_addr),
+                }
+                match result {
+                    // Routine, and still backed off from like any failed attempt
+                    Err(e) if e.downcast_ref::<HandshakeClosed>().is_some() => {
+                        trace!("Peer hung up during the handshake")
+                    }
+                    Err(e) => debug!("Session ended: {:#}", e),
+                    Ok(()) => {}
+                }
+            }
+            .instrument(span),
+        );
+        true
+    }
+
+    /// Accepts inbound peers for every torrent in the session until `shutdown`.
+    ///
+    /// Connections beyond the peer cap are closed immediately.
+    pub async fn listen(self: Arc<Self>) -> anyhow::Result<()> {
+        let address = self
+            .options
+            .bind
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let listener = TcpListener::bind((address, self.options.listen_port))
+            .await
+            .context(format!(
+                "Failed to listen on port {}",
+                self.options.listen_port
+            ))?;
+        info!("Listening for peers on port {}", self.options.listen_port);
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+
+            // Dropping the stream closes it before any handshake bytes are read
+            if self.is_blocked(addr.ip()) {
+                continue;
+            }
+            let Ok(permit) = self.connection_slots.clone().try_acquire_owned() else {
+                continue;
+            };
+            let session = self.clone();
+            tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    match session.handle_inbound(stream, addr).await {
+                        Err(e) if e.downcast_ref::<HandshakeClosed>().is_some() => {
+                            trace!("Peer hung up during the handshake")
+                        }
+                        Err(e) => debug!("Inbound session ended: {:#}", e),
+                        Ok(()) => {}
+                    }
+                }
+                .instrument(info_span!("peer", %addr)),
+            );
+        }
+    }
+
+    /// Routes an inbound connection to the torrent named in its handshake.
+    async fn handle_inbound(&self, stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+        let info_hashes: Vec<[u8; 20]> = self.torrents.read().unwrap().keys().copied().collect();
+        let (stream, remote) =
+            network::read_inbound_handshake(stream, &info_hashes, self.options.encryption).await?;
+        let handle = self
+            .torrent(&remote.info_hash)
+            .ok_or_else(|| anyhow::anyhow!("Peer {} asked for an unknown torrent", addr))?;
+        if handle.manager.is_paused() {
+            anyhow::bail!("Peer {} asked for a paused torrent", addr);
+        }
+        // Our slot is already taken, so it counts as free for this check
+        if !self.within_fair_share(&handle, self.connection_slots.available_permits() + 1) {
+            anyhow::bail!(
+                "Peer {} asked for a torrent over its share of connections",
+                addr
+            );
+        }
+        let _held = HeldConnection::new(&handle);
+        let ctx = self.peer_context(&handle);
+        network::run_inbound_session(stream, remote, addr.to_string(), ctx).await
+    }
+
+    /// Whether `handle` may hold one more connection slot, given `free` unused ones
+    /// (counting the slot in question).
+    ///
+    /// Below its fair share (the cap divided among running torrents) it always may.
+    /// Beyond that, it only gets a slot not needed to bring torrents that were recently
+    /// refused one up to their share, so one busy torrent can't starve the others.
+    fn within_fair_share(&self, handle: &TorrentHandle, free: usize) -> bool {
+        let torrents = self.torrents.read().unwrap();
+        let running: Vec<_> = torrents
+            .values()
+            .filter(|t| !t.manager.is_paused())
+            .collect();
+        let share = self.options.max_peers.div_ceil(running.len().max(1));
+        if handle.connections.load(Ordering::Relaxed) < share {
+            return true;
+        }
+        let reserved: usize = running
+            .iter()
+            .filter(|t| t.info_hash != handle.info_hash)
+            .filter(|t| {
+                t.refused_at
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|at| at.elapsed() < SLOT_WAIT_MEMORY)
+            })
+            .map(|t| share.saturating_sub(t.connections.load(Ordering::Relaxed)))
+            .sum();
+        free > reserved
+    }
+
+    /// Replaces the shared upload and download caps (bytes per second, 0 = unlimited).
+    pub fn set_rate_limits(&self, upload: u64, download: u64) {
+        self.upload_limit.set_rate(upload);
+        self.download_limit.set_rate(download);
+    }
+
+    /// Stops the accept loop and every peer session, then flushes all torrents.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+        for handle in self.torrents.read().unwrap().values() {
+            if let Err(e) = handle.manager.flush() {
+                warn!(
+                    "Failed to flush {}: {}",
+                    handle.manager.torrent.info().name,
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util;
+    use crate::network::handshake::Handshake;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    fn session_in(dir: &TempDir, max_peers: usize) -> Session {
+        Session::new(SessionOptions {
+            max_peers,
+            storage: StorageOptions {
+                output_dir: dir.path().to_string_lossy().into_owned(),
+                ..StorageOptions::default()
+            },
+            encryption: EncryptionMode::Disable,
+            ..SessionOptions::default()
+        })
+    }
+
+    /// A one-piece torrent; different names give different info hashes.
+    fn torrent(name: &str) -> Torrent {
+        test_util::torrent(name, &[1; 16384], 16384)
+    }
+
+    /// A peer that completes the handshake for `info_hash` and then holds the
+    /// connection open until the session closes it.
+    async fn mock_peer(info_hash: [u8; 20], id: u8) -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peer = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            socket.read_exact(&mut handshake).await.unwrap();
+            let reply = Handshake::new(info_hash, [id; 20]);
+            socket.write_all(&reply.as_bytes()).await.unwrap();
+            let _ = socket.read_to_end(&mut Vec::new()).await;
+        });
+        (addr, peer)
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn torrents_run_independently_within_the_shared_peer_cap() {
+        let dir = TempDir::new().unwrap();
+        let session = session_in(&dir, 2);
+        let a = session.add(torrent("a")).unwrap();
+        let b = session.add(torrent("b")).unwrap();
+
+        let (a_addr, a_peer) = mock_peer(a.info_hash, 1).await;
+        let (b_addr, _b_peer) = mock_peer(b.info_hash, 2).await;
+        assert!(session.connect(&a, a_addr));
+        assert!(session.connect(&b, b_addr));
+        wait_for(|| a.manager.peer_count() == 1 && b.manager.peer_count() == 1).await;
+
+        // Both slots are taken, whichever torrent asks
+        assert!(!session.connect(&a, "127.0.0.1:1".to_string()));
+        assert!(!session.connect(&b, "127.0.0.1:1".to_string()));
+
+        // Pausing one torrent closes only its own peers and frees their slot
+        assert!(session.pause_torrent(&a.info_hash));
+        timeout(Duration::from_secs(5), a_peer)
+            .await
+            .unwrap()
+            .unwrap();
+        wait_for(|| session.connection_slots.available_permits() == 1).await;
+        assert_eq!(b.manager.peer_count(), 1);
+
+        let (extra_addr, _extra_peer) = mock_peer(b.info_hash, 3).await;
+        assert!(session.connect(&b, extra_addr));
+        wait_for(|| b.manager.peer_count() == 2).await;
+        session.shutdown();
+    }
+
+    #[tokio::test]
+    async fn a_cap_of_three_is_shared_fairly_between_two_torrents() {
+        let dir = TempDir::new().unwrap();
+        let session = session_in(&dir, 3);
+        let a = session.add(torrent("a")).unwrap();
+        let b = session.add(torrent("b")).unwrap();
+        let connected = || a.manager.peer_count() + b.manager.peer_count();
+
+        // With nobody else waiting, one torrent may take every slot
+        let mut a_peers = Vec::new();
+        for id in 1..=3 {
+            let (addr, peer) = mock_peer(a.info_hash, id).await;
+            assert!(session.connect(&a, addr));
+            a_peers.push(peer);
+        }
+        wait_for(|| a.manager.peer_count() == 3).await;
+        assert!(!session.connect(&b, "127.0.0.1:1".to_string()));
+
+        // A freed slot goes to the refused torrent, not back to the one at its share
+        a_peers.pop().unwrap().abort();
+        wait_for(|| session.connection_slots.available_permits() == 1).await;
+        assert!(!session.connect(&a, "127.0.0.1:1".to_string()));
+        let (addr, _b_peer) = mock_peer(b.info_hash, 4).await;
+        assert!(session.connect(&b, addr));
+        wait_for(|| b.manager.peer_count() == 1).await;
+        assert_eq!(connected(), 3);
+        assert!(!session.connect(&a, "127.0.0.1:1".to_string()));
+        assert!(!session.connect(&b, "127.0.0.1:1".to_string()));
+        assert_eq!(connected(), 3);
+        session.shutdown();
+    }
+
+    #[test]
+    fn blocked_peers_are_dropped_before_connecting() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocklist.txt");
+        std::fs::write(&path, "bad range:10.0.0.0-10.255.255.255\n2001:db8::/32\n").unwrap();
+        let session = Session::new(SessionOptions {
+            blocklist: Arc::new(Blocklist::load(path.to_str().unwrap()).unwrap()),
+            ..SessionOptions::default()
+        });
+
+        let mut peers = vec![
+            "10.1.2.3:6881".to_string(),
+            "192.168.1.1:6881".to_string(),
+            "[2001:db8::7]:51413".to_string(),
+            "[::1]:6881".to_string(),
+            "tracker.invalid:6881".to_string(),
+        ];
+        assert_eq!(session.drop_blocked_peers(&mut peers), 2);
+        assert_eq!(
+            peers,
+            vec!["192.168.1.1:6881", "[::1]:6881", "tracker.invalid:6881"]
+        );
+    }
+}
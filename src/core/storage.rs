@@ -0,0 +1,1237 @@
+use crate::core::file_cache::{DEFAULT_MAX_OPEN_FILES, FileCache};
+use crate::core::torrent_info::Torrent;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Controls how often written data is forced to disk with `sync_all()`.
+///
+/// Syncing after every piece serializes downloads behind fsync latency. The trade-off
+/// is crash safety: anything written since the last sync may be lost on power failure,
+/// but the resume check re-hashes every piece on startup, so lost pieces are simply
+/// downloaded again rather than corrupting the result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Sync every file touched by a piece immediately after writing it.
+    PerPiece,
+    /// Sync dirty files once `interval` has elapsed or `every` pieces have been written.
+    Periodic { interval: Duration, every: usize },
+    /// Sync only when the download completes or the client shuts down.
+    OnComplete,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Periodic {
+            interval: Duration::from_secs(10),
+            every: 64,
+        }
+    }
+}
+
+impl SyncPolicy {
+    /// Parses the `--sync-policy` CLI value (`per-piece`, `periodic`, `on-complete`).
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "per-piece" => Ok(SyncPolicy::PerPiece),
+            "periodic" => Ok(SyncPolicy::default()),
+            "on-complete" => Ok(SyncPolicy::OnComplete),
+            _ => anyhow::bail!(
+                "Unknown sync policy '{}'. Expected per-piece, periodic or on-complete",
+                value
+            ),
+        }
+    }
+}
+
+/// How files are sized on disk before any data is downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AllocationPolicy {
+    /// Create empty files and only write where pieces land. Costs no space up front,
+    /// but files fragment as pieces arrive out of order.
+    Sparse,
+    /// Extend files to their full length with `set_len`. On most filesystems this makes
+    /// a sparse file; on some (e.g. FAT) it zero-fills, stalling until done.
+    #[default]
+    Full,
+    /// Reserve the blocks with `fallocate` (Linux), so the download can't run out of
+    /// space halfway and files stay contiguous. Falls back to `Full` elsewhere.
+    Prealloc,
+}
+
+impl AllocationPolicy {
+    /// Parses the `--alloc` CLI value (`sparse`, `full`, `prealloc`).
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "sparse" => Ok(AllocationPolicy::Sparse),
+            "full" => Ok(AllocationPolicy::Full),
+            "prealloc" => Ok(AllocationPolicy::Prealloc),
+            _ => anyhow::bail!(
+                "Unknown allocation policy '{}'. Expected sparse, full or prealloc",
+                value
+            ),
+        }
+    }
+}
+
+/// What to do with a file already at a torrent file's final path when the torrent is
+/// added (`--if-exists`). Our own `.part` staging files are always resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExistingFilePolicy {
+    /// Leave the file alone and refuse to start unless it matches the torrent.
+    Skip,
+    /// Treat its bytes as resume data: pieces that verify are kept, the rest are
+    /// downloaded over it.
+    #[default]
+    Verify,
+    /// Delete it and download the file from scratch.
+    Overwrite,
+}
+
+impl ExistingFilePolicy {
+    /// Parses the `--if-exists` CLI value (`skip`, `verify`, `overwrite`).
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "skip" => Ok(ExistingFilePolicy::Skip),
+            "verify" => Ok(ExistingFilePolicy::Verify),
+            "overwrite" => Ok(ExistingFilePolicy::Overwrite),
+            _ => anyhow::bail!(
+                "Unknown --if-exists policy '{}'. Expected skip, verify or overwrite",
+                value
+            ),
+        }
+    }
+}
+
+/// Tunables controlling where and how torrent data is stored.
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    /// Directory the torrent's files are placed under.
+    pub output_dir: String,
+    /// Where the files are moved once the torrent completes (`--complete-dir`).
+    pub complete_dir: Option<String>,
+    /// Maximum number of file handles kept open at once.
+    pub max_open_files: usize,
+    /// When written data is flushed to disk.
+    pub sync_policy: SyncPolicy,
+    /// How files are sized before data arrives.
+    pub allocation: AllocationPolicy,
+    /// How files already at their final path are treated.
+    pub if_exists: ExistingFilePolicy,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            output_dir: "downloads".to_string(),
+            complete_dir: None,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            sync_policy: SyncPolicy::default(),
+            allocation: AllocationPolicy::default(),
+            if_exists: ExistingFilePolicy::default(),
+        }
+    }
+}
+
+/// Mutable disk state guarded by a single lock: open handles plus sync bookkeeping.
+///
+/// The lock only covers handle lookup and bookkeeping. Reads, writes and `sync_all()`
+/// run on cloned handles after it is released, so serving uploads doesn't queue behind
+/// piece writes and fsyncs of other files.
+struct DiskState {
+    handles: FileCache,
+    pieces_since_sync: usize,
+    last_sync: Instant,
+    /// Per file: true once it has been renamed from `.part` to its final name.
+    /// Kept under the same lock as the handles so no lookup races a rename; a handle
+    /// cloned before the rename still refers to the same (renamed) file.
+    finalized: Vec<bool>,
+    /// True once the files live under `complete_dir` instead of `output_dir`.
+    relocated: bool,
+    /// True while `move_to_complete_dir` is moving files without holding the lock.
+    moving: bool,
+}
+
+/// Fills `buf` from `offset` without touching the shared file cursor, so several
+/// threads can use one handle at once.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes all of `buf` at `offset`; see `read_at`.
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reserves disk blocks for the whole file; existing data is kept.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is owned by `file` and stays open for the call
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    // Some filesystems (e.g. tmpfs on old kernels, NFS) can't do it; size it instead
+    if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        return file.set_len(length);
+    }
+    Err(err)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    file.set_len(length)
+}
+
+/// Maps a path under `from_dir` to the same relative path under `to_dir`.
+fn relocate(path: &Path, from_dir: &Path, to_dir: &Path) -> PathBuf {
+    match path.strip_prefix(from_dir) {
+        Ok(relative) => to_dir.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Makes one component of a torrent's paths (its `name`, or a part of a file's `path`)
+/// safe to join under the output directory: separators become `_`, as do empty, `.`
+/// and `..` components, so a torrent can't place files outside its own folder.
+/// Spaces and Unicode are kept as they are.
+fn safe_component(part: &str) -> String {
+    match part {
+        "" | "." | ".." => "_".to_string(),
+        _ => part.replace(['/', '\\', '\0'], "_"),
+    }
+}
+
+/// Moves a file, copying and deleting when a rename can't cross filesystems. Never
+/// overwrites an existing file.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if to.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists", to),
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let copied = std::fs::copy(from, to).and_then(|_| std::fs::File::open(to)?.sync_all());
+            if let Err(e) = copied {
+                // Don't leave a partial copy that would block a retry
+                let _ = std::fs::remove_file(to);
+                return Err(e);
+            }
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Returns the staging path used while a file is still downloading.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Sets a file's modification time, given in seconds since the Unix epoch.
+fn set_mtime(path: &Path, mtime: i64) -> std::io::Result<()> {
+    let offset = Duration::from_secs(mtime.unsigned_abs());
+    let time = if mtime >= 0 {
+        UNIX_EPOCH + offset
+    } else {
+        UNIX_EPOCH - offset
+    };
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(time)
+}
+
+/// A single file placed within the torrent's continuous byte stream.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Final path on disk (output directory + torrent name + path components).
+    /// While incomplete, the data lives at the staging path (`<path>.part`).
+    pub path: PathBuf,
+    /// Length of the file in bytes.
+    pub length: u64,
+    /// Global byte offset at which this file starts in the torrent stream.
+    pub offset: u64,
+    /// Modification time recorded in the torrent (seconds since the Unix epoch), given
+    /// to the file when it completes.
+    pub mtime: Option<i64>,
+}
+
+/// The portion of a piece that lands inside one file.
+struct Span {
+    file_index: usize,
+    /// Byte range relative to the PIECE.
+    start_in_piece: usize,
+    end_in_piece: usize,
+    /// Byte offset relative to the FILE.
+    seek_pos_in_file: u64,
+}
+
+/// Maps pieces onto the files that hold them on disk.
+///
+/// BitTorrent treats a multi-file torrent as one long string of bytes. The flattened
+/// layout (path, length, global offset) is computed once here so that piece reads and
+/// writes don't rebuild it on every call. Open file handles are cached and reused.
+///
+/// Incomplete files are written under a `.part` staging name and only renamed to their
+/// final name once all of their pieces are verified, so a finished-looking file on disk
+/// is always a finished file.
+pub struct Storage {
+    pub files: Vec<FileEntry>,
+    output_dir: PathBuf,
+    complete_dir: Option<PathBuf>,
+    /// Sidecar holding partly downloaded pieces between runs, `<output_dir>/.<name>.partial`.
+    partial_path: PathBuf,
+    piece_length: u64,
+    sync_policy: SyncPolicy,
+    allocation: AllocationPolicy,
+    if_exists: ExistingFilePolicy,
+    state: Mutex<DiskState>,
+}
+
+impl Storage {
+    pub fn new(torrent: &Torrent, options: &StorageOptions) -> Self {
+        let name = safe_component(&torrent.info().name);
+        if name != torrent.info().name {
+            warn!(
+                "Unsafe torrent name {:?}; saving as {:?}",
+                torrent.info().name,
+                name
+            );
+        }
+        let mut root = PathBuf::from(&options.output_dir);
+        root.push(&name);
+
+        let files = if let Some(files) = &torrent.info().files {
+            let mut offset = 0u64;
+            files
+                .iter()
+                .map(|f| {
+                    let mut path = root.clone();
+                    for part in &f.path {
+                        path.push(safe_component(part));
+                    }
+                    let entry = FileEntry {
+                        path,
+                        length: f.length as u64,
+                        offset,
+                        mtime: f.mtime,
+                    };
+                    offset += f.length as u64;
+                    entry
+                })
+                .collect()
+        } else {
+            vec![FileEntry {
+                path: root,
+                length: torrent.total_length() as u64,
+                offset: 0,
+                mtime: torrent.info().mtime,
+            }]
+        };
+
+        // Resume: a torrent found only in the completion directory was moved there by
+        // a previous run, and is read from there
+        let output_dir = PathBuf::from(&options.output_dir);
+        let complete_dir = options.complete_dir.as_ref().map(PathBuf::from);
+        let relocated = complete_dir.as_ref().is_some_and(|dir| {
+            let root = Path::new(&name);
+            dir.join(root).exists() && !output_dir.join(root).exists()
+        });
+        let locate = |path: &Path| match &complete_dir {
+            Some(dir) if relocated => relocate(path, &output_dir, dir),
+            _ => path.to_path_buf(),
+        };
+
+        // Resume: a file already at its final name (with no staging copy) was
+        // completed in a previous run and is read from there.
+        let finalized = files
+            .iter()
+            .map(|f: &FileEntry| {
+                let path = locate(&f.path);
+                path.exists() && !part_path(&path).exists()
+            })
+            .collect();
+
+        let partial_path = output_dir.join(format!(".{}.partial", name));
+
+        Self {
+            files,
+            output_dir,
+            complete_dir,
+            partial_path,
+            piece_length: torrent.info().piece_length as u64,
+            sync_policy: options.sync_policy,
+            allocation: options.allocation,
+            if_exists: options.if_exists,
+            state: Mutex::new(DiskState {
+                handles: FileCache::new(options.max_open_files),
+                pieces_since_sync: 0,
+                last_sync: Instant::now(),
+                finalized,
+                relocated,
+                moving: false,
+            }),
+        }
+    }
+
+    /// Writes partly downloaded pieces (index and the bytes received so far) to the
+    /// sidecar, replacing it atomically, or removes the sidecar if there are none.
+    pub fn save_partial_pieces(&self, pieces: &HashMap<usize, Vec<u8>>) -> anyhow::Result<()> {
+        if pieces.is_empty() {
+            return match std::fs::remove_file(&self.partial_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        // Each entry: piece index and length (u32, big-endian), then the bytes
+        let mut bytes = Vec::new();
+        for (&index, received) in pieces {
+            bytes.write_u32::<BigEndian>(index as u32)?;
+            bytes.write_u32::<BigEndian>(received.len() as u32)?;
+            bytes.extend_from_slice(received);
+        }
+        let mut staging = self.partial_path.clone().into_os_string();
+        staging.push(".tmp");
+        std::fs::create_dir_all(&self.output_dir)?;
+        std::fs::write(&staging, &bytes)?;
+        std::fs::rename(&staging, &self.partial_path)?;
+        Ok(())
+    }
+
+    /// Reads the sidecar written by `save_partial_pieces`. No sidecar means no pieces.
+    pub fn load_partial_pieces(&self) -> anyhow::Result<HashMap<usize, Vec<u8>>> {
+        let bytes = match std::fs::read(&self.partial_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut pieces = HashMap::new();
+        let mut reader = std::io::Cursor::new(&bytes[..]);
+        while (reader.position() as usize) < bytes.len() {
+            let index = reader.read_u32::<BigEndian>()? as usize;
+            let length = reader.read_u32::<BigEndian>()? as usize;
+            // Check the length before allocating: a corrupt entry could ask for 4 GiB
+            let left = bytes.len() - reader.position() as usize;
+            if length as u64 > self.piece_length || length > left {
+                warn!(
+                    "Corrupt partial pieces file {:?} (piece {} claims {} bytes); ignoring it",
+                    self.partial_path, index, length
+                );
+                return Ok(HashMap::new());
+            }
+            let mut received = vec![0u8; length];
+            reader.read_exact(&mut received)?;
+            pieces.insert(index, received);
+        }
+        Ok(pieces)
+    }
+
+    /// Returns where a file's data currently lives: the staging path until it's finalized.
+    pub fn disk_path(&self, file_index: usize) -> PathBuf {
+        let state = self.state.lock().unwrap();
+        self.resolve(&state, file_index)
+    }
+
+    fn resolve(&self, state: &DiskState, file_index: usize) -> PathBuf {
+        let path = self.final_path(state, file_index);
+        if state.finalized[file_index] {
+            path
+        } else {
+            part_path(&path)
+        }
+    }
+
+    /// A file's final path in whichever directory the torrent currently lives in.
+    fn final_path(&self, state: &DiskState, file_index: usize) -> PathBuf {
+        let path = &self.files[file_index].path;
+        match &self.complete_dir {
+            Some(dir) if state.relocated => relocate(path, &self.output_dir, dir),
+            _ => path.clone(),
+        }
+    }
+
+    /// Applies the `ExistingFilePolicy` to a file found at its final path, before
+    /// `allocate` could extend it. Any existing file longer than the torrent's is
+    /// warned about, since it is probably unrelated data.
+    ///
+    /// Fails under `Skip` if the file's size doesn't match. Returns true if the file
+    /// was kept under `Skip`; its content must then match too, which the caller checks
+    /// once the pieces are verified.
+    pub fn check_existing(&self, file_index: usize) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let path = self.resolve(&state, file_index);
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            return Ok(false);
+        };
+        let expected = self.files[file_index].length;
+        if metadata.len() > expected {
+            warn!(
+                "{:?} is larger than the torrent's file ({} > {} bytes); it may be unrelated data",
+                path,
+                metadata.len(),
+                expected
+            );
+        }
+        if !state.finalized[file_index] {
+            return Ok(false);
+        }
+
+        match self.if_exists {
+            ExistingFilePolicy::Verify => Ok(false),
+            ExistingFilePolicy::Skip if metadata.len() != expected => anyhow::bail!(
+                "{:?} already exists with a different size ({} bytes, expected {}); leaving it alone (--if-exists skip)",
+                path,
+                metadata.len(),
+                expected
+            ),
+            ExistingFilePolicy::Skip => Ok(true),
+            ExistingFilePolicy::Overwrite => {
+                state.handles.close(&path)?;
+                std::fs::remove_file(&path)?;
+                // Downloaded again through `.part` staging like any new file
+                state.finalized[file_index] = false;
+                info!("Discarded existing {:?} (--if-exists overwrite)", path);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Creates a file on disk (if missing) and sizes it according to the allocation policy.
+    ///
+    /// Files that already reach their full length are left alone. Under `Sparse`, a file
+    /// stays short until its last bytes are written; reading a piece beyond the end fails,
+    /// which the resume check treats like any missing piece.
+    pub fn allocate(&self, file_index: usize) -> std::io::Result<()> {
+        let path = self.disk_path(file_index);
+        let length = self.files[file_index].length;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .open(&path)?;
+        if self.allocation == AllocationPolicy::Sparse || file.metadata()?.len() >= length {
+            return Ok(());
+        }
+
+        debug!("Pre-allocating file: {:?} ({} bytes)", path, length);
+        match self.allocation {
+            AllocationPolicy::Prealloc => preallocate(&file, length)?,
+            _ => file.set_len(length)?,
+        }
+        // Force the size change to disk so a reader never sees a 0-byte file
+        file.sync_all()
+    }
+
+    /// Moves a fully verified file from its `.part` staging name to its final name.
+    ///
+    /// The handle is synced and closed first; the rename itself is atomic on the same
+    /// filesystem. Returns `false` if the file was already finalized.
+    pub fn finalize_file(&self, file_index: usize) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.finalized[file_index] {
+            return Ok(false);
+        }
+
+        let final_path = &self.final_path(&state, file_index);
+        let staging = part_path(final_path);
+        state.handles.close(&staging)?;
+        if !staging.exists() {
+            // Zero-length files may never have been touched
+            if let Some(parent) = final_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(&staging)?;
+        }
+        std::fs::rename(&staging, final_path)?;
+        state.finalized[file_index] = true;
+
+        // The data is complete either way; a file system without settable times only
+        // loses the timestamp
+        if let Some(mtime) = self.files[file_index].mtime
+            && let Err(e) = set_mtime(final_path, mtime)
+        {
+            warn!("Failed to set modification time of {:?}: {}", final_path, e);
+        }
+        Ok(true)
+    }
+
+    /// Computes which files a piece overlaps and the matching byte ranges.
+    ///
+    /// The layout is sorted by offset, so a binary search finds the first file
+    /// containing the piece start instead of scanning the whole list.
+    fn spans(&self, index: usize, piece_size: u64) -> Vec<Span> {
+        let piece_global_start = (index as u64) * self.piece_length;
+        let piece_global_end = piece_global_start + piece_size;
+
+        let first = self
+            .files
+            .partition_point(|f| f.offset + f.length <= piece_global_start);
+
+        let mut spans = Vec::new();
+        for (file_index, file) in self.files.iter().enumerate().skip(first) {
+            if file.offset >= piece_global_end {
+                break;
+            }
+            let file_global_end = file.offset + file.length;
+            if file.length == 0 || file_global_end <= piece_global_start {
+                // Zero-length files hold no bytes of any piece. Skipping them also means
+                // a piece never fails to read because an empty file was never created.
+                continue;
+            }
+
+            let start_in_piece = file.offset.saturating_sub(piece_global_start);
+            let end_in_piece = if file_global_end < piece_global_end {
+                file_global_end - piece_global_start
+            } else {
+                piece_size
+            };
+
+            spans.push(Span {
+                file_index,
+                start_in_piece: start_in_piece as usize,
+                end_in_piece: end_in_piece as usize,
+                seek_pos_in_file: piece_global_start.saturating_sub(file.offset),
+            });
+        }
+        spans
+    }
+
+    /// Reads a piece from disk, stitching it together from every file it spans.
+    pub fn read_piece(&self, index: usize, piece_size: u64) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; piece_size as usize];
+        let mut bytes_read = 0;
+        let spans = self.spans(index, piece_size);
+
+        let mut files = Vec::with_capacity(spans.len());
+        {
+            let mut state = self.state.lock().unwrap();
+            for span in &spans {
+                let path = self.resolve(&state, span.file_index);
+                if !path.exists() {
+                    anyhow::bail!("File missing during read operation");
+                }
+                files.push(state.handles.get(&path)?);
+            }
+        }
+
+        for (span, file) in spans.iter().zip(&files) {
+            read_at(
+                file,
+                &mut buffer[span.start_in_piece..span.end_in_piece],
+                span.seek_pos_in_file,
+            )?;
+            bytes_read += span.end_in_piece - span.start_in_piece;
+        }
+
+        if bytes_read == piece_size as usize {
+            Ok(buffer)
+        } else {
+            anyhow::bail!(
+                "Incomplete read: expected {} bytes, got {}",
+                piece_size,
+                bytes_read
+            )
+        }
+    }
+
+    /// Writes a piece to disk, distributing it across file boundaries.
+    ///
+    /// Whether the data is synced right away depends on the configured `SyncPolicy`.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let spans = self.spans(index, data.len() as u64);
+
+        let mut targets = Vec::with_capacity(spans.len());
+        {
+            let mut state = self.state.lock().unwrap();
+            for span in &spans {
+                let path = self.resolve(&state, span.file_index);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).ok();
+                }
+                let file = state.handles.get(&path)?;
+                targets.push((path, file));
+            }
+        }
+
+        for (span, (_, file)) in spans.iter().zip(&targets) {
+            write_at(
+                file,
+                &data[span.start_in_piece..span.end_in_piece],
+                span.seek_pos_in_file,
+            )?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for (path, file) in &targets {
+            if !state.handles.mark_dirty(path) {
+                // Evicted while we wrote; nothing else will sync this handle
+                file.sync_all()?;
+            }
+        }
+        state.pieces_since_sync += 1;
+
+        let due = match self.sync_policy {
+            SyncPolicy::PerPiece => true,
+            SyncPolicy::Periodic { interval, every } => {
+                state.pieces_since_sync >= every || state.last_sync.elapsed() >= interval
+            }
+            SyncPolicy::OnComplete => false,
+        };
+        if due {
+            Self::sync_state(state)?;
+        }
+        Ok(())
+    }
+
+    /// Moves every file of the torrent from the output directory to `complete_dir`, once.
+    ///
+    /// Files are renamed, or copied and then deleted when the directories are on
+    /// different filesystems. If any file fails, those already moved are moved back and
+    /// the torrent stays where it was. Returns `false` if there is nothing to do.
+    pub fn move_to_complete_dir(&self) -> anyhow::Result<bool> {
+        let Some(complete_dir) = &self.complete_dir else {
+            return Ok(false);
+        };
+        // Only the bookkeeping happens under the lock; the files are moved without it
+        // so reads keep going through the cached handles, which follow a rename.
+        let (moves, dirty) = {
+            let mut state = self.state.lock().unwrap();
+            if state.relocated || state.moving {
+                return Ok(false);
+            }
+            state.moving = true;
+            state.pieces_since_sync = 0;
+            state.last_sync = Instant::now();
+            let moves: Vec<(PathBuf, PathBuf)> = (0..self.files.len())
+                .map(|file_index| self.resolve(&state, file_index))
+                // Skipped files may never have been created
+                .filter(|from| from.exists())
+                .map(|from| {
+                    let to = relocate(&from, &self.output_dir, complete_dir);
+                    (from, to)
+                })
+                .collect();
+            (moves, state.handles.take_dirty())
+        };
+
+        let result = Self::move_files(dirty, &moves);
+        let mut state = self.state.lock().unwrap();
+        state.moving = false;
+        result?;
+        state.relocated = true;
+        for (from, _) in &moves {
+            state.handles.close(from)?;
+        }
+        drop(state);
+
+        // Leave no empty directories behind in the output directory
+        if let Some(root) = self.files.first().and_then(|f| {
+            let relative = f.path.strip_prefix(&self.output_dir).ok()?;
+            Some(self.output_dir.join(relative.components().next()?))
+        }) && root.is_dir()
+        {
+            for entry in walkdir::WalkDir::new(&root)
+                .contents_first(true)
+                .into_iter()
+                .flatten()
+            {
+                if entry.file_type().is_dir() {
+                    let _ = std::fs::remove_dir(entry.path());
+                }
+            }
+        }
+        info!("Moved {} files to {:?}", moves.len(), complete_dir);
+        Ok(true)
+    }
+
+    /// Syncs `dirty`, then moves each file `from` -> `to`, moving the ones already done
+    /// back if any fails.
+    fn move_files(dirty: Vec<Arc<File>>, moves: &[(PathBuf, PathBuf)]) -> anyhow::Result<()> {
+        for file in dirty {
+            file.sync_all()?;
+        }
+        for (done, (from, to)) in moves.iter().enumerate() {
+            if let Err(e) = move_file(from, to) {
+                for (from, to) in moves[..done].iter().rev() {
+                    if let Err(e) = move_file(to, from) {
+                        warn!("Failed to move {:?} back to {:?}: {}", to, from, e);
+                    }
+                }
+                return Err(
+                    anyhow::anyhow!(e).context(format!("Failed to move {:?} to {:?}", from, to))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs dirty files if the periodic interval has elapsed.
+    ///
+    /// Called from the supervision loop so a quiet download still gets flushed on time.
+    pub fn sync_if_due(&self) -> anyhow::Result<()> {
+        if let SyncPolicy::Periodic { interval, .. } = self.sync_policy {
+            let state = self.state.lock().unwrap();
+            if state.pieces_since_sync > 0 && state.last_sync.elapsed() >= interval {
+                Self::sync_state(state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forces every file with unsynced writes to disk, regardless of policy.
+    /// Used on completion and shutdown.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        Self::sync_state(self.state.lock().unwrap())
+    }
+
+    /// Resets the sync bookkeeping and syncs the dirty handles once the lock is released.
+    fn sync_state(mut state: MutexGuard<'_, DiskState>) -> anyhow::Result<()> {
+        let files = state.handles.take_dirty();
+        state.pieces_since_sync = 0;
+        state.last_sync = Instant::now();
+        drop(state);
+
+        for file in files {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::torrent_info::{FileNode, Info};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use serde_bytes::ByteBuf;
+    use tempfile::TempDir;
+
+    /// A multi-file torrent laid out under a fresh temporary output directory.
+    fn storage(lengths: &[u64], piece_length: u64) -> (TempDir, Storage) {
+        let dir = TempDir::new().unwrap();
+        let total: u64 = lengths.iter().sum();
+        let piece_count = total.div_ceil(piece_length) as usize;
+        let files = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &length)| FileNode {
+                length: length as i64,
+                path: vec![format!("file{}", i)],
+                md5sum: None,
+                mtime: None,
+            })
+            .collect();
+        let info = Info {
+            name: "layout".to_string(),
+            piece_length: piece_length as usize,
+            pieces: ByteBuf::from(vec![0; piece_count * 20]),
+            files: Some(files),
+            ..Info::default()
+        };
+        let torrent = Torrent::new(String::new(), None, info);
+        let options = StorageOptions {
+            output_dir: dir.path().to_string_lossy().into_owned(),
+            ..StorageOptions::default()
+        };
+        (dir, Storage::new(&torrent, &options))
+    }
+
+    fn piece_sizes(total: u64, piece_length: u64) -> Vec<u64> {
+        (0..total.div_ceil(piece_length))
+            .map(|i| piece_length.min(total - i * piece_length))
+            .collect()
+    }
+
+    /// Writes `data` piece by piece in the given order, then checks every piece reads
+    /// back and every file on disk holds exactly its slice of `data`.
+    fn round_trip(lengths: &[u64], piece_length: u64, data: &[u8], order: &[usize]) {
+        let (_dir, storage) = storage(lengths, piece_length);
+        let sizes = piece_sizes(data.len() as u64, piece_length);
+        let piece = |index: usize| {
+            let start = index * piece_length as usize;
+            &data[start..start + sizes[index] as usize]
+        };
+
+        for &index in order {
+            storage.write_piece(index, piece(index)).unwrap();
+        }
+        for (index, &size) in sizes.iter().enumerate() {
+            assert_eq!(
+                storage.read_piece(index, size).unwrap(),
+                piece(index),
+                "piece {} of {:?} at {} bytes per piece",
+                index,
+                lengths,
+                piece_length
+            );
+        }
+
+        let mut offset = 0;
+        for (file_index, &length) in lengths.iter().enumerate() {
+            let on_disk = match std::fs::read(storage.disk_path(file_index)) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => panic!("{}", e),
+            };
+            let end = offset + length as usize;
+            assert_eq!(on_disk, data[offset..end], "file {}", file_index);
+            offset = end;
+        }
+    }
+
+    fn sequential_round_trip(lengths: &[u64], piece_length: u64) {
+        let total: u64 = lengths.iter().sum();
+        let data: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+        let order: Vec<usize> = (0..piece_sizes(total, piece_length).len()).collect();
+        round_trip(lengths, piece_length, &data, &order);
+    }
+
+    #[test]
+    fn piece_straddles_two_files() {
+        sequential_round_trip(&[6, 10], 8);
+    }
+
+    #[test]
+    fn piece_straddles_three_files() {
+        sequential_round_trip(&[3, 4, 3], 10);
+        sequential_round_trip(&[5, 1, 1, 9], 8);
+    }
+
+    #[test]
+    fn short_last_piece() {
+        // Pieces of 8, 8 and 1 bytes
+        sequential_round_trip(&[10, 7], 8);
+        assert_eq!(piece_sizes(17, 8), vec![8, 8, 1]);
+    }
+
+    #[test]
+    fn zero_length_files_anywhere() {
+        sequential_round_trip(&[0, 5, 7], 4);
+        sequential_round_trip(&[5, 0, 7], 4);
+        sequential_round_trip(&[5, 7, 0], 4);
+        sequential_round_trip(&[0, 5, 0, 0, 7, 0], 4);
+    }
+
+    #[test]
+    fn randomized_layouts() {
+        let mut rng = StdRng::seed_from_u64(605);
+        for _ in 0..200 {
+            let lengths: Vec<u64> = (0..rng.gen_range(1..7))
+                .map(|_| {
+                    if rng.gen_bool(0.2) {
+                        0
+                    } else {
+                        rng.gen_range(1..40)
+                    }
+                })
+                .collect();
+            let total: u64 = lengths.iter().sum();
+            if total == 0 {
+                continue;
+            }
+            let piece_length = rng.gen_range(1..24);
+            let mut data = vec![0u8; total as usize];
+            rng.fill(&mut data[..]);
+            let mut order: Vec<usize> = (0..piece_sizes(total, piece_length).len()).collect();
+            order.shuffle(&mut rng);
+            round_trip(&lengths, piece_length, &data, &order);
+        }
+    }
+
+    #[test]
+    fn finalizing_renames_the_part_file() {
+        let (_dir, storage) = storage(&[10, 6], 8);
+        storage.write_piece(0, &[1; 8]).unwrap();
+        storage.write_piece(1, &[2; 8]).unwrap();
+        let staged = storage.disk_path(0);
+        let final_path = storage.files[0].path.clone();
+        assert_eq!(staged, part_path(&final_path));
+        assert!(staged.exists() && !final_path.exists());
+
+        assert!(storage.finalize_file(0).unwrap());
+        assert!(!staged.exists());
+        assert_eq!(storage.disk_path(0), final_path);
+        assert_eq!(
+            std::fs::read(&final_path).unwrap(),
+            [&[1; 8][..], &[2; 2]].concat()
+        );
+        // Only once, and the data stays readable under its new name
+        assert!(!storage.finalize_file(0).unwrap());
+        assert_eq!(storage.read_piece(0, 8).unwrap(), [1; 8]);
+        // The other file is still staged
+        assert_eq!(storage.disk_path(1), part_path(&storage.files[1].path));
+    }
+
+    #[test]
+    fn writing_many_pieces_opens_the_file_once() {
+        let (_dir, storage) = storage(&[64 * 16], 16);
+        for index in 0..64 {
+            storage.write_piece(index, &[index as u8; 16]).unwrap();
+        }
+        assert_eq!(storage.state.lock().unwrap().handles.opens(), 1);
+    }
+
+    /// Writes four pieces of one file under `policy` and returns the syncs made.
+    fn syncs_for(policy: SyncPolicy) -> (TempDir, Storage, u64) {
+        let (dir, mut storage) = storage(&[64], 16);
+        storage.sync_policy = policy;
+        for index in 0..4 {
+            storage.write_piece(index, &[1; 16]).unwrap();
+        }
+        let syncs = storage.state.lock().unwrap().handles.syncs();
+        (dir, storage, syncs)
+    }
+
+    #[test]
+    fn per_piece_policy_syncs_every_piece() {
+        assert_eq!(syncs_for(SyncPolicy::PerPiece).2, 4);
+    }
+
+    #[test]
+    fn periodic_policy_syncs_every_few_pieces() {
+        let policy = SyncPolicy::Periodic {
+            interval: Duration::from_secs(3600),
+            every: 2,
+        };
+        assert_eq!(syncs_for(policy).2, 2);
+    }
+
+    #[test]
+    fn on_complete_policy_syncs_only_when_flushed() {
+        let (_dir, storage, syncs) = syncs_for(SyncPolicy::OnComplete);
+        assert_eq!(syncs, 0);
+        storage.sync_if_due().unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.state.lock().unwrap().handles.syncs(), 1);
+    }
+
+    #[test]
+    fn parses_if_exists_policies() {
+        assert_eq!(
+            ExistingFilePolicy::parse("skip").unwrap(),
+            ExistingFilePolicy::Skip
+        );
+        assert_eq!(
+            ExistingFilePolicy::parse("verify").unwrap(),
+            ExistingFilePolicy::Verify
+        );
+        assert_eq!(
+            ExistingFilePolicy::parse("overwrite").unwrap(),
+            ExistingFilePolicy::Overwrite
+        );
+        assert!(ExistingFilePolicy::parse("Skip").is_err());
+        assert_eq!(ExistingFilePolicy::default(), ExistingFilePolicy::Verify);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sparse_allocation_only_uses_the_blocks_written() {
+        use std::os::unix::fs::MetadataExt;
+        const LENGTH: u64 = 8 * 1024 * 1024;
+        let (_dir, mut storage) = storage(&[LENGTH], 16384);
+        storage.allocation = AllocationPolicy::Sparse;
+        storage.allocate(0).unwrap();
+        let path = storage.disk_path(0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        let last = (LENGTH / 16384 - 1) as usize;
+        storage.write_piece(last, &[7; 16384]).unwrap();
+        storage.flush().unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.len(), LENGTH);
+        // `blocks` counts 512-byte units actually stored, whatever the block size
+        assert!(
+            metadata.blocks() * 512 < LENGTH / 8,
+            "{} blocks",
+            metadata.blocks()
+        );
+    }
+
+    #[test]
+    fn safe_component_neutralizes_separators_and_dot_names() {
+        assert_eq!(safe_component("../evil"), ".._evil");
+        assert_eq!(safe_component("sub/dir\\file"), "sub_dir_file");
+        assert_eq!(safe_component(".."), "_");
+        assert_eq!(safe_component("."), "_");
+        assert_eq!(safe_component(""), "_");
+        assert_eq!(safe_component("my file é.txt"), "my file é.txt");
+        assert_eq!(safe_component(".hidden"), ".hidden");
+    }
+
+    #[test]
+    fn single_file_names_stay_inside_the_output_dir() {
+        let dir = TempDir::new().unwrap();
+        let options = StorageOptions {
+            output_dir: dir.path().to_string_lossy().into_owned(),
+            ..StorageOptions::default()
+        };
+        for (name, placed) in [
+            ("../evil", ".._evil"),
+            ("sub/dir/file", "sub_dir_file"),
+            ("..", "_"),
+            ("my file é.txt", "my file é.txt"),
+        ] {
+            let info = Info {
+                name: name.to_string(),
+                piece_length: 16,
+                pieces: ByteBuf::from(vec![0; 20]),
+                length: Some(10),
+                ..Info::default()
+            };
+            let storage = Storage::new(&Torrent::new(String::new(), None, info), &options);
+            assert_eq!(storage.files[0].path, dir.path().join(placed), "{}", name);
+        }
+    }
+
+    #[test]
+    fn multi_file_paths_stay_inside_the_torrent_folder() {
+        let dir = TempDir::new().unwrap();
+        let options = StorageOptions {
+            output_dir: dir.path().to_string_lossy().into_owned(),
+            ..StorageOptions::default()
+        };
+        let info = Info {
+            name: "..".to_string(),
+            piece_length: 16,
+            pieces: ByteBuf::from(vec![0; 20]),
+            files: Some(vec![FileNode {
+                length: 10,
+                path: vec!["..".to_string(), "a/b".to_string()],
+                md5sum: None,
+                mtime: None,
+            }]),
+            ..Info::default()
+        };
+        let storage = Storage::new(&Torrent::new(String::new(), None, info), &options);
+        assert_eq!(storage.files[0].path, dir.path().join("_/_/a_b"));
+    }
+
+    #[test]
+    fn partial_pieces_round_trip() {
+        let (_dir, storage) = storage(&[100], 32);
+        let pieces = HashMap::from([(0, vec![1; 16]), (2, vec![2; 31])]);
+        storage.save_partial_pieces(&pieces).unwrap();
+        assert_eq!(storage.load_partial_pieces().unwrap(), pieces);
+    }
+
+    #[test]
+    fn corrupt_partial_pieces_file_is_ignored() {
+        let (_dir, storage) = storage(&[100], 32);
+        let entry = |index: u32, length: u32, data: &[u8]| {
+            let mut bytes = Vec::new();
+            bytes.write_u32::<BigEndian>(index).unwrap();
+            bytes.write_u32::<BigEndian>(length).unwrap();
+            bytes.extend_from_slice(data);
+            bytes
+        };
+        std::fs::create_dir_all(&storage.output_dir).unwrap();
+
+        // Longer than a piece, even though the bytes are there
+        std::fs::write(&storage.partial_path, entry(0, 40, &[0; 40])).unwrap();
+        assert!(storage.load_partial_pieces().unwrap().is_empty());
+
+        // Truncated: claims more than the file holds
+        let mut bytes = entry(0, 8, &[1; 8]);
+        bytes.extend(entry(1, 16, &[2; 4]));
+        std::fs::write(&storage.partial_path, &bytes).unwrap();
+        assert!(storage.load_partial_pieces().unwrap().is_empty());
+
+        // Garbage length, which would otherwise allocate 4 GiB
+        std::fs::write(&storage.partial_path, entry(0, u32::MAX, &[])).unwrap();
+        assert!(storage.load_partial_pieces().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finalized_files_get_their_recorded_mtime() {
+        let dir = TempDir::new().unwrap();
+        let file = |name: &str, mtime| FileNode {
+            length: 8,
+            path: vec![name.to_string()],
+            md5sum: None,
+            mtime,
+        };
+        let info = Info {
+            name: "dated".to_string(),
+            piece_length: 16,
+            pieces: ByteBuf::from(vec![0; 20]),
+            files: Some(vec![file("old", Some(1_600_000_000)), file("new", None)]),
+            ..Info::default()
+        };
+        let options = StorageOptions {
+            output_dir: dir.path().to_string_lossy().into_owned(),
+            ..StorageOptions::default()
+        };
+        let storage = Storage::new(&Torrent::new(String::new(), None, info), &options);
+        storage.write_piece(0, &[1; 16]).unwrap();
+        assert!(storage.finalize_file(0).unwrap());
+        assert!(storage.finalize_file(1).unwrap());
+
+        let modified = |index: usize| {
+            std::fs::metadata(&storage.files[index].path)
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        assert_eq!(modified(0), UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert!(modified(1) > UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    }
+}
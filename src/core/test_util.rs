@@ -0,0 +1,37 @@
+//! Fixtures shared by the unit tests.
+
+use crate::core::manager::TorrentManager;
+use crate::core::storage::StorageOptions;
+use crate::core::torrent_info::{Info, Torrent};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A single-file torrent `name` of `data`, with real piece hashes.
+pub fn torrent(name: &str, data: &[u8], piece_length: usize) -> Torrent {
+    let pieces: Vec<u8> = data
+        .chunks(piece_length)
+        .flat_map(|p| Sha1::digest(p).to_vec())
+        .collect();
+    let info = Info {
+        name: name.to_string(),
+        piece_length,
+        pieces: ByteBuf::from(pieces),
+        length: Some(data.len() as i64),
+        ..Info::default()
+    };
+    Torrent::new(String::new(), None, info)
+}
+
+/// A manager downloading the torrent "data" of `data` into `dir`.
+pub fn manager_for(dir: &Path, data: &[u8], piece_length: usize) -> Arc<TorrentManager> {
+    let options = StorageOptions {
+        output_dir: dir.to_string_lossy().into_owned(),
+        ..StorageOptions::default()
+    };
+    Arc::new(TorrentManager::new(
+        torrent("data", data, piece_length),
+        &options,
+    ))
+}
@@ -1,8 +1,36 @@
 use anyhow::Context;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
+use std::sync::OnceLock;
+
+/// Smallest piece length accepted for a torrent with more than one piece: one request
+/// block. Anything smaller only inflates the piece count.
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+
+/// Caps on what a .torrent may declare, checked before any per-piece or per-file state
+/// is allocated, so hostile metadata can't exhaust memory.
+#[derive(Debug, Clone, Copy)]
+pub struct MetadataLimits {
+    pub max_pieces: usize,
+    pub max_files: usize,
+    /// In bytes.
+    pub max_total_length: u64,
+}
+
+impl Default for MetadataLimits {
+    fn default() -> Self {
+        Self {
+            max_pieces: 10_000_000,
+            max_files: 1_000_000,
+            max_total_length: 16 << 40, // 16 TiB
+        }
+    }
+}
 
 /// Represents the top-level dictionary of a Metainfo (.torrent) file.
 ///
@@ -18,15 +46,56 @@ pub struct Torrent {
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<String>>>,
 
+    /// DHT nodes to bootstrap from, as `(host, port)` pairs (BEP 5). Mostly found in
+    /// trackerless torrents.
+    pub nodes: Option<Vec<(String, i64)>>,
+
+    /// HTTP web seeds (BEP 19). A single URL or a list of them.
+    #[serde(rename = "url-list")]
+    pub url_list: Option<UrlList>,
+
     /// The dictionary containing specific metadata about the file(s) and pieces.
-    pub info: Info,
+    /// Read-only (see `info()`), so the cached `info_hash` can't go stale.
+    info: Info,
+
+    /// Lazily computed Info Hash. Filled on the first call to `info_hash()` and
+    /// carried along when the torrent is cloned, since the clone shares the same `info`.
+    #[serde(skip)]
+    info_hash: OnceLock<[u8; 20]>,
+
+    /// Tracker tiers, shuffled once (see `get_tracker_tiers`). Filled when the torrent
+    /// is loaded and rebuilt after the tracker list is edited.
+    #[serde(skip)]
+    tracker_tiers: OnceLock<Vec<Vec<String>>>,
+}
+
+/// A piece lookup the metadata can't satisfy (corrupt `pieces`, impossible sizes).
+/// Unlike network or disk errors this can never succeed on retry, so peer sessions
+/// that hit it stop the whole torrent (see `TorrentManager::report_invalid_metadata`).
+#[derive(Debug)]
+pub struct InvalidMetadata(pub String);
+
+impl std::fmt::Display for InvalidMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid torrent metadata: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMetadata {}
+
+/// The `url-list` value, which metainfo files give either as one string or a list.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
 }
 
 /// The 'info' dictionary containing file structure and integrity data.
 ///
 /// The SHA-1 hash of the Bencoded form of this struct is the "Info Hash",
 /// which uniquely identifies the torrent in the global swarm.
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Info {
     /// Suggested name for the file (single-file) or root directory (multi-file).
     pub name: String,
@@ -44,6 +113,82 @@ pub struct Info {
 
     /// List of files. Present only in multi-file mode.
     pub files: Option<Vec<FileNode>>,
+
+    /// Optional MD5 of the file as 32 hex characters. Single-file mode only; multi-file
+    /// torrents carry it per file.
+    pub md5sum: Option<String>,
+
+    /// Optional modification time of the file, in seconds since the Unix epoch, which
+    /// the downloader gives the finished file. Single-file mode only, like `md5sum`.
+    /// Part of `info`, so recording it changes the Info Hash.
+    pub mtime: Option<i64>,
+
+    /// `1` marks a private torrent (BEP 27): peers should only come from its trackers.
+    pub private: Option<i64>,
+
+    /// Set by private trackers so the same content gets a distinct Info Hash per tracker.
+    pub source: Option<String>,
+
+    /// Any other keys, kept so that re-serializing the dictionary (and so the Info Hash)
+    /// doesn't change.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// Finds the byte range of the `info` value in bencoded metainfo.
+fn info_span(bytes: &[u8]) -> anyhow::Result<std::ops::Range<usize>> {
+    if bytes.first() != Some(&b'd') {
+        anyhow::bail!("Metainfo is not a dictionary");
+    }
+    let mut pos = 1;
+    while bytes.get(pos) != Some(&b'e') {
+        let key_end = skip_value(bytes, pos)?;
+        let value_end = skip_value(bytes, key_end)?;
+        if &bytes[pos..key_end] == b"4:info" {
+            return Ok(key_end..value_end);
+        }
+        pos = value_end;
+    }
+    anyhow::bail!("Metainfo has no 'info' dictionary")
+}
+
+/// Returns the position just past the bencoded value that starts at `pos`.
+fn skip_value(bytes: &[u8], pos: usize) -> anyhow::Result<usize> {
+    let malformed = || anyhow::anyhow!("Malformed bencode at byte {}", pos);
+    match bytes.get(pos) {
+        // Integer: i<digits>e
+        Some(b'i') => bytes[pos..]
+            .iter()
+            .position(|&b| b == b'e')
+            .map(|offset| pos + offset + 1)
+            .ok_or_else(malformed),
+        // List or dictionary: values (or key/value pairs) up to the closing e
+        Some(b'l' | b'd') => {
+            let mut end = pos + 1;
+            while bytes.get(end) != Some(&b'e') {
+                end = skip_value(bytes, end)?;
+            }
+            Ok(end + 1)
+        }
+        // String: <length>:<bytes>
+        Some(b'0'..=b'9') => {
+            let colon = pos
+                + bytes[pos..]
+                    .iter()
+                    .position(|&b| b == b':')
+                    .ok_or_else(malformed)?;
+            let length: usize = std::str::from_utf8(&bytes[pos..colon])
+                .ok()
+                .and_then(|digits| digits.parse().ok())
+                .ok_or_else(malformed)?;
+            let end = (colon + 1).checked_add(length).ok_or_else(malformed)?;
+            if end > bytes.len() {
+                return Err(malformed());
+            }
+            Ok(end)
+        }
+        _ => Err(malformed()),
+    }
 }
 
 /// Represents a single file within a multi-file torrent structure.
@@ -52,23 +197,224 @@ pub struct FileNode {
     pub length: i64,
     /// The path components of the file (e.g., ["folder", "subfolder", "file.txt"]).
     pub path: Vec<String>,
+    /// Optional MD5 of the file as 32 hex characters; omitted when absent, so torrents
+    /// without it keep their Info Hash.
+    pub md5sum: Option<String>,
+    /// Optional modification time in seconds since the Unix epoch, restored on the
+    /// downloaded file. Omitted when absent, like `md5sum`.
+    pub mtime: Option<i64>,
+}
+
+/// Machine-readable overview of a torrent, produced by `Torrent::summary()`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TorrentSummary {
+    pub name: String,
+    pub total_length: i64,
+    pub piece_length: usize,
+    pub piece_count: usize,
+    /// Hex-encoded Info Hash.
+    pub info_hash: String,
+    /// Tracker URLs in metainfo order (primary first), without duplicates.
+    pub trackers: Vec<String>,
+    pub files: Vec<FileSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct FileSummary {
+    /// Path relative to the torrent root, joined with `/`.
+    pub path: String,
+    pub length: i64,
 }
 
 impl Torrent {
+    /// Builds a torrent from its parts. Used by the creator when generating new metainfo.
+    pub fn new(announce: String, announce_list: Option<Vec<Vec<String>>>, info: Info) -> Self {
+        Self {
+            announce,
+            announce_list,
+            nodes: None,
+            url_list: None,
+            info,
+            info_hash: OnceLock::new(),
+            tracker_tiers: OnceLock::new(),
+        }
+    }
+
     /// Reads and deserializes a .torrent file from the specified path.
     pub fn read(file_path: &str) -> anyhow::Result<Self> {
+        Self::read_with_limits(file_path, &MetadataLimits::default())
+    }
+
+    /// Like `read`, with caps other than the default `MetadataLimits`.
+    pub fn read_with_limits(file_path: &str, limits: &MetadataLimits) -> anyhow::Result<Self> {
         let file_content = fs::read(file_path).context("Failed to read torrent file")?;
+        Self::from_bytes_with_limits(&file_content, limits)
+    }
+
+    /// Decodes and validates bencoded metainfo.
+    ///
+    /// The Info Hash is taken from the `info` dictionary exactly as it appears in
+    /// `bytes`, so keys we don't model, key order or integer formatting in the original
+    /// can't change it.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Self::from_bytes_with_limits(bytes, &MetadataLimits::default())
+    }
 
+    /// Like `from_bytes`, with caps other than the default `MetadataLimits`.
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &MetadataLimits) -> anyhow::Result<Self> {
         let torrent: Torrent =
-            serde_bencode::from_bytes(&file_content).context("Failed to decode bencode data")?;
+            serde_bencode::from_bytes(bytes).context("Failed to decode bencode data")?;
+        torrent
+            .check_limits(limits)
+            .context("Torrent exceeds metadata limits")?;
+        torrent.validate().context("Invalid torrent metadata")?;
 
+        let info_bytes = &bytes[info_span(bytes)?];
+        let _ = torrent.info_hash.set(Sha1::digest(info_bytes).into());
+        torrent.get_tracker_tiers();
         Ok(torrent)
     }
 
-    /// Calculates the Info Hash (SHA-1) of the 'info' dictionary.
+    /// Encodes the torrent back into metainfo bytes.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    /// Rejects torrents declaring more pieces, files or bytes than `limits` allow, or a
+    /// piece length under `MIN_PIECE_LENGTH` spread over several pieces.
+    ///
+    /// Runs before `validate`, on declared values only, so nothing is sized from them yet.
+    fn check_limits(&self, limits: &MetadataLimits) -> anyhow::Result<()> {
+        let file_count = self.info.files.as_ref().map_or(1, Vec::len);
+        if file_count > limits.max_files {
+            anyhow::bail!("{} files (limit {})", file_count, limits.max_files);
+        }
+
+        let total_length = match &self.info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| file.length.max(0) as u64)
+                .fold(0u64, u64::saturating_add),
+            None => self.info.length.unwrap_or(0).max(0) as u64,
+        };
+        if total_length > limits.max_total_length {
+            anyhow::bail!(
+                "{} bytes of content (limit {})",
+                total_length,
+                limits.max_total_length
+            );
+        }
+
+        let piece_length = self.info.piece_length.max(1);
+        if piece_length < MIN_PIECE_LENGTH && total_length > piece_length as u64 {
+            anyhow::bail!(
+                "'piece length' of {} bytes is too small for {} bytes of content (at least {})",
+                self.info.piece_length,
+                total_length,
+                MIN_PIECE_LENGTH
+            );
+        }
+
+        let piece_count =
+            (self.info.pieces.len() / 20).max(total_length.div_ceil(piece_length as u64) as usize);
+        if piece_count > limits.max_pieces {
+            anyhow::bail!("{} pieces (limit {})", piece_count, limits.max_pieces);
+        }
+        Ok(())
+    }
+
+    /// Checks that the `info` dictionary is internally consistent.
     ///
-    /// This requires re-serializing the parsed `Info` struct back into Bencode
-    /// to ensure the hash matches the original file exactly.
+    /// Rejects torrents that set both or neither of `length` and `files`, contain a
+    /// negative length, overflow when summing file lengths, have a zero piece length,
+    /// or whose `pieces` blob
+    /// doesn't hold exactly one hash per `piece length` bytes of content.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let total_length = match (self.info.length, &self.info.files) {
+            (Some(_), Some(_)) => anyhow::bail!("Both 'length' and 'files' are present"),
+            (None, None) => anyhow::bail!("Neither 'length' nor 'files' is present"),
+            (Some(length), None) => {
+                if length < 0 {
+                    anyhow::bail!("Negative file length: {}", length);
+                }
+                length
+            }
+            (None, Some(files)) => {
+                let mut total: i64 = 0;
+                for file in files {
+                    if file.length < 0 {
+                        anyhow::bail!("Negative length {} for file {:?}", file.length, file.path);
+                    }
+                    total = total
+                        .checked_add(file.length)
+                        .ok_or_else(|| anyhow::anyhow!("Total file length overflows"))?;
+                }
+                total
+            }
+        };
+
+        if self.info.piece_length == 0 {
+            anyhow::bail!("'piece length' must be greater than zero");
+        }
+        if !self.info.pieces.len().is_multiple_of(20) {
+            anyhow::bail!(
+                "'pieces' length {} is not a multiple of 20",
+                self.info.pieces.len()
+            );
+        }
+
+        let piece_count = self.info.pieces.len() / 20;
+        let expected = (total_length as u64).div_ceil(self.info.piece_length as u64) as usize;
+        if piece_count != expected {
+            anyhow::bail!(
+                "Torrent has {} piece hashes, but {} bytes at {} bytes per piece needs {}",
+                piece_count,
+                total_length,
+                self.info.piece_length,
+                expected
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The parsed 'info' dictionary.
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    /// Returns the Info Hash, computing it only once per torrent.
+    ///
+    /// The tracker announces and peer sessions need the hash repeatedly; re-serializing
+    /// the whole `info` dictionary (including the `pieces` blob) each time is wasteful.
+    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        if let Some(hash) = self.info_hash.get() {
+            return Ok(*hash);
+        }
+        let hash = self.calculate_info_hash()?;
+        Ok(*self.info_hash.get_or_init(|| hash))
+    }
+
+    /// Checks the Info Hash against one obtained elsewhere, e.g. the `btih` of a magnet
+    /// link or a hash published next to the download.
+    pub fn verify_info_hash(&self, expected: &[u8; 20]) -> anyhow::Result<()> {
+        let actual = self.info_hash()?;
+        if &actual != expected {
+            anyhow::bail!(
+                "Info hash mismatch: expected {}, but the torrent has {}",
+                hex::encode(expected),
+                hex::encode(actual)
+            );
+        }
+        Ok(())
+    }
+
+    /// Calculates the Info Hash (SHA-1) of the 'info' dictionary by re-serializing the
+    /// `Info` struct.
+    ///
+    /// Only needed for torrents built in memory (the creator); torrents read from a file
+    /// already carry the hash of their original bytes. Always performs a fresh
+    /// computation; prefer `info_hash()` for repeated lookups.
     pub fn calculate_info_hash(&self) -> anyhow::Result<[u8; 20]> {
         let info_bytes = serde_bencode::to_bytes(&self.info)?;
 
@@ -89,7 +435,9 @@ impl Torrent {
         let end = start + HASH_LEN;
 
         if end > self.info.pieces.len() {
-            anyhow::bail!("Piece index out of bounds");
+            return Err(
+                InvalidMetadata(format!("piece index {} out of bounds", piece_index)).into(),
+            );
         }
 
         let mut hash = [0u8; 20];
@@ -112,24 +460,165 @@ impl Torrent {
     /// Aggregates all tracker URLs into a single flat list.
     ///
     /// Combines the primary `announce` URL with the `announce-list` tiers,
-    /// ensuring no duplicates are returned.
+    /// ensuring no duplicates are returned. Tier priority order is preserved.
     pub fn get_tracker_urls(&self) -> Vec<String> {
-        let mut trackers = Vec::new();
+        self.get_tracker_tiers().iter().flatten().cloned().collect()
+    }
 
-        // 1. Add primary tracker
-        trackers.push(self.announce.clone());
+    /// Web seed URLs from `url-list`, skipping empty entries.
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match &self.url_list {
+            Some(UrlList::One(url)) => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            None => Vec::new(),
+        };
+        urls.into_iter().filter(|url| !url.is_empty()).collect()
+    }
 
-        // 2. Add backup trackers
-        if let Some(tiers) = &self.announce_list {
-            for tier in tiers {
-                for url in tier {
-                    if !trackers.contains(url) {
-                        trackers.push(url.clone());
-                    }
+    /// DHT bootstrap nodes from the `nodes` key as `host:port` strings; entries with an
+    /// invalid port are skipped.
+    pub fn dht_nodes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter_map(|(host, port)| {
+                let port = u16::try_from(*port).ok().filter(|&p| p != 0)?;
+                Some(format!("{}:{}", host, port))
+            })
+            .collect()
+    }
+
+    /// Appends `url` as a new, lowest-priority tier. Returns false if it is already listed.
+    ///
+    /// A torrent with only `announce` gets an `announce-list` starting with it, since
+    /// clients ignore `announce` once `announce-list` is present.
+    pub fn add_tracker(&mut self, url: &str) -> bool {
+        if self.get_tracker_urls().iter().any(|u| u == url) {
+            return false;
+        }
+        self.tracker_tiers.take();
+        if self.announce.is_empty() && self.announce_list.is_none() {
+            self.announce = url.to_string();
+            return true;
+        }
+        let primary = self.announce.clone();
+        self.announce_list
+            .get_or_insert_with(|| vec![vec![primary]])
+            .push(vec![url.to_string()]);
+        if self.announce.is_empty() {
+            self.announce = url.to_string();
+        }
+        true
+    }
+
+    /// Removes `url` from `announce` and every tier, dropping tiers left empty. If it
+    /// was the primary tracker, the first remaining URL takes its place. Returns false
+    /// if it wasn't listed.
+    pub fn remove_tracker(&mut self, url: &str) -> bool {
+        self.tracker_tiers.take();
+        let mut found = false;
+        if let Some(tiers) = &mut self.announce_list {
+            for tier in tiers.iter_mut() {
+                let before = tier.len();
+                tier.retain(|u| u != url);
+                found |= tier.len() != before;
+            }
+            tiers.retain(|tier| !tier.is_empty());
+            if tiers.is_empty() {
+                self.announce_list = None;
+            }
+        }
+        if self.announce == url {
+            found = true;
+            self.announce = self
+                .announce_list
+                .iter()
+                .flatten()
+                .flatten()
+                .next()
+                .cloned()
+                .unwrap_or_default();
+        }
+        found
+    }
+
+    /// Replaces `old` with `new` wherever it is listed, keeping its tier. Returns false
+    /// if `old` wasn't listed.
+    pub fn replace_tracker(&mut self, old: &str, new: &str) -> bool {
+        self.tracker_tiers.take();
+        let mut found = false;
+        let urls = std::iter::once(&mut self.announce)
+            .chain(self.announce_list.iter_mut().flatten().flatten());
+        for url in urls.filter(|url| *url == old) {
+            *url = new.to_string();
+            found = true;
+        }
+        found
+    }
+
+    /// Returns the trackers grouped into tiers (BEP 12 Multitracker Metadata Extension).
+    ///
+    /// URLs within each tier are shuffled so load spreads across mirrors and a dead
+    /// tracker isn't always contacted first, while the tiers themselves keep their order.
+    /// The shuffle happens once per loaded torrent, so every call sees the same order.
+    /// As BEP 12 requires, `announce` is used only when `announce-list` is missing or
+    /// empty. Duplicate URLs are kept only at their first (highest priority) position.
+    pub fn get_tracker_tiers(&self) -> &[Vec<String>] {
+        self.tracker_tiers.get_or_init(|| {
+            let mut rng = rand::thread_rng();
+            let mut seen = HashSet::new();
+            let mut tiers = Vec::new();
+            for tier in self.announce_list.iter().flatten() {
+                let mut urls: Vec<String> = tier
+                    .iter()
+                    .filter(|url| !url.is_empty() && seen.insert((*url).clone()))
+                    .cloned()
+                    .collect();
+                if urls.is_empty() {
+                    continue;
                 }
+                urls.shuffle(&mut rng);
+                tiers.push(urls);
+            }
+            if tiers.is_empty() && !self.announce.is_empty() {
+                tiers.push(vec![self.announce.clone()]);
+            }
+            tiers
+        })
+    }
+
+    /// Collects the torrent's key metadata into a serializable summary.
+    pub fn summary(&self) -> anyhow::Result<TorrentSummary> {
+        let mut trackers = vec![self.announce.clone()];
+        for url in self.announce_list.iter().flatten().flatten() {
+            if !trackers.contains(url) {
+                trackers.push(url.clone());
             }
         }
-        trackers
+
+        let files = match &self.info.files {
+            Some(files) => files
+                .iter()
+                .map(|f| FileSummary {
+                    path: f.path.join("/"),
+                    length: f.length,
+                })
+                .collect(),
+            None => vec![FileSummary {
+                path: self.info.name.clone(),
+                length: self.total_length(),
+            }],
+        };
+
+        Ok(TorrentSummary {
+            name: self.info.name.clone(),
+            total_length: self.total_length(),
+            piece_length: self.info.piece_length,
+            piece_count: self.info.pieces.len() / 20,
+            info_hash: hex::encode(self.info_hash()?),
+            trackers,
+            files,
+        })
     }
 
     /// Calculates the exact byte size of a specific piece.
@@ -137,23 +626,473 @@ impl Torrent {
     /// While most pieces are exactly `piece_length`, the final piece is usually smaller
     /// (the remainder of the total size). Requesting the wrong size for the last piece
     /// will cause peers to drop the connection.
-    pub fn calculate_piece_size(&self, piece_index: usize) -> u32 {
+    ///
+    /// Fails for an index past the last piece, which also covers torrents with no pieces
+    /// at all, and for a zero piece length. The last piece's size is derived from where it
+    /// starts, so a piece count that disagrees with the total length (which `validate`
+    /// rejects) fails here too instead of yielding a zero-length or oversized piece.
+    pub fn calculate_piece_size(&self, piece_index: usize) -> anyhow::Result<u32> {
         let piece_len = self.info.piece_length as u64;
-        let total_len = self.total_length();
-        // Calculate total number of pieces (ceiling division)
+        let total_len = self.total_length().max(0) as u64;
         let num_pieces = self.info.pieces.len() / 20;
 
-        // Check if this is the last piece
-        if piece_index == num_pieces - 1 {
-            let remainder = total_len % piece_len as i64;
-            if remainder == 0 {
-                piece_len as u32
-            } else {
-                remainder as u32
-            }
-        } else {
-            // Standard piece
-            piece_len as u32
+        if piece_len == 0 {
+            return Err(InvalidMetadata("piece length is zero".to_string()).into());
+        }
+        if piece_index >= num_pieces {
+            return Err(InvalidMetadata(format!(
+                "piece index {} out of range ({} pieces)",
+                piece_index, num_pieces
+            ))
+            .into());
+        }
+
+        if piece_index < num_pieces - 1 {
+            return Ok(piece_len as u32);
+        }
+
+        // The last piece holds whatever remains after the full pieces before it: exactly
+        // `piece_len` when the total is a multiple of it, less otherwise
+        let start = piece_index as u64 * piece_len;
+        let size = total_len.saturating_sub(start);
+        if size == 0 || size > piece_len {
+            return Err(InvalidMetadata(format!(
+                "{} pieces of {} bytes don't fit a total length of {} bytes",
+                num_pieces, piece_len, total_len
+            ))
+            .into());
+        }
+        Ok(size as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent(announce: &str, announce_list: Option<Vec<Vec<&str>>>) -> Torrent {
+        let announce_list = announce_list.map(|tiers| {
+            tiers
+                .into_iter()
+                .map(|tier| tier.into_iter().map(String::from).collect())
+                .collect()
+        });
+        Torrent::new(announce.to_string(), announce_list, Info::default())
+    }
+
+    fn sorted(tier: &[String]) -> Vec<String> {
+        let mut tier = tier.to_vec();
+        tier.sort();
+        tier
+    }
+
+    #[test]
+    fn tiers_fall_back_to_announce() {
+        let expected = vec![vec!["http://a/announce".to_string()]];
+        assert_eq!(
+            torrent("http://a/announce", None).get_tracker_tiers(),
+            expected
+        );
+        assert_eq!(
+            torrent("http://a/announce", Some(vec![])).get_tracker_tiers(),
+            expected
+        );
+        assert_eq!(
+            torrent("http://a/announce", Some(vec![vec![], vec![""]])).get_tracker_tiers(),
+            expected
+        );
+        assert!(torrent("", None).get_tracker_tiers().is_empty());
+    }
+
+    #[test]
+    fn tiers_ignore_announce_when_announce_list_is_present() {
+        let t = torrent(
+            "http://primary/announce",
+            Some(vec![vec!["http://b/announce"], vec!["http://c/announce"]]),
+        );
+        assert_eq!(
+            t.get_tracker_tiers(),
+            vec![
+                vec!["http://b/announce".to_string()],
+                vec!["http://c/announce".to_string()]
+            ]
+        );
+    }
+
+    #[test]
+    fn tiers_keep_order_and_drop_duplicates() {
+        let t = torrent(
+            "",
+            Some(vec![
+                vec!["udp://a:1", "udp://b:1", "udp://c:1"],
+                vec!["udp://b:1", "udp://d:1"],
+                vec!["udp://a:1"],
+                vec!["udp://e:1"],
+            ]),
+        );
+        let tiers = t.get_tracker_tiers();
+        assert_eq!(tiers.len(), 3);
+        assert_eq!(
+            sorted(&tiers[0]),
+            vec!["udp://a:1", "udp://b:1", "udp://c:1"]
+        );
+        assert_eq!(tiers[1], vec!["udp://d:1"]);
+        assert_eq!(tiers[2], vec!["udp://e:1"]);
+    }
+
+    #[test]
+    fn tiers_are_shuffled_once() {
+        let urls: Vec<String> = (0..32).map(|i| format!("http://t{}/announce", i)).collect();
+        let t = torrent("", Some(vec![urls.iter().map(String::as_str).collect()]));
+        let first = t.get_tracker_tiers().to_vec();
+        for _ in 0..8 {
+            assert_eq!(t.get_tracker_tiers(), first);
+        }
+        assert_eq!(t.get_tracker_urls(), first[0]);
+        assert_eq!(sorted(&first[0]), sorted(&urls));
+    }
+
+    #[test]
+    fn editing_trackers_rebuilds_tiers() {
+        let mut t = torrent("http://a/announce", None);
+        assert!(t.add_tracker("http://b/announce"));
+        assert_eq!(
+            t.get_tracker_tiers(),
+            vec![
+                vec!["http://a/announce".to_string()],
+                vec!["http://b/announce".to_string()]
+            ]
+        );
+        assert!(t.replace_tracker("http://b/announce", "http://c/announce"));
+        assert_eq!(
+            t.get_tracker_urls(),
+            vec!["http://a/announce", "http://c/announce"]
+        );
+        assert!(t.remove_tracker("http://a/announce"));
+        assert_eq!(t.get_tracker_urls(), vec!["http://c/announce"]);
+    }
+
+    /// Metainfo with the given `info` dictionary body (without the surrounding `d`/`e`).
+    fn metainfo(info: &str) -> Vec<u8> {
+        format!("d8:announce14:http://t/annce4:infod{}ee", info).into_bytes()
+    }
+
+    /// `pieces` entry holding `count` (all-zero) hashes.
+    fn pieces(count: usize) -> String {
+        format!("6:pieces{}:{}", count * 20, "\0".repeat(count * 20))
+    }
+
+    fn rejection(info: &str) -> String {
+        format!("{:#}", Torrent::from_bytes(&metainfo(info)).unwrap_err())
+    }
+
+    #[test]
+    fn accepts_well_formed_torrents() {
+        let single = format!(
+            "6:lengthi40000e4:name1:a12:piece lengthi16384e{}",
+            pieces(3)
+        );
+        assert_eq!(
+            Torrent::from_bytes(&metainfo(&single))
+                .unwrap()
+                .total_length(),
+            40000
+        );
+        let multi = format!(
+            "5:filesld6:lengthi10e4:pathl1:aeed6:lengthi0e4:pathl1:beee4:name1:d12:piece lengthi16e{}",
+            pieces(1)
+        );
+        assert_eq!(
+            Torrent::from_bytes(&metainfo(&multi))
+                .unwrap()
+                .total_length(),
+            10
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_bencode() {
+        assert!(Torrent::from_bytes(b"").is_err());
+        assert!(Torrent::from_bytes(b"d8:announce").is_err());
+        assert!(Torrent::from_bytes(b"le").is_err());
+        // A dictionary without `info`
+        assert!(Torrent::from_bytes(b"d8:announce1:ae").is_err());
+    }
+
+    #[test]
+    fn rejects_inconsistent_lengths() {
+        let files = "5:filesld6:lengthi10e4:pathl1:aeee";
+        let error = rejection(&format!(
+            "{}6:lengthi10e4:name1:a12:piece lengthi16e{}",
+            files,
+            pieces(1)
+        ));
+        assert!(error.contains("Both 'length' and 'files'"), "{}", error);
+        let error = rejection(&format!("4:name1:a12:piece lengthi16e{}", pieces(1)));
+        assert!(error.contains("Neither 'length' nor 'files'"), "{}", error);
+        let error = rejection(&format!(
+            "6:lengthi-1e4:name1:a12:piece lengthi16e{}",
+            pieces(0)
+        ));
+        assert!(error.contains("Negative file length"), "{}", error);
+        let error = rejection(&format!(
+            "5:filesld6:lengthi-5e4:pathl1:aeee4:name1:a12:piece lengthi16e{}",
+            pieces(0)
+        ));
+        assert!(error.contains("Negative length"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_bad_piece_metadata() {
+        let error = rejection(&format!(
+            "6:lengthi1e4:name1:a12:piece lengthi0e{}",
+            pieces(1)
+        ));
+        assert!(
+            error.contains("'piece length' must be greater than zero"),
+            "{}",
+            error
+        );
+        let error =
+            rejection("6:lengthi10e4:name1:a12:piece lengthi16e6:pieces19:0123456789012345678");
+        assert!(error.contains("not a multiple of 20"), "{}", error);
+        for count in [2, 4] {
+            let error = rejection(&format!(
+                "6:lengthi40000e4:name1:a12:piece lengthi16384e{}",
+                pieces(count)
+            ));
+            assert!(error.contains("needs 3"), "{}", error);
         }
     }
+
+    /// An unvalidated single-file torrent, as `calculate_piece_size` may still meet one.
+    fn sized(length: i64, piece_length: usize, piece_count: usize) -> Torrent {
+        let info = Info {
+            name: "a".to_string(),
+            piece_length,
+            pieces: ByteBuf::from(vec![0; piece_count * 20]),
+            length: Some(length),
+            ..Info::default()
+        };
+        Torrent::new(String::new(), None, info)
+    }
+
+    #[test]
+    fn piece_size_fails_for_empty_torrents() {
+        assert!(sized(0, 16384, 0).calculate_piece_size(0).is_err());
+        assert!(sized(100, 16384, 0).calculate_piece_size(0).is_err());
+    }
+
+    #[test]
+    fn piece_size_fails_for_bad_indices() {
+        let torrent = sized(40000, 16384, 3);
+        assert!(torrent.calculate_piece_size(3).is_err());
+        assert!(torrent.calculate_piece_size(usize::MAX).is_err());
+        assert!(sized(40000, 0, 3).calculate_piece_size(0).is_err());
+        let error = torrent.calculate_piece_size(3).unwrap_err();
+        assert!(error.downcast_ref::<InvalidMetadata>().is_some());
+    }
+
+    #[test]
+    fn last_piece_holds_the_remainder() {
+        let torrent = sized(40000, 16384, 3);
+        assert_eq!(torrent.calculate_piece_size(0).unwrap(), 16384);
+        assert_eq!(torrent.calculate_piece_size(1).unwrap(), 16384);
+        assert_eq!(torrent.calculate_piece_size(2).unwrap(), 40000 - 2 * 16384);
+        assert_eq!(sized(1, 16384, 1).calculate_piece_size(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn last_piece_is_full_when_the_length_divides_evenly() {
+        let torrent = sized(3 * 16384, 16384, 3);
+        assert_eq!(torrent.calculate_piece_size(2).unwrap(), 16384);
+        assert_eq!(
+            sized(16384, 16384, 1).calculate_piece_size(0).unwrap(),
+            16384
+        );
+    }
+
+    #[test]
+    fn last_piece_fails_when_the_piece_count_disagrees() {
+        // Too many pieces would leave the last one empty, too few make it oversized
+        assert!(sized(40000, 16384, 4).calculate_piece_size(3).is_err());
+        assert!(sized(40000, 16384, 2).calculate_piece_size(1).is_err());
+        // Full pieces before the last are unaffected
+        assert_eq!(
+            sized(40000, 16384, 4).calculate_piece_size(2).unwrap(),
+            16384
+        );
+    }
+
+    #[test]
+    fn info_hash_is_computed_once() {
+        let info = Info {
+            name: "a".to_string(),
+            piece_length: 16384,
+            pieces: ByteBuf::from(vec![0; 20]),
+            length: Some(100),
+            ..Info::default()
+        };
+        let torrent = Torrent::new(String::new(), None, info);
+        assert!(torrent.info_hash.get().is_none());
+        let hash = torrent.info_hash().unwrap();
+        assert_eq!(hash, torrent.calculate_info_hash().unwrap());
+
+        // Later lookups, and clones, reuse the cached value
+        assert_eq!(torrent.info_hash.get(), Some(&hash));
+        assert_eq!(torrent.info_hash().unwrap(), hash);
+        assert_eq!(torrent.clone().info_hash.get(), Some(&hash));
+    }
+
+    #[test]
+    fn summary_json_round_trips() {
+        let metainfo = [
+            &b"d8:announce14:http://t/annce13:announce-listll14:http://t/annceel14:http://u/annceee"[..],
+            b"4:infod5:filesld6:lengthi10e4:pathl1:a5:b.txteed6:lengthi5e4:pathl1:ceee",
+            b"4:name4:root12:piece lengthi16e6:pieces20:",
+            &[0; 20],
+            b"ee",
+        ]
+        .concat();
+        let torrent: Torrent = serde_bencode::from_bytes(&metainfo).unwrap();
+        let json = serde_json::to_string_pretty(&torrent.summary().unwrap()).unwrap();
+
+        let parsed: TorrentSummary = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            parsed,
+            TorrentSummary {
+                name: "root".to_string(),
+                total_length: 15,
+                piece_length: 16,
+                piece_count: 1,
+                info_hash: hex::encode(torrent.info_hash().unwrap()),
+                trackers: vec!["http://t/annce".to_string(), "http://u/annce".to_string()],
+                files: vec![
+                    FileSummary {
+                        path: "a/b.txt".to_string(),
+                        length: 10,
+                    },
+                    FileSummary {
+                        path: "c".to_string(),
+                        length: 5,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn verify_info_hash_accepts_only_the_matching_hash() {
+        let bytes = metainfo(&format!(
+            "6:lengthi5e4:name1:x12:piece lengthi16384e{}",
+            pieces(1)
+        ));
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        // The info dictionary runs from after its key to just before the final `e`
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let expected: [u8; 20] = Sha1::digest(&bytes[start..bytes.len() - 1]).into();
+        torrent.verify_info_hash(&expected).unwrap();
+
+        let mut other = expected;
+        other[0] ^= 1;
+        assert_eq!(
+            torrent.verify_info_hash(&other).unwrap_err().to_string(),
+            format!(
+                "Info hash mismatch: expected {}, but the torrent has {}",
+                hex::encode(other),
+                hex::encode(expected)
+            )
+        );
+    }
+
+    /// SHA-1 of the `info` dictionary as it appears in metainfo built by `metainfo`.
+    fn raw_info_hash(bytes: &[u8]) -> [u8; 20] {
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        Sha1::digest(&bytes[start..bytes.len() - 1]).into()
+    }
+
+    #[test]
+    fn source_and_unknown_keys_keep_the_info_hash() {
+        let bytes = metainfo(&format!(
+            "6:lengthi5e4:name1:x12:piece lengthi16384e{}7:privatei1e6:source3:PTP12:x-cross-seed4:abcd",
+            pieces(1)
+        ));
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info.source.as_deref(), Some("PTP"));
+        let expected = raw_info_hash(&bytes);
+        assert_eq!(torrent.info_hash().unwrap(), expected);
+        // Re-serializing (e.g. after editing trackers) keeps the keys and so the hash
+        assert_eq!(torrent.calculate_info_hash().unwrap(), expected);
+        let reread = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(reread.info_hash().unwrap(), expected);
+    }
+
+    #[test]
+    fn info_hash_comes_from_the_original_bytes() {
+        // Keys out of order, as some older clients wrote them: serde sorts them again
+        let bytes = metainfo(&format!(
+            "4:name1:x6:lengthi5e{}12:piece lengthi16384e",
+            pieces(1)
+        ));
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        let expected = raw_info_hash(&bytes);
+        assert_ne!(torrent.calculate_info_hash().unwrap(), expected);
+        assert_eq!(torrent.info_hash().unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_billions_of_pieces_before_allocating() {
+        // 16 TiB of 16 KiB pieces: over a billion, with only one hash actually present
+        let error = rejection(&format!(
+            "6:lengthi{}e4:name1:x12:piece lengthi16384e{}",
+            16u64 << 40,
+            pieces(1)
+        ));
+        assert_eq!(
+            error,
+            "Torrent exceeds metadata limits: 1073741824 pieces (limit 10000000)"
+        );
+    }
+
+    #[test]
+    fn rejects_a_one_byte_piece_length_over_a_huge_file() {
+        let error = rejection(&format!(
+            "6:lengthi{}e4:name1:x12:piece lengthi1e{}",
+            1u64 << 40,
+            pieces(1)
+        ));
+        assert!(
+            error.contains("'piece length' of 1 bytes is too small"),
+            "{}",
+            error
+        );
+        // A single piece that small is fine
+        let tiny = metainfo(&format!(
+            "6:lengthi1e4:name1:x12:piece lengthi1e{}",
+            pieces(1)
+        ));
+        Torrent::from_bytes(&tiny).unwrap();
+    }
+
+    #[test]
+    fn limits_are_configurable() {
+        let bytes = metainfo(&format!(
+            "6:lengthi40000e4:name1:a12:piece lengthi16384e{}",
+            pieces(3)
+        ));
+        let limits = MetadataLimits {
+            max_pieces: 2,
+            ..MetadataLimits::default()
+        };
+        let error = Torrent::from_bytes_with_limits(&bytes, &limits).unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Torrent exceeds metadata limits: 3 pieces (limit 2)"
+        );
+        let limits = MetadataLimits {
+            max_total_length: 39999,
+            ..MetadataLimits::default()
+        };
+        assert!(Torrent::from_bytes_with_limits(&bytes, &limits).is_err());
+    }
 }
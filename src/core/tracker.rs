@@ -1,25 +1,75 @@
 use crate::core::torrent_info::Torrent;
+use crate::network::socks5::ProxyConfig;
 use crate::utils::url_encode;
 use anyhow::Context;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
-use std::collections::HashSet;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
 use tokio::time::timeout;
+use tracing::{debug, info, warn};
 
 /// Represents the response structure from a BitTorrent tracker.
 ///
 /// Trackers return a list of peers (IP:Port) that are currently part of the swarm.
 /// This structure handles both standard dictionary-based responses and compact binary responses.
+/// On error, a tracker replies with only a `failure reason` and no peer list.
 #[derive(Debug, Deserialize)]
 pub struct Response {
+    /// Human-readable error from the tracker. If present, no other keys are meaningful.
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    /// Non-fatal notice from the tracker; the response is otherwise processed normally.
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
     /// Interval in seconds that the client should wait before sending the next announce.
     /// Optional because not all trackers provide it immediately or on errors.
-    pub _interval: Option<i64>,
-    /// The list of peers provided by the tracker.
-    pub peers: Peers,
+    pub interval: Option<i64>,
+    /// Minimum interval in seconds the tracker allows between announces.
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<i64>,
+    /// The list of peers provided by the tracker. Absent on failure responses.
+    pub peers: Option<Peers>,
+    /// Compact IPv6 peers (BEP 7): 18 bytes per peer, 16 for IP and 2 for Port.
+    pub peers6: Option<ByteBuf>,
+    /// Number of seeders in the swarm, if the tracker reports it.
+    pub complete: Option<i64>,
+    /// Number of leechers in the swarm, if the tracker reports it.
+    pub incomplete: Option<i64>,
+}
+
+/// Swarm size a tracker reported with an announce: `complete` and `incomplete` over
+/// HTTP, the seeders and leechers counts over UDP.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SwarmSize {
+    pub seeders: u32,
+    pub leechers: u32,
+}
+
+/// Result of an announce round (see `Response::request_peers`).
+#[derive(Debug, Default)]
+pub struct Announced {
+    /// Unique peers, as `ip:port` strings.
+    pub peers: Vec<String>,
+    /// Swarm size reported by the tracker the peers came from, if it sent one.
+    pub swarm: Option<SwarmSize>,
+    /// Whether any tracker answered. False when the throttle skipped every tracker.
+    pub reached: bool,
+}
+
+/// What a single tracker answered to an announce.
+struct AnnounceReply {
+    peers: Vec<String>,
+    /// `interval` and `min interval`, if the tracker sent them.
+    interval: Option<i64>,
+    min_interval: Option<i64>,
+    swarm: Option<SwarmSize>,
 }
 
 /// Enum handling the two possible formats for the peer list:
@@ -38,116 +88,628 @@ pub struct Peer {
     pub port: u16,
 }
 
+/// Swarm health for a single torrent, as reported by a tracker scrape.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub leechers: u32,
+    /// Number of times the torrent has been fully downloaded.
+    pub completed: u32,
+}
+
+/// Bencoded body of an HTTP scrape response.
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    /// Keyed by the raw 20-byte info hash.
+    files: Option<HashMap<ByteBuf, ScrapeFile>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: u32,
+    #[serde(default)]
+    incomplete: u32,
+    #[serde(default)]
+    downloaded: u32,
+}
+
+/// Builds the HTTP client shared by every HTTP tracker announce.
+///
+/// `reqwest::Client` holds a connection pool and TLS configuration, so it is created
+/// once and cloned (cheaply, it is reference counted) rather than rebuilt per announce.
+/// The short timeout prevents slow HTTP trackers from blocking the gather phase.
+/// Redirects are followed, up to `MAX_REDIRECTS` hops.
+/// With a proxy, every request (HTTP and HTTPS) is tunnelled through it.
+/// Every request carries `user_agent`, since some trackers reject anonymous clients.
+/// Connections are made from `bind` when given.
+pub fn http_client(
+    proxy: Option<&ProxyConfig>,
+    user_agent: &str,
+    bind: Option<IpAddr>,
+) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .local_address(bind)
+        .timeout(Duration::from_secs(5))
+        .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(&proxy.url)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Parses a peer address from a tracker into the one form it is deduplicated and
+/// connected by: IPv4-mapped IPv6 addresses become plain IPv4, so the same peer
+/// reported by a UDP and an HTTP tracker is one entry. Host names, malformed
+/// addresses and port 0 give `None`.
+fn normalize_peer(addr: &str) -> Option<SocketAddr> {
+    let addr: SocketAddr = addr.trim().parse().ok()?;
+    (addr.port() != 0).then(|| SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+}
+
+/// `peers` normalized with `normalize_peer`, each address once, in the order first
+/// seen. Used wherever peer lists from several sources (trackers, the DHT) meet.
+pub fn unique_peers(peers: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    peers
+        .iter()
+        .filter_map(|peer| normalize_peer(peer))
+        .filter(|addr| seen.insert(*addr))
+        .map(|addr| addr.to_string())
+        .collect()
+}
+
+/// Redirects an HTTP tracker request may go through before it is given up on.
+const MAX_REDIRECTS: usize = 5;
+
+/// `User-Agent` sent to HTTP trackers unless `--user-agent` overrides it.
+pub const DEFAULT_USER_AGENT: &str = concat!("RT/", env!("CARGO_PKG_VERSION"));
+
+/// The `event` sent with an announce (BEP 3; numbered as in BEP 15 for UDP).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    /// A regular re-announce.
+    None,
+    /// First announce after the torrent was (re)started.
+    Started,
+    /// We're leaving the swarm; the tracker should drop us from its peer list.
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the HTTP `event` query parameter, if one is sent.
+    fn http_name(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    fn udp_code(self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+/// Largest `numwant` we ask for. Trackers commonly cap replies at 50-200 peers anyway.
+pub const MAX_NUMWANT: u32 = 1000;
+
+/// Port used for `udp://` tracker URLs that don't name one; BEP 15 defines no default,
+/// and 6969 is what nearly every UDP tracker listens on.
+const DEFAULT_UDP_TRACKER_PORT: u16 = 6969;
+
+/// BEP 41 option types appended to a UDP announce request.
+const UDP_OPTION_END: u8 = 0x0;
+const UDP_OPTION_URL_DATA: u8 = 0x2;
+
+/// No tracker is re-announced to sooner than this, whatever its intervals say.
+const MIN_ANNOUNCE_FLOOR: Duration = Duration::from_secs(30);
+
+/// Announce times file kept in the output directory.
+const ANNOUNCES_FILE: &str = ".announces.json";
+
+/// When each tracker was last contacted for each torrent, so that pause/resume cycles,
+/// retries after failures and restarts can't announce faster than the tracker allows
+/// (and get us banned).
+///
+/// Regular announces wait for the tracker's `interval`; announces carrying an event
+/// only for its `min interval`. Saved to `<output_dir>/.announces.json` (see `save`)
+/// so a restart doesn't re-announce straight away.
+#[derive(Default)]
+pub struct AnnounceThrottle {
+    last: Mutex<HashMap<([u8; 20], String), LastAnnounce>>,
+    /// Announces file; `None` keeps the throttle in memory only.
+    path: Option<PathBuf>,
+}
+
+struct LastAnnounce {
+    at: Instant,
+    /// The tracker's `interval`, never below `MIN_ANNOUNCE_FLOOR`.
+    interval: Duration,
+    /// The tracker's `min interval`, never below `MIN_ANNOUNCE_FLOOR`.
+    min_interval: Duration,
+    /// A `Started` held back by the throttle, sent with the next announce instead.
+    pending_started: bool,
+}
+
+/// One `LastAnnounce` as saved in the announces file.
+#[derive(Serialize, Deserialize)]
+struct SavedAnnounce {
+    info_hash: String,
+    url: String,
+    /// Unix time of the announce, in seconds.
+    at: u64,
+    interval: u64,
+    min_interval: u64,
+}
+
+impl AnnounceThrottle {
+    /// A throttle saved in `output_dir`, resuming the announce times of earlier runs.
+    /// An unreadable file is warned about and ignored.
+    pub fn load(output_dir: &str) -> Self {
+        let path = Path::new(output_dir).join(ANNOUNCES_FILE);
+        let saved: Vec<SavedAnnounce> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring invalid announces file {:?}: {}", path, e);
+                Vec::new()
+            }),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to read {:?}: {}", path, e);
+                }
+                Vec::new()
+            }
+        };
+
+        let now = SystemTime::now();
+        let mut last = HashMap::new();
+        for entry in saved {
+            let mut info_hash = [0u8; 20];
+            let age = now
+                .duration_since(UNIX_EPOCH + Duration::from_secs(entry.at))
+                .unwrap_or_default();
+            let Some(at) = Instant::now().checked_sub(age) else {
+                continue;
+            };
+            if hex::decode_to_slice(&entry.info_hash, &mut info_hash).is_err() {
+                continue;
+            }
+            last.insert(
+                (info_hash, entry.url),
+                LastAnnounce {
+                    at,
+                    interval: Duration::from_secs(entry.interval),
+                    min_interval: Duration::from_secs(entry.min_interval),
+                    pending_started: false,
+                },
+            );
+        }
+        Self {
+            last: Mutex::new(last),
+            path: Some(path),
+        }
+    }
+
+    /// Writes the announces still holding a tracker back to the announces file,
+    /// replacing it atomically. Does nothing for an in-memory throttle.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let now = SystemTime::now();
+        let saved: Vec<SavedAnnounce> = self
+            .last
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.at.elapsed() < entry.interval.max(entry.min_interval))
+            .map(|((info_hash, url), entry)| SavedAnnounce {
+                info_hash: hex::encode(info_hash),
+                url: url.clone(),
+                at: (now - entry.at.elapsed())
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                interval: entry.interval.as_secs(),
+                min_interval: entry.min_interval.as_secs(),
+            })
+            .collect();
+        let mut staging = path.clone().into_os_string();
+        staging.push(".tmp");
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&staging, serde_json::to_vec(&saved)?)?;
+        std::fs::rename(&staging, path)?;
+        Ok(())
+    }
+
+    /// Claims an announce to `url`, returning the event to send, or `None` if the
+    /// tracker was contacted too recently. `Stopped` always goes out so the tracker
+    /// drops us from its peer list.
+    fn begin(
+        &self,
+        info_hash: &[u8; 20],
+        url: &str,
+        event: AnnounceEvent,
+    ) -> Option<AnnounceEvent> {
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        let mut event = event;
+        let mut interval = MIN_ANNOUNCE_FLOOR;
+        let mut min_interval = MIN_ANNOUNCE_FLOOR;
+        if let Some(entry) = last.get_mut(&(*info_hash, url.to_string())) {
+            let wait = if event == AnnounceEvent::None && !entry.pending_started {
+                entry.interval.max(entry.min_interval)
+            } else {
+                entry.min_interval
+            };
+            if event != AnnounceEvent::Stopped && now.duration_since(entry.at) < wait {
+                entry.pending_started |= event == AnnounceEvent::Started;
+                return None;
+            }
+            if entry.pending_started && event == AnnounceEvent::None {
+                event = AnnounceEvent::Started;
+            }
+            interval = entry.interval;
+            min_interval = entry.min_interval;
+        }
+        last.insert(
+            (*info_hash, url.to_string()),
+            LastAnnounce {
+                at: now,
+                interval,
+                min_interval,
+                pending_started: false,
+            },
+        );
+        Some(event)
+    }
+
+    /// Records the `interval` and `min interval` (seconds) a tracker sent in its reply.
+    fn set_intervals(
+        &self,
+        info_hash: &[u8; 20],
+        url: &str,
+        interval: Option<i64>,
+        min_interval: Option<i64>,
+    ) {
+        let floor =
+            |seconds: i64| Duration::from_secs(seconds.max(0) as u64).max(MIN_ANNOUNCE_FLOOR);
+        if let Some(entry) = self
+            .last
+            .lock()
+            .unwrap()
+            .get_mut(&(*info_hash, url.to_string()))
+        {
+            if let Some(seconds) = interval {
+                entry.interval = floor(seconds);
+            }
+            if let Some(seconds) = min_interval {
+                entry.min_interval = floor(seconds);
+            }
+        }
+    }
+}
+
+/// How long a tracker may take to answer before the next one in its tier is tried
+/// alongside it.
+const TIER_FALLBACK_DELAY: Duration = Duration::from_secs(3);
+
+/// The tracker order announces use for each torrent: its tiers, shuffled once, with
+/// each tier's last working tracker moved to the front (BEP 12).
+///
+/// Kept in memory only; a restart starts from the torrent's own order again.
+#[derive(Default)]
+pub struct TrackerTiers {
+    order: Mutex<HashMap<[u8; 20], Vec<Vec<String>>>>,
+}
+
+impl TrackerTiers {
+    fn get(&self, info_hash: &[u8; 20], torrent: &Torrent) -> Vec<Vec<String>> {
+        self.order
+            .lock()
+            .unwrap()
+            .entry(*info_hash)
+            .or_insert_with(|| torrent.get_tracker_tiers().to_vec())
+            .clone()
+    }
+
+    fn promote(&self, info_hash: &[u8; 20], tier_index: usize, url: &str) {
+        let mut order = self.order.lock().unwrap();
+        if let Some(tier) = order
+            .get_mut(info_hash)
+            .and_then(|tiers| tiers.get_mut(tier_index))
+            && let Some(position) = tier.iter().position(|u| u == url)
+        {
+            let promoted = tier.remove(position);
+            tier.insert(0, promoted);
+        }
+    }
+}
+
+/// How we present ourselves to trackers in announces.
+#[derive(Debug, Clone, Copy)]
+pub struct AnnounceParams {
+    /// The port other peers can reach us on.
+    pub port: u16,
+    /// Whether UDP trackers may be contacted. Off when traffic must go through a
+    /// SOCKS5 proxy, since a TCP proxy connection can't carry UDP announces.
+    pub use_udp: bool,
+    pub event: AnnounceEvent,
+    /// Number of peers to ask for, or `None` for the tracker's default.
+    pub numwant: Option<u32>,
+    /// Random value, fixed for the process, that lets a tracker recognise us across
+    /// IP address changes (BEP 3 `key`, BEP 15 key field).
+    pub key: u32,
+    /// Local address UDP tracker sockets are bound to (`--bind`).
+    pub bind: Option<IpAddr>,
+    /// Bytes sent to and received from peers this session, and bytes still needed.
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
 impl Response {
-    /// Contacts all trackers listed in the Torrent file concurrently to retrieve a list of peers.
+    /// Announces to the torrent's trackers tier by tier (BEP 12).
     ///
-    /// Implements a "Scatter-Gather" pattern:
-    /// 1. Scatter: Spawns an async task for every tracker URL found in the torrent metadata.
-    /// 2. Gather: Collects results as they finish, disregarding slow or failed trackers.
-    /// 3. Deduplicate: Uses a HashSet to ensure unique peer addresses.
+    /// Tier 0 is tried first and lower tiers only when it yields no peers. Within a
+    /// tier trackers are tried in order, the next one starting if the current one
+    /// fails or hasn't answered within `TIER_FALLBACK_DELAY`; the first to return peers
+    /// wins, the others are abandoned, and it moves to the front of its tier for the
+    /// next announce. A `Stopped` announce ends at the first tracker that accepts it.
     ///
-    /// This approach significantly reduces startup time compared to sequential announcements.
+    /// Trackers the throttle says were contacted too recently are left out.
     pub async fn request_peers(
         torrent: &Torrent,
         peer_id: &[u8; 20],
-    ) -> anyhow::Result<Vec<String>> {
-        let tracker_urls = torrent.get_tracker_urls();
-        let info_hash = torrent.calculate_info_hash()?;
-        let total_length = torrent.total_length();
-        let peer_id_fixed = *peer_id; // Copy to move into async closure
-
-        println!(
-            "Found {} trackers. Contacting all concurrently...",
-            tracker_urls.len()
-        );
+        params: AnnounceParams,
+        client: &reqwest::Client,
+        throttle: &AnnounceThrottle,
+        tiers: &TrackerTiers,
+    ) -> anyhow::Result<Announced> {
+        let info_hash = torrent.info_hash()?;
+        let mut tracker_tiers = tiers.get(&info_hash, torrent);
+        if !params.use_udp {
+            let before: usize = tracker_tiers.iter().map(Vec::len).sum();
+            for tier in &mut tracker_tiers {
+                tier.retain(|url| !url.starts_with("udp://"));
+            }
+            let skipped = before - tracker_tiers.iter().map(Vec::len).sum::<usize>();
+            if skipped > 0 {
+                info!(
+                    "Skipping {} UDP trackers (not reachable through the proxy)",
+                    skipped
+                );
+            }
+        }
 
-        let mut handles = Vec::new();
-
-        // SCATTER: Spawn a task for every tracker
-        for url in tracker_urls {
-            let url = url.clone();
-            let info_hash = info_hash;
-            let peer_id = peer_id_fixed;
-
-            handles.push(tokio::spawn(async move {
-                // Determine protocol and dispatch to appropriate handler
-                let res = if url.starts_with("udp://") {
-                    Self::udp_announce(&url, &info_hash, &peer_id).await
-                } else if url.starts_with("http://") || url.starts_with("https://") {
-                    Self::http_announce(&url, &info_hash, total_length, &peer_id).await
-                } else {
-                    Err(anyhow::anyhow!("Unsupported protocol"))
+        // Spawns an announce to the next tracker of a tier the throttle allows.
+        // Returns false once the tier has none left.
+        let start_next = |running: &mut JoinSet<_>, queued: &mut std::slice::Iter<String>| {
+            for url in queued.by_ref() {
+                let Some(event) = throttle.begin(&info_hash, url, params.event) else {
+                    debug!("Skipping {}: announced to too recently", url);
+                    continue;
                 };
+                let params = AnnounceParams { event, ..params };
+                let url = url.clone();
+                let peer_id = *peer_id;
+                let client = client.clone();
 
-                (url, res)
-            }));
-        }
+                running.spawn(async move {
+                    // Determine protocol and dispatch to appropriate handler
+                    let res = if url.starts_with("udp://") {
+                        Self::udp_announce(&url, &info_hash, &peer_id, params).await
+                    } else if url.starts_with("http://") || url.starts_with("https://") {
+                        Self::http_announce(&client, &url, &info_hash, &peer_id, params).await
+                    } else {
+                        Err(anyhow::anyhow!("Unsupported protocol"))
+                    };
 
-        // GATHER: Collect successful results
-        let mut unique_peers = HashSet::new();
+                    (url, res)
+                });
+                return true;
+            }
+            false
+        };
 
-        for handle in handles {
-            if let Ok((url, result)) = handle.await {
+        let mut contacted = 0;
+        // Swarm size from a tracker that answered without peers, kept in case none
+        // of the others has any either
+        let mut answered_empty: Option<Option<SwarmSize>> = None;
+        for (tier_index, tier) in tracker_tiers.iter().enumerate() {
+            let mut running = JoinSet::new();
+            let mut queued = tier.iter();
+            loop {
+                if running.is_empty() && !start_next(&mut running, &mut queued) {
+                    break;
+                }
+                let next_due = !queued.as_slice().is_empty();
+                let joined = tokio::select! {
+                    joined = running.join_next() => joined,
+                    _ = tokio::time::sleep(TIER_FALLBACK_DELAY), if next_due => {
+                        start_next(&mut running, &mut queued);
+                        continue;
+                    }
+                };
+                contacted += 1;
+                let Some(Ok((url, result))) = joined else {
+                    continue;
+                };
                 match result {
-                    Ok(peers) => {
-                        if !peers.is_empty() {
-                            println!("{} returned {} peers.", url, peers.len());
-                            for p in peers {
-                                unique_peers.insert(p);
-                            }
+                    Ok(AnnounceReply {
+                        peers,
+                        interval,
+                        min_interval,
+                        swarm,
+                    }) => {
+                        throttle.set_intervals(&info_hash, &url, interval, min_interval);
+                        if peers.is_empty() && params.event != AnnounceEvent::Stopped {
+                            debug!("{} returned no peers", url);
+                            answered_empty.get_or_insert(swarm);
+                            continue;
                         }
+                        debug!("{} returned {} peers.", url, peers.len());
+                        tiers.promote(&info_hash, tier_index, &url);
+                        let unique = unique_peers(&peers);
+                        info!("Tracker list: {} unique peers found.", unique.len());
+                        return Ok(Announced {
+                            peers: unique,
+                            swarm,
+                            reached: true,
+                        });
                     }
-                    Err(_) => {
-                        // Fail silently for individual trackers to keep CLI output clean.
-                        // We only care about the trackers that actually work.
-                    }
+                    Err(e) => debug!("{} failed: {:#}", url, e),
                 }
             }
         }
 
-        if unique_peers.is_empty() {
-            anyhow::bail!("All trackers failed. Could not find any peers.");
+        if let Some(swarm) = answered_empty {
+            return Ok(Announced {
+                peers: Vec::new(),
+                swarm,
+                reached: true,
+            });
         }
-
-        println!("Merged list: {} unique peers found.", unique_peers.len());
-        Ok(unique_peers.into_iter().collect())
+        if contacted == 0 || params.event == AnnounceEvent::Stopped {
+            return Ok(Announced::default());
+        }
+        anyhow::bail!("All trackers failed. Could not find any peers.");
     }
 
     /// performs an announce request to an HTTP/HTTPS tracker.
+    /// Returns the peers, plus the tracker's `min interval` and swarm size if it sent
+    /// them.
     async fn http_announce(
+        client: &reqwest::Client,
         url: &str,
         info_hash: &[u8; 20],
-        total_length: i64,
         peer_id: &[u8; 20],
-    ) -> anyhow::Result<Vec<String>> {
+        params: AnnounceParams,
+    ) -> anyhow::Result<AnnounceReply> {
         let encoded_info_hash = url_encode(info_hash);
         let encoded_peer_id = url_encode(peer_id);
 
-        let final_url = format!(
-            "{}?info_hash={}&peer_id={}&port=8888&uploaded=0&downloaded=0&compact=1&left={}",
-            url, encoded_info_hash, encoded_peer_id, total_length
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&compact=1&left={}",
+            encoded_info_hash,
+            encoded_peer_id,
+            params.port,
+            params.uploaded,
+            params.downloaded,
+            params.left
         );
+        if let Some(event) = params.event.http_name() {
+            query.push_str("&event=");
+            query.push_str(event);
+        }
+        if let Some(numwant) = params.numwant {
+            query.push_str(&format!("&numwant={}", numwant));
+        }
+        query.push_str(&format!("&key={:08X}", params.key));
+        let final_url = Self::append_query(url, &query);
 
-        // Enforce a short timeout to prevent slow HTTP trackers from blocking the gather phase
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()?;
+        let response_bytes = Self::http_get(client, &final_url).await?;
+        let tracker_response: Response = Self::decode_http_reply(&response_bytes)
+            .context("Failed to decode HTTP tracker response")?;
 
+        if let Some(reason) = tracker_response.failure_reason {
+            anyhow::bail!("Tracker returned failure: {}", reason);
+        }
+        if let Some(warning) = &tracker_response.warning_message {
+            warn!("{} warning: {}", url, warning);
+        }
+
+        if tracker_response.peers.is_none() && tracker_response.peers6.is_none() {
+            anyhow::bail!("Tracker response contained no peer list");
+        }
+
+        let mut peers = match tracker_response.peers {
+            Some(peers) => Self::extract_peers(peers)?,
+            None => Vec::new(),
+        };
+        if let Some(peers6) = tracker_response.peers6 {
+            peers.extend(Self::extract_peers6(&peers6));
+        }
+        let swarm = match (tracker_response.complete, tracker_response.incomplete) {
+            (Some(complete), Some(incomplete)) => Some(SwarmSize {
+                seeders: complete.clamp(0, u32::MAX as i64) as u32,
+                leechers: incomplete.clamp(0, u32::MAX as i64) as u32,
+            }),
+            _ => None,
+        };
+        Ok(AnnounceReply {
+            peers,
+            interval: tracker_response.interval,
+            min_interval: tracker_response.min_interval,
+            swarm,
+        })
+    }
+
+    /// Fetches a tracker URL, failing with the status code on anything but a 2xx reply
+    /// (redirects have already been followed by the client).
+    async fn http_get(client: &reqwest::Client, url: &str) -> anyhow::Result<Vec<u8>> {
         let response = client
-            .get(&final_url)
+            .get(url)
             .send()
             .await
             .context("Failed to connect to HTTP tracker")?;
 
-        let response_bytes = response
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("Tracker returned HTTP {}", status);
+        }
+
+        let bytes = response
             .bytes()
             .await
             .context("Failed to read HTTP response bytes")?;
+        Ok(bytes.to_vec())
+    }
 
-        let tracker_response: Response = serde_bencode::from_bytes(&response_bytes)
-            .context("Failed to decode HTTP tracker response")?;
+    /// Decodes a tracker's HTTP reply, telling a body that isn't bencode at all (an HTML
+    /// error page, say) apart from a bencoded dictionary with unexpected contents.
+    fn decode_http_reply<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<T> {
+        if bytes.first() != Some(&b'd') {
+            let preview: String = String::from_utf8_lossy(&bytes[..bytes.len().min(60)])
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            anyhow::bail!(
+                "Reply is not bencode ({} bytes, starting {:?})",
+                bytes.len(),
+                preview
+            );
+        }
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    /// Appends announce parameters to a tracker URL that may already carry a query.
+    ///
+    /// Private trackers often embed a passkey (e.g. `/announce?key=abc`), so blindly
+    /// adding `?` would produce a malformed URL. Existing parameters are decoded and
+    /// re-encoded so they stay valid, then ours are joined with `&`. Any fragment is dropped.
+    fn append_query(base: &str, query: &str) -> String {
+        let base = base.split('#').next().unwrap_or(base);
 
-        Self::extract_peers(tracker_response.peers)
+        match base.split_once('?') {
+            Some((path, existing)) if !existing.is_empty() => {
+                let existing: String = url::form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(url::form_urlencoded::parse(existing.as_bytes()))
+                    .finish();
+                format!("{}?{}&{}", path, existing, query)
+            }
+            Some((path, _)) => format!("{}?{}", path, query),
+            None => format!("{}?{}", base, query),
+        }
     }
 
     /// Performs an announce request to a UDP tracker implementing BEP 15.
@@ -155,57 +717,66 @@ impl Response {
     /// The UDP protocol involves a two-step handshake:
     /// 1. Connect Request -> Connect Response (Get Connection ID)
     /// 2. Announce Request -> Announce Response (Get Peers)
+    ///
+    /// BEP 15 has no `min interval`, so that is always `None`.
     async fn udp_announce(
         announce_url: &str,
         info_hash: &[u8; 20],
         peer_id: &[u8; 20],
-    ) -> anyhow::Result<Vec<String>> {
-        // Parse host:port from URL
-        let url_part = announce_url.strip_prefix("udp://").unwrap_or(announce_url);
-        let host_port = url_part.split('/').next().unwrap();
-
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket
-            .connect(host_port)
-            .await
-            .context("UDP Connect failed")?;
-
+        params: AnnounceParams,
+    ) -> anyhow::Result<AnnounceReply> {
         // --- Step 1: Connection Request ---
-        let mut connect_req = Vec::new();
-        connect_req.write_u64::<BigEndian>(0x41727101980)?; // Magic Constant
-        connect_req.write_u32::<BigEndian>(0)?; // Action: Connect
-        connect_req.write_u32::<BigEndian>(12345)?; // Transaction ID
-        socket.send(&connect_req).await?;
-
-        // Read Connection Response
-        let mut buf = [0u8; 16];
-        let (len, _) = timeout(Duration::from_secs(3), socket.recv_from(&mut buf))
-            .await
-            .context("UDP Connect Timeout")??;
-
-        if len < 16 {
-            anyhow::bail!("Invalid UDP Connect Response length");
-        }
-        let mut rdr = std::io::Cursor::new(&buf[..len]);
-        let _action = rdr.read_u32::<BigEndian>()?;
-        let _trans_id = rdr.read_u32::<BigEndian>()?;
-        let connection_id = rdr.read_u64::<BigEndian>()?;
+        let (socket, connection_id) = Self::udp_connect(announce_url, params.bind).await?;
 
         // --- Step 2: Announce Request ---
+        Self::udp_announce_on(
+            &socket,
+            connection_id,
+            announce_url,
+            info_hash,
+            peer_id,
+            params,
+        )
+        .await
+    }
+
+    /// Sends the announce request over a socket that has completed the connect
+    /// exchange, and reads the reply.
+    async fn udp_announce_on(
+        socket: &UdpSocket,
+        connection_id: u64,
+        announce_url: &str,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+        params: AnnounceParams,
+    ) -> anyhow::Result<AnnounceReply> {
         let mut announce_req = Vec::new();
         announce_req.write_u64::<BigEndian>(connection_id)?;
         announce_req.write_u32::<BigEndian>(1)?; // Action: Announce
         announce_req.write_u32::<BigEndian>(12345)?; // Transaction ID
         announce_req.extend_from_slice(info_hash);
         announce_req.extend_from_slice(peer_id);
-        announce_req.write_u64::<BigEndian>(0)?; // Downloaded
-        announce_req.write_u64::<BigEndian>(0)?; // Left
-        announce_req.write_u64::<BigEndian>(0)?; // Uploaded
-        announce_req.write_u32::<BigEndian>(0)?; // Event: None
+        announce_req.write_u64::<BigEndian>(params.downloaded)?;
+        announce_req.write_u64::<BigEndian>(params.left)?;
+        announce_req.write_u64::<BigEndian>(params.uploaded)?;
+        announce_req.write_u32::<BigEndian>(params.event.udp_code())?; // Event
         announce_req.write_u32::<BigEndian>(0)?; // IP (0 = default)
-        announce_req.write_u32::<BigEndian>(0)?; // Key
-        announce_req.write_i32::<BigEndian>(-1)?; // Num Want (-1 = default)
-        announce_req.write_u16::<BigEndian>(8888)?; // Port
+        announce_req.write_u32::<BigEndian>(params.key)?; // Key
+        // Num Want (-1 = tracker default)
+        announce_req.write_i32::<BigEndian>(params.numwant.map_or(-1, |n| n as i32))?;
+        announce_req.write_u16::<BigEndian>(params.port)?; // Port
+
+        // BEP 41 URLData options carrying the path and query, in chunks of at most
+        // 255 bytes. Trackers that don't support options ignore the trailing bytes.
+        let (_, _, request) = Self::parse_udp_url(announce_url)?;
+        if !request.is_empty() {
+            for chunk in request.as_bytes().chunks(255) {
+                announce_req.push(UDP_OPTION_URL_DATA);
+                announce_req.push(chunk.len() as u8);
+                announce_req.extend_from_slice(chunk);
+            }
+            announce_req.push(UDP_OPTION_END);
+        }
         socket.send(&announce_req).await?;
 
         // Read Announce Response
@@ -217,9 +788,20 @@ impl Response {
         let mut rdr = std::io::Cursor::new(&response_buf[..len]);
         let _action = rdr.read_u32::<BigEndian>()?;
         let _trans_id = rdr.read_u32::<BigEndian>()?;
-        let _interval = rdr.read_u32::<BigEndian>()?;
-        let _leechers = rdr.read_u32::<BigEndian>()?;
-        let _seeders = rdr.read_u32::<BigEndian>()?;
+        let interval = Some(rdr.read_u32::<BigEndian>()? as i64);
+        let leechers = rdr.read_u32::<BigEndian>()?;
+        let seeders = rdr.read_u32::<BigEndian>()?;
+        let swarm = Some(SwarmSize { seeders, leechers });
+
+        // BEP 15: over IPv6 the tracker answers with 18-byte IPv6 entries
+        if socket.peer_addr()?.is_ipv6() {
+            return Ok(AnnounceReply {
+                peers: Self::extract_peers6(&response_buf[rdr.position() as usize..len]),
+                interval,
+                min_interval: None,
+                swarm,
+            });
+        }
 
         // Extract Peers (Compact IP/Port pairs)
         let mut peers = Vec::new();
@@ -233,7 +815,258 @@ impl Response {
                 break;
             }
         }
-        Ok(peers)
+        Ok(AnnounceReply {
+            peers,
+            interval,
+            min_interval: None,
+            swarm,
+        })
+    }
+
+    /// Splits a `udp://host[:port][/path][?query]` URL into host, port (default
+    /// `DEFAULT_UDP_TRACKER_PORT`) and the path plus query, which BEP 41 lets us pass on
+    /// to trackers that serve several announce endpoints.
+    fn parse_udp_url(announce_url: &str) -> anyhow::Result<(String, u16, String)> {
+        let url = url::Url::parse(announce_url)
+            .context(format!("Invalid UDP tracker URL {}", announce_url))?;
+        let host = match url.host() {
+            Some(url::Host::Domain(domain)) => domain.to_string(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            None => anyhow::bail!("UDP tracker URL {} has no host", announce_url),
+        };
+        let port = url.port().unwrap_or(DEFAULT_UDP_TRACKER_PORT);
+
+        let mut request = url.path().to_string();
+        if let Some(query) = url.query() {
+            request.push('?');
+            request.push_str(query);
+        }
+        if request == "/" {
+            request.clear();
+        }
+        Ok((host, port, request))
+    }
+
+    /// Opens a UDP socket to the tracker and performs the BEP 15 connect exchange.
+    ///
+    /// The host is resolved explicitly and each address (IPv4 or IPv6) is tried in
+    /// turn until one answers. Returns the connected socket and the Connection ID required by
+    /// every subsequent request (announce or scrape).
+    async fn udp_connect(
+        announce_url: &str,
+        bind: Option<IpAddr>,
+    ) -> anyhow::Result<(UdpSocket, u64)> {
+        let (host, port, _) = Self::parse_udp_url(announce_url)?;
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .context(format!("Failed to resolve {}", host))?
+            .collect();
+        Self::udp_connect_any(&host, addrs, bind).await
+    }
+
+    /// Tries the connect exchange with each of `host`'s resolved addresses in order,
+    /// returning the first that answers.
+    async fn udp_connect_any(
+        host: &str,
+        addrs: Vec<SocketAddr>,
+        bind: Option<IpAddr>,
+    ) -> anyhow::Result<(UdpSocket, u64)> {
+        if addrs.is_empty() {
+            anyhow::bail!("{} did not resolve to any address", host);
+        }
+
+        let mut last_err = None;
+        for addr in addrs {
+            match Self::udp_connect_to(addr, bind).await {
+                Ok(connected) => return Ok(connected),
+                Err(e) => {
+                    debug!("UDP tracker {} ({}) failed: {:#}", host, addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    /// Performs the connect exchange with one resolved tracker address, from a socket
+    /// of the same address family (bound to `bind` if given).
+    async fn udp_connect_to(
+        addr: SocketAddr,
+        bind: Option<IpAddr>,
+    ) -> anyhow::Result<(UdpSocket, u64)> {
+        let local = match bind {
+            Some(ip) if ip.is_ipv4() != addr.is_ipv4() => {
+                anyhow::bail!("{} is not reachable from {}", addr, ip)
+            }
+            Some(ip) => ip,
+            None if addr.is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        socket.connect(addr).await.context("UDP Connect failed")?;
+
+        let mut connect_req = Vec::new();
+        connect_req.write_u64::<BigEndian>(0x41727101980)?; // Magic Constant
+        connect_req.write_u32::<BigEndian>(0)?; // Action: Connect
+        connect_req.write_u32::<BigEndian>(12345)?; // Transaction ID
+        socket.send(&connect_req).await?;
+
+        // Read Connection Response
+        let mut buf = [0u8; 16];
+        let (len, _) = timeout(Duration::from_secs(3), socket.recv_from(&mut buf))
+            .await
+            .context("UDP Connect Timeout")??;
+
+        if len < 16 {
+            anyhow::bail!("Invalid UDP Connect Response length");
+        }
+        let mut rdr = std::io::Cursor::new(&buf[..len]);
+        let _action = rdr.read_u32::<BigEndian>()?;
+        let _trans_id = rdr.read_u32::<BigEndian>()?;
+        let connection_id = rdr.read_u64::<BigEndian>()?;
+
+        Ok((socket, connection_id))
+    }
+
+    /// Queries a tracker for swarm health without announcing.
+    ///
+    /// Returns seeders/leechers/completed counts for each requested info hash the
+    /// tracker knows about. Trackers without scrape support produce an error.
+    pub async fn scrape(
+        url: &str,
+        info_hashes: &[[u8; 20]],
+        client: &reqwest::Client,
+    ) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+        if url.starts_with("udp://") {
+            Self::udp_scrape(url, info_hashes).await
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Self::http_scrape(client, url, info_hashes).await
+        } else {
+            Err(anyhow::anyhow!("Unsupported protocol"))
+        }
+    }
+
+    /// Derives the scrape URL from an announce URL.
+    ///
+    /// By convention, scrape is only supported when the last path segment starts with
+    /// `announce`; that word is replaced with `scrape` (e.g. `/x/announce.php` ->
+    /// `/x/scrape.php`). Any other URL means the tracker doesn't support scrape.
+    fn scrape_url(announce_url: &str) -> Option<String> {
+        let (path, query) = match announce_url.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (announce_url, None),
+        };
+        let slash = path.rfind('/')?;
+        let last_segment = &path[slash + 1..];
+        let rest = last_segment.strip_prefix("announce")?;
+
+        let mut scrape = format!("{}/scrape{}", &path[..slash], rest);
+        if let Some(query) = query {
+            scrape.push('?');
+            scrape.push_str(query);
+        }
+        Some(scrape)
+    }
+
+    /// Performs an HTTP scrape, sending one `info_hash` parameter per torrent.
+    async fn http_scrape(
+        client: &reqwest::Client,
+        url: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+        let scrape_url = Self::scrape_url(url)
+            .ok_or_else(|| anyhow::anyhow!("Tracker does not support scrape"))?;
+
+        let query = info_hashes
+            .iter()
+            .map(|hash| format!("info_hash={}", url_encode(hash)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let final_url = Self::append_query(&scrape_url, &query);
+
+        let response_bytes = Self::http_get(client, &final_url).await?;
+        let scrape: ScrapeResponse = Self::decode_http_reply(&response_bytes)
+            .context("Failed to decode HTTP scrape response")?;
+
+        if let Some(reason) = scrape.failure_reason {
+            anyhow::bail!("Tracker returned failure: {}", reason);
+        }
+
+        let mut stats = HashMap::new();
+        for (hash, file) in scrape.files.unwrap_or_default() {
+            if hash.len() != 20 {
+                continue;
+            }
+            let mut key = [0u8; 20];
+            key.copy_from_slice(&hash);
+            stats.insert(
+                key,
+                ScrapeStats {
+                    seeders: file.complete,
+                    leechers: file.incomplete,
+                    completed: file.downloaded,
+                },
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Performs a UDP scrape (action 2, BEP 15).
+    ///
+    /// The response carries 12 bytes per requested hash, in request order:
+    /// seeders, completed, leechers.
+    async fn udp_scrape(
+        announce_url: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+        let (socket, connection_id) = Self::udp_connect(announce_url, None).await?;
+
+        let mut scrape_req = Vec::new();
+        scrape_req.write_u64::<BigEndian>(connection_id)?;
+        scrape_req.write_u32::<BigEndian>(2)?; // Action: Scrape
+        scrape_req.write_u32::<BigEndian>(12345)?; // Transaction ID
+        for hash in info_hashes {
+            scrape_req.extend_from_slice(hash);
+        }
+        socket.send(&scrape_req).await?;
+
+        let mut response_buf = [0u8; 4096];
+        let (len, _) = timeout(Duration::from_secs(3), socket.recv_from(&mut response_buf))
+            .await
+            .context("UDP Scrape Timeout")??;
+
+        let mut rdr = std::io::Cursor::new(&response_buf[..len]);
+        let action = rdr.read_u32::<BigEndian>()?;
+        let _trans_id = rdr.read_u32::<BigEndian>()?;
+        if action == 3 {
+            // Action 3 = Error, followed by a message string
+            let message = String::from_utf8_lossy(&response_buf[8..len]);
+            anyhow::bail!("Tracker returned failure: {}", message);
+        }
+        if action != 2 {
+            anyhow::bail!("Unexpected UDP scrape action: {}", action);
+        }
+
+        let mut stats = HashMap::new();
+        for hash in info_hashes {
+            let (Ok(seeders), Ok(completed), Ok(leechers)) = (
+                rdr.read_u32::<BigEndian>(),
+                rdr.read_u32::<BigEndian>(),
+                rdr.read_u32::<BigEndian>(),
+            ) else {
+                break;
+            };
+            stats.insert(
+                *hash,
+                ScrapeStats {
+                    seeders,
+                    leechers,
+                    completed,
+                },
+            );
+        }
+        Ok(stats)
     }
 
     /// Helper to convert raw peer data (Binary or List) into a standardized string format.
@@ -252,10 +1085,913 @@ impl Response {
             }
             Peers::List(list) => {
                 for peer in list {
-                    peer_addresses.push(format!("{}:{}", peer.ip, peer.port));
+                    // Route through SocketAddr so IPv6 entries get the `[addr]:port` form
+                    match peer.ip.parse::<IpAddr>() {
+                        Ok(ip) => peer_addresses.push(SocketAddr::new(ip, peer.port).to_string()),
+                        Err(_) => peer_addresses.push(format!("{}:{}", peer.ip, peer.port)),
+                    }
                 }
             }
         }
         Ok(peer_addresses)
     }
+
+    /// Parses compact IPv6 peers (18 bytes each) into `[addr]:port` strings,
+    /// the form `TcpStream::connect` expects for IPv6.
+    fn extract_peers6(data: &[u8]) -> Vec<String> {
+        data.chunks_exact(18)
+            .map(|chunk| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&chunk[..16]);
+                let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port).to_string()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves one HTTP request with `body` and returns the tracker URL plus a handle
+    /// yielding the request line and headers that were received.
+    async fn serve_once(body: &'static [u8]) -> (String, tokio::task::JoinHandle<String>) {
+        serve_status("200 OK", body).await
+    }
+
+    /// Answers every request with `body`, counting the requests.
+    async fn serve_counting(body: &'static [u8]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+        (url, hits)
+    }
+
+    /// Like `serve_once`, answering with the given status line instead of `200 OK`.
+    async fn serve_status(
+        status: &'static str,
+        body: &'static [u8],
+    ) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+        (url, handle)
+    }
+
+    /// Answers one BEP 15 connect and announce on `ip` with a single peer, and returns
+    /// the tracker URL plus a handle yielding the announce packet and where it came from.
+    async fn udp_tracker(ip: IpAddr) -> (String, tokio::task::JoinHandle<(Vec<u8>, SocketAddr)>) {
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0, 0, 0, 0];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&7u64.to_be_bytes());
+            socket.send_to(&reply, from).await.unwrap();
+
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0, 0, 0, 1];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 2, 0, 0, 0, 1]);
+            match ip {
+                IpAddr::V4(_) => reply.extend_from_slice(&[10, 0, 0, 1]),
+                IpAddr::V6(_) => reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets()),
+            }
+            reply.extend_from_slice(&6881u16.to_be_bytes());
+            socket.send_to(&reply, from).await.unwrap();
+            (buf[..len].to_vec(), from)
+        });
+        (url, handle)
+    }
+
+    /// Answers one BEP 15 connect on loopback, then replies to the next request with
+    /// `action` and `body` (echoing its transaction ID). Returns the tracker URL.
+    async fn udp_tracker_replying(action: u32, body: Vec<u8>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0, 0, 0, 0];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&7u64.to_be_bytes());
+            socket.send_to(&reply, from).await.unwrap();
+
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = action.to_be_bytes().to_vec();
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&body);
+            socket.send_to(&reply, from).await.unwrap();
+        });
+        url
+    }
+
+    fn params() -> AnnounceParams {
+        AnnounceParams {
+            port: 6881,
+            use_udp: true,
+            event: AnnounceEvent::None,
+            numwant: None,
+            key: 0,
+            bind: None,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+        }
+    }
+
+    async fn announce(url: &str) -> anyhow::Result<AnnounceReply> {
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        Response::http_announce(&client, url, &[1; 20], &[2; 20], params()).await
+    }
+
+    #[test]
+    fn decodes_failure_reason() {
+        let response: Response =
+            Response::decode_http_reply(b"d14:failure reason11:bad passkeye").unwrap();
+        assert_eq!(response.failure_reason.as_deref(), Some("bad passkey"));
+        assert!(response.peers.is_none());
+        assert!(response.warning_message.is_none());
+    }
+
+    #[test]
+    fn decodes_warning_alongside_peers() {
+        let response: Response = Response::decode_http_reply(
+            b"d15:warning message4:slow5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert!(response.failure_reason.is_none());
+        assert!(response.peers.is_some());
+    }
+
+    #[test]
+    fn decodes_complete_and_incomplete_counts() {
+        let response: Response =
+            Response::decode_http_reply(b"d8:completei12e10:incompletei34e5:peers0:e").unwrap();
+        assert_eq!(
+            (response.complete, response.incomplete),
+            (Some(12), Some(34))
+        );
+
+        let response: Response = Response::decode_http_reply(b"d5:peers0:e").unwrap();
+        assert_eq!((response.complete, response.incomplete), (None, None));
+    }
+
+    #[tokio::test]
+    async fn announce_reports_the_swarm_size() {
+        let (url, _server) =
+            serve_once(b"d8:completei12e10:incompletei34e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+        let reply = announce(&url).await.unwrap();
+        assert_eq!(reply.swarm.map(|s| (s.seeders, s.leechers)), Some((12, 34)));
+    }
+
+    #[test]
+    fn rejects_replies_that_are_not_bencode() {
+        let error = Response::decode_http_reply::<Response>(b"<html>Not Found</html>")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not bencode"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn announce_surfaces_failure_reason() {
+        let (url, _server) = serve_once(b"d14:failure reason11:bad passkeye").await;
+        let error = announce(&url).await.err().unwrap().to_string();
+        assert_eq!(error, "Tracker returned failure: bad passkey");
+    }
+
+    #[tokio::test]
+    async fn announce_reports_http_errors_and_non_bencode_bodies() {
+        let (url, _server) = serve_status("404 Not Found", b"<html>Not Found</html>").await;
+        let error = format!("{:#}", announce(&url).await.err().unwrap());
+        assert!(
+            error.contains("Tracker returned HTTP 404 Not Found"),
+            "{}",
+            error
+        );
+        assert!(!error.contains("bencode"), "{}", error);
+
+        let (url, _server) = serve_once(b"<html>Maintenance</html>").await;
+        let error = format!("{:#}", announce(&url).await.err().unwrap());
+        assert!(error.contains("not bencode"), "{}", error);
+    }
+
+    #[test]
+    fn extracts_compact_ipv6_peers() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&6881u16.to_be_bytes());
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        data.extend_from_slice(&51413u16.to_be_bytes());
+        assert_eq!(
+            Response::extract_peers6(&data),
+            vec!["[2001:db8::1]:6881", "[::1]:51413"]
+        );
+        // A trailing partial entry is ignored
+        data.extend_from_slice(&[0; 17]);
+        assert_eq!(Response::extract_peers6(&data).len(), 2);
+        assert!(Response::extract_peers6(&[]).is_empty());
+    }
+
+    #[test]
+    fn ipv6_peers_parse_back_for_connecting() {
+        let mut data = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 7).octets().to_vec();
+        data.extend_from_slice(&80u16.to_be_bytes());
+        let peers = Response::extract_peers6(&data);
+        assert_eq!(
+            normalize_peer(&peers[0]),
+            Some("[fe80::7]:80".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn equal_addresses_written_differently_normalize_to_one_peer() {
+        let peers = [
+            "10.0.0.1:6881",
+            "[::ffff:10.0.0.1]:6881",
+            " 10.0.0.1:6881\n",
+            "[2001:db8::1]:80",
+            "[2001:0db8:0:0:0:0:0:0001]:80",
+        ];
+        let unique: HashSet<SocketAddr> = peers.iter().filter_map(|p| normalize_peer(p)).collect();
+        let expected: HashSet<SocketAddr> = ["10.0.0.1:6881", "[2001:db8::1]:80"]
+            .iter()
+            .map(|p| p.parse().unwrap())
+            .collect();
+        assert_eq!(unique, expected);
+
+        for rejected in ["10.0.0.1:0", "tracker.example:6881", "10.0.0.1"] {
+            assert_eq!(normalize_peer(rejected), None, "{}", rejected);
+        }
+    }
+
+    #[test]
+    fn unique_peers_keeps_the_first_of_each_address() {
+        let peers: Vec<String> = [
+            "[::ffff:10.0.0.2]:6881",
+            "10.0.0.1:6881",
+            "10.0.0.2:6881",
+            "tracker.example:6881",
+            " 10.0.0.1:6881",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(unique_peers(&peers), vec!["10.0.0.2:6881", "10.0.0.1:6881"]);
+    }
+
+    #[tokio::test]
+    async fn announce_merges_peers_and_peers6() {
+        let (url, _server) = serve_once(
+            b"d5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e",
+        )
+        .await;
+        let reply = announce(&url).await.unwrap();
+        assert_eq!(reply.peers, vec!["127.0.0.1:6881", "[::1]:6882"]);
+    }
+
+    #[test]
+    fn append_query_starts_a_query() {
+        assert_eq!(
+            Response::append_query("http://t/announce", "a=1"),
+            "http://t/announce?a=1"
+        );
+        assert_eq!(
+            Response::append_query("http://t/announce?", "a=1"),
+            "http://t/announce?a=1"
+        );
+    }
+
+    #[test]
+    fn append_query_extends_an_existing_query() {
+        assert_eq!(
+            Response::append_query("http://t/announce?passkey=abc", "a=1"),
+            "http://t/announce?passkey=abc&a=1"
+        );
+        assert_eq!(
+            Response::append_query("http://t/announce.php?uk=x&id=7#frag", "a=1&b=2"),
+            "http://t/announce.php?uk=x&id=7&a=1&b=2"
+        );
+        // Existing parameters are re-encoded, so a stray space can't break the URL
+        assert_eq!(
+            Response::append_query("http://t/announce?pk=a b", "a=1"),
+            "http://t/announce?pk=a+b&a=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_keeps_the_passkey() {
+        let (url, server) = serve_once(b"d5:peers0:e").await;
+        announce(&format!("{}?passkey=abc", url)).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(
+            request.starts_with("GET /announce?passkey=abc&info_hash="),
+            "{}",
+            request
+        );
+        assert_eq!(request.matches('?').count(), 1);
+    }
+
+    #[tokio::test]
+    async fn announce_sends_the_key_and_user_agent() {
+        let (url, server) = serve_once(b"d5:peers0:e").await;
+        let client = http_client(None, "custom/1.0", None).unwrap();
+        let params = AnnounceParams {
+            key: 0xDEADBEEF,
+            ..params()
+        };
+        Response::http_announce(&client, &url, &[1; 20], &[2; 20], params)
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        let request_line = request.lines().next().unwrap();
+        assert!(request_line.contains("&key=DEADBEEF"), "{}", request_line);
+        assert!(
+            request
+                .lines()
+                .any(|line| line.eq_ignore_ascii_case("user-agent: custom/1.0")),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_reports_transfer_totals() {
+        let (url, server) = serve_once(b"d5:peers0:e").await;
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        let params = AnnounceParams {
+            uploaded: 3000,
+            downloaded: 2000,
+            left: 500,
+            ..params()
+        };
+        Response::http_announce(&client, &url, &[1; 20], &[2; 20], params)
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(
+            request.contains("&uploaded=3000&downloaded=2000&compact=1&left=500"),
+            "{}",
+            request
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_announce_reports_transfer_totals() {
+        let (url, tracker) = udp_tracker(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        let params = AnnounceParams {
+            uploaded: 3000,
+            downloaded: 2000,
+            left: 500,
+            ..params()
+        };
+        let reply = Response::udp_announce(&url, &[1; 20], &[2; 20], params)
+            .await
+            .unwrap();
+        assert_eq!(reply.peers, vec!["10.0.0.1:6881"]);
+        let (packet, _) = tracker.await.unwrap();
+        let field = |at: usize| u64::from_be_bytes(packet[at..at + 8].try_into().unwrap());
+        assert_eq!((field(56), field(64), field(72)), (2000, 500, 3000));
+    }
+
+    #[tokio::test]
+    async fn numwant_is_sent_over_http_and_udp() {
+        let with_numwant = || AnnounceParams {
+            numwant: Some(80),
+            ..params()
+        };
+        let (url, server) = serve_once(b"d5:peers0:e").await;
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        Response::http_announce(&client, &url, &[1; 20], &[2; 20], with_numwant())
+            .await
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(request.contains("&numwant=80"), "{}", request);
+
+        let (url, tracker) = udp_tracker(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        Response::udp_announce(&url, &[1; 20], &[2; 20], with_numwant())
+            .await
+            .unwrap();
+        let (packet, _) = tracker.await.unwrap();
+        assert_eq!(i32::from_be_bytes(packet[92..96].try_into().unwrap()), 80);
+
+        // Without one the tracker decides: nothing over HTTP, -1 over UDP
+        let (url, server) = serve_once(b"d5:peers0:e").await;
+        announce(&url).await.unwrap();
+        assert!(!server.await.unwrap().contains("numwant"));
+        let (url, tracker) = udp_tracker(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        Response::udp_announce(&url, &[1; 20], &[2; 20], params())
+            .await
+            .unwrap();
+        let (packet, _) = tracker.await.unwrap();
+        assert_eq!(i32::from_be_bytes(packet[92..96].try_into().unwrap()), -1);
+    }
+
+    #[test]
+    fn parses_udp_tracker_urls() {
+        let parse = |url| Response::parse_udp_url(url).unwrap();
+        assert_eq!(
+            parse("udp://tracker.example:2710/announce"),
+            ("tracker.example".to_string(), 2710, "/announce".to_string())
+        );
+        assert_eq!(
+            parse("udp://tracker.example"),
+            (
+                "tracker.example".to_string(),
+                DEFAULT_UDP_TRACKER_PORT,
+                String::new()
+            )
+        );
+        assert_eq!(
+            parse("udp://10.0.0.1:80/a/announce?passkey=x"),
+            (
+                "10.0.0.1".to_string(),
+                80,
+                "/a/announce?passkey=x".to_string()
+            )
+        );
+        assert_eq!(
+            parse("udp://[2001:db8::1]:1337/"),
+            ("2001:db8::1".to_string(), 1337, String::new())
+        );
+        assert!(Response::parse_udp_url("not a url").is_err());
+        assert!(Response::parse_udp_url("udp://:80").is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_announce_over_ipv6_returns_ipv6_peers() {
+        let (url, tracker) = udp_tracker(IpAddr::V6(Ipv6Addr::LOCALHOST)).await;
+        assert!(url.starts_with("udp://[::1]:"), "{}", url);
+        let reply = Response::udp_announce(&url, &[1; 20], &[2; 20], params())
+            .await
+            .unwrap();
+        assert_eq!(reply.peers, vec!["[::1]:6881"]);
+        assert_eq!(reply.swarm.map(|s| (s.seeders, s.leechers)), Some((1, 2)));
+        let (_, from) = tracker.await.unwrap();
+        assert!(from.is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn udp_announce_is_sent_from_the_bound_address() {
+        // All of 127.0.0.0/8 is loopback, so a second local address is always there
+        let bind = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let (url, tracker) = udp_tracker(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        let params = AnnounceParams {
+            bind: Some(bind),
+            ..params()
+        };
+        Response::udp_announce(&url, &[1; 20], &[2; 20], params)
+            .await
+            .unwrap();
+        let (_, from) = tracker.await.unwrap();
+        assert_eq!(from.ip(), bind);
+    }
+
+    #[tokio::test]
+    async fn udp_announce_falls_back_to_the_next_resolved_address() {
+        // Nothing listens on the first address: a freed port on the IPv6 loopback
+        let dead = std::net::UdpSocket::bind("[::1]:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (url, tracker) = udp_tracker(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        let live: SocketAddr = url.trim_start_matches("udp://").parse().unwrap();
+
+        let (socket, connection_id) = Response::udp_connect_any("tracker", vec![dead, live], None)
+            .await
+            .unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), live);
+        let reply =
+            Response::udp_announce_on(&socket, connection_id, &url, &[1; 20], &[2; 20], params())
+                .await
+                .unwrap();
+        assert_eq!(reply.peers, vec!["10.0.0.1:6881"]);
+        let (packet, _) = tracker.await.unwrap();
+        assert_eq!(packet[..8], 7u64.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn repeated_announces_reuse_the_connection() {
+        // Serves any number of requests, keeping each connection open between them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let connections = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let body = b"d5:peers0:e";
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        request.clear();
+                        let head =
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                        stream.write_all(head.as_bytes()).await.unwrap();
+                        stream.write_all(body).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        for _ in 0..3 {
+            Response::http_announce(&client, &url, &[1; 20], &[2; 20], params())
+                .await
+                .unwrap();
+        }
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn derives_scrape_urls() {
+        assert_eq!(
+            Response::scrape_url("http://t/announce").as_deref(),
+            Some("http://t/scrape")
+        );
+        assert_eq!(
+            Response::scrape_url("http://t/x/announce.php?pk=1").as_deref(),
+            Some("http://t/x/scrape.php?pk=1")
+        );
+        assert_eq!(Response::scrape_url("http://t/a"), None);
+        assert_eq!(Response::scrape_url("http://t/announce/x"), None);
+    }
+
+    #[test]
+    fn decodes_scrape_files() {
+        let scrape: ScrapeResponse = Response::decode_http_reply(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei3eeee",
+        )
+        .unwrap();
+        let files = scrape.files.unwrap();
+        let file = &files[&ByteBuf::from(vec![b'a'; 20])];
+        assert_eq!(
+            (file.complete, file.incomplete, file.downloaded),
+            (5, 3, 50)
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_reports_each_known_hash() {
+        // The second file has a malformed key and the third misses counts
+        let (url, server) = serve_once(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei3ee3:bad\
+              d8:completei1ee20:bbbbbbbbbbbbbbbbbbbbd8:completei2eeee",
+        )
+        .await;
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        let stats = Response::scrape(&url, &[[b'a'; 20], [b'b'; 20]], &client)
+            .await
+            .unwrap();
+        assert!(server.await.unwrap().starts_with("GET /scrape?info_hash="));
+        assert_eq!(stats.len(), 2);
+        let a = stats[&[b'a'; 20]];
+        assert_eq!((a.seeders, a.leechers, a.completed), (5, 3, 50));
+        let b = stats[&[b'b'; 20]];
+        assert_eq!((b.seeders, b.leechers, b.completed), (2, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn scrape_surfaces_failure_reason() {
+        let (url, _server) = serve_once(b"d14:failure reason9:forbiddene").await;
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        let error = Response::scrape(&url, &[[0; 20]], &client)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "Tracker returned failure: forbidden");
+    }
+
+    #[tokio::test]
+    async fn udp_scrape_reports_each_hash() {
+        // Seeders, completed, leechers for each hash, in request order
+        let counts: Vec<u8> = [5u32, 50, 3, 2, 0, 1]
+            .iter()
+            .flat_map(|n| n.to_be_bytes())
+            .collect();
+        let url = udp_tracker_replying(2, counts).await;
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        let stats = Response::scrape(&url, &[[b'a'; 20], [b'b'; 20]], &client)
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 2);
+        let a = stats[&[b'a'; 20]];
+        assert_eq!((a.seeders, a.leechers, a.completed), (5, 3, 50));
+        let b = stats[&[b'b'; 20]];
+        assert_eq!((b.seeders, b.leechers, b.completed), (2, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn udp_scrape_surfaces_the_error_message() {
+        let url = udp_tracker_replying(3, b"unknown torrent".to_vec()).await;
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        let error = Response::scrape(&url, &[[0; 20]], &client)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "Tracker returned failure: unknown torrent");
+    }
+
+    const HASH: [u8; 20] = [7; 20];
+    const URL: &str = "udp://t:6969";
+
+    /// Pretends the last announce to `URL` happened `ago` earlier than recorded.
+    fn age(throttle: &AnnounceThrottle, ago: Duration) {
+        let mut last = throttle.last.lock().unwrap();
+        let entry = last.get_mut(&(HASH, URL.to_string())).unwrap();
+        entry.at -= ago;
+    }
+
+    #[test]
+    fn throttle_holds_announces_within_the_floor() {
+        let throttle = AnnounceThrottle::default();
+        assert_eq!(
+            throttle.begin(&HASH, URL, AnnounceEvent::Started),
+            Some(AnnounceEvent::Started)
+        );
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::None), None);
+        // Other trackers and torrents are throttled separately
+        assert!(
+            throttle
+                .begin(&HASH, "udp://u:6969", AnnounceEvent::None)
+                .is_some()
+        );
+        assert!(throttle.begin(&[8; 20], URL, AnnounceEvent::None).is_some());
+
+        age(&throttle, MIN_ANNOUNCE_FLOOR);
+        assert_eq!(
+            throttle.begin(&HASH, URL, AnnounceEvent::None),
+            Some(AnnounceEvent::None)
+        );
+    }
+
+    #[test]
+    fn throttle_honours_min_interval() {
+        let throttle = AnnounceThrottle::default();
+        throttle.begin(&HASH, URL, AnnounceEvent::None);
+        throttle.set_intervals(&HASH, URL, None, Some(120));
+        age(&throttle, Duration::from_secs(60));
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::None), None);
+        age(&throttle, Duration::from_secs(60));
+        assert!(throttle.begin(&HASH, URL, AnnounceEvent::None).is_some());
+
+        // The interval is kept for the next round too
+        age(&throttle, Duration::from_secs(119));
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::None), None);
+    }
+
+    #[test]
+    fn throttle_waits_for_the_interval_between_regular_announces() {
+        let throttle = AnnounceThrottle::default();
+        throttle.begin(&HASH, URL, AnnounceEvent::None);
+        throttle.set_intervals(&HASH, URL, Some(1800), None);
+        age(&throttle, Duration::from_secs(600));
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::None), None);
+        // Events only wait for the `min interval`
+        assert_eq!(
+            throttle.begin(&HASH, URL, AnnounceEvent::Started),
+            Some(AnnounceEvent::Started)
+        );
+        age(&throttle, Duration::from_secs(1800));
+        assert!(throttle.begin(&HASH, URL, AnnounceEvent::None).is_some());
+    }
+
+    #[test]
+    fn throttle_survives_a_restart() {
+        let dir = TempDir::new().unwrap();
+        let output_dir = dir.path().to_string_lossy().into_owned();
+        let throttle = AnnounceThrottle::load(&output_dir);
+        throttle.begin(&HASH, URL, AnnounceEvent::Started);
+        throttle.set_intervals(&HASH, URL, Some(1800), Some(60));
+        throttle.save().unwrap();
+
+        let restarted = AnnounceThrottle::load(&output_dir);
+        assert_eq!(restarted.begin(&HASH, URL, AnnounceEvent::None), None);
+        age(&restarted, Duration::from_secs(60));
+        assert_eq!(
+            restarted.begin(&HASH, URL, AnnounceEvent::Started),
+            Some(AnnounceEvent::Started)
+        );
+    }
+
+    #[test]
+    fn throttle_never_goes_below_the_floor() {
+        let throttle = AnnounceThrottle::default();
+        throttle.begin(&HASH, URL, AnnounceEvent::None);
+        throttle.set_intervals(&HASH, URL, Some(1), Some(1));
+        age(&throttle, Duration::from_secs(5));
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::None), None);
+        throttle.set_intervals(&HASH, URL, Some(-10), Some(-10));
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::None), None);
+    }
+
+    #[test]
+    fn throttle_lets_stopped_through_and_keeps_a_held_started() {
+        let throttle = AnnounceThrottle::default();
+        throttle.begin(&HASH, URL, AnnounceEvent::Started);
+        assert_eq!(
+            throttle.begin(&HASH, URL, AnnounceEvent::Stopped),
+            Some(AnnounceEvent::Stopped)
+        );
+        // A resume right after the pause is held back, then sent as `started`
+        assert_eq!(throttle.begin(&HASH, URL, AnnounceEvent::Started), None);
+        age(&throttle, MIN_ANNOUNCE_FLOOR);
+        assert_eq!(
+            throttle.begin(&HASH, URL, AnnounceEvent::None),
+            Some(AnnounceEvent::Started)
+        );
+        age(&throttle, MIN_ANNOUNCE_FLOOR);
+        assert_eq!(
+            throttle.begin(&HASH, URL, AnnounceEvent::None),
+            Some(AnnounceEvent::None)
+        );
+    }
+
+    fn torrent_with_tiers(tiers: Vec<Vec<String>>) -> Torrent {
+        let info = crate::core::torrent_info::Info {
+            name: "tiers".to_string(),
+            ..Default::default()
+        };
+        Torrent::new(String::new(), Some(tiers), info)
+    }
+
+    async fn announce_round(
+        torrent: &Torrent,
+        throttle: &AnnounceThrottle,
+        tiers: &TrackerTiers,
+    ) -> anyhow::Result<Announced> {
+        let client = http_client(None, DEFAULT_USER_AGENT, None).unwrap();
+        Response::request_peers(torrent, &[2; 20], params(), &client, throttle, tiers).await
+    }
+
+    #[test]
+    fn promote_moves_a_tracker_to_the_front_of_its_tier() {
+        let urls: Vec<String> = (0..4).map(|i| format!("udp://t{}:1", i)).collect();
+        let torrent = torrent_with_tiers(vec![urls.clone(), vec!["udp://x:1".to_string()]]);
+        let tiers = TrackerTiers::default();
+        let order = tiers.get(&HASH, &torrent);
+        let last = order[0][3].clone();
+
+        tiers.promote(&HASH, 0, &last);
+        let promoted = tiers.get(&HASH, &torrent);
+        assert_eq!(promoted[0][0], last);
+        assert_eq!(promoted[0][1..], order[0][..3]);
+        assert_eq!(promoted[1], order[1]);
+
+        // Unknown trackers and tiers leave the order alone
+        tiers.promote(&HASH, 0, "udp://unknown:1");
+        tiers.promote(&HASH, 5, &last);
+        assert_eq!(tiers.get(&HASH, &torrent), promoted);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_tier() {
+        let (down, _a) = serve_once(b"d14:failure reason4:downe").await;
+        let (up, _b) = serve_once(b"d5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+        let torrent = torrent_with_tiers(vec![vec![down], vec![up]]);
+
+        let announced = announce_round(
+            &torrent,
+            &AnnounceThrottle::default(),
+            &TrackerTiers::default(),
+        )
+        .await
+        .unwrap();
+        assert!(announced.reached);
+        assert_eq!(announced.peers, vec!["127.0.0.1:6881"]);
+    }
+
+    #[tokio::test]
+    async fn lower_tiers_are_left_alone_when_tier_0_has_peers() {
+        let (first, _a) = serve_once(b"d5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+        let (second, hits) = serve_counting(b"d5:peers6:\x7f\x00\x00\x02\x1a\xe1e").await;
+        let torrent = torrent_with_tiers(vec![vec![first], vec![second]]);
+
+        let announced = announce_round(
+            &torrent,
+            &AnnounceThrottle::default(),
+            &TrackerTiers::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(announced.peers, vec!["127.0.0.1:6881"]);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn promotes_the_tracker_that_answered() {
+        let (down, _a) = serve_once(b"d14:failure reason4:downe").await;
+        let (up, _b) = serve_once(b"d5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+        let torrent = torrent_with_tiers(vec![vec![down, up.clone()]]);
+        let tiers = TrackerTiers::default();
+
+        announce_round(&torrent, &AnnounceThrottle::default(), &tiers)
+            .await
+            .unwrap();
+        let info_hash = torrent.info_hash().unwrap();
+        assert_eq!(tiers.get(&info_hash, &torrent)[0][0], up);
+    }
+
+    #[tokio::test]
+    async fn second_round_within_the_interval_does_not_reach_the_tracker() {
+        let (url, hits) =
+            serve_counting(b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+        let torrent = torrent_with_tiers(vec![vec![url]]);
+        let throttle = AnnounceThrottle::default();
+        let tiers = TrackerTiers::default();
+
+        let first = announce_round(&torrent, &throttle, &tiers).await.unwrap();
+        assert!(first.reached);
+        let second = announce_round(&torrent, &throttle, &tiers).await.unwrap();
+        assert!(!second.reached);
+        assert!(second.peers.is_empty());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_when_every_tier_fails() {
+        let (a, _a) = serve_once(b"d14:failure reason4:downe").await;
+        let (b, _b) = serve_once(b"d14:failure reason4:downe").await;
+        let torrent = torrent_with_tiers(vec![vec![a], vec![b]]);
+        let throttle = AnnounceThrottle::default();
+        let tiers = TrackerTiers::default();
+        assert!(announce_round(&torrent, &throttle, &tiers).await.is_err());
+
+        // Straight after, the throttle skips both: nothing was reached, nothing failed
+        let announced = announce_round(&torrent, &throttle, &tiers).await.unwrap();
+        assert!(!announced.reached);
+        assert!(announced.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_tracker_without_peers_still_counts_as_reached() {
+        let (empty, _a) = serve_once(b"d8:completei4e10:incompletei0e5:peers0:e").await;
+        let torrent = torrent_with_tiers(vec![vec![empty]]);
+        let announced = announce_round(
+            &torrent,
+            &AnnounceThrottle::default(),
+            &TrackerTiers::default(),
+        )
+        .await
+        .unwrap();
+        assert!(announced.reached);
+        assert!(announced.peers.is_empty());
+        assert_eq!(
+            announced.swarm,
+            Some(SwarmSize {
+                seeders: 4,
+                leechers: 0
+            })
+        );
+    }
 }
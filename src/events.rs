@@ -0,0 +1,171 @@
+use crate::core::manager::{TorrentEvent, TorrentManager};
+use anyhow::Context;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+/// Version of the `--events` schema, sent as `"v"` in every line. Bumped only when an
+/// existing event or field changes meaning or goes away; new ones don't bump it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Machine-readable event feed (`--events`): one JSON object per line, to a file or
+/// stdout.
+///
+/// Every line has `v` (schema version), `time` (Unix milliseconds), `event` and
+/// `info_hash`, plus fields specific to the event:
+/// - `piece_completed`: `piece`
+/// - `file_completed`: `path`
+/// - `download_complete`
+/// - `peer_connected`: `addr`, `client`
+/// - `peer_disconnected`: `addr`
+/// - `tracker_announced`: `peers` (unique peers returned by all trackers together)
+/// - `error`: `message`
+pub struct EventLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventLog")
+    }
+}
+
+impl EventLog {
+    /// Appends to the file at `target`, or writes to stdout for `-`.
+    pub fn open(target: &str) -> anyhow::Result<Self> {
+        let out: Box<dyn Write + Send> = if target == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(target)
+                    .with_context(|| format!("Failed to open --events file {}", target))?,
+            )
+        };
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Streams every event the manager publishes from now on, until the manager is
+    /// dropped.
+    pub fn follow(self: &Arc<Self>, manager: &TorrentManager) -> anyhow::Result<()> {
+        let info_hash = hex::encode(manager.torrent.info_hash()?);
+        let mut events = manager.subscribe();
+        let log = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let (name, fields) = describe(&event);
+                        log.write(&info_hash, name, fields);
+                    }
+                    Err(RecvError::Lagged(missed)) => log.write(
+                        &info_hash,
+                        "error",
+                        serde_json::json!({
+                            "message": format!("Event stream fell behind; {} events lost", missed)
+                        }),
+                    ),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn write(&self, info_hash: &str, name: &str, fields: serde_json::Value) {
+        let mut line = serde_json::json!({
+            "v": SCHEMA_VERSION,
+            "time": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            "event": name,
+            "info_hash": info_hash,
+        });
+        if let (Some(line), serde_json::Value::Object(fields)) = (line.as_object_mut(), fields) {
+            line.extend(fields);
+        }
+
+        // Flushed per line so anything tailing the file sees events as they happen
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            warn!("Failed to write event: {}", e);
+        }
+    }
+}
+
+/// The `event` name and event-specific fields of a torrent event.
+fn describe(event: &TorrentEvent) -> (&'static str, serde_json::Value) {
+    match event {
+        TorrentEvent::PieceCompleted { index } => {
+            ("piece_completed", serde_json::json!({ "piece": index }))
+        }
+        TorrentEvent::FileCompleted { path } => (
+            "file_completed",
+            serde_json::json!({ "path": path.to_string_lossy() }),
+        ),
+        TorrentEvent::Completed => ("download_complete", serde_json::json!({})),
+        TorrentEvent::PeerConnected { addr, client } => (
+            "peer_connected",
+            serde_json::json!({ "addr": addr, "client": client }),
+        ),
+        TorrentEvent::PeerDisconnected { addr } => {
+            ("peer_disconnected", serde_json::json!({ "addr": addr }))
+        }
+        TorrentEvent::TrackerAnnounced { peers } => {
+            ("tracker_announced", serde_json::json!({ "peers": peers }))
+        }
+        TorrentEvent::DiskError { message } | TorrentEvent::InvalidMetadata { message } => {
+            ("error", serde_json::json!({ "message": message }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::manager_for;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn piece_completion_writes_one_json_line() {
+        let dir = TempDir::new().unwrap();
+        let manager = manager_for(dir.path(), &[1; 20000], 16384);
+        let path = dir.path().join("events.jsonl");
+        let log = Arc::new(EventLog::open(path.to_str().unwrap()).unwrap());
+        log.follow(&manager).unwrap();
+
+        manager.mark_piece_complete(1);
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = std::fs::read_to_string(&path).unwrap();
+            if contents.ends_with('\n') {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", contents);
+        let mut event: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert!(event["time"].as_u64().unwrap() > 0);
+        event.as_object_mut().unwrap().remove("time");
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "v": SCHEMA_VERSION,
+                "event": "piece_completed",
+                "info_hash": hex::encode(manager.torrent.info_hash().unwrap()),
+                "piece": 1,
+            })
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Level used when neither `--log-level` nor `RUST_LOG` is set.
+const DEFAULT_LEVEL: Level = Level::INFO;
+
+/// A minimal `tracing` subscriber that prints events to stdout (or `out`).
+///
+/// Each line carries the level, the chain of entered spans with their fields (e.g.
+/// `peer{addr=1.2.3.4:6881}`) and the event's message. Filtering follows `RUST_LOG`
+/// syntax: a comma separated list of `level` or `target=level` directives, where the
+/// longest matching target prefix wins (e.g. `info,p2p_file_transfer::network=debug`).
+struct Logger {
+    default: Level,
+    /// `(target prefix, level)`, longest prefix first.
+    directives: Vec<(String, Level)>,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+struct SpanData {
+    name: &'static str,
+    fields: String,
+    refs: usize,
+}
+
+thread_local! {
+    /// Spans entered on this thread, innermost last. Tokio enters a task's span around
+    /// every poll, so this is always the stack of the task currently running here.
+    static CURRENT: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Installs the global subscriber.
+///
+/// `level` (from `--log-level`) overrides `RUST_LOG`; both accept the directive syntax
+/// described on `Logger`.
+pub fn init(level: Option<&str>) -> anyhow::Result<()> {
+    let spec = match level {
+        Some(level) => level.to_string(),
+        None => std::env::var("RUST_LOG").unwrap_or_default(),
+    };
+    let logger = Logger::new(&spec, Box::new(std::io::stdout()))?;
+    tracing::subscriber::set_global_default(logger)?;
+    Ok(())
+}
+
+fn parse_level(value: &str) -> anyhow::Result<Level> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid log level '{}'", value))
+}
+
+impl Logger {
+    /// A logger filtering by the directives in `spec` and writing lines to `out`.
+    fn new(spec: &str, out: Box<dyn Write + Send>) -> anyhow::Result<Self> {
+        let mut logger = Logger {
+            default: DEFAULT_LEVEL,
+            directives: Vec::new(),
+            spans: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            out: Mutex::new(out),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => logger
+                    .directives
+                    .push((target.to_string(), parse_level(level)?)),
+                None => logger.default = parse_level(directive)?,
+            }
+        }
+        logger
+            .directives
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(logger)
+    }
+
+    fn level_for(&self, target: &str) -> Level {
+        self.directives
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map_or(self.default, |&(_, level)| level)
+    }
+}
+
+/// Formats fields as ` key=value`, keeping the `message` field separate.
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl Subscriber for Logger {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.enabled(metadata) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let mut writer = FieldWriter::default();
+        attrs.record(&mut writer);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.lock().unwrap().insert(
+            id,
+            SpanData {
+                name: attrs.metadata().name(),
+                fields: writer.fields,
+                refs: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut writer = FieldWriter::default();
+        values.record(&mut writer);
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.fields.push_str(&writer.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut writer = FieldWriter::default();
+        event.record(&mut writer);
+
+        let mut line = format!("{:>5} ", event.metadata().level());
+        {
+            let spans = self.spans.lock().unwrap();
+            CURRENT.with(|stack| {
+                for id in stack.borrow().iter() {
+                    match spans.get(id) {
+                        Some(span) if span.fields.is_empty() => {
+                            let _ = write!(line, "{}: ", span.name);
+                        }
+                        Some(span) => {
+                            let _ = write!(line, "{}{{{}}}: ", span.name, span.fields.trim_start());
+                        }
+                        None => {}
+                    }
+                }
+            });
+        }
+        line.push_str(&writer.message);
+        line.push_str(&writer.fields);
+        let _ = writeln!(self.out.lock().unwrap(), "{}", line);
+    }
+
+    fn enter(&self, span: &Id) {
+        CURRENT.with(|stack| stack.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        CURRENT.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(pos) = stack.iter().rposition(|&id| id == span.into_u64()) {
+                stack.remove(pos);
+            }
+        });
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            data.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let id = id.into_u64();
+        let closed = spans.get_mut(&id).is_some_and(|data| {
+            data.refs -= 1;
+            data.refs == 0
+        });
+        if closed {
+            spans.remove(&id);
+        }
+        closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_util::manager_for;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Collects everything a logger writes.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Completes piece 0 of a two-piece torrent inside a peer span, logging with `spec`,
+    /// and returns the output.
+    fn log_piece_completion(spec: &str) -> String {
+        let dir = TempDir::new().unwrap();
+        let manager = manager_for(dir.path(), &[1; 32768], 16384);
+
+        let capture = Capture::default();
+        let logger = Logger::new(spec, Box::new(capture.clone())).unwrap();
+        tracing::subscriber::with_default(logger, || {
+            let _peer = tracing::info_span!("peer", addr = "10.0.0.1:6881").entered();
+            manager.mark_piece_complete(0);
+        });
+        String::from_utf8(capture.0.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn piece_completion_is_logged_at_info_within_the_peer_span() {
+        assert_eq!(
+            log_piece_completion(""),
+            " INFO peer{addr=10.0.0.1:6881}: Piece finished. Progress: 1/2 piece=0\n"
+        );
+    }
+
+    #[test]
+    fn directives_filter_by_level_and_target() {
+        assert_eq!(log_piece_completion("warn"), "");
+        assert_eq!(
+            log_piece_completion("info,p2p_file_transfer::core::manager=warn"),
+            ""
+        );
+        assert!(!log_piece_completion("error,p2p_file_transfer::core=debug").is_empty());
+    }
+}
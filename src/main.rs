@@ -1,13 +1,35 @@
+mod api;
 mod core;
+mod events;
+mod logging;
 mod network;
 mod utils;
 
-use crate::core::manager::TorrentManager;
+use crate::api::Api;
+use crate::core::creator::CreateOptions;
+use crate::core::dht::Dht;
+use crate::core::lsd::Lsd;
+use crate::core::manager::{
+    FilePriority, Md5Check, PickStrategy, SeedLimits, SeedWatch, TorrentEvent, TorrentManager,
+};
+use crate::core::quota::Quota;
+use crate::core::session::{Session, SessionOptions};
+use crate::core::storage::{AllocationPolicy, ExistingFilePolicy, StorageOptions, SyncPolicy};
+use crate::core::tracker::{AnnounceEvent, AnnounceParams, MAX_NUMWANT};
+use crate::events::EventLog;
+use crate::network::blocklist::Blocklist;
+use crate::network::mse::EncryptionMode;
+use crate::network::port_mapping::PortMapping;
+use crate::network::schedule::BandwidthSchedule;
+use crate::network::socks5::ProxyConfig;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::net::{IpAddr, Ipv4Addr};
 use std::process;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, sleep};
+use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -15,28 +37,238 @@ async fn main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         eprintln!("Usage:");
-        eprintln!("  Create:   cargo run -- create <input_path> <output_torrent_path>");
-        eprintln!("  Download: cargo run -- download <file.torrent>");
-        eprintln!("  Seed:     cargo run -- seed <file.torrent>");
+        eprintln!("  Create:   cargo run -- create <input_path> <output_torrent_path> [options]");
+        eprintln!("  Download: cargo run -- download <file.torrent> [options]");
+        eprintln!("  Seed:     cargo run -- seed <file.torrent> [options]");
+        eprintln!("  Scrape:   cargo run -- scrape <file.torrent>");
+        eprintln!("  Verify:   cargo run -- verify <file.torrent> [--md5]");
+        eprintln!("  Info:     cargo run -- info <file.torrent> [--json]");
+        eprintln!("  Edit:     cargo run -- edit <file.torrent> [tracker options]");
+        eprintln!();
+        eprintln!("Options:");
+        eprintln!("  --max-open-files <n>   Cap on cached open file handles (default 64)");
+        eprintln!("  --sync-policy <p>      per-piece | periodic | on-complete (default periodic)");
+        eprintln!("  --alloc <policy>       sparse | full | prealloc (default full)");
+        eprintln!(
+            "  --if-exists <policy>   skip | verify | overwrite existing files (default verify)"
+        );
+        eprintln!("  --complete-dir <path>  Move finished torrents here, and seed from there");
+        eprintln!("  --select <list>        Only download these files (1-based), e.g. \"1,3-5\"");
+        eprintln!(
+            "  --pieces <list>        Only download these pieces (0-based), e.g. \"100-200\""
+        );
+        eprintln!("  --sequential           Download pieces strictly in order (for streaming)");
+        eprintln!(
+            "  --first-last           With --sequential, fetch each file's first/last piece first"
+        );
+        eprintln!(
+            "  --max-pending-pieces <n> Pieces downloading at once per torrent (default no cap)"
+        );
+        eprintln!(
+            "  --max-pieces-per-peer <n> Pieces downloading at once per peer (default no cap)"
+        );
+        eprintln!("  --port <n>             Port to accept peers on (default 8888)");
+        eprintln!(
+            "  --max-connections <n>  Cap on peer connections across all torrents (default 50)"
+        );
+        eprintln!("  --upload-limit <kib>   Upload cap in KiB/s (default unlimited)");
+        eprintln!("  --download-limit <kib> Download cap in KiB/s (default unlimited)");
+        eprintln!("  --download-quota <b>   Stop downloading after this many bytes, across runs");
+        eprintln!("  --upload-quota <b>     Stop uploading after this many bytes, across runs");
+        eprintln!(
+            "  --upload-slots <n>     Peers unchoked at once, all torrents (default unlimited)"
+        );
+        eprintln!(
+            "  --schedule <windows>   Time-of-day caps, e.g. \"22:00-08:00:unlimited,08:00-22:00:500k\""
+        );
+        eprintln!("  --port-forward         Map the listen port on the router (UPnP/NAT-PMP)");
+        eprintln!("  --dht                  Also find peers through the mainline DHT");
+        eprintln!("  --lsd                  Find peers on the LAN via multicast (BEP 14)");
+        eprintln!("  --peer-id-prefix <p>   First 8 bytes of our peer ID (default -RT0100-)");
+        eprintln!("  --bind <ip>            Local address for peer, tracker and DHT traffic");
+        eprintln!("  --user-agent <s>       User-Agent for HTTP trackers (default RT/<version>)");
+        eprintln!("  --connect-timeout <d>  Fixed peer connect timeout (default adapts, 1-10s)");
+        eprintln!("  --read-timeout <dur>   Drop peers silent this long (default 30s)");
+        eprintln!("  --max-pieces <n>       Refuse torrents with more pieces (default 10000000)");
+        eprintln!("  --max-files <n>        Refuse torrents with more files (default 1000000)");
+        eprintln!("  --max-size <gib>       Refuse torrents larger than this (default 16384 GiB)");
+        eprintln!("  --block-size <bytes>   Size of each block request (default 16384)");
+        eprintln!(
+            "  --pipeline <n>         Fixed block requests in flight per peer (default: adaptive)"
+        );
+        eprintln!(
+            "  --numwant <n>          Peers to ask each tracker for (default: tracker's choice)"
+        );
+        eprintln!("  --blocklist <file>     Never connect to IPs listed (P2P or CIDR format)");
+        eprintln!("  --block-client <name>  Refuse peers of this client, e.g. Xunlei; repeatable");
+        eprintln!("  --info-hash <hex>      Refuse to start unless the torrent has this info hash");
+        eprintln!("  --seed-ratio <f>       (seed) Stop once uploaded = f x torrent size");
+        eprintln!("  --seed-time <dur>      (seed) Stop after seeding this long, e.g. 90m, 2h, 1d");
+        eprintln!(
+            "  --super-seed           (seed) Reveal pieces one at a time to spread them faster"
+        );
+        eprintln!("  --verify-on-seed       Re-hash pieces from disk before uploading them");
+        eprintln!("  --api-addr <addr>      Serve the HTTP control API, e.g. 127.0.0.1:8080");
+        eprintln!("  --events <path|->      Write JSON Lines events to a file (or - for stdout)");
+        eprintln!("  --proxy <url>          Route peers and HTTP trackers via socks5://host:port");
+        eprintln!("  --encryption <mode>    prefer | require | disable (default prefer)");
+        eprintln!("  --exclude <glob>       (create) Skip matching files; repeatable");
+        eprintln!("  --include-hidden       (create) Include dotfiles and dot-directories");
+        eprintln!(
+            "  --name <name>          (create) Torrent name; required when input is - (stdin)"
+        );
+        eprintln!("  --dry-run              (create) List files and piece count without hashing");
+        eprintln!("  --tracker <url>        (create) Tracker to announce to; repeatable");
+        eprintln!("  --md5                  (create) Record each file's MD5; (verify) check them");
+        eprintln!("  --mtime                (create) Record file modification times to restore");
+        eprintln!("  --add-tracker <url>    (edit) Append a tracker tier; repeatable");
+        eprintln!("  --remove-tracker <url> (edit) Remove a tracker; repeatable");
+        eprintln!("  --replace-tracker <old> <new>  (edit) Swap one tracker URL for another");
+        eprintln!("  --log-level <filter>   trace | debug | info | warn | error (default info,");
+        eprintln!(
+            "                         or RUST_LOG); e.g. \"info,p2p_file_transfer::network=debug\""
+        );
         process::exit(1);
     }
 
+    // Diagnostics go through `tracing`; command output (info, verify, ...) stays on stdout
+    logging::init(flag_value(&args, "--log-level"))?;
+
     let command = &args[1];
 
     // --- MODE 1: CREATE TORRENT ---
     if command == "create" {
         if args.len() < 4 {
-            eprintln!("Usage: cargo run -- create <input_path> <output_torrent_path>");
+            eprintln!("Usage: cargo run -- create <input_path> <output_torrent_path> [options]");
+            process::exit(1);
+        }
+        create_torrent(&args[2], &args[3], &args)?;
+        return Ok(());
+    }
+
+    // --- SCRAPE: QUERY SWARM HEALTH ---
+    if command == "scrape" {
+        if args.len() < 3 {
+            eprintln!("Usage: cargo run -- scrape <file.torrent>");
+            process::exit(1);
+        }
+        let torrent = core::torrent_info::Torrent::read(&args[2])?;
+        let info_hash = torrent.info_hash()?;
+        let http_client =
+            core::tracker::http_client(None, core::tracker::DEFAULT_USER_AGENT, None)?;
+
+        for url in torrent.get_tracker_urls() {
+            match core::tracker::Response::scrape(&url, &[info_hash], &http_client).await {
+                Ok(stats) => match stats.get(&info_hash) {
+                    Some(s) => println!(
+                        "{}: {} seeders, {} leechers, {} completed",
+                        url, s.seeders, s.leechers, s.completed
+                    ),
+                    None => println!("{}: torrent not known to tracker", url),
+                },
+                Err(e) => println!("{}: scrape failed ({})", url, e),
+            }
+        }
+        return Ok(());
+    }
+
+    // --- INFO: DUMP TORRENT METADATA ---
+    if command == "info" {
+        if args.len() < 3 {
+            eprintln!("Usage: cargo run -- info <file.torrent> [--json]");
+            process::exit(1);
+        }
+        let torrent = core::torrent_info::Torrent::read(&args[2])?;
+        let summary = torrent.summary()?;
+
+        if args.iter().any(|a| a == "--json") {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        } else {
+            println!("---------------------------------");
+            println!("Name:         {}", summary.name);
+            println!("Info Hash:    {}", summary.info_hash);
+            println!("Total Size:   {} bytes", summary.total_length);
+            println!("Piece Length: {} bytes", summary.piece_length);
+            println!("Pieces:       {}", summary.piece_count);
+            println!("Trackers:");
+            for url in &summary.trackers {
+                println!("  {}", url);
+            }
+            println!("Files:");
+            for file in &summary.files {
+                println!("  {:>14}  {}", file.length, file.path);
+            }
+            println!("---------------------------------");
+        }
+        return Ok(());
+    }
+
+    // --- EDIT: CHANGE TRACKERS WITHOUT REHASHING ---
+    if command == "edit" {
+        if args.len() < 3 {
+            eprintln!(
+                "Usage: cargo run -- edit <file.torrent> [--add-tracker <url>]... \
+                 [--remove-tracker <url>]... [--replace-tracker <old> <new>]..."
+            );
+            process::exit(1);
+        }
+        let path = &args[2];
+        let (edited, info_hash) = edit_trackers(path, &args)?;
+
+        println!("Info Hash: {} (unchanged)", hex::encode(info_hash));
+        println!("Announce:  {}", edited.announce);
+        for (tier, urls) in edited.announce_list.iter().flatten().enumerate() {
+            println!("Tier {}:    {}", tier + 1, urls.join(", "));
+        }
+        return Ok(());
+    }
+
+    // --- VERIFY: FULL RECHECK OF DATA ON DISK ---
+    if command == "verify" {
+        if args.len() < 3 {
+            eprintln!("Usage: cargo run -- verify <file.torrent> [--md5] [--complete-dir <dir>]");
             process::exit(1);
         }
-        let input_path = &args[2];
-        let output_path = &args[3];
+        let torrent = core::torrent_info::Torrent::read(&args[2])?;
+        // Same layout as download/seed, so a finished torrent under --complete-dir is found
+        let manager = TorrentManager::new(torrent, &parse_storage_options(&args)?);
 
-        // Use a reliable public UDP tracker by default
-        let tracker = "udp://tracker.opentrackr.org:1337";
+        println!("Verifying {} pieces...", manager.piece_count());
+        let report = manager.verify_pieces();
+        println!("---------------------------------");
+        println!("Complete:   {}", report.complete);
+        println!("Corrupt:    {}", report.corrupt);
+        println!("Missing:    {}", report.missing);
+        println!("---------------------------------");
 
-        // Generate the .torrent file
-        core::creator::create_torrent_file(input_path, tracker, output_path)?;
+        // Optional extra check against the per-file MD5s some tools record
+        let mut md5_failed = false;
+        if args.iter().any(|a| a == "--md5") {
+            let checks = manager.verify_md5sums();
+            if checks.is_empty() {
+                println!("No MD5 sums recorded in the torrent.");
+            }
+            for (path, check) in &checks {
+                match check {
+                    Md5Check::Match => {}
+                    Md5Check::Mismatch => println!("MD5 mismatch: {}", path.display()),
+                    Md5Check::Missing => {
+                        println!("MD5 not checked (file missing): {}", path.display())
+                    }
+                }
+            }
+            let matched = checks.iter().filter(|(_, c)| *c == Md5Check::Match).count();
+            if !checks.is_empty() {
+                println!("MD5:        {}/{} files match", matched, checks.len());
+            }
+            md5_failed = matched < checks.len();
+        }
+
+        if !report.is_valid() || md5_failed {
+            println!("Verification FAILED.");
+            process::exit(1);
+        }
+        println!("All pieces valid.");
         return Ok(());
     }
 
@@ -50,85 +282,1025 @@ async fn main() -> anyhow::Result<()> {
         let torrent_path = &args[2];
         let is_seeding_mode = command == "seed";
 
-        // 2. Load Metadata
-        println!("Loading torrent file: {}", torrent_path);
-        let torrent = core::torrent_info::Torrent::read(torrent_path)?;
-        let info_hash = torrent.calculate_info_hash()?;
-        let peer_id = utils::generate_peer_id();
+        // 2. Session Options
+        let mut storage_options = parse_storage_options(&args)?;
+        // A --pieces run ends with the torrent still incomplete, so nothing is moved
+        if flag_value(&args, "--pieces").is_some() && storage_options.complete_dir.take().is_some()
+        {
+            warn!("--complete-dir is ignored with --pieces");
+        }
+        let mut options = SessionOptions {
+            storage: storage_options,
+            ..SessionOptions::default()
+        };
+        if args.iter().any(|a| a == "--sequential") {
+            options.strategy = PickStrategy::Sequential {
+                first_last: args.iter().any(|a| a == "--first-last"),
+            };
+        }
+        if let Some(value) = flag_value(&args, "--max-pending-pieces") {
+            options.max_pending_pieces = Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n > 0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Invalid --max-pending-pieces value: {}", value)
+                    })?,
+            );
+        }
+        if let Some(value) = flag_value(&args, "--max-pieces-per-peer") {
+            options.max_pieces_per_peer = Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n > 0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Invalid --max-pieces-per-peer value: {}", value)
+                    })?,
+            );
+        }
+        if let Some(value) = flag_value(&args, "--port") {
+            options.listen_port = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid --port value: {}", value))?;
+        }
+        if let Some(value) =
+            flag_value(&args, "--max-connections").or_else(|| flag_value(&args, "--max-peers"))
+        {
+            options.max_peers = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid --max-connections value: {}", value))?;
+        }
+        if let Some(value) = flag_value(&args, "--upload-limit") {
+            options.upload_limit = parse_rate(value, "--upload-limit")?;
+        }
+        if let Some(value) = flag_value(&args, "--download-limit") {
+            options.download_limit = parse_rate(value, "--download-limit")?;
+        }
+        if let Some(value) = flag_value(&args, "--upload-slots") {
+            options.upload_slots = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid --upload-slots value: {}", value))?;
+        }
+        options.super_seed = args.iter().any(|a| a == "--super-seed");
+        options.verify_on_seed = args.iter().any(|a| a == "--verify-on-seed");
+        if let Some(value) = flag_value(&args, "--peer-id-prefix") {
+            options.peer_id_prefix = utils::parse_peer_id_prefix(value)?;
+        }
+        if let Some(value) = flag_value(&args, "--bind") {
+            options.bind = Some(
+                value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid --bind address: {}", value))?,
+            );
+        }
+        if let Some(value) = flag_value(&args, "--user-agent") {
+            options.user_agent = value.to_string();
+        }
+        if let Some(value) = flag_value(&args, "--connect-timeout") {
+            options.connect_timeout = Some(parse_duration(value, "--connect-timeout")?)
+                .filter(|timeout| !timeout.is_zero());
+        }
+        if let Some(value) = flag_value(&args, "--max-pieces") {
+            options.metadata_limits.max_pieces = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid --max-pieces value: {}", value))?;
+        }
+        if let Some(value) = flag_value(&args, "--max-files") {
+            options.metadata_limits.max_files = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid --max-files value: {}", value))?;
+        }
+        if let Some(value) = flag_value(&args, "--max-size") {
+            let gib: u64 = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid --max-size value: {}", value))?;
+            options.metadata_limits.max_total_length = gib.saturating_mul(1 << 30);
+        }
+        if let Some(value) = flag_value(&args, "--read-timeout") {
+            options.read_timeout = parse_duration(value, "--read-timeout")?;
+            if options.read_timeout.is_zero() {
+                anyhow::bail!("--read-timeout must be positive");
+            }
+        }
+        options.blocked_clients = flag_values(&args, "--block-client")
+            .into_iter()
+            .map(str::to_lowercase)
+            .collect();
+        if let Some(target) = flag_value(&args, "--events") {
+            options.event_log = Some(Arc::new(EventLog::open(target)?));
+        }
+        let parse_quota = |flag: &str| {
+            flag_value(&args, flag)
+                .map(|value| {
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| anyhow::anyhow!("Invalid {} value: {}", flag, value))
+                })
+                .transpose()
+        };
+        options.quota = Arc::new(Quota::load(
+            &options.storage.output_dir,
+            parse_quota("--download-quota")?,
+            parse_quota("--upload-quota")?,
+        )?);
+        if let Some(value) = flag_value(&args, "--encryption") {
+            options.encryption = EncryptionMode::parse(value)?;
+        }
+        if let Some(value) = flag_value(&args, "--proxy") {
+            options.proxy = Some(ProxyConfig::parse(value)?);
+        }
+        if let Some(value) = flag_value(&args, "--block-size") {
+            options.block_size = value
+                .parse()
+                .ok()
+                .filter(|size| (1..=network::MAX_BLOCK_SIZE).contains(size))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid --block-size value: {} (expected 1-{} bytes)",
+                        value,
+                        network::MAX_BLOCK_SIZE
+                    )
+                })?;
+        }
+        if let Some(value) = flag_value(&args, "--pipeline") {
+            options.pipeline_depth = Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&depth| depth > 0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid --pipeline value: {}", value))?,
+            );
+        }
+        if let Some(path) = flag_value(&args, "--blocklist") {
+            let blocklist = Blocklist::load(path)?;
+            info!("Blocklist: {} ranges loaded", blocklist.range_count());
+            options.blocklist = Arc::new(blocklist);
+        }
+        let schedule = flag_value(&args, "--schedule")
+            .map(BandwidthSchedule::parse)
+            .transpose()?;
+        let session = Arc::new(Session::new(options));
+        if let Some(schedule) = schedule {
+            schedule.spawn(session.clone());
+        }
+
+        let numwant = match flag_value(&args, "--numwant") {
+            Some(value) => Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=MAX_NUMWANT).contains(n))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Invalid --numwant value: {} (expected 1-{})",
+                            value,
+                            MAX_NUMWANT
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+
+        let mut seed_limits = SeedLimits::default();
+        if let Some(value) = flag_value(&args, "--seed-ratio") {
+            seed_limits.ratio = Some(
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|ratio| ratio.is_finite() && *ratio > 0.0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid --seed-ratio value: {}", value))?,
+            );
+        }
+        if let Some(value) = flag_value(&args, "--seed-time") {
+            seed_limits.time = Some(parse_duration(value, "--seed-time")?);
+        }
+
+        // The check must come before `add_torrent`, which already creates files on disk
+        if let Some(value) = flag_value(&args, "--info-hash") {
+            let expected: [u8; 20] = hex::decode(value)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid --info-hash value: {} (expected 40 hex characters)",
+                        value
+                    )
+                })?;
+            core::torrent_info::Torrent::read_with_limits(
+                torrent_path,
+                &session.options.metadata_limits,
+            )?
+            .verify_info_hash(&expected)?;
+        }
+
+        // 3. Load Metadata & Initialize Manager
+        // Note: Verification runs immediately to pre-allocate files and check resume state.
+        info!("Loading torrent file: {}", torrent_path);
+        let handle = session.add_torrent(torrent_path)?;
+        let manager = handle.manager.clone();
+        let torrent = &manager.torrent;
 
         println!("---------------------------------");
-        println!("File:       {}", torrent.info.name);
-        println!("Info Hash:  {}", hex::encode(&info_hash));
+        println!("File:       {}", torrent.info().name);
+        println!("Info Hash:  {}", hex::encode(&handle.info_hash));
         if is_seeding_mode {
             println!("Mode:       SEEDING (Upload Only)");
         }
         println!("---------------------------------");
 
-        // 3. Initialize Manager
-        // Note: Verification runs immediately to pre-allocate files and check resume state.
-        let mut temp_manager = TorrentManager::new(torrent.clone());
-        temp_manager.verify_existing_data();
-        let manager = Arc::new(Mutex::new(temp_manager));
+        // Selective download: unselected files are skipped (boundary pieces still download)
+        if let Some(spec) = flag_value(&args, "--select") {
+            let selected = utils::parse_index_list(spec)?;
+            let file_count = manager.storage.files.len();
+            if let Some(bad) = selected.iter().find(|&&i| i == 0 || i > file_count) {
+                anyhow::bail!("--select index {} is out of range (1-{})", bad, file_count);
+            }
+            let priorities: Vec<FilePriority> = (1..=file_count)
+                .map(|i| {
+                    if selected.contains(&i) {
+                        FilePriority::Normal
+                    } else {
+                        FilePriority::Skip
+                    }
+                })
+                .collect();
+            manager.set_file_priorities(&priorities)?;
+        }
+        // Partial fetch: pieces outside the range are skipped like unselected files
+        if let Some(spec) = flag_value(&args, "--pieces") {
+            let pieces = utils::parse_index_list(spec)?;
+            let piece_count = manager.piece_count();
+            if let Some(bad) = pieces.iter().find(|&&i| i >= piece_count) {
+                anyhow::bail!(
+                    "--pieces index {} is out of range (the torrent has {} pieces)",
+                    bad,
+                    piece_count
+                );
+            }
+            manager.restrict_to_pieces(&pieces);
+            info!("Downloading {} pieces only", pieces.len());
+        }
+
+        // Wakes the supervision loop as soon as the last piece lands, instead of at the next poll
+        let mut events = manager.subscribe();
+
+        // Inbound peers (for this and any other torrent in the session)
+        let listener = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = listener.listen().await {
+                warn!("Inbound connections disabled: {:#}", e);
+            }
+        });
+
+        // Optional HTTP control API (status, pause/resume, adding torrents)
+        if let Some(addr) = flag_value(&args, "--api-addr") {
+            let api = Arc::new(Api::new(session.clone()));
+            let addr = addr.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = api.serve(&addr).await {
+                    warn!("HTTP API disabled: {:#}", e);
+                }
+            });
+        }
+
+        // Shared HTTP client for tracker announces (connection pool reused across intervals)
+        let user_agent = &session.options.user_agent;
+        let http_client = core::tracker::http_client(
+            session.options.proxy.as_ref(),
+            user_agent,
+            session.options.bind,
+        )?;
+        // The router is on the LAN, so port mapping never goes through the proxy
+        let lan_client = core::tracker::http_client(None, user_agent, None)?;
+
+        // Optionally ask the router to forward our listen port so peers can reach us
+        let mut port_mapping = None;
+        if args.iter().any(|a| a == "--port-forward") {
+            match PortMapping::create(session.options.listen_port, &lan_client).await {
+                Ok(mapping) => {
+                    match mapping.external_ip {
+                        Some(ip) => info!("Port forwarded: {}:{}", ip, mapping.external_port),
+                        None => info!("Port forwarded: external port {}", mapping.external_port),
+                    }
+                    port_mapping = Some(mapping);
+                }
+                Err(e) => warn!("Port forwarding unavailable: {:#}", e),
+            }
+        }
+        // Trackers must hand out the port that is reachable from outside
+        let announce_port = port_mapping
+            .as_ref()
+            .map_or(session.options.listen_port, |m| m.external_port);
+
+        // Optional DHT node, sharing the listen port number over UDP
+        let mut dht = None;
+        if args.iter().any(|a| a == "--dht") {
+            if session.options.proxy.is_some() {
+                warn!("DHT disabled: its UDP traffic can't go through the proxy");
+            } else {
+                // The DHT only speaks IPv4
+                let address = match session.options.bind {
+                    None => Some(Ipv4Addr::UNSPECIFIED),
+                    Some(IpAddr::V4(address)) => Some(address),
+                    Some(IpAddr::V6(_)) => None,
+                };
+                match address {
+                    Some(address) => match Dht::bind(address, session.options.listen_port).await {
+                        Ok(node) => dht = Some(node),
+                        Err(e) => warn!("DHT disabled: {:#}", e),
+                    },
+                    None => warn!("DHT disabled: it needs an IPv4 --bind address"),
+                }
+            }
+        }
+
+        // Optional Local Service Discovery: LAN peers connect without any tracker
+        if args.iter().any(|a| a == "--lsd") {
+            if session.options.proxy.is_some() {
+                warn!("LSD disabled: its multicast traffic can't go through the proxy");
+            } else if let Err(e) = Lsd::start(session.clone()) {
+                warn!("LSD disabled: {:#}", e);
+            }
+        }
+
+        let announce_params = |event, manager: &TorrentManager| AnnounceParams {
+            port: announce_port,
+            // A SOCKS5 TCP tunnel can't carry UDP tracker traffic
+            use_udp: session.options.proxy.is_none(),
+            event,
+            numwant,
+            key: session.announce_key,
+            bind: session.options.bind,
+            uploaded: manager.uploaded(),
+            downloaded: manager.downloaded(),
+            left: manager.bytes_left(),
+        };
+
+        // Keeps re-announces to each tracker its `interval` apart, across restarts too
+        let throttle = core::tracker::AnnounceThrottle::load(&session.options.storage.output_dir);
+        // Tracker order per tier, with the last tracker that worked first
+        let tiers = core::tracker::TrackerTiers::default();
+        // Transfer totals at the previous status line, for the rates it shows
+        let mut rate_sample = (Instant::now(), 0u64, 0u64);
+        // Announce bookkeeping per info hash: the first announce after starting (or
+        // resuming) carries `started`, and a paused torrent gets one `stopped` announce
+        let mut announced = HashSet::new();
+        let mut stopped = HashSet::new();
+        // When each torrent is next looked up in the DHT; far less often than the
+        // tracker rounds, since a lookup queries dozens of nodes
+        let mut dht_due: HashMap<[u8; 20], Instant> = HashMap::new();
+        // Seeding time and limits of the main torrent (--seed-ratio, --seed-time)
+        let mut seed_watch = SeedWatch::new(seed_limits);
 
         // 4. Supervision Loop
         // This loop manages the high-level state: contacting trackers and checking completion.
         loop {
+            // Quota usage is saved every round, so a crash loses at most one round's worth
+            if let Err(e) = session.options.quota.save() {
+                warn!("Failed to save quota usage: {:#}", e);
+            }
+
             // A. Check Download Status
+            let mut seed_limit_reached = false;
             {
-                let m = manager.lock().await;
+                let m = &manager;
+                let stats = m.stats()?;
+                let (sampled_at, downloaded, uploaded) = rate_sample;
+                let secs = sampled_at.elapsed().as_secs_f64().max(0.001);
+                let download_rate =
+                    (stats.downloaded.saturating_sub(downloaded) as f64 / secs) as u64;
+                let upload_rate = (stats.uploaded.saturating_sub(uploaded) as f64 / secs) as u64;
+                rate_sample = (Instant::now(), stats.downloaded, stats.uploaded);
+
                 if m.is_complete() {
                     if !is_seeding_mode {
-                        println!("DOWNLOAD COMPLETE!");
+                        info!("DOWNLOAD COMPLETE!");
 
-                        // Safety: Wait for background threads to finish `file.sync_all()`
-                        drop(m);
-                        sleep(Duration::from_secs(2)).await;
+                        // Safety: Make sure every written piece is durable before exiting
+                        m.flush()?;
 
-                        println!("Exiting.");
+                        info!("Exiting.");
                         break;
                     } else {
-                        // In Seed mode, we continue running to serve requests
-                        println!("Seeding... (Status: 100% complete)");
+                        // In Seed mode, we continue running to serve requests until a
+                        // seed limit (if any) is reached
+                        info!(
+                            "Seeding... (Status: 100% complete, up {}/s, {} peers{})",
+                            utils::format_bytes(upload_rate),
+                            stats.peers,
+                            describe_swarm(stats.swarm)
+                        );
+                        if let Some(reason) =
+                            seed_watch.check(m.uploaded(), torrent.total_length() as u64)
+                        {
+                            info!("{}. Stopping.", reason);
+                            seed_limit_reached = true;
+                        }
                     }
                 } else {
-                    println!(
-                        "Status: {}/{} pieces. Refreshing peers...",
-                        m.downloaded_pieces,
-                        m.piece_status.len()
+                    let eta = match utils::eta(m.bytes_left(), download_rate) {
+                        Some(eta) => utils::format_duration(eta),
+                        None => "unknown".to_string(),
+                    };
+                    info!(
+                        "Status: {:.1}% ({}/{} pieces), down {}/s, up {}/s, {} peers{}, ETA {}. Refreshing peers...",
+                        stats.pieces_complete as f64 * 100.0 / stats.piece_count.max(1) as f64,
+                        stats.pieces_complete,
+                        stats.piece_count,
+                        utils::format_bytes(download_rate),
+                        utils::format_bytes(upload_rate),
+                        stats.peers,
+                        describe_swarm(stats.swarm),
+                        eta
                     );
                 }
+
+                // Periodic sync policy: flush even when pieces trickle in slowly
+                if let Err(e) = m.storage.sync_if_due() {
+                    warn!("Disk sync failed: {}", e);
+                }
+            }
+
+            if seed_limit_reached {
+                // Leave the swarm properly so the tracker stops handing us out
+                let _ = core::tracker::Response::request_peers(
+                    torrent,
+                    &session.peer_id,
+                    announce_params(AnnounceEvent::Stopped, &manager),
+                    &http_client,
+                    &throttle,
+                    &tiers,
+                )
+                .await;
+                break;
             }
 
             // B. Contact Tracker (Scatter-Gather)
-            println!("Contacting Tracker...");
-            match core::tracker::Response::request_peers(&torrent, &peer_id).await {
-                Ok(peers) => {
-                    println!("Found {} peers. Spawning workers...", peers.len());
-
-                    // C. Spawn Peer Workers
-                    // Limit concurrency to avoid file handle exhaustion
-                    for peer in peers.into_iter().take(20) {
-                        let m_clone = manager.clone();
-                        let p_clone = peer_id;
-                        let h_clone = info_hash;
-
-                        tokio::spawn(async move {
-                            // Each session handles the handshake, download, and upload logic independently
-                            let _ =
-                                network::run_peer_session(peer, h_clone, p_clone, m_clone).await;
-                        });
+            // Every torrent in the session is announced, including ones added over the API.
+            for handle in session.torrents() {
+                let name = &handle.manager.torrent.info().name;
+
+                if handle.manager.is_paused() {
+                    if stopped.insert(handle.info_hash) {
+                        info!("{} paused. Sending Stopped announce...", name);
+                        announced.remove(&handle.info_hash);
+                        // No peers are expected back, so failures don't matter here
+                        let _ = core::tracker::Response::request_peers(
+                            &handle.manager.torrent,
+                            &session.peer_id,
+                            announce_params(AnnounceEvent::Stopped, &handle.manager),
+                            &http_client,
+                            &throttle,
+                            &tiers,
+                        )
+                        .await;
+                    }
+                    continue;
+                }
+                stopped.remove(&handle.info_hash);
+                let event = if announced.insert(handle.info_hash) {
+                    AnnounceEvent::Started
+                } else {
+                    AnnounceEvent::None
+                };
+
+                info!("Contacting Tracker for {}...", name);
+                let mut peers = match core::tracker::Response::request_peers(
+                    &handle.manager.torrent,
+                    &session.peer_id,
+                    announce_params(event, &handle.manager),
+                    &http_client,
+                    &throttle,
+                    &tiers,
+                )
+                .await
+                {
+                    Ok(announced) => {
+                        handle.manager.set_swarm(announced.swarm);
+                        // Not reached when every tracker was skipped by the throttle
+                        if announced.reached {
+                            handle.manager.publish(TorrentEvent::TrackerAnnounced {
+                                peers: announced.peers.len(),
+                            });
+                        }
+                        announced.peers
+                    }
+                    Err(e) => {
+                        warn!("Tracker failed: {}. Retrying in 5s...", e);
+                        Vec::new()
                     }
+                };
+                if let Some(dht) = &dht
+                    && dht_due
+                        .get(&handle.info_hash)
+                        .is_none_or(|&due| Instant::now() >= due)
+                {
+                    dht_due.insert(
+                        handle.info_hash,
+                        Instant::now() + core::dht::LOOKUP_INTERVAL,
+                    );
+                    let found = dht
+                        .get_peers(&handle.info_hash, &handle.manager.torrent.dht_nodes())
+                        .await;
+                    info!("DHT: found {} peers", found.len());
+                    peers.extend(found);
+                }
+                if peers.is_empty() {
+                    continue;
                 }
-                Err(e) => println!("Tracker failed: {}. Retrying in 5s...", e),
+
+                // Trackers and the DHT often hand out the same peers, not always
+                // written the same way
+                let mut peers = core::tracker::unique_peers(&peers);
+
+                let dropped = session.drop_blocked_peers(&mut peers);
+                if dropped > 0 {
+                    info!("Blocklist: dropped {} peers", dropped);
+                }
+                info!("Found {} peers. Spawning workers...", peers.len());
+
+                // C. Spawn Peer Workers
+                // Each session handles the handshake, download, and upload logic
+                // independently. The session-wide peer cap limits concurrency (and
+                // file handle use).
+                for peer in peers {
+                    if !session.connect(&handle, peer) {
+                        break;
+                    }
+                }
+            }
+            if let Err(e) = throttle.save() {
+                warn!("Failed to save announce times: {:#}", e);
             }
 
             // D. Wait Interval
             // Standard re-announce interval (or shorter for aggressive discovery)
-            sleep(Duration::from_secs(10)).await;
+            let fatal_error = tokio::select! {
+                _ = sleep(Duration::from_secs(10)) => None,
+                // A torrent was paused or resumed: tell its trackers right away
+                _ = session.announce_requested() => None,
+                failure = next_wakeup(&mut events) => failure,
+                _ = tokio::signal::ctrl_c() => {
+                    // Shutdown: flushed below, once the loop is left
+                    info!("Shutting down. Flushing data to disk...");
+                    break;
+                }
+            };
+
+            // Retrying would fail the same way (and re-download the piece forever)
+            if let Some(message) = fatal_error {
+                return stop_download(&session, port_mapping.take(), &lan_client, message).await;
+            }
         }
+
+        shut_down(&session, port_mapping, &lan_client).await;
     } else {
         eprintln!("Unknown command: {}", command);
     }
 
     Ok(())
 }
+
+/// Waits for a torrent event that wakes the supervision loop before its next round.
+///
+/// Per-file events don't need a re-announce; only completion (`None`) and disk
+/// failures or broken metadata (`Some` with the message) end the wait.
+async fn next_wakeup(events: &mut broadcast::Receiver<TorrentEvent>) -> Option<String> {
+    loop {
+        match events.recv().await {
+            Ok(TorrentEvent::Completed) | Err(_) => return None,
+            Ok(TorrentEvent::DiskError { message } | TorrentEvent::InvalidMetadata { message }) => {
+                return Some(message);
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Ends the download after a failure retrying can't fix, shutting the session down
+/// like a normal exit so nothing the next run resumes from is lost.
+async fn stop_download(
+    session: &Session,
+    port_mapping: Option<PortMapping>,
+    lan_client: &reqwest::Client,
+    message: String,
+) -> anyhow::Result<()> {
+    shut_down(session, port_mapping, lan_client).await;
+    anyhow::bail!("Download stopped: {}", message)
+}
+
+/// Removes the port mapping, closes peer connections and flushes anything the sync
+/// policy has been holding back, then saves partly downloaded pieces and quota usage
+/// for the next run.
+async fn shut_down(
+    session: &Session,
+    port_mapping: Option<PortMapping>,
+    lan_client: &reqwest::Client,
+) {
+    if let Some(mapping) = port_mapping
+        && let Err(e) = mapping.remove(lan_client).await
+    {
+        warn!("Failed to remove port mapping: {}", e);
+    }
+
+    let torrents = session.torrents();
+    for handle in &torrents {
+        session.remove_torrent(&handle.info_hash);
+    }
+    session.shutdown();
+    // Sessions get a moment to hand back their pieces before these are saved
+    for handle in torrents {
+        handle.manager.save_partial_pieces().await;
+    }
+    if let Err(e) = session.options.quota.save() {
+        warn!("Failed to save quota usage: {:#}", e);
+    }
+}
+
+/// ` (swarm: S seeders, L leechers)` for the status line, or nothing if no tracker
+/// reported the swarm size.
+fn describe_swarm(swarm: Option<core::tracker::SwarmSize>) -> String {
+    swarm.map_or(String::new(), |swarm| {
+        format!(
+            " (swarm: {} seeders, {} leechers)",
+            swarm.seeders, swarm.leechers
+        )
+    })
+}
+
+/// Returns the value following a `--flag` in the argument list, if present.
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+/// Returns every value given for a repeatable `--flag`, in order.
+fn flag_values<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+/// Parses a duration such as `90`, `90s`, `30m`, `2h` or `1d` (bare numbers are seconds).
+fn parse_duration(value: &str, flag: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow::anyhow!("Invalid {} value: {}", flag, value);
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Storage flags shared by `download`, `seed` and `verify`.
+fn parse_storage_options(args: &[String]) -> anyhow::Result<StorageOptions> {
+    let mut options = StorageOptions::default();
+    if let Some(value) = flag_value(args, "--max-open-files") {
+        options.max_open_files = value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid --max-open-files value: {}", value))?;
+    }
+    if let Some(value) = flag_value(args, "--sync-policy") {
+        options.sync_policy = SyncPolicy::parse(value)?;
+    }
+    if let Some(value) = flag_value(args, "--alloc") {
+        options.allocation = AllocationPolicy::parse(value)?;
+    }
+    if let Some(value) = flag_value(args, "--if-exists") {
+        options.if_exists = ExistingFilePolicy::parse(value)?;
+    }
+    options.complete_dir = flag_value(args, "--complete-dir").map(String::from);
+    Ok(options)
+}
+
+/// Runs the `create` command: hashes `input_path` into a torrent at `output_path`, or
+/// with `--dry-run` only lists what would be hashed.
+fn create_torrent(input_path: &str, output_path: &str, args: &[String]) -> anyhow::Result<()> {
+    // Use a reliable public UDP tracker unless the user names their own
+    let mut trackers: Vec<String> = flag_values(args, "--tracker")
+        .into_iter()
+        .map(String::from)
+        .collect();
+    if trackers.is_empty() {
+        trackers.push("udp://tracker.opentrackr.org:1337".to_string());
+    }
+
+    let options = CreateOptions {
+        include_hidden: args.iter().any(|a| a == "--include-hidden"),
+        exclude: flag_values(args, "--exclude")
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        name: flag_value(args, "--name").map(String::from),
+        md5: args.iter().any(|a| a == "--md5"),
+        mtime: args.iter().any(|a| a == "--mtime"),
+    };
+
+    // Show what would be hashed, without reading or writing anything
+    if args.iter().any(|a| a == "--dry-run") {
+        let plan = core::creator::plan_torrent(input_path, &options)?;
+        println!("Name: {}", plan.name);
+        for (path, length) in &plan.files {
+            println!(
+                "  {:>14}  {}",
+                length,
+                if path.is_empty() { &plan.name } else { path }
+            );
+        }
+        println!("Files: {}", plan.files.len());
+        println!("Total size: {} bytes", plan.total_length);
+        println!("Piece length: {} bytes", plan.piece_length);
+        println!("Pieces: {}", plan.piece_count);
+        return Ok(());
+    }
+
+    // Generate the .torrent file
+    let mut last_percent = None;
+    core::creator::create_torrent_file(
+        input_path,
+        &trackers,
+        output_path,
+        &options,
+        |hashed, total| {
+            // Redraw only when the percentage changes to keep output cheap
+            let percent = (hashed * 100).checked_div(total).unwrap_or(100);
+            if last_percent != Some(percent) {
+                last_percent = Some(percent);
+                print!("\rHashing: {:>3}% ({}/{} bytes)", percent, hashed, total);
+                let _ = std::io::Write::flush(&mut std::io::stdout());
+                if hashed == total {
+                    println!();
+                }
+            }
+        },
+    )?;
+    Ok(())
+}
+
+/// Applies the `edit` command's tracker flags to the torrent at `path` and saves it.
+///
+/// Returns the edited torrent as read back, and its info hash, which is checked not
+/// to have changed before anything is written.
+fn edit_trackers(
+    path: &str,
+    args: &[String],
+) -> anyhow::Result<(core::torrent_info::Torrent, [u8; 20])> {
+    let mut torrent = core::torrent_info::Torrent::read(path)?;
+    let info_hash = torrent.info_hash()?;
+
+    for url in flag_values(args, "--remove-tracker") {
+        if !torrent.remove_tracker(url) {
+            println!("Not listed, nothing to remove: {}", url);
+        }
+    }
+    for pair in args.windows(3).filter(|w| w[0] == "--replace-tracker") {
+        if !torrent.replace_tracker(&pair[1], &pair[2]) {
+            println!("Not listed, nothing to replace: {}", pair[1]);
+        }
+    }
+    for url in flag_values(args, "--add-tracker") {
+        if !torrent.add_tracker(url) {
+            println!("Already listed: {}", url);
+        }
+    }
+
+    // Only keys outside `info` change, so the info hash must survive the round trip
+    let bytes = torrent.to_bytes()?;
+    let edited = core::torrent_info::Torrent::from_bytes(&bytes)?;
+    if edited.info_hash()? != info_hash {
+        anyhow::bail!("Edit would change the info hash; {} left untouched", path);
+    }
+    std::fs::write(path, bytes)?;
+    Ok((edited, info_hash))
+}
+
+/// Parses a rate limit given in KiB/s into bytes per second (0 = unlimited).
+fn parse_rate(value: &str, flag: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("Invalid {} value: {}", flag, value);
+    let kib: u64 = value.parse().map_err(|_| invalid())?;
+    kib.checked_mul(1024).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::torrent_info::{Info, Torrent};
+    use crate::network::handshake::Handshake;
+    use crate::network::message::Message;
+    use serde_bytes::ByteBuf;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    /// A session storing its data (and quota usage) under `dir`, with plaintext peer
+    /// connections so a mock peer can speak to it.
+    fn session_in(dir: &TempDir, download_quota: Option<u64>) -> Arc<Session> {
+        let output_dir = dir.path().to_string_lossy().into_owned();
+        let options = SessionOptions {
+            quota: Arc::new(Quota::load(&output_dir, download_quota, None).unwrap()),
+            storage: StorageOptions {
+                output_dir,
+                ..StorageOptions::default()
+            },
+            encryption: EncryptionMode::Disable,
+            ..SessionOptions::default()
+        };
+        Arc::new(Session::new(options))
+    }
+
+    /// A 40000-byte torrent of 16 KiB pieces, whose `pieces` field holds `hash_bytes`.
+    fn torrent(hash_bytes: usize) -> Torrent {
+        let info = Info {
+            name: "fixture".to_string(),
+            piece_length: 16384,
+            pieces: ByteBuf::from(vec![0; hash_bytes]),
+            length: Some(40000),
+            ..Info::default()
+        };
+        Torrent::new(String::new(), None, info)
+    }
+
+    #[tokio::test]
+    async fn truncated_pieces_stop_the_download() {
+        let dir = TempDir::new().unwrap();
+        let session = session_in(&dir, None);
+        // A hash and a half: the metadata can't describe piece 0. Loading from a file
+        // would reject it up front; a peer session must not retry it forever either.
+        let handle = session.add(torrent(30)).unwrap();
+        let mut events = handle.manager.subscribe();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = handle.info_hash;
+        let peer = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            socket.read_exact(&mut handshake).await.unwrap();
+            let reply = Handshake::new(info_hash, [9u8; 20]);
+            socket.write_all(&reply.as_bytes()).await.unwrap();
+            socket
+                .write_all(&Message::Bitfield(vec![0x80]).serialize())
+                .await
+                .unwrap();
+            socket
+                .write_all(&Message::Unchoke.serialize())
+                .await
+                .unwrap();
+            // Held open until the session gives up on it
+            let _ = socket.read_to_end(&mut Vec::new()).await;
+        });
+        assert!(session.connect(&handle, addr.to_string()));
+
+        let message = timeout(Duration::from_secs(10), next_wakeup(&mut events))
+            .await
+            .unwrap()
+            .expect("a fatal event");
+        let error = stop_download(&session, None, &reqwest::Client::new(), message)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Download stopped: "), "{}", error);
+        assert!(error.contains("Invalid torrent metadata"), "{}", error);
+        timeout(Duration::from_secs(5), peer)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn fatal_stop_saves_quota_and_partial_pieces() {
+        let dir = TempDir::new().unwrap();
+        let session = session_in(&dir, Some(1 << 20));
+        let handle = session.add(torrent(60)).unwrap();
+        let manager = &handle.manager;
+        let mut events = manager.subscribe();
+
+        // Half of piece 0 arrived before the disk failed on piece 1
+        manager.record_download(24576);
+        manager.release_piece(0, vec![7; 8192]);
+        manager.report_disk_error(1, &anyhow::anyhow!("disk full"));
+
+        let message = next_wakeup(&mut events).await.expect("a fatal event");
+        let error = stop_download(&session, None, &reqwest::Client::new(), message)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Download stopped: "));
+
+        let usage: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join(".quota.json")).unwrap())
+                .unwrap();
+        assert_eq!(usage["downloaded"], 24576);
+        let partial = manager.storage.load_partial_pieces().unwrap();
+        assert_eq!(partial.get(&0).map(Vec::len), Some(8192));
+    }
+
+    #[test]
+    fn create_dry_run_writes_no_output() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("data");
+        std::fs::write(&input, vec![7; 1000]).unwrap();
+        let output = dir.path().join("data.torrent");
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+
+        let args = ["p2p", "create", input, output, "--dry-run"].map(String::from);
+        create_torrent(input, output, &args).unwrap();
+        // Neither the torrent nor a hashing checkpoint
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["data"]);
+    }
+
+    #[test]
+    fn create_puts_the_given_trackers_in_announce() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("data");
+        std::fs::write(&input, vec![7; 1000]).unwrap();
+        let output = dir.path().join("data.torrent");
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        let create = |extra: &[&str]| {
+            let mut args = vec!["p2p", "create", input, output];
+            args.extend_from_slice(extra);
+            let args: Vec<String> = args.into_iter().map(String::from).collect();
+            create_torrent(input, output, &args).unwrap();
+            Torrent::read(output).unwrap()
+        };
+
+        let torrent = create(&["--tracker", "http://mine/announce"]);
+        assert_eq!(torrent.announce, "http://mine/announce");
+        assert_eq!(torrent.get_tracker_urls(), vec!["http://mine/announce"]);
+
+        let torrent = create(&[]);
+        assert_eq!(torrent.announce, "udp://tracker.opentrackr.org:1337");
+    }
+
+    #[test]
+    fn edit_changes_trackers_but_not_the_info_hash() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("edit.torrent");
+        let mut original = torrent(60);
+        original.announce = "http://a/announce".to_string();
+        std::fs::write(&path, original.to_bytes().unwrap()).unwrap();
+        let path = path.to_str().unwrap();
+        let info_hash = Torrent::read(path).unwrap().info_hash().unwrap();
+
+        let args: Vec<String> = [
+            "p2p",
+            "edit",
+            path,
+            "--add-tracker",
+            "http://b/announce",
+            "--replace-tracker",
+            "http://a/announce",
+            "udp://c:6969",
+        ]
+        .map(String::from)
+        .to_vec();
+        let (edited, unchanged) = edit_trackers(path, &args).unwrap();
+        assert_eq!(unchanged, info_hash);
+        assert_eq!(
+            edited.announce_list,
+            Some(vec![
+                vec!["udp://c:6969".to_string()],
+                vec!["http://b/announce".to_string()]
+            ])
+        );
+
+        let saved = Torrent::read(path).unwrap();
+        assert_eq!(saved.info_hash().unwrap(), info_hash);
+        assert_eq!(
+            saved.get_tracker_urls(),
+            vec!["udp://c:6969", "http://b/announce"]
+        );
+    }
+
+    #[test]
+    fn parse_rate_converts_kib() {
+        assert_eq!(parse_rate("0", "--download-limit").unwrap(), 0);
+        assert_eq!(parse_rate("512", "--download-limit").unwrap(), 512 * 1024);
+    }
+
+    #[test]
+    fn parse_rate_rejects_overflow_and_garbage() {
+        assert!(parse_rate(&u64::MAX.to_string(), "--download-limit").is_err());
+        assert!(parse_rate("-1", "--download-limit").is_err());
+        assert!(parse_rate("fast", "--download-limit").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Wait after a peer's first failed connection attempt; doubles with every further one.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// After this many failures in a row the peer is not tried again this session.
+const MAX_FAILURES: u32 = 5;
+
+/// Remembers peers whose connection or handshake failed recently, so each tracker
+/// round doesn't hammer dead addresses and waste connection slots on them.
+///
+/// A peer that fails waits 30s, then 60s, 120s, ... before the next attempt, and is
+/// dropped for good after `MAX_FAILURES` failures in a row. Any successful handshake
+/// clears its record.
+#[derive(Default)]
+pub struct PeerBackoff {
+    peers: Mutex<HashMap<String, Failures>>,
+}
+
+struct Failures {
+    count: u32,
+    retry_at: Instant,
+}
+
+impl PeerBackoff {
+    /// Whether `peer` may be connected to now.
+    pub fn allows(&self, peer: &str) -> bool {
+        match self.peers.lock().unwrap().get(peer) {
+            Some(failures) => failures.count < MAX_FAILURES && Instant::now() >= failures.retry_at,
+            None => true,
+        }
+    }
+
+    pub fn record_failure(&self, peer: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        let failures = peers.entry(peer.to_string()).or_insert(Failures {
+            count: 0,
+            retry_at: Instant::now(),
+        });
+        failures.count += 1;
+        failures.retry_at =
+            Instant::now() + INITIAL_BACKOFF * 2u32.pow((failures.count - 1).min(MAX_FAILURES));
+    }
+
+    pub fn record_success(&self, peer: &str) {
+        self.peers.lock().unwrap().remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    const PEER: &str = "10.0.0.1:6881";
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_peer_waits_out_a_doubling_window() {
+        let backoff = PeerBackoff::default();
+        backoff.record_failure(PEER);
+        assert!(!backoff.allows(PEER));
+        assert!(backoff.allows("10.0.0.2:6881"));
+        advance(INITIAL_BACKOFF - Duration::from_secs(1)).await;
+        assert!(!backoff.allows(PEER));
+        advance(Duration::from_secs(1)).await;
+        assert!(backoff.allows(PEER));
+
+        // The second failure doubles the wait
+        backoff.record_failure(PEER);
+        advance(INITIAL_BACKOFF).await;
+        assert!(!backoff.allows(PEER));
+        advance(INITIAL_BACKOFF).await;
+        assert!(backoff.allows(PEER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_clears_and_repeated_failure_gives_up() {
+        let backoff = PeerBackoff::default();
+        backoff.record_failure(PEER);
+        backoff.record_success(PEER);
+        assert!(backoff.allows(PEER));
+
+        for _ in 0..MAX_FAILURES {
+            backoff.record_failure(PEER);
+        }
+        advance(Duration::from_secs(24 * 60 * 60)).await;
+        assert!(!backoff.allows(PEER));
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::Context;
+use std::fs;
+use std::net::IpAddr;
+use tracing::warn;
+
+/// A set of blocked IP ranges, loaded from a P2P or CIDR blocklist file.
+///
+/// IPv4 addresses are stored as IPv4-mapped IPv6 addresses so both families share one
+/// sorted, merged list of inclusive ranges, and a lookup is a single binary search.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    ranges: Vec<(u128, u128)>,
+}
+
+impl Blocklist {
+    /// Loads a blocklist file. Each line is one of:
+    ///
+    /// - P2P format: `description:1.2.3.0-1.2.3.255`
+    /// - A plain range: `1.2.3.0-1.2.3.255`
+    /// - CIDR: `1.2.3.0/24` or `2001:db8::/32`
+    /// - A single address
+    ///
+    /// Blank lines and lines starting with `#` are ignored. Lines that don't parse are
+    /// skipped (published lists often contain a few), and the count is reported.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content =
+            fs::read_to_string(path).context(format!("Failed to read blocklist {}", path))?;
+
+        let mut ranges = Vec::new();
+        let mut invalid = 0;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_line(line) {
+                Some(range) => ranges.push(range),
+                None => invalid += 1,
+            }
+        }
+        if invalid > 0 {
+            warn!("Blocklist: skipped {} unparseable lines", invalid);
+        }
+
+        Ok(Self::from_ranges(ranges))
+    }
+
+    /// Sorts the ranges and merges overlapping or adjacent ones.
+    fn from_ranges(mut ranges: Vec<(u128, u128)>) -> Self {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    /// Number of distinct ranges after merging.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = to_u128(ip);
+        // First range starting after `ip`; the one before it is the only candidate
+        let idx = self.ranges.partition_point(|&(start, _)| start <= ip);
+        idx > 0 && ip <= self.ranges[idx - 1].1
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u128, u128)> {
+    // P2P lines are `description:start-end`. The description may itself contain
+    // colons, and IPv6 addresses do too, so only strip it when an IPv4 range follows.
+    let range = match line.rsplit_once(':') {
+        Some((_, range)) if range.contains('-') && range.contains('.') => range,
+        _ => line,
+    };
+
+    if let Some((addr, prefix)) = range.split_once('/') {
+        return parse_cidr(addr.trim(), prefix.trim());
+    }
+
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let start = to_u128(start.trim().parse().ok()?);
+            let end = to_u128(end.trim().parse().ok()?);
+            (start <= end).then_some((start, end))
+        }
+        None => {
+            let ip = to_u128(range.trim().parse().ok()?);
+            Some((ip, ip))
+        }
+    }
+}
+
+fn parse_cidr(addr: &str, prefix: &str) -> Option<(u128, u128)> {
+    let prefix: u32 = prefix.parse().ok()?;
+    let (base, prefix) = match addr.parse::<IpAddr>().ok()? {
+        IpAddr::V4(v4) if prefix <= 32 => (u128::from(v4.to_ipv6_mapped()), prefix + 96),
+        IpAddr::V6(v6) if prefix <= 128 => (u128::from(v6), prefix),
+        _ => return None,
+    };
+
+    let host_bits = u128::MAX.checked_shr(prefix).unwrap_or(0);
+    let start = base & !host_bits;
+    Some((start, start | host_bits))
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// How often the unchoked set is recomputed.
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic slot moves on to the next peer every this many rechokes (30s).
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// Decides which peers are unchoked, across every session (`--upload-slots`).
+///
+/// Every 10 seconds the interested peers are ranked by the rate they give us (the rate
+/// we upload to them once their torrent is complete, as a seed downloads nothing). The
+/// best `slots - 1` are unchoked, plus one optimistic slot that rotates through the
+/// others every 30 seconds, so a newcomer gets the chance to prove itself. Everyone
+/// else is choked. With unlimited slots every interested peer is unchoked.
+///
+/// Sessions report through their `ChokerSlot` on every tick, and the first report after
+/// the interval is up triggers the rechoke; the others pick up the result at their own
+/// next tick.
+pub struct Choker {
+    /// Peers unchoked at once; 0 for no limit.
+    slots: usize,
+    state: Mutex<ChokerState>,
+}
+
+#[derive(Default)]
+struct ChokerState {
+    /// Candidates by slot ID, with what their sessions last reported.
+    peers: HashMap<u64, Report>,
+    unchoked: HashSet<u64>,
+    optimistic: Option<u64>,
+    /// Rechokes run so far.
+    rounds: u64,
+    next_id: u64,
+    next_rechoke: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Report {
+    interested: bool,
+    rate: u64,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            state: Mutex::new(ChokerState::default()),
+        }
+    }
+
+    /// Adds a peer, choked until the next rechoke. It leaves when the slot is dropped.
+    pub fn join(self: &Arc<Self>) -> ChokerSlot {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.peers.insert(id, Report::default());
+        ChokerSlot {
+            choker: self.clone(),
+            id,
+        }
+    }
+
+    /// Recomputes the unchoked set from the latest reports.
+    fn rechoke(&self) {
+        let mut state = self.state.lock().unwrap();
+        let mut interested: Vec<(u64, u64)> = state
+            .peers
+            .iter()
+            .filter(|(_, report)| report.interested)
+            .map(|(&id, report)| (id, report.rate))
+            .collect();
+        state.rounds += 1;
+        if self.slots == 0 {
+            state.unchoked = interested.into_iter().map(|(id, _)| id).collect();
+            state.optimistic = None;
+            return;
+        }
+
+        // Fastest first; the slot ID (join order) breaks ties
+        interested.sort_by_key(|&(id, rate)| (std::cmp::Reverse(rate), id));
+        let regular: HashSet<u64> = interested
+            .iter()
+            .take(self.slots - 1)
+            .map(|&(id, _)| id)
+            .collect();
+
+        // The optimistic peer keeps its slot for OPTIMISTIC_ROUNDS, unless it lost
+        // interest or earned a regular slot; then it passes to the next peer by ID
+        let mut others: Vec<u64> = interested
+            .iter()
+            .map(|&(id, _)| id)
+            .filter(|id| !regular.contains(id))
+            .collect();
+        others.sort_unstable();
+        let keep = state
+            .optimistic
+            .filter(|id| others.contains(id) && state.rounds % OPTIMISTIC_ROUNDS != 1);
+        state.optimistic = keep.or_else(|| {
+            let previous = state.optimistic;
+            others
+                .iter()
+                .find(|&&id| previous.is_some_and(|previous| id > previous))
+                .or(others.first())
+                .copied()
+        });
+
+        state.unchoked = regular;
+        if let Some(id) = state.optimistic {
+            state.unchoked.insert(id);
+        }
+    }
+}
+
+/// A session's place in the `Choker`.
+pub struct ChokerSlot {
+    choker: Arc<Choker>,
+    id: u64,
+}
+
+impl ChokerSlot {
+    /// Records whether the peer wants a slot and the rate it is ranked by, rechokes if
+    /// one is due, and returns whether the peer should be unchoked.
+    pub fn tick(&self, interested: bool, rate: u64) -> bool {
+        self.report(interested, rate);
+        let now = Instant::now();
+        let due = {
+            let mut state = self.choker.state.lock().unwrap();
+            let due = state.next_rechoke.is_none_or(|at| now >= at);
+            if due {
+                state.next_rechoke = Some(now + RECHOKE_INTERVAL);
+            }
+            due
+        };
+        if due {
+            self.choker.rechoke();
+        }
+        self.is_unchoked()
+    }
+
+    /// Gives the slot up until the next tick, e.g. when the peer lost interest.
+    pub fn withdraw(&self) {
+        let mut state = self.choker.state.lock().unwrap();
+        if let Some(report) = state.peers.get_mut(&self.id) {
+            report.interested = false;
+        }
+        state.unchoked.remove(&self.id);
+    }
+
+    fn report(&self, interested: bool, rate: u64) {
+        let mut state = self.choker.state.lock().unwrap();
+        state.peers.insert(self.id, Report { interested, rate });
+        // A peer that lost interest gives its slot up straight away
+        if !interested {
+            state.unchoked.remove(&self.id);
+        }
+    }
+
+    fn is_unchoked(&self) -> bool {
+        self.choker
+            .state
+            .lock()
+            .unwrap()
+            .unchoked
+            .contains(&self.id)
+    }
+}
+
+impl Drop for ChokerSlot {
+    fn drop(&mut self) {
+        let mut state = self.choker.state.lock().unwrap();
+        state.peers.remove(&self.id);
+        state.unchoked.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unchoked(slots: &[ChokerSlot]) -> Vec<usize> {
+        (0..slots.len())
+            .filter(|&i| slots[i].is_unchoked())
+            .collect()
+    }
+
+    #[test]
+    fn fastest_peers_keep_their_slots() {
+        let choker = Arc::new(Choker::new(3));
+        let peers: Vec<ChokerSlot> = (0..5).map(|_| choker.join()).collect();
+        for (peer, rate) in peers.iter().zip([10, 500, 0, 300, 20]) {
+            peer.report(true, rate);
+        }
+        choker.rechoke();
+        // 1 and 3 are fastest; 0 is first in line for the optimistic slot
+        assert_eq!(unchoked(&peers), vec![0, 1, 3]);
+
+        // The ranking follows the rates reported, and the optimistic peer holds on
+        peers[1].report(true, 0);
+        peers[2].report(true, 900);
+        choker.rechoke();
+        assert_eq!(unchoked(&peers), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn optimistic_slot_rotates() {
+        let choker = Arc::new(Choker::new(2));
+        let peers: Vec<ChokerSlot> = (0..4).map(|_| choker.join()).collect();
+        for (peer, rate) in peers.iter().zip([1000, 30, 20, 10]) {
+            peer.report(true, rate);
+        }
+
+        let mut sets = Vec::new();
+        for _ in 0..3 * OPTIMISTIC_ROUNDS {
+            choker.rechoke();
+            let set = unchoked(&peers);
+            assert_eq!(set.len(), 2);
+            // The fastest peer always keeps its regular slot
+            assert!(set.contains(&0));
+            sets.push(set);
+        }
+        // The optimistic slot holds for OPTIMISTIC_ROUNDS, then moves on
+        let rounds = OPTIMISTIC_ROUNDS as usize;
+        assert!(sets[..rounds].iter().all(|set| *set == sets[0]));
+        assert_ne!(sets[0], sets[rounds]);
+        // Over three rotations every other peer got its turn
+        for peer in 1..4 {
+            assert!(sets.iter().any(|set| set.contains(&peer)));
+        }
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let choker = Arc::new(Choker::new(4));
+        let peers: Vec<ChokerSlot> = (0..3).map(|_| choker.join()).collect();
+        peers[0].report(true, 0);
+        peers[1].report(false, 1000);
+        peers[2].report(true, 5);
+        choker.rechoke();
+        assert_eq!(unchoked(&peers), vec![0, 2]);
+
+        // Losing interest frees the slot without waiting for a rechoke
+        peers[2].report(false, 5);
+        assert_eq!(unchoked(&peers), vec![0]);
+    }
+
+    #[test]
+    fn unlimited_slots_unchoke_every_interested_peer() {
+        let choker = Arc::new(Choker::new(0));
+        let peers: Vec<ChokerSlot> = (0..6).map(|_| choker.join()).collect();
+        for (i, peer) in peers.iter().enumerate() {
+            peer.report(i % 2 == 0, i as u64);
+        }
+        choker.rechoke();
+        assert_eq!(unchoked(&peers), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn departed_peers_free_their_slot() {
+        let choker = Arc::new(Choker::new(1));
+        let first = choker.join();
+        let second = choker.join();
+        first.report(true, 0);
+        second.report(true, 0);
+        assert!(first.tick(true, 0));
+        assert!(!second.tick(true, 0));
+        drop(first);
+        choker.rechoke();
+        assert!(second.is_unchoked());
+    }
+
+    #[test]
+    fn tick_rechokes_once_per_interval() {
+        let choker = Arc::new(Choker::new(2));
+        let peer = choker.join();
+        assert!(peer.tick(true, 0));
+        let rounds = choker.state.lock().unwrap().rounds;
+        peer.tick(true, 0);
+        assert_eq!(choker.state.lock().unwrap().rounds, rounds);
+    }
+}
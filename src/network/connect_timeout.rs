@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Duration;
+
+/// Connect timeout used until enough connections have been timed to adapt it.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Bounds for the adaptive timeout: never impatient enough to drop a slow-but-alive
+/// peer, never so patient that dead peers tie up connection slots.
+const MIN_ADAPTIVE: Duration = Duration::from_secs(1);
+const MAX_ADAPTIVE: Duration = Duration::from_secs(10);
+
+/// The adaptive timeout is this many times the average successful connect time.
+const LATENCY_MULTIPLIER: u64 = 4;
+
+/// Timeout for outbound TCP connects, shared by every peer session.
+///
+/// Fixed when `--connect-timeout` is given. Otherwise it follows the network: each
+/// successful connect feeds a moving average of connect latency (weight 1/8 per
+/// sample), and the timeout is 4x that average, kept between 1 and 10 seconds.
+pub struct ConnectTimeout {
+    fixed: Option<Duration>,
+    /// Moving average of successful connect times, in milliseconds.
+    average_ms: AtomicU64,
+}
+
+impl ConnectTimeout {
+    pub fn new(fixed: Option<Duration>) -> Self {
+        Self {
+            fixed,
+            average_ms: AtomicU64::new(
+                DEFAULT_CONNECT_TIMEOUT.as_millis() as u64 / LATENCY_MULTIPLIER,
+            ),
+        }
+    }
+
+    pub fn get(&self) -> Duration {
+        if let Some(fixed) = self.fixed {
+            return fixed;
+        }
+        let average = Duration::from_millis(self.average_ms.load(Ordering::Relaxed));
+        (average * LATENCY_MULTIPLIER as u32).clamp(MIN_ADAPTIVE, MAX_ADAPTIVE)
+    }
+
+    /// Feeds the time a successful connect took into the average.
+    pub fn record_success(&self, elapsed: Duration) {
+        let sample = elapsed.as_millis() as u64;
+        // Lost updates between racing sessions only drop a sample, which is harmless
+        let average = self.average_ms.load(Ordering::Relaxed);
+        self.average_ms
+            .store((average * 7 + sample) / 8, Ordering::Relaxed);
+    }
+}
@@ -1,3 +1,46 @@
+/// Protocol identifier carried in every BitTorrent handshake.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Optional protocol features a peer advertises through the handshake's reserved bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerCapabilities {
+    /// BEP 10 Extension Protocol (bit 0x10 of reserved byte 5). Gates PEX and metadata exchange.
+    pub supports_extended: bool,
+    /// BEP 5 DHT (bit 0x01 of reserved byte 7). The peer accepts a DHT `port` message.
+    pub supports_dht: bool,
+    /// BEP 6 Fast Extension (bit 0x04 of reserved byte 7).
+    pub supports_fast: bool,
+}
+
+impl PeerCapabilities {
+    /// Decodes the capability flags from the 8 reserved handshake bytes.
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        Self {
+            supports_extended: reserved[5] & 0x10 != 0,
+            supports_dht: reserved[7] & 0x01 != 0,
+            supports_fast: reserved[7] & 0x04 != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for PeerCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flags: Vec<&str> = [
+            (self.supports_extended, "extended"),
+            (self.supports_dht, "dht"),
+            (self.supports_fast, "fast"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect();
+        if flags.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", flags.join(", "))
+        }
+    }
+}
+
 /// Represents the initial Handshake message exchanged between peers.
 ///
 /// The handshake is the first message sent immediately after establishing a TCP connection.
@@ -7,11 +50,12 @@
 /// Structure (Total 68 bytes):
 /// - 1 byte:  Length of the protocol identifier (19).
 /// - 19 bytes: Protocol identifier string ("BitTorrent protocol").
-/// - 8 bytes: Reserved bytes (set to 0, reserved for extensions like DHT/Fast).
+/// - 8 bytes: Reserved bytes, flagging extensions like DHT/Fast (we send all zeros).
 /// - 20 bytes: Info Hash (SHA-1 hash of the metainfo file).
 /// - 20 bytes: Peer ID (Unique identifier for this client).
 pub struct Handshake {
     pub protocol_string: String,
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -21,6 +65,7 @@ impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
             protocol_string: "BitTorrent protocol".to_string(),
+            reserved: [0u8; 8],
             info_hash,
             peer_id,
         }
@@ -38,8 +83,8 @@ impl Handshake {
         // 2. Protocol identifier string
         bytes.extend_from_slice(self.protocol_string.as_bytes());
 
-        // 3. Reserved Bytes (8 bytes set to 0)
-        bytes.extend_from_slice(&[0u8; 8]);
+        // 3. Reserved Bytes (capability flags)
+        bytes.extend_from_slice(&self.reserved);
 
         // 4. Info Hash
         bytes.extend_from_slice(&self.info_hash);
@@ -49,4 +94,69 @@ impl Handshake {
 
         bytes
     }
-}
\ No newline at end of file
+
+    /// Parses a 68-byte handshake received from a peer.
+    ///
+    /// Fails unless the length byte is 19 and the identifier is exactly
+    /// "BitTorrent protocol", so a non-BitTorrent service can't pass as a peer.
+    pub fn parse(bytes: &[u8; 68]) -> anyhow::Result<Self> {
+        if bytes[0] as usize != PROTOCOL.len() {
+            anyhow::bail!("Invalid handshake: protocol length {}", bytes[0]);
+        }
+        if &bytes[1..20] != PROTOCOL {
+            anyhow::bail!("Invalid handshake: unknown protocol identifier");
+        }
+
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&bytes[20..28]);
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&bytes[28..48]);
+        let mut peer_id = [0u8; 20];
+        peer_id.copy_from_slice(&bytes[48..68]);
+
+        Ok(Self {
+            reserved,
+            ..Self::new(info_hash, peer_id)
+        })
+    }
+
+    /// Capabilities advertised in this handshake's reserved bytes.
+    pub fn capabilities(&self) -> PeerCapabilities {
+        PeerCapabilities::from_reserved(&self.reserved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserved_with(byte: usize, bit: u8) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        reserved[byte] = bit;
+        reserved
+    }
+
+    #[test]
+    fn each_reserved_bit_maps_to_its_capability() {
+        let extended = PeerCapabilities::from_reserved(&reserved_with(5, 0x10));
+        assert!(extended.supports_extended && !extended.supports_dht && !extended.supports_fast);
+        let dht = PeerCapabilities::from_reserved(&reserved_with(7, 0x01));
+        assert!(dht.supports_dht && !dht.supports_extended && !dht.supports_fast);
+        let fast = PeerCapabilities::from_reserved(&reserved_with(7, 0x04));
+        assert!(fast.supports_fast && !fast.supports_extended && !fast.supports_dht);
+        assert_eq!(
+            PeerCapabilities::from_reserved(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xef, 0xff, 0xfa]),
+            PeerCapabilities::default()
+        );
+    }
+
+    #[test]
+    fn capabilities_survive_a_parse() {
+        let mut handshake = Handshake::new([1; 20], [2; 20]);
+        handshake.reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        let bytes: [u8; 68] = handshake.as_bytes().try_into().unwrap();
+        let capabilities = Handshake::parse(&bytes).unwrap().capabilities();
+        assert_eq!(capabilities.to_string(), "extended, dht, fast");
+        assert_eq!(PeerCapabilities::default().to_string(), "none");
+    }
+}
@@ -1,6 +1,11 @@
 use anyhow::{Context, Result};
 use tokio::io::AsyncReadExt;
 
+/// Largest length prefix accepted. Covers a Piece carrying the biggest block we'd ever
+/// request and the Bitfield of a torrent with millions of pieces; anything longer is a
+/// broken or hostile peer, and must not make us allocate gigabytes up front.
+pub const MAX_MESSAGE_LENGTH: u32 = 2 * 1024 * 1024;
+
 /// Represents the standard messages of the BitTorrent Peer Wire Protocol.
 ///
 /// Messages generally follow the format: `<Length Prefix><Message ID><Payload>`.
@@ -31,6 +36,8 @@ pub enum Message {
         begin: u32,
         block: Vec<u8>,
     },
+    /// Withdraws an earlier Request that hasn't been answered yet.
+    Cancel { index: u32, begin: u32, length: u32 },
 }
 
 impl Message {
@@ -102,6 +109,18 @@ impl Message {
                 bytes.extend_from_slice(block);
                 bytes
             }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                // Same layout as Request, ID: 8
+                let mut bytes = vec![0, 0, 0, 13, 8];
+                bytes.extend_from_slice(&index.to_be_bytes());
+                bytes.extend_from_slice(&begin.to_be_bytes());
+                bytes.extend_from_slice(&length.to_be_bytes());
+                bytes
+            }
         }
     }
 
@@ -123,6 +142,13 @@ impl Message {
         if length == 0 {
             return Ok(Message::KeepAlive);
         }
+        if length > MAX_MESSAGE_LENGTH {
+            anyhow::bail!(
+                "Message length {} exceeds the {} byte limit",
+                length,
+                MAX_MESSAGE_LENGTH
+            );
+        }
 
         // 3. Read the Message ID (1 byte)
         let mut id_buf = [0u8; 1];
@@ -144,6 +170,10 @@ impl Message {
 
         // 5. Parse the Payload based on ID
         match id {
+            // Choke, Unchoke, Interested and NotInterested carry no payload
+            0..=3 if !payload.is_empty() => {
+                anyhow::bail!("Unexpected payload for message ID {}", id)
+            }
             0 => Ok(Message::Choke),
             1 => Ok(Message::Unchoke),
             2 => Ok(Message::Interested),
@@ -185,6 +215,20 @@ impl Message {
                     block,
                 })
             }
+            8 => {
+                // Cancel: 12 bytes (index, begin, length), like Request
+                if payload.len() != 12 {
+                    anyhow::bail!("Invalid payload length for Cancel message");
+                }
+                let index = u32::from_be_bytes(payload[0..4].try_into()?);
+                let begin = u32::from_be_bytes(payload[4..8].try_into()?);
+                let length = u32::from_be_bytes(payload[8..12].try_into()?);
+                Ok(Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                })
+            }
             _ => {
                 // Unknown ID (possibly Extension Protocol handshake, which we don't support yet)
                 anyhow::bail!("Unknown message ID: {}", id);
@@ -192,3 +236,137 @@ impl Message {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    async fn read(bytes: &[u8]) -> Result<Message> {
+        let mut stream = bytes;
+        Message::read(&mut stream).await
+    }
+
+    fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (1 + payload.len() as u32).to_be_bytes().to_vec();
+        bytes.push(id);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn samples() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 7 },
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 2,
+                begin: 0,
+                block: vec![1, 2, 3],
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn round_trips_every_message() {
+        for message in samples() {
+            assert_eq!(read(&message.serialize()).await.unwrap(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_messages_are_errors() {
+        for message in samples() {
+            let bytes = message.serialize();
+            for end in 0..bytes.len() {
+                assert!(
+                    read(&bytes[..end]).await.is_err(),
+                    "{:?} cut at {}",
+                    message,
+                    end
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_length_prefix() {
+        let mut bytes = (MAX_MESSAGE_LENGTH + 1).to_be_bytes().to_vec();
+        bytes.push(7);
+        assert!(read(&bytes).await.is_err());
+        assert!(read(&u32::MAX.to_be_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_payload() {
+        let mut bytes = frame(5, &[0xff; 10]);
+        bytes.truncate(bytes.len() - 1);
+        assert!(read(&bytes).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_id() {
+        for id in [9, 20, 255] {
+            assert!(read(&frame(id, &[])).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_payload_on_bodiless_messages() {
+        for id in 0..=3 {
+            assert!(read(&frame(id, &[0])).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_short_bodies() {
+        assert!(read(&frame(4, &[0; 3])).await.is_err());
+        for length in [0, 4, 11, 13] {
+            assert!(read(&frame(6, &vec![0; length])).await.is_err());
+            assert!(read(&frame(8, &vec![0; length])).await.is_err());
+        }
+        for length in [0, 4, 7] {
+            assert!(read(&frame(7, &vec![0; length])).await.is_err());
+        }
+        // An empty block is still a well-formed Piece
+        assert!(read(&frame(7, &[0; 8])).await.is_ok());
+    }
+
+    /// Arbitrary input never panics, and whatever parses re-serializes to the exact
+    /// bytes it was read from.
+    #[tokio::test]
+    async fn arbitrary_bytes_never_panic() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..20_000 {
+            let mut bytes = vec![0u8; rng.gen_range(0..40)];
+            rng.fill(&mut bytes[..]);
+            // Mostly small length prefixes and known IDs, so every parse path is hit
+            if bytes.len() >= 5 && rng.gen_bool(0.8) {
+                let length = rng.gen_range(0..bytes.len() as u32 + 2);
+                bytes[..4].copy_from_slice(&length.to_be_bytes());
+                bytes[4] = rng.gen_range(0..10);
+            }
+
+            let mut stream = &bytes[..];
+            if let Ok(message) = Message::read(&mut stream).await {
+                let consumed = bytes.len() - stream.len();
+                assert_eq!(message.serialize(), bytes[..consumed]);
+            }
+        }
+    }
+}
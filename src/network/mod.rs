@@ -1,30 +1,304 @@
+pub mod backoff;
+pub mod blocklist;
+pub mod choker;
+pub mod connect_timeout;
 pub mod handshake;
 pub mod message;
+pub mod mse;
+pub mod pipeline;
+pub mod port_mapping;
+pub mod rate_limiter;
+pub mod schedule;
+pub mod socks5;
+pub mod webseed;
 
-use crate::core::manager::TorrentManager;
+use crate::core::bitfield::Bitfield;
+use crate::core::disk::DiskWriter;
+use crate::core::manager::{PeerStats, TorrentManager};
+use crate::core::torrent_info::InvalidMetadata;
+use crate::utils;
 use anyhow::{Context, Result};
+use choker::Choker;
+use connect_timeout::ConnectTimeout;
 use handshake::Handshake;
 use message::Message;
+use mse::{EncryptionMode, PeerStream};
+use pipeline::RequestWindow;
+use rate_limiter::RateLimiter;
 use sha1::{Digest, Sha1};
+use socks5::ProxyConfig;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, timeout};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant, interval, sleep_until, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
 
-/// Maximum block size requested from peers (16KB is the standard).
-const BLOCK_MAX: u32 = 16384;
+/// Block size requested from peers by default (16KB is the standard).
+pub const DEFAULT_BLOCK_SIZE: u32 = 16384;
+
+/// Largest block size we allow; most clients drop peers requesting more than 128KB.
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// Time allowed for sending our handshake and receiving the peer's.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// If the peer sends nothing at all for this long, the connection is considered dead
+/// (default for `--read-timeout`).
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// If requests are outstanding while unchoked and no block arrives for this long, the
+/// peer is snubbing us (e.g. only sending KeepAlives) and the piece is handed back.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Send a KeepAlive if we haven't written anything for this long. Peers commonly drop
+/// connections that are silent for two minutes.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// How often we re-evaluate whether to choke or unchoke the peer.
+const CHOKE_ROTATION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Frames read ahead by the reader task before it waits for the session to catch up.
+const FRAME_QUEUE_DEPTH: usize = 16;
+
+/// Requests from one peer queued for upload; more are dropped until it catches up.
+const MAX_QUEUED_UPLOADS: usize = 256;
+
+/// Write side of a peer connection that remembers when we last sent anything.
+struct PeerWriter {
+    half: WriteHalf<PeerStream>,
+    last_write: Instant,
+}
+
+impl PeerWriter {
+    fn new(half: WriteHalf<PeerStream>) -> Self {
+        Self {
+            half,
+            last_write: Instant::now(),
+        }
+    }
+
+    async fn send(&mut self, msg: &Message) -> Result<()> {
+        self.half.write_all(&msg.serialize()).await?;
+        // Encrypted streams may hold back part of a write until flushed
+        self.half.flush().await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Sends `Interested` or `NotInterested` if `interested` differs from what the peer
+    /// was last told (`told`).
+    async fn update_interest(&mut self, told: &mut bool, interested: bool) -> Result<()> {
+        if *told != interested {
+            self.send(if interested {
+                &Message::Interested
+            } else {
+                &Message::NotInterested
+            })
+            .await?;
+            *told = interested;
+        }
+        Ok(())
+    }
+}
 
 /// Tracks the progress of a specific piece being downloaded by this peer.
 struct PeerSessionState {
     piece_index: usize,
     piece_buffer: Vec<u8>,
+    /// Bytes of the piece received, each block counted once.
     downloaded: u32,
+    /// Offset of the next block that has never been requested.
     requested: u32,
+    /// Requests sent and not yet answered, as `(begin, length)` in sending order.
+    pending: Vec<(u32, u32)>,
+    /// Requests a choke discarded, to be sent again before any new block.
+    dropped: VecDeque<(u32, u32)>,
     piece_length: u32,
+    /// When the last block for this piece arrived (or the piece was assigned).
+    last_block_at: Instant,
 }
 
-/// Manages a single TCP connection to a peer.
+impl PeerSessionState {
+    fn new(piece_index: usize, piece_buffer: Vec<u8>, resumed: u32) -> Self {
+        Self {
+            piece_index,
+            piece_length: piece_buffer.len() as u32,
+            piece_buffer,
+            downloaded: resumed,
+            requested: resumed,
+            pending: Vec::new(),
+            dropped: VecDeque::new(),
+            last_block_at: Instant::now(),
+        }
+    }
+
+    /// When the peer counts as snubbing us, if we're waiting on blocks.
+    fn snub_deadline(&self, am_unchoked: bool) -> Option<Instant> {
+        if am_unchoked && !self.pending.is_empty() {
+            Some(self.last_block_at + SNUB_TIMEOUT)
+        } else {
+            None
+        }
+    }
+
+    fn outstanding(&self) -> u32 {
+        self.pending.iter().map(|&(_, length)| length).sum()
+    }
+
+    /// The next block to request: one a choke discarded, else the next new one.
+    fn next_request(&mut self, block_size: u32) -> Option<(u32, u32)> {
+        if let Some(block) = self.dropped.pop_front() {
+            return Some(block);
+        }
+        if self.requested >= self.piece_length {
+            return None;
+        }
+        let length = std::cmp::min(block_size, self.piece_length - self.requested);
+        let block = (self.requested, length);
+        self.requested += length;
+        Some(block)
+    }
+
+    /// Marks a block as answered if it is exactly one we have outstanding. A block we
+    /// never asked for, already received, or cancelled returns `false` and must be
+    /// ignored; counting it would complete the piece early or run past its end.
+    fn accept(&mut self, index: u32, begin: u32, length: usize) -> bool {
+        if self.piece_index != index as usize {
+            return false;
+        }
+        match self
+            .pending
+            .iter()
+            .position(|&(b, l)| b == begin && l as usize == length)
+        {
+            Some(position) => {
+                self.pending.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Length of the leading run of received bytes, which is what can be kept when
+    /// the piece is handed back unfinished.
+    fn received_prefix(&self) -> u32 {
+        self.pending
+            .iter()
+            .chain(&self.dropped)
+            .map(|&(begin, _)| begin)
+            .min()
+            .unwrap_or(self.requested)
+    }
+}
+
+/// A session's `PeerStats`, published to the manager's peer registry on every choke
+/// rotation and whenever the choke or interest state changes.
+struct PeerStatsTracker {
+    stats: PeerStats,
+    connected_at: Instant,
+    /// When the stats were last published, and the byte counters then (for the rates).
+    published_at: Instant,
+    published_downloaded: u64,
+    published_uploaded: u64,
+}
+
+impl PeerStatsTracker {
+    fn new(addr: String, peer_id: &[u8; 20]) -> Self {
+        let now = Instant::now();
+        Self {
+            stats: PeerStats {
+                addr,
+                client: utils::client_name(peer_id),
+                downloaded: 0,
+                uploaded: 0,
+                download_rate: 0,
+                upload_rate: 0,
+                am_choking: true,
+                am_interested: false,
+                peer_choking: true,
+                peer_interested: false,
+                connected_secs: 0,
+            },
+            connected_at: now,
+            published_at: now,
+            published_downloaded: 0,
+            published_uploaded: 0,
+        }
+    }
+
+    fn publish(&mut self, manager: &TorrentManager, peer_id: &[u8; 20]) {
+        let now = Instant::now();
+        let secs = now.duration_since(self.published_at).as_secs_f64();
+        // Too short an interval gives meaningless rates; keep the previous ones
+        if secs >= 1.0 {
+            let stats = &mut self.stats;
+            stats.download_rate =
+                ((stats.downloaded - self.published_downloaded) as f64 / secs) as u64;
+            stats.upload_rate = ((stats.uploaded - self.published_uploaded) as f64 / secs) as u64;
+            self.published_at = now;
+            self.published_downloaded = stats.downloaded;
+            self.published_uploaded = stats.uploaded;
+        }
+        self.stats.connected_secs = now.duration_since(self.connected_at).as_secs();
+        manager.update_peer(peer_id, self.stats.clone());
+    }
+}
+
+/// Everything a peer session needs from its torrent and from the surrounding session.
+#[derive(Clone)]
+pub struct PeerContext {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub manager: Arc<TorrentManager>,
+    pub disk: DiskWriter,
+    /// Ends the session cleanly (the torrent was removed or the client is shutting down).
+    pub cancel: CancellationToken,
+    /// Shared limits on payload bytes sent to and received from all peers.
+    pub upload_limit: Arc<RateLimiter>,
+    pub download_limit: Arc<RateLimiter>,
+    /// Decides which peers are unchoked, across every session.
+    pub choker: Arc<Choker>,
+    /// Outbound connections are tunnelled through this SOCKS5 proxy when set.
+    pub proxy: Option<ProxyConfig>,
+    /// Size of each block requested.
+    pub block_size: u32,
+    /// Blocks kept outstanding at once; `None` adapts the window to the peer.
+    pub pipeline_depth: Option<u32>,
+    /// Pieces this session may have in progress at once (`None` = no cap of its own).
+    pub max_pieces_per_peer: Option<usize>,
+    /// Reveal one piece at a time to each peer instead of our whole bitfield (BEP 16).
+    /// Only takes effect once the torrent is complete.
+    pub super_seed: bool,
+    /// Re-hash each piece read from disk the first time this peer requests it, and
+    /// refuse to serve it if it no longer matches.
+    pub verify_on_seed: bool,
+    /// Refuse peers whose decoded client name starts with one of these (lowercase).
+    pub blocked_clients: Vec<String>,
+    /// Whether outbound connections start with an MSE handshake (and may fall back).
+    pub encryption: EncryptionMode,
+    /// Limit on outbound TCP connects, fixed or adapted to observed latency.
+    pub connect_timeout: Arc<ConnectTimeout>,
+    /// Silence after which an established connection is considered dead.
+    pub read_timeout: Duration,
+    /// Local address outbound connections are made from (`--bind`).
+    pub bind: Option<IpAddr>,
+}
+
+/// Re-hashes a piece read back from disk; a mismatch sends it back for re-download.
+fn piece_still_valid(manager: &TorrentManager, index: usize, data: &[u8]) -> Result<bool> {
+    let actual: [u8; 20] = Sha1::digest(data).into();
+    if actual == manager.torrent.get_piece_hash(index)? {
+        return Ok(true);
+    }
+    manager.invalidate_piece(index);
+    Ok(false)
+}
+
+/// Manages a single outbound TCP connection to a peer.
 ///
 /// This function handles the entire lifecycle:
 /// 1. TCP Connect & Handshake
@@ -32,87 +306,500 @@ struct PeerSessionState {
 /// 3. Download loop (requesting blocks and assembling pieces)
 /// 4. Upload loop (responding to peer requests)
 /// 5. Cleanup on disconnection
-pub async fn run_peer_session(
-    peer_addr: String,
-    info_hash: [u8; 20],
-    peer_id: [u8; 20],
-    manager: Arc<Mutex<TorrentManager>>,
-) -> Result<()> {
-    // println!("Connecting to {}...", peer_addr);
+///
+/// Verified pieces are handed to the `DiskWriter` rather than written inline.
+/// The session ends cleanly (returning `Ok`) once `ctx.cancel` is triggered.
+///
+/// Failures before the handshake completes carry a `ConnectFailed` context, so the
+/// caller can back off from the peer.
+pub async fn run_peer_session(peer_addr: String, ctx: PeerContext) -> Result<()> {
+    let (stream, response) = connect_and_handshake(&peer_addr, &ctx)
+        .await
+        .context(ConnectFailed)?;
+    run_connected_session(stream, response, peer_addr, ctx).await
+}
+
+/// Error context marking a failure to connect to a peer or complete the handshake,
+/// as opposed to a session that ended after it was established.
+#[derive(Debug)]
+pub struct ConnectFailed;
+
+impl std::fmt::Display for ConnectFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection failed")
+    }
+}
+
+/// Error context marking a peer that closed or reset the connection before its
+/// handshake arrived. Common and harmless: the peer is full, doesn't serve the
+/// torrent, or didn't like ours.
+#[derive(Debug)]
+pub struct HandshakeClosed;
+
+impl std::fmt::Display for HandshakeClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer closed the connection during the handshake")
+    }
+}
+
+/// Tags a failed handshake read with `HandshakeClosed` if the peer hung up.
+fn handshake_read_error(e: std::io::Error) -> anyhow::Error {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+            anyhow::Error::new(e).context(HandshakeClosed)
+        }
+        _ => e.into(),
+    }
+}
+
+/// Opens a TCP connection to `addr` (`host:port`), from the local address `bind` if
+/// given. Only target addresses of the same family as `bind` are tried, so nothing
+/// leaves through another interface.
+pub async fn tcp_connect(addr: &str, bind: Option<IpAddr>) -> std::io::Result<TcpStream> {
+    let Some(bind) = bind else {
+        return TcpStream::connect(addr).await;
+    };
+    let mut last_err = None;
+    for target in lookup_host(addr).await? {
+        if target.is_ipv4() != bind.is_ipv4() {
+            continue;
+        }
+        let socket = if bind.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(SocketAddr::new(bind, 0))?;
+        match socket.connect(target).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address reachable from {}", addr, bind),
+        )
+    }))
+}
+
+/// Connects (optionally through the proxy and MSE) and exchanges handshakes.
+async fn connect_and_handshake(
+    peer_addr: &str,
+    ctx: &PeerContext,
+) -> Result<(PeerStream, Handshake)> {
+    trace!("Connecting");
 
     // Enforce a strict connection timeout to avoid hanging on dead peers
-    let mut stream = timeout(Duration::from_secs(3), TcpStream::connect(&peer_addr))
-        .await
-        .context("Connection timed out")?
-        .context(format!("Failed to connect to peer: {}", peer_addr))?;
+    let dial = || async {
+        match &ctx.proxy {
+            Some(proxy) => proxy.connect(peer_addr, ctx.bind).await,
+            None => Ok(tcp_connect(peer_addr, ctx.bind).await?),
+        }
+    };
+    let connect = || async {
+        let started = Instant::now();
+        let stream = timeout(ctx.connect_timeout.get(), dial())
+            .await
+            .context("Connection timed out")?
+            .context(format!("Failed to connect to peer: {}", peer_addr))?;
+        ctx.connect_timeout.record_success(started.elapsed());
+        anyhow::Ok(stream)
+    };
 
-    // --- 1. Handshake ---
-    let handshake = Handshake::new(info_hash, peer_id);
-    stream.write_all(&handshake.as_bytes()).await?;
+    // --- 0. Optional MSE handshake ---
+    let mut stream =
+        mse::establish(connect, &ctx.info_hash, ctx.encryption, HANDSHAKE_TIMEOUT).await?;
 
+    // --- 1. Handshake ---
+    // A peer that accepts the connection but never answers would otherwise hang the task
+    let handshake = Handshake::new(ctx.info_hash, ctx.peer_id);
     let mut response_buf = [0u8; 68];
-    stream.read_exact(&mut response_buf).await?;
+    timeout(HANDSHAKE_TIMEOUT, async {
+        stream.write_all(&handshake.as_bytes()).await?;
+        stream.flush().await?;
+        stream
+            .read_exact(&mut response_buf)
+            .await
+            .map_err(handshake_read_error)?;
+        anyhow::Ok(())
+    })
+    .await
+    .context("Handshake timed out")??;
+
+    Ok((stream, Handshake::parse(&response_buf)?))
+}
+
+/// Reads the handshake a peer sends on an inbound connection, after completing the
+/// MSE handshake first if the peer starts with one (`info_hashes` are the torrents it
+/// may ask for).
+///
+/// The caller uses the handshake's info hash to find the torrent, then hands the
+/// connection to `run_inbound_session`.
+pub async fn read_inbound_handshake(
+    stream: TcpStream,
+    info_hashes: &[[u8; 20]],
+    encryption: EncryptionMode,
+) -> Result<(PeerStream, Handshake)> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        let mut stream = mse::accept(stream, info_hashes, encryption).await?;
+        let mut buf = [0u8; 68];
+        stream
+            .read_exact(&mut buf)
+            .await
+            .map_err(handshake_read_error)?;
+        Ok((stream, Handshake::parse(&buf)?))
+    })
+    .await
+    .context("Handshake timed out")?
+}
+
+/// Runs a session on an inbound connection whose handshake has already been read.
+/// Replies with our handshake, then proceeds exactly like an outbound session.
+pub async fn run_inbound_session(
+    mut stream: PeerStream,
+    remote: Handshake,
+    peer_addr: String,
+    ctx: PeerContext,
+) -> Result<()> {
+    let handshake = Handshake::new(ctx.info_hash, ctx.peer_id);
+    timeout(HANDSHAKE_TIMEOUT, async {
+        stream.write_all(&handshake.as_bytes()).await?;
+        stream.flush().await
+    })
+    .await
+    .context("Handshake timed out")??;
+    run_connected_session(stream, remote, peer_addr, ctx).await
+}
+
+/// The shared part of a session once both handshakes have been exchanged.
+///
+/// Log events carry the peer address through the `peer` span the caller runs this in.
+async fn run_connected_session(
+    stream: PeerStream,
+    response: Handshake,
+    peer_addr: String,
+    ctx: PeerContext,
+) -> Result<()> {
+    let PeerContext {
+        info_hash,
+        peer_id,
+        manager,
+        disk,
+        cancel,
+        upload_limit,
+        download_limit,
+        choker,
+        block_size,
+        pipeline_depth,
+        max_pieces_per_peer,
+        super_seed,
+        verify_on_seed,
+        blocked_clients,
+        proxy: _,
+        encryption: _,
+        connect_timeout: _,
+        bind: _,
+        read_timeout,
+    } = ctx;
 
     // Verify the peer is serving the correct torrent
-    if &response_buf[28..48] != info_hash {
+    if response.info_hash != info_hash {
         anyhow::bail!("Invalid Info Hash");
     }
-    // println!("{}: Handshake Successful", peer_addr);
+
+    // Trackers may hand us our own address; the echoed peer ID gives it away
+    let remote_peer_id = response.peer_id;
+    if remote_peer_id == peer_id {
+        anyhow::bail!("Self-connection detected");
+    }
+    // The same client can be reachable under several addresses (e.g. IPv4 and IPv6)
+    let mut tracker = PeerStatsTracker::new(peer_addr, &remote_peer_id);
+    let client = tracker.stats.client.to_lowercase();
+    if blocked_clients.iter().any(|name| client.starts_with(name)) {
+        anyhow::bail!("Refusing blocked client {}", tracker.stats.client);
+    }
+    if !manager.register_peer(remote_peer_id, tracker.stats.clone()) {
+        anyhow::bail!("Duplicate connection to an already connected peer");
+    }
+    // Decides which extension messages we may exchange with this peer
+    let capabilities = response.capabilities();
+    info!(
+        "Connected to {} (capabilities: {}{})",
+        tracker.stats.client,
+        capabilities,
+        if stream.is_encrypted() {
+            ", encrypted"
+        } else {
+            ""
+        }
+    );
 
     // --- 2. BitTorrent Protocol Setup ---
-    // Signal that we are interested in downloading
-    let msg = Message::Interested;
-    stream.write_all(&msg.serialize()).await?;
+    // Frames are read by a dedicated task and handed over a channel, so waiting for the
+    // next message can be raced against timers without losing a half-read frame.
+    let (mut read_half, write_half) = tokio::io::split(stream);
+    let mut peer = PeerWriter::new(write_half);
+    let (frame_tx, mut frames) = mpsc::channel(FRAME_QUEUE_DEPTH);
+    let reader = tokio::spawn(async move {
+        loop {
+            let frame = Message::read(&mut read_half).await;
+            // Throttling the reader applies backpressure to the peer through TCP
+            if let Ok(Message::Piece { block, .. }) = &frame {
+                download_limit.acquire(block.len()).await;
+            }
+            let failed = frame.is_err();
+            if frame_tx.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
 
     // --- Session State ---
     let mut am_unchoked = false;
+    // Whether we are choking the peer (refusing its requests). Every connection starts choked.
+    let mut am_choking = true;
+    // Our place in the ranking for upload slots; the peer is unchoked while it holds one
+    let choker_slot = choker.join();
+    // Requests from the peer not yet served, as `(index, begin, length)` in arrival order
+    let mut upload_queue: VecDeque<(u32, u32, u32)> = VecDeque::new();
+    // Whether the peer wants our data. Only interested peers are worth an upload slot.
+    let mut peer_interested = false;
+    // Whether we told the peer we want its data: only while it has a wanted piece we lack
+    let mut am_interested = false;
+    let mut choke_rotation = interval(CHOKE_ROTATION_INTERVAL);
 
     // Initialize local bitfield to track what the peer has
-    let piece_count = manager.lock().await.piece_status.len();
-    let mut peer_has_pieces = vec![false; piece_count];
+    let piece_count = manager.piece_count();
+    let mut peer_has_pieces = Bitfield::new(piece_count);
+    // Whether nothing but keep-alives has arrived yet; a Bitfield is only valid first
+    let mut awaiting_first_message = true;
 
     // The current piece assignment for this worker
     let mut current_work: Option<PeerSessionState> = None;
+    let mut window = RequestWindow::new(block_size, pipeline_depth);
+
+    // Super-seeding: the pieces revealed to this peer (the only ones it may request) and
+    // the one it hasn't reported having yet. Decided once, at connect time.
+    let super_seeding = super_seed && manager.is_complete();
+    let mut revealed = Bitfield::new(piece_count);
+    let mut offered: Option<usize> = None;
+    // Pieces re-hashed for this peer under `verify_on_seed`
+    let mut reverified = Bitfield::new(piece_count);
 
     // --- 3. Event Loop ---
+    // Each iteration waits on whichever comes first: the next frame from the peer, a
+    // timeout, an outbound timer, or cancellation. Frames are then handled below.
     // Wrapped in an async block to ensure cleanup runs even on error/return
+    let mut last_read = Instant::now();
     let result: Result<()> = async {
+        // Advertise what we have. The bitfield may only be sent first, right after the
+        // handshake. A super-seed pretends to have nothing and reveals pieces via Have.
+        if super_seeding {
+            if let Some(index) = manager.next_super_seed_piece(&peer_has_pieces) {
+                revealed.set(index, true);
+                offered = Some(index);
+                peer.send(&Message::Have {
+                    index: index as u32,
+                })
+                .await?;
+            }
+        } else {
+            let ours = manager.bitfield();
+            if !ours.none() {
+                peer.send(&Message::Bitfield(ours.to_bytes())).await?;
+            }
+        }
+
+        // Interest is declared once the peer's Bitfield or Haves show something we need
+
         loop {
             // Keep-Alive / Stalled Check:
             // If the peer sends nothing for 30 seconds, we assume the connection is dead.
-            let frame = match timeout(Duration::from_secs(30), Message::read(&mut stream)).await {
-                Ok(res) => res?, // Propagate protocol errors (e.g. malformed message)
-                Err(_) => {
+            // While blocks are outstanding, KeepAlives alone don't count: the wait is capped
+            // at the snub deadline, so a peer sitting on our requests is dropped and the
+            // piece released (by the cleanup below) for another peer.
+            let stall_deadline = last_read + read_timeout;
+            let snub_deadline = current_work
+                .as_ref()
+                .and_then(|state| state.snub_deadline(am_unchoked))
+                .filter(|&deadline| deadline < stall_deadline);
+
+            let frame = tokio::select! {
+                frame = frames.recv() => match frame {
+                    // Propagate protocol errors (e.g. malformed message)
+                    Some(res) => res?,
+                    None => anyhow::bail!("Reader task stopped"),
+                },
+                _ = sleep_until(snub_deadline.unwrap_or(stall_deadline)) => {
+                    if snub_deadline.is_some() {
+                        anyhow::bail!("Peer snubbed us (no blocks for {:?})", SNUB_TIMEOUT);
+                    }
                     return Err(anyhow::anyhow!("Connection timed out (Stalled)"));
                 }
+                // Stay visible to the peer while we're choked or have nothing to ask for
+                _ = sleep_until(peer.last_write + KEEPALIVE_INTERVAL) => {
+                    peer.send(&Message::KeepAlive).await?;
+                    continue;
+                }
+                // Queued requests are served one block per turn, so frames (a Cancel in
+                // particular) are still handled while a long queue drains. Frames already
+                // received go first: a Cancel right behind its Request always wins.
+                _ = std::future::ready(()), if !upload_queue.is_empty() && frames.is_empty() => {
+                    // Out of upload quota: choke rather than leave requests hanging
+                    if manager.quota.upload_exhausted() {
+                        peer.send(&Message::Choke).await?;
+                        am_choking = true;
+                        choker_slot.withdraw();
+                        upload_queue.clear();
+                        tracker.stats.am_choking = true;
+                        continue;
+                    }
+                    let (index, begin, length) = upload_queue.pop_front().unwrap();
+                    // Only pieces we have fully validated (and, when super-seeding,
+                    // revealed to this peer) are served
+                    if !manager.has_piece(index as usize)
+                        || (super_seeding && !revealed.get(index as usize))
+                    {
+                        continue;
+                    }
+                    let piece_len = manager.torrent.calculate_piece_size(index as usize)? as u64;
+
+                    // Read off the runtime so a slow disk doesn't stall other sessions
+                    let reader_manager = manager.clone();
+                    let read = tokio::task::spawn_blocking(move || {
+                        reader_manager.read_piece_from_disk(index as usize, piece_len)
+                    })
+                    .await?;
+                    if let Ok(buffer) = read
+                        && (!verify_on_seed
+                            || reverified.get(index as usize)
+                            || piece_still_valid(&manager, index as usize, &buffer)?)
+                    {
+                        reverified.set(index as usize, true);
+                        let start = begin as usize;
+                        let end = start + length as usize;
+
+                        if end <= buffer.len() {
+                            let response = Message::Piece {
+                                index,
+                                begin,
+                                block: buffer[start..end].to_vec(),
+                            };
+
+                            upload_limit.acquire(length as usize).await;
+                            peer.send(&response).await?;
+                            manager.record_upload(length as usize);
+                            tracker.stats.uploaded += length as u64;
+                            trace!(piece = index, "Uploaded {} bytes", length);
+                        }
+                    }
+                    continue;
+                }
+                _ = choke_rotation.tick() => {
+                    // Peers are ranked by what they give us; a seed ranks them by what
+                    // they take instead, as nobody uploads to it
+                    let rate = if manager.is_complete() {
+                        tracker.stats.upload_rate
+                    } else {
+                        tracker.stats.download_rate
+                    };
+                    let wants_slot = peer_interested && !manager.quota.upload_exhausted();
+                    let unchoke = choker_slot.tick(wants_slot, rate);
+                    if unchoke && am_choking {
+                        peer.send(&Message::Unchoke).await?;
+                        am_choking = false;
+                    } else if !unchoke && !am_choking {
+                        // Lost the slot to a faster peer or the optimistic rotation
+                        peer.send(&Message::Choke).await?;
+                        am_choking = true;
+                        upload_queue.clear();
+                    }
+                    // Our own progress (or a pause, or new file priorities) can end our
+                    // interest; a seed is interested in nobody
+                    peer.update_interest(&mut am_interested, manager.wants_any(&peer_has_pieces))
+                        .await?;
+                    tracker.stats.am_choking = am_choking;
+                    tracker.stats.am_interested = am_interested;
+                    tracker.publish(&manager, &remote_peer_id);
+                    continue;
+                }
+                _ = cancel.cancelled() => return Ok(()),
             };
+            last_read = Instant::now();
+            let is_first_message = awaiting_first_message;
+            if frame != Message::KeepAlive {
+                awaiting_first_message = false;
+            }
+            let choke_state_changed = matches!(
+                frame,
+                Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested
+            );
 
             match frame {
                 Message::Choke => {
-                    // println!("{}: Choked", peer_addr);
+                    debug!("Choked");
                     am_unchoked = false;
+                    // A choking peer discards our queued requests. Cancel them in case it
+                    // doesn't, and rewind so they are requested again after the unchoke.
+                    if let Some(state) = &mut current_work {
+                        for &(begin, length) in &state.pending {
+                            peer.send(&Message::Cancel {
+                                index: state.piece_index as u32,
+                                begin,
+                                length,
+                            })
+                            .await?;
+                        }
+                        let pending = std::mem::take(&mut state.pending);
+                        state.dropped.extend(pending);
+                    }
+                    window.on_choke();
                 }
                 Message::Unchoke => {
-                    // println!("{}: Unchoked", peer_addr);
+                    debug!("Unchoked");
                     am_unchoked = true;
+                    // Time spent choked doesn't count towards the snub timeout
+                    if let Some(state) = &mut current_work {
+                        state.last_block_at = Instant::now();
+                    }
+                }
+                Message::Interested => peer_interested = true,
+                Message::NotInterested => {
+                    peer_interested = false;
+                    // Free the slot right away rather than at the next rotation. Choking
+                    // discards the peer's queued requests, as it expects.
+                    choker_slot.withdraw();
+                    if !am_choking {
+                        peer.send(&Message::Choke).await?;
+                        am_choking = true;
+                        upload_queue.clear();
+                    }
                 }
-                Message::Interested => {}
-                Message::NotInterested => {}
 
                 // Update Peer Bitfield
                 Message::Have { index } => {
-                    if (index as usize) < peer_has_pieces.len() {
-                        peer_has_pieces[index as usize] = true;
+                    peer_has_pieces.set(index as usize, true);
+                    // A new piece can only start our interest, so only it is checked
+                    if !am_interested && manager.wants_piece(index as usize) {
+                        peer.update_interest(&mut am_interested, true).await?;
+                        tracker.stats.am_interested = true;
                     }
                 }
                 Message::Bitfield(bitfield) => {
-                    for (i, byte) in bitfield.iter().enumerate() {
-                        for bit in 0..8 {
-                            let piece_idx = i * 8 + bit;
-                            if piece_idx < peer_has_pieces.len() && (byte & (1 << (7 - bit))) != 0 {
-                                peer_has_pieces[piece_idx] = true;
-                            }
-                        }
+                    if is_first_message {
+                        peer_has_pieces = Bitfield::from_bytes(&bitfield, piece_count)
+                            .context("Peer sent an invalid bitfield")?;
+                        peer.update_interest(
+                            &mut am_interested,
+                            manager.wants_any(&peer_has_pieces),
+                        )
+                        .await?;
+                        tracker.stats.am_interested = am_interested;
+                    } else {
+                        // It would overwrite what earlier Haves told us
+                        debug!("Ignoring a bitfield that wasn't the first message");
                     }
                 }
 
@@ -123,151 +810,946 @@ pub async fn run_peer_session(
                     block,
                 } => {
                     if let Some(state) = &mut current_work {
-                        // Ensure this block belongs to the piece we are currently downloading
-                        if state.piece_index == index as usize {
+                        // Only a block we have outstanding for the current piece is taken;
+                        // our requests always lie within the piece, so this bounds it too
+                        if state.accept(index, begin, block.len()) {
                             let begin_usize = begin as usize;
+                            state.piece_buffer[begin_usize..begin_usize + block.len()]
+                                .copy_from_slice(&block);
+                            manager.record_download(block.len());
+                            tracker.stats.downloaded += block.len() as u64;
+                            state.downloaded += block.len() as u32;
+                            state.last_block_at = Instant::now();
+                            window.on_block(block.len());
+
+                            // Check if the piece is fully assembled
+                            if state.downloaded == state.piece_length {
+                                // Verify Integrity (SHA-1)
+                                let mut hasher = Sha1::new();
+                                hasher.update(&state.piece_buffer);
+                                let actual_hash: [u8; 20] = hasher.finalize().into();
+
+                                let expected_hash =
+                                    manager.torrent.get_piece_hash(state.piece_index)?;
 
-                            // Bounds check to prevent buffer overflow attacks
-                            if begin_usize + block.len() <= state.piece_buffer.len() {
-                                state.piece_buffer[begin_usize..begin_usize + block.len()]
-                                    .copy_from_slice(&block);
-                                state.downloaded += block.len() as u32;
-
-                                // Check if the piece is fully assembled
-                                if state.downloaded == state.piece_length {
-                                    // Verify Integrity (SHA-1)
-                                    let mut hasher = Sha1::new();
-                                    hasher.update(&state.piece_buffer);
-                                    let actual_hash: [u8; 20] = hasher.finalize().into();
-
-                                    let mut m = manager.lock().await;
-                                    let expected_hash =
-                                        m.torrent.get_piece_hash(state.piece_index)?;
-
-                                    if actual_hash == expected_hash {
-                                        // println!("{}: Piece {} Verified!", peer_addr, state.piece_index);
-                                        m.mark_piece_complete(state.piece_index);
-
-                                        // Delegate writing to Manager (Single Source of Truth for file I/O)
-                                        if let Err(e) = m.write_piece_to_disk(
-                                            state.piece_index,
-                                            &state.piece_buffer,
-                                        ) {
-                                            println!("Disk Write Failed: {}", e);
-                                        }
-
-                                        current_work = None;
-                                    } else {
-                                        println!(
-                                            "{}: Piece {} Hash Mismatch",
-                                            peer_addr, state.piece_index
-                                        );
-                                        // Failed hash check -> Release piece for re-download
-                                        m.reset_piece(state.piece_index);
-                                        current_work = None;
-                                    }
+                                if actual_hash == expected_hash {
+                                    debug!(piece = state.piece_index, "Piece verified");
+
+                                    // Delegate writing to the disk-writer task, which marks
+                                    // the piece complete once it is safely on disk.
+                                    let buffer = std::mem::take(&mut state.piece_buffer);
+                                    disk.write(state.piece_index, buffer).await?;
+
+                                    current_work = None;
+                                } else {
+                                    warn!(piece = state.piece_index, "Hash mismatch");
+                                    // Failed hash check -> Release piece for re-download
+                                    manager.reset_piece(state.piece_index);
+                                    current_work = None;
                                 }
                             }
+                        } else {
+                            debug!(piece = index, begin, "Ignoring a block we didn't request");
                         }
                     }
                 }
 
-                // SEEDING LOGIC: Respond to requests from the peer
+                // SEEDING LOGIC: Queue requests from the peer; they are served above
                 Message::Request {
                     index,
                     begin,
                     length,
                 } => {
-                    let m = manager.lock().await;
-
-                    // Only serve pieces we have fully validated
-                    if m.piece_status.get(index as usize)
-                        == Some(&crate::core::manager::PieceStatus::Complete)
-                    {
-                        let piece_len = m.torrent.calculate_piece_size(index as usize) as u64;
-
-                        // Read directly from disk
-                        if let Ok(buffer) =
-                            m.read_piece_from_disk(index as usize, piece_len, "downloads")
-                        {
-                            let start = begin as usize;
-                            let end = start + length as usize;
-
-                            if end <= buffer.len() {
-                                let block_data = buffer[start..end].to_vec();
-                                let response = Message::Piece {
-                                    index,
-                                    begin,
-                                    block: block_data,
-                                };
-
-                                // Release lock before network I/O
-                                drop(m);
-                                stream.write_all(&response.serialize()).await?;
-                                // println!("Uploaded {} bytes to {}", length, peer_addr);
-                            }
-                        }
+                    // Requests from a choked peer are discarded, as the protocol expects
+                    if am_choking {
+                        debug!(piece = index, begin, "Ignoring a request while choking");
+                    } else if upload_queue.len() >= MAX_QUEUED_UPLOADS {
+                        debug!(piece = index, begin, "Upload queue full, dropping request");
+                    } else {
+                        upload_queue.push_back((index, begin, length));
                     }
                 }
+                // A queued request that hasn't been served yet is simply dropped
+                Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => upload_queue.retain(|&queued| queued != (index, begin, length)),
                 Message::KeepAlive => {}
             }
 
+            if choke_state_changed {
+                tracker.stats.am_choking = am_choking;
+                tracker.stats.peer_choking = !am_unchoked;
+                tracker.stats.peer_interested = peer_interested;
+                tracker.publish(&manager, &remote_peer_id);
+            }
+
+            // Super-seeding: once the peer has the piece we revealed (a Have, or its
+            // bitfield showing it already had it), hand out the next one
+            if let Some(index) = offered
+                && peer_has_pieces.get(index)
+            {
+                offered = manager.next_super_seed_piece(&peer_has_pieces);
+                if let Some(next) = offered {
+                    revealed.set(next, true);
+                    peer.send(&Message::Have { index: next as u32 }).await?;
+                }
+            }
+
             // --- WORK ASSIGNMENT STRATEGY ---
             // If we are ready to download (unchoked + idle), ask the Manager for a new piece.
             if am_unchoked && current_work.is_none() {
-                let mut m = manager.lock().await;
-                // Only pick a piece that this specific peer actually has
-                if let Some(index) = m.pick_next_piece(&peer_has_pieces) {
-                    let piece_len = m.torrent.calculate_piece_size(index);
-                    drop(m); // Unlock ASAP
+                // Only pick a piece that this specific peer actually has. The session
+                // works on one piece at a time, so it holds none at this point.
+                if let Some(index) =
+                    manager.pick_next_piece_for(&peer_has_pieces, 0, max_pieces_per_peer)
+                {
+                    let piece_len = manager.torrent.calculate_piece_size(index)?;
+
+                    // Carry on from the blocks an earlier session (or run) received
+                    let mut piece_buffer = vec![0u8; piece_len as usize];
+                    let resumed = match manager.take_partial_piece(index) {
+                        Some(received) if received.len() < piece_buffer.len() => {
+                            piece_buffer[..received.len()].copy_from_slice(&received);
+                            received.len() as u32
+                        }
+                        _ => 0,
+                    };
 
-                    // println!("{}: Starting Piece {}", peer_addr, index);
+                    debug!(piece = index, resumed, "Starting piece");
 
                     // Initialize state for the new piece
-                    current_work = Some(PeerSessionState {
-                        piece_index: index,
-                        piece_buffer: vec![0u8; piece_len as usize],
-                        downloaded: 0,
-                        requested: 0,
-                        piece_length: piece_len,
-                    });
-                } else {
-                    drop(m);
-                    // No pieces available that this peer has (or we are done)
+                    current_work = Some(PeerSessionState::new(index, piece_buffer, resumed));
                 }
+                // Otherwise no pieces are available that this peer has (or we are done)
             }
 
             // --- PIPELINING REQUESTS ---
-            // To maximize throughput, we keep up to 5 blocks (approx 80KB) "in flight" at once.
+            // To maximize throughput, we keep the request window's worth of bytes
+            // "in flight" at once (see `RequestWindow`).
             if let Some(state) = &mut current_work {
-                while am_unchoked
-                    && state.requested < state.piece_length
-                    && (state.requested - state.downloaded) < (BLOCK_MAX * 5)
-                {
-                    let remaining = state.piece_length - state.requested;
-                    let block_size = std::cmp::min(BLOCK_MAX, remaining);
-
+                while am_unchoked && state.outstanding() < window.target() {
+                    let Some((begin, length)) = state.next_request(block_size) else {
+                        break;
+                    };
                     let request = Message::Request {
                         index: state.piece_index as u32,
-                        begin: state.requested,
-                        length: block_size,
+                        begin,
+                        length,
                     };
-                    stream.write_all(&request.serialize()).await?;
-                    state.requested += block_size;
+                    peer.send(&request).await?;
+                    window.on_request();
+                    state.pending.push((begin, length));
                 }
             }
         }
     }
     .await;
 
+    reader.abort();
+
     // --- FAILURE CLEANUP ---
     // If the connection drops while we were working on a piece, we MUST release it
-    // so another peer can pick it up.
+    // so another peer can pick it up. The blocks received so far are handed back too.
+    // Done before unregistering, which shutdown waits for before saving them.
     if let Some(state) = current_work {
-        // println!("{}: Connection died. Releasing Piece {}", peer_addr, state.piece_index);
-        let mut m = manager.lock().await;
-        m.reset_piece(state.piece_index);
+        debug!(
+            piece = state.piece_index,
+            "Connection closed. Releasing piece"
+        );
+        let prefix = state.received_prefix();
+        let mut received = state.piece_buffer;
+        received.truncate(prefix as usize);
+        manager.release_piece(state.piece_index, received);
     }
+    manager.unregister_peer(&remote_peer_id);
 
+    if let Err(e) = &result
+        && e.chain().any(|cause| cause.is::<InvalidMetadata>())
+    {
+        manager.report_invalid_metadata(e);
+    }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::manager::TorrentEvent;
+    use crate::core::storage::StorageOptions;
+    use crate::core::test_util::manager_for;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Piece length of the test torrent: two default blocks.
+    const PIECE: usize = 2 * DEFAULT_BLOCK_SIZE as usize;
+
+    /// The mock peer's ID.
+    const REMOTE_ID: [u8; 20] = *b"-XX0001-mockpeer0000";
+
+    /// `pieces` pieces of patterned data.
+    fn content(pieces: usize) -> Vec<u8> {
+        (0..pieces * PIECE).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// A torrent of `data` (with real piece hashes) downloading into a fresh directory.
+    fn manager(data: &[u8]) -> (TempDir, Arc<TorrentManager>) {
+        let dir = TempDir::new().unwrap();
+        let manager = manager_for(dir.path(), data, PIECE);
+        (dir, manager)
+    }
+
+    /// Like `manager`, with every piece already on disk and verified.
+    fn seeding(data: &[u8]) -> (TempDir, Arc<TorrentManager>) {
+        let (dir, manager) = manager(data);
+        for (index, piece) in data.chunks(PIECE).enumerate() {
+            manager.storage.write_piece(index, piece).unwrap();
+            manager.mark_piece_complete(index);
+        }
+        (dir, manager)
+    }
+
+    fn context(manager: &Arc<TorrentManager>) -> PeerContext {
+        PeerContext {
+            info_hash: manager.torrent.info_hash().unwrap(),
+            peer_id: [1; 20],
+            manager: manager.clone(),
+            disk: DiskWriter::spawn(manager.clone()),
+            cancel: CancellationToken::new(),
+            upload_limit: Arc::new(RateLimiter::new(0)),
+            download_limit: Arc::new(RateLimiter::new(0)),
+            choker: Arc::new(Choker::new(0)),
+            proxy: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            pipeline_depth: None,
+            max_pieces_per_peer: None,
+            super_seed: false,
+            verify_on_seed: false,
+            blocked_clients: Vec::new(),
+            encryption: EncryptionMode::Disable,
+            connect_timeout: Arc::new(ConnectTimeout::new(None)),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            bind: None,
+        }
+    }
+
+    /// The far end of a session under test.
+    struct MockPeer {
+        stream: TcpStream,
+    }
+
+    impl MockPeer {
+        async fn send(&mut self, msg: &Message) {
+            self.stream.write_all(&msg.serialize()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Message {
+            timeout(Duration::from_secs(300), Message::read(&mut self.stream))
+                .await
+                .expect("the session went quiet")
+                .unwrap()
+        }
+
+        /// Answers requests with blocks of `data` until `blocks` have been sent.
+        async fn serve(&mut self, data: &[u8], blocks: usize) {
+            let mut sent = 0;
+            while sent < blocks {
+                if let Message::Request {
+                    index,
+                    begin,
+                    length,
+                } = self.recv().await
+                {
+                    let start = index as usize * PIECE + begin as usize;
+                    let block = data[start..start + length as usize].to_vec();
+                    self.send(&Message::Piece {
+                        index,
+                        begin,
+                        block,
+                    })
+                    .await;
+                    sent += 1;
+                }
+            }
+        }
+
+        /// Whether the session sends nothing for a while. Only for when nothing is
+        /// expected: a message cut off by the timeout is lost.
+        async fn is_quiet(&mut self) -> bool {
+            timeout(Duration::from_millis(300), Message::read(&mut self.stream))
+                .await
+                .is_err()
+        }
+
+        /// Reads until a message matches, returning it.
+        async fn recv_until(&mut self, wanted: impl Fn(&Message) -> bool) -> Message {
+            loop {
+                let msg = self.recv().await;
+                if wanted(&msg) {
+                    return msg;
+                }
+            }
+        }
+    }
+
+    /// Starts an outbound session to a mock peer and reads our handshake, leaving
+    /// the reply to the caller. Returns the raw connection and the session's task.
+    async fn accept(ctx: PeerContext) -> (TcpStream, JoinHandle<Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let info_hash = ctx.info_hash;
+        let session = tokio::spawn(run_peer_session(addr, ctx));
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(Handshake::parse(&handshake).unwrap().info_hash, info_hash);
+        (stream, session)
+    }
+
+    /// Starts an outbound session to a mock peer and completes the handshake with
+    /// `remote_id`. Returns the peer and the session's task.
+    async fn connect_as(
+        ctx: PeerContext,
+        remote_id: [u8; 20],
+    ) -> (MockPeer, JoinHandle<Result<()>>) {
+        let info_hash = ctx.info_hash;
+        let (mut stream, session) = accept(ctx).await;
+        stream
+            .write_all(&Handshake::new(info_hash, remote_id).as_bytes())
+            .await
+            .unwrap();
+        (MockPeer { stream }, session)
+    }
+
+    async fn connect(ctx: PeerContext) -> (MockPeer, JoinHandle<Result<()>>) {
+        connect_as(ctx, REMOTE_ID).await
+    }
+
+    /// A bitfield with every piece set.
+    fn seed_bitfield(manager: &TorrentManager) -> Message {
+        let mut bitfield = Bitfield::new(manager.piece_count());
+        for index in 0..manager.piece_count() {
+            bitfield.set(index, true);
+        }
+        Message::Bitfield(bitfield.to_bytes())
+    }
+
+    fn is_request(msg: &Message) -> bool {
+        matches!(msg, Message::Request { .. })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snubbing_peer_releases_its_piece() {
+        let (_dir, manager) = manager(&content(1));
+        let (mut peer, session) = connect(context(&manager)).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+        peer.recv_until(is_request).await;
+
+        // KeepAlives keep the connection alive, but no block ever comes
+        let keepalives = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                if peer
+                    .stream
+                    .write_all(&Message::KeepAlive.serialize())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        let error = session.await.unwrap().unwrap_err();
+        assert!(
+            error.to_string().starts_with("Peer snubbed us"),
+            "{}",
+            error
+        );
+        keepalives.abort();
+
+        // The piece is free for another peer
+        let mut all = Bitfield::new(1);
+        all.set(0, true);
+        assert_eq!(manager.pick_next_piece(&all), Some(0));
+        assert_eq!(manager.peer_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_session_sends_a_keepalive() {
+        let (_dir, manager) = manager(&content(1));
+        let ctx = PeerContext {
+            read_timeout: 2 * KEEPALIVE_INTERVAL,
+            ..context(&manager)
+        };
+        let (mut peer, session) = connect(ctx).await;
+        let connected = Instant::now();
+
+        // We have nothing and the peer never says what it has: the only thing to send
+        // is a KeepAlive once the connection has been quiet for the interval
+        assert_eq!(peer.recv().await, Message::KeepAlive);
+        let quiet = connected.elapsed();
+        assert!(
+            quiet >= KEEPALIVE_INTERVAL - Duration::from_secs(1),
+            "{:?}",
+            quiet
+        );
+        assert!(
+            quiet < KEEPALIVE_INTERVAL + Duration::from_secs(5),
+            "{:?}",
+            quiet
+        );
+        assert!(!session.is_finished());
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn downloads_until_complete_then_stops_on_cancel() {
+        let data = content(2);
+        let (_dir, manager) = manager(&data);
+        let mut events = manager.subscribe();
+        let ctx = context(&manager);
+        let cancel = ctx.cancel.clone();
+        let (mut peer, session) = connect(ctx).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+        peer.serve(&data, 4).await;
+
+        timeout(Duration::from_secs(10), async {
+            while !matches!(events.recv().await, Ok(TorrentEvent::Completed)) {}
+        })
+        .await
+        .unwrap();
+        assert!(manager.is_complete());
+        assert_eq!(
+            manager.read_piece_from_disk(1, PIECE as u64).unwrap(),
+            data[PIECE..]
+        );
+
+        // Cancelling ends the session cleanly, not with an error
+        cancel.cancel();
+        timeout(Duration::from_secs(5), session)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(manager.peer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn half_complete_piece_resumes_after_a_restart() {
+        let data = content(1);
+        let block = DEFAULT_BLOCK_SIZE as usize;
+        let (dir, earlier) = manager(&data);
+        earlier.release_piece(0, data[..block].to_vec());
+        earlier.save_partial_pieces().await;
+
+        // A new run over the same directory picks up the saved first block
+        let options = StorageOptions {
+            output_dir: dir.path().to_string_lossy().into_owned(),
+            ..StorageOptions::default()
+        };
+        let manager = Arc::new(TorrentManager::new(earlier.torrent.clone(), &options));
+        manager.verify_existing_data().unwrap();
+        let mut events = manager.subscribe();
+        let (mut peer, _session) = connect(context(&manager)).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+
+        let Message::Request {
+            index: 0,
+            begin,
+            length,
+        } = peer.recv_until(is_request).await
+        else {
+            panic!("expected a request for piece 0");
+        };
+        assert_eq!((begin as usize, length as usize), (block, block));
+        peer.send(&Message::Piece {
+            index: 0,
+            begin,
+            block: data[block..].to_vec(),
+        })
+        .await;
+
+        timeout(Duration::from_secs(10), async {
+            while !matches!(events.recv().await, Ok(TorrentEvent::Completed)) {}
+        })
+        .await
+        .unwrap();
+        assert_eq!(manager.read_piece_from_disk(0, PIECE as u64).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn duplicate_block_is_counted_once() {
+        let data = content(1);
+        let block = DEFAULT_BLOCK_SIZE as usize;
+        let (_dir, manager) = manager(&data);
+        let mut events = manager.subscribe();
+        let (mut peer, _session) = connect(context(&manager)).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+        peer.recv_until(is_request).await;
+
+        let first = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: data[..block].to_vec(),
+        };
+        peer.send(&first).await;
+        peer.send(&first).await;
+        peer.send(&Message::Piece {
+            index: 0,
+            begin: block as u32,
+            block: data[block..].to_vec(),
+        })
+        .await;
+
+        timeout(Duration::from_secs(10), async {
+            while !matches!(events.recv().await, Ok(TorrentEvent::Completed)) {}
+        })
+        .await
+        .unwrap();
+        assert_eq!(manager.downloaded(), PIECE as u64);
+        assert_eq!(manager.read_piece_from_disk(0, PIECE as u64).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn not_interested_once_the_peer_has_nothing_we_need() {
+        let data = content(2);
+        let (_dir, manager) = manager(&data);
+        manager.storage.write_piece(0, &data[..PIECE]).unwrap();
+        manager.mark_piece_complete(0);
+        let mut events = manager.subscribe();
+        let (mut peer, _session) = connect(context(&manager)).await;
+
+        // The peer has only piece 1, which we still need
+        peer.send(&Message::Bitfield(vec![0b0100_0000])).await;
+        peer.recv_until(|msg| matches!(msg, Message::Interested))
+            .await;
+        peer.send(&Message::Unchoke).await;
+        peer.serve(&data, 2).await;
+        timeout(Duration::from_secs(10), async {
+            while !matches!(events.recv().await, Ok(TorrentEvent::Completed)) {}
+        })
+        .await
+        .unwrap();
+
+        // Interest is re-checked at the next choke rotation; skip ahead to it
+        tokio::time::pause();
+        peer.recv_until(|msg| matches!(msg, Message::NotInterested))
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn uninterested_peer_stays_choked() {
+        let (_dir, manager) = manager(&content(1));
+        // No limit on upload slots: only interest decides
+        let (mut peer, session) = connect(context(&manager)).await;
+        peer.send(&Message::NotInterested).await;
+
+        let unchoke = |msg: &Message| *msg == Message::Unchoke;
+        let waited = timeout(2 * CHOKE_ROTATION_INTERVAL, peer.recv_until(unchoke)).await;
+        assert!(waited.is_err(), "unchoked without interest");
+
+        peer.send(&Message::Interested).await;
+        timeout(2 * CHOKE_ROTATION_INTERVAL, peer.recv_until(unchoke))
+            .await
+            .unwrap();
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn unanswered_connect_fails_within_the_connect_timeout() {
+        let (_dir, manager) = manager(&content(1));
+        // A proxy that accepts but never replies leaves the dial hanging
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("socks5://{}", proxy.local_addr().unwrap());
+        let ctx = PeerContext {
+            proxy: Some(ProxyConfig::parse(&url).unwrap()),
+            connect_timeout: Arc::new(ConnectTimeout::new(Some(Duration::from_secs(1)))),
+            ..context(&manager)
+        };
+        let started = Instant::now();
+        let session = tokio::spawn(run_peer_session("127.0.0.1:1".to_string(), ctx));
+        let _held = proxy.accept().await.unwrap();
+        let error = session.await.unwrap().unwrap_err();
+        assert!(format!("{:#}", error).contains("Connection timed out"));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn self_connection_is_refused() {
+        let (_dir, manager) = manager(&content(1));
+        let ctx = context(&manager);
+        let our_id = ctx.peer_id;
+        let (_peer, session) = connect_as(ctx, our_id).await;
+        let error = session.await.unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "Self-connection detected");
+        assert_eq!(manager.peer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn blocked_clients_are_refused_by_their_peer_id() {
+        let (_dir, manager) = manager(&content(1));
+        let peer_id = |prefix: &[u8]| {
+            let mut id: [u8; 20] = rand::random();
+            id[..prefix.len()].copy_from_slice(prefix);
+            id
+        };
+        let blocking = || PeerContext {
+            blocked_clients: vec!["qbittorrent".to_string(), "µtorrent".to_string()],
+            ..context(&manager)
+        };
+        for prefix in [&b"-qB4620-"[..], b"-UT355W-"] {
+            let (_peer, session) = connect_as(blocking(), peer_id(prefix)).await;
+            let error = session.await.unwrap().unwrap_err();
+            assert!(error.to_string().starts_with("Refusing blocked client"));
+        }
+        assert_eq!(manager.peer_count(), 0);
+
+        let (mut peer, session) = connect_as(blocking(), peer_id(b"-TR3000-")).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.recv_until(|msg| matches!(msg, Message::Interested))
+            .await;
+        assert_eq!(manager.peer_count(), 1);
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn second_connection_to_a_peer_is_refused() {
+        let (_dir, manager) = manager(&content(1));
+        let (_first, first_session) = connect(context(&manager)).await;
+        let (_second, second_session) = connect(context(&manager)).await;
+        let error = second_session.await.unwrap().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Duplicate connection to an already connected peer"
+        );
+        assert_eq!(manager.peer_count(), 1);
+        assert!(!first_session.is_finished());
+        first_session.abort();
+    }
+
+    #[tokio::test]
+    async fn peer_closing_before_its_handshake_is_classified() {
+        let (_dir, manager) = manager(&content(1));
+        let (stream, session) = accept(context(&manager)).await;
+        drop(stream);
+        let error = session.await.unwrap().unwrap_err();
+        assert!(
+            error.downcast_ref::<HandshakeClosed>().is_some(),
+            "{:#}",
+            error
+        );
+
+        // Half a handshake, then gone, counts the same
+        let (mut stream, session) = accept(context(&manager)).await;
+        stream.write_all(&[19; 30]).await.unwrap();
+        drop(stream);
+        let error = session.await.unwrap().unwrap_err();
+        assert!(
+            error.downcast_ref::<HandshakeClosed>().is_some(),
+            "{:#}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_protocol_string_fails_the_handshake() {
+        let (_dir, manager) = manager(&content(1));
+        let ctx = context(&manager);
+        let mut reply = Handshake::new(ctx.info_hash, REMOTE_ID).as_bytes();
+        reply[1..20].copy_from_slice(b"NotTorrent protocol");
+        let (mut stream, session) = accept(ctx).await;
+        stream.write_all(&reply).await.unwrap();
+        let error = session.await.unwrap().unwrap_err();
+        assert!(error.is::<ConnectFailed>());
+        assert_eq!(
+            error.root_cause().to_string(),
+            "Invalid handshake: unknown protocol identifier"
+        );
+        assert_eq!(manager.peer_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_times_out_the_handshake() {
+        let (_dir, manager) = manager(&content(1));
+        let (_stream, session) = accept(context(&manager)).await;
+        let started = Instant::now();
+        let error = session.await.unwrap().unwrap_err();
+        assert!(error.is::<ConnectFailed>());
+        assert!(format!("{:#}", error).contains("Handshake timed out"));
+        assert!(started.elapsed() <= HANDSHAKE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn outstanding_requests_stay_within_the_pipeline_depth() {
+        let data = content(1);
+        let (_dir, manager) = manager(&data);
+        let mut ctx = context(&manager);
+        ctx.block_size = 1024;
+        ctx.pipeline_depth = Some(3);
+        let (mut peer, session) = connect(ctx).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+
+        let mut outstanding = Vec::new();
+        while outstanding.len() < 3 {
+            outstanding.push(peer.recv_until(is_request).await);
+        }
+        assert!(peer.is_quiet().await, "a fourth request was sent");
+
+        // Each block answered frees room for exactly one more request
+        let Message::Request {
+            index,
+            begin,
+            length,
+        } = outstanding.remove(0)
+        else {
+            unreachable!()
+        };
+        let start = begin as usize;
+        let block = data[start..start + length as usize].to_vec();
+        peer.send(&Message::Piece {
+            index,
+            begin,
+            block,
+        })
+        .await;
+        let next = peer.recv_until(is_request).await;
+        assert!(matches!(
+            next,
+            Message::Request {
+                begin: 3072,
+                length: 1024,
+                ..
+            }
+        ));
+        assert!(peer.is_quiet().await, "more than one request followed");
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn super_seed_reveals_the_next_piece_once_the_last_is_reported() {
+        let (_dir, manager) = seeding(&content(3));
+        let mut ctx = context(&manager);
+        ctx.super_seed = true;
+        let (mut peer, session) = connect(ctx).await;
+
+        // No bitfield: a single piece is revealed, and nothing more for now
+        let Message::Have { index: first } = peer.recv().await else {
+            panic!("expected a Have");
+        };
+        assert!(peer.is_quiet().await);
+
+        // Reporting some other piece doesn't count
+        let other = (first + 1) % 3;
+        peer.send(&Message::Have { index: other }).await;
+        assert!(peer.is_quiet().await);
+
+        peer.send(&Message::Have { index: first }).await;
+        let Message::Have { index: next } = peer.recv().await else {
+            panic!("expected a Have");
+        };
+        assert_eq!(next, 3 - first - other);
+        assert!(peer.is_quiet().await);
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn choke_mid_piece_rerequests_the_missing_blocks() {
+        let data = content(1);
+        let (_dir, manager) = manager(&data);
+        let mut ctx = context(&manager);
+        ctx.block_size = 1024;
+        ctx.pipeline_depth = Some(4);
+        let (mut peer, session) = connect(ctx).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+
+        let begin_of = |msg: Message| match msg {
+            Message::Request { begin, .. } | Message::Cancel { begin, .. } => begin,
+            other => panic!("unexpected {:?}", other),
+        };
+        let mut requested = Vec::new();
+        while requested.len() < 4 {
+            requested.push(begin_of(peer.recv_until(is_request).await));
+        }
+        assert_eq!(requested, [0, 1024, 2048, 3072]);
+        peer.send(&Message::Piece {
+            index: 0,
+            begin: 0,
+            block: data[..1024].to_vec(),
+        })
+        .await;
+        assert_eq!(begin_of(peer.recv_until(is_request).await), 4096);
+
+        // Everything still outstanding is cancelled, then asked for again, first
+        peer.send(&Message::Choke).await;
+        let mut cancelled = Vec::new();
+        while cancelled.len() < 4 {
+            let msg = peer
+                .recv_until(|m| matches!(m, Message::Cancel { .. }))
+                .await;
+            cancelled.push(begin_of(msg));
+        }
+        assert_eq!(cancelled, [1024, 2048, 3072, 4096]);
+        peer.send(&Message::Unchoke).await;
+        let mut again = Vec::new();
+        while again.len() < 4 {
+            again.push(begin_of(peer.recv_until(is_request).await));
+        }
+        assert_eq!(again, cancelled);
+
+        // The rest completes the piece without asking for block 0 again
+        let mut outstanding: VecDeque<u32> = again.into();
+        let mut served = vec![0];
+        while let Some(begin) = outstanding.pop_front() {
+            let start = begin as usize;
+            peer.send(&Message::Piece {
+                index: 0,
+                begin,
+                block: data[start..start + 1024].to_vec(),
+            })
+            .await;
+            served.push(begin);
+            if served.len() + outstanding.len() < PIECE / 1024 {
+                outstanding.push_back(begin_of(peer.recv_until(is_request).await));
+            }
+        }
+        served.sort();
+        assert_eq!(served, (0..PIECE as u32).step_by(1024).collect::<Vec<_>>());
+        timeout(Duration::from_secs(5), async {
+            while !manager.has_piece(0) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn too_long_bitfield_ends_the_session() {
+        let (_dir, manager) = manager(&content(2));
+        let (mut peer, session) = connect(context(&manager)).await;
+        peer.send(&Message::Bitfield(vec![0xc0, 0])).await;
+        let error = session.await.unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "Peer sent an invalid bitfield");
+        assert_eq!(manager.peer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn bitfield_after_have_is_ignored() {
+        let (_dir, manager) = manager(&content(2));
+        let (mut peer, session) = connect(context(&manager)).await;
+        peer.send(&Message::Have { index: 1 }).await;
+        peer.send(&seed_bitfield(&manager)).await;
+        peer.send(&Message::Unchoke).await;
+
+        // Only the Have counts: piece 0 is never asked for
+        assert_eq!(
+            peer.recv_until(|m| matches!(m, Message::Interested)).await,
+            Message::Interested
+        );
+        for _ in 0..PIECE / DEFAULT_BLOCK_SIZE as usize {
+            let Message::Request { index, .. } = peer.recv_until(is_request).await else {
+                unreachable!()
+            };
+            assert_eq!(index, 1);
+        }
+        session.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_request_is_never_served() {
+        let data = content(2);
+        let (_dir, manager) = seeding(&data);
+        let (mut peer, session) = connect(context(&manager)).await;
+        assert_eq!(peer.recv().await, seed_bitfield(&manager));
+        peer.send(&Message::Interested).await;
+        peer.recv_until(|m| *m == Message::Unchoke).await;
+        tokio::time::resume();
+
+        // Several requests and a Cancel for one of them, all in one go
+        let request = |index, begin| Message::Request {
+            index,
+            begin,
+            length: 1024,
+        };
+        let requested = [(0, 0), (0, 1024), (0, 2048), (1, 0), (1, 1024)];
+        let cancelled = (0, 2048);
+        let mut burst: Vec<u8> = requested
+            .iter()
+            .flat_map(|&(index, begin)| request(index, begin).serialize())
+            .collect();
+        burst.extend(
+            Message::Cancel {
+                index: cancelled.0,
+                begin: cancelled.1,
+                length: 1024,
+            }
+            .serialize(),
+        );
+        peer.stream.write_all(&burst).await.unwrap();
+
+        let mut served = Vec::new();
+        while served.len() < requested.len() - 1 {
+            let Message::Piece {
+                index,
+                begin,
+                block,
+            } = peer
+                .recv_until(|m| matches!(m, Message::Piece { .. }))
+                .await
+            else {
+                unreachable!()
+            };
+            assert_ne!((index, begin), cancelled, "served a cancelled block");
+            let start = index as usize * PIECE + begin as usize;
+            assert_eq!(block, data[start..start + 1024]);
+            served.push((index, begin));
+        }
+        let expected: Vec<_> = requested.into_iter().filter(|&r| r != cancelled).collect();
+        assert_eq!(served, expected);
+        assert!(peer.is_quiet().await, "served a cancelled block");
+        session.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn corrupted_piece_is_not_served_under_verify_on_seed() {
+        let data = content(2);
+        let (_dir, manager) = seeding(&data);
+        manager.storage.write_piece(1, &vec![0; PIECE]).unwrap();
+        let mut ctx = context(&manager);
+        ctx.verify_on_seed = true;
+        let (mut peer, session) = connect(ctx).await;
+        // Taken before writing anything, so the session's writes and ours don't overlap
+        // while the clock is paused
+        assert_eq!(peer.recv().await, seed_bitfield(&manager));
+        peer.send(&Message::Interested).await;
+        peer.recv_until(|m| *m == Message::Unchoke).await;
+        // Real time from here: the exchange below involves no timers of the session
+        tokio::time::resume();
+
+        let request = |index| Message::Request {
+            index,
+            begin: 0,
+            length: 1024,
+        };
+        peer.send(&request(0)).await;
+        let Message::Piece { index, block, .. } = peer.recv().await else {
+            panic!("expected a block");
+        };
+        assert_eq!((index, &block[..]), (0, &data[..1024]));
+
+        peer.send(&request(1)).await;
+        assert!(peer.is_quiet().await, "served a corrupted piece");
+        assert!(!manager.has_piece(1));
+        session.abort();
+    }
+}
@@ -0,0 +1,971 @@
+use anyhow::Result;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::trace;
+
+/// The 768-bit safe prime all MSE peers use for Diffie-Hellman (generator 2).
+const DH_PRIME: [u8; 96] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+/// Bytes of a DH public key on the wire.
+const KEY_LEN: usize = 96;
+
+/// Longest random padding either side may send after its public key (or in PadC/PadD).
+const MAX_PAD: usize = 512;
+
+/// Verification constant: eight zero bytes, sent encrypted so the other side can find
+/// where the RC4 stream starts.
+const VC: [u8; 8] = [0; 8];
+
+/// `crypto_provide` / `crypto_select` bits.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Keystream bytes discarded before use, as the spec requires (RC4-drop1024).
+const RC4_DROP: usize = 1024;
+
+/// The plaintext handshake prefix; anything else on an inbound connection is MSE.
+const PLAIN_HANDSHAKE_PREFIX: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Connection encryption policy (`--encryption`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EncryptionMode {
+    /// Plaintext only; encrypted inbound connections are refused.
+    Disable,
+    /// Try MSE first and fall back to plaintext; accept both inbound.
+    #[default]
+    Prefer,
+    /// RC4-encrypted connections only, in both directions.
+    Require,
+}
+
+impl EncryptionMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "disable" => Ok(Self::Disable),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            _ => anyhow::bail!(
+                "Invalid --encryption value: {} (expected prefer, require or disable)",
+                value
+            ),
+        }
+    }
+
+    /// Methods we offer (outbound) or accept (inbound).
+    fn allowed(self) -> u32 {
+        match self {
+            Self::Disable => CRYPTO_PLAINTEXT,
+            Self::Prefer => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            Self::Require => CRYPTO_RC4,
+        }
+    }
+}
+
+/// A peer connection, either plaintext or RC4-encrypted after an MSE handshake.
+///
+/// Bytes that arrived during the handshake but belong to the payload (the initial
+/// payload of an inbound MSE handshake, or the first 20 bytes of a plaintext one) are
+/// handed out before anything else is read from the socket.
+pub struct PeerStream {
+    inner: TcpStream,
+    prefix: Vec<u8>,
+    cipher: Option<Cipher>,
+}
+
+struct Cipher {
+    read: Rc4,
+    write: Rc4,
+    /// Encrypted bytes accepted by `poll_write` but not yet written to the socket.
+    /// They have already advanced the keystream, so they must go out before anything else.
+    pending: Vec<u8>,
+}
+
+impl PeerStream {
+    pub fn plain(inner: TcpStream) -> Self {
+        Self {
+            inner,
+            prefix: Vec::new(),
+            cipher: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..n]);
+            this.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let already = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.read.apply(&mut buf.filled_mut()[already..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(cipher) = &mut this.cipher else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        ready!(poll_drain(&mut this.inner, &mut cipher.pending, cx))?;
+        let start = cipher.pending.len();
+        cipher.pending.extend_from_slice(buf);
+        cipher.write.apply(&mut cipher.pending[start..]);
+        // Whatever doesn't go out now is sent by the next write or flush
+        if let Poll::Ready(Err(e)) = poll_drain(&mut this.inner, &mut cipher.pending, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(cipher) = &mut this.cipher {
+            ready!(poll_drain(&mut this.inner, &mut cipher.pending, cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(cipher) = &mut this.cipher {
+            ready!(poll_drain(&mut this.inner, &mut cipher.pending, cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Writes out `pending` completely, or returns `Pending` with the rest still queued.
+fn poll_drain(
+    inner: &mut TcpStream,
+    pending: &mut Vec<u8>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    while !pending.is_empty() {
+        let n = ready!(Pin::new(&mut *inner).poll_write(cx, pending))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        pending.drain(..n);
+    }
+    Poll::Ready(Ok(()))
+}
+
+/// Opens a connection with `connect` and runs the MSE handshake `mode` calls for,
+/// giving up on it after `handshake_timeout`.
+///
+/// Under `Prefer`, a peer that fails the encrypted handshake is connected to again in
+/// plaintext, since peers without MSE support usually just drop the connection.
+pub async fn establish<F, Fut>(
+    connect: F,
+    info_hash: &[u8; 20],
+    mode: EncryptionMode,
+    handshake_timeout: Duration,
+) -> Result<PeerStream>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<TcpStream>>,
+{
+    match mode {
+        EncryptionMode::Disable => Ok(PeerStream::plain(connect().await?)),
+        EncryptionMode::Require => timeout(
+            handshake_timeout,
+            initiate(connect().await?, info_hash, mode),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Encrypted handshake timed out"))?,
+        EncryptionMode::Prefer => {
+            let encrypted = timeout(
+                handshake_timeout,
+                initiate(connect().await?, info_hash, mode),
+            )
+            .await;
+            match encrypted {
+                Ok(Ok(stream)) => Ok(stream),
+                _ => {
+                    trace!("Encrypted handshake failed, reconnecting in plaintext");
+                    Ok(PeerStream::plain(connect().await?))
+                }
+            }
+        }
+    }
+}
+
+/// Performs the initiating side of the MSE handshake on a fresh connection.
+///
+/// Offers the methods `mode` allows and returns the stream wrapped according to the
+/// peer's choice. The BitTorrent handshake is then sent over the returned stream.
+pub async fn initiate(
+    mut stream: TcpStream,
+    info_hash: &[u8; 20],
+    mode: EncryptionMode,
+) -> Result<PeerStream> {
+    let (crypto_select, encrypt, decrypt) =
+        initiator_handshake(&mut stream, info_hash, mode, &[]).await?;
+    wrap(stream, Vec::new(), crypto_select, mode, encrypt, decrypt)
+}
+
+/// The initiator's half of the handshake, sending `initial_payload` as IA. Returns the
+/// method the peer selected and the (encrypt, decrypt) keystreams.
+async fn initiator_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hash: &[u8; 20],
+    mode: EncryptionMode,
+    initial_payload: &[u8],
+) -> Result<(u32, Rc4, Rc4)> {
+    // 1. A -> B: Ya, PadA
+    let private: [u8; 20] = rand::random();
+    let public = dh_public(&private);
+    stream.write_all(&public).await?;
+    stream.write_all(&random_pad()).await?;
+
+    // 2. B -> A: Yb, PadB
+    let mut remote = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote).await?;
+    let secret = dh_secret(&remote, &private)?;
+
+    let mut encrypt = Rc4::new(&sha1(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&sha1(&[b"keyB", &secret, info_hash]));
+
+    // 3. A -> B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    //    ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA). We send
+    //    no PadC.
+    let ia_len = u16::try_from(initial_payload.len())
+        .map_err(|_| anyhow::anyhow!("MSE: initial payload too long"))?;
+    let mut msg = Vec::with_capacity(40 + 16 + initial_payload.len());
+    msg.extend_from_slice(&sha1(&[b"req1", &secret]));
+    msg.extend(xor(
+        &sha1(&[b"req2", info_hash]),
+        &sha1(&[b"req3", &secret]),
+    ));
+    let mut payload = Vec::with_capacity(16 + initial_payload.len());
+    payload.extend_from_slice(&VC);
+    payload.extend_from_slice(&mode.allowed().to_be_bytes());
+    payload.extend_from_slice(&0u16.to_be_bytes()); // len(PadC)
+    payload.extend_from_slice(&ia_len.to_be_bytes());
+    payload.extend_from_slice(initial_payload);
+    encrypt.apply(&mut payload);
+    msg.extend_from_slice(&payload);
+    stream.write_all(&msg).await?;
+
+    // 4. B -> A: ENCRYPT(VC, crypto_select, len(PadD), PadD). PadB has an unknown
+    //    length, so scan for the encrypted VC to find where this starts.
+    let mut marker = VC;
+    decrypt.clone().apply(&mut marker);
+    read_until_marker(stream, &marker, MAX_PAD + VC.len()).await?;
+    decrypt.apply(&mut [0u8; 8]);
+
+    let mut select = [0u8; 6];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let crypto_select = u32::from_be_bytes(select[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([select[4], select[5]]) as usize;
+    if pad_len > MAX_PAD {
+        anyhow::bail!("MSE: PadD too long ({} bytes)", pad_len);
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    Ok((crypto_select, encrypt, decrypt))
+}
+
+/// Performs the receiving side of the handshake on an inbound connection.
+///
+/// Plaintext handshakes (`\x13BitTorrent protocol...`) are passed through untouched
+/// unless `mode` requires encryption. For MSE, the torrent is identified by matching
+/// the obfuscated info hash against `info_hashes`.
+pub async fn accept(
+    mut stream: TcpStream,
+    info_hashes: &[[u8; 20]],
+    mode: EncryptionMode,
+) -> Result<PeerStream> {
+    match responder_handshake(&mut stream, info_hashes, mode).await? {
+        None => {
+            let mut plain = PeerStream::plain(stream);
+            plain.prefix = PLAIN_HANDSHAKE_PREFIX.to_vec();
+            Ok(plain)
+        }
+        Some(Negotiated {
+            crypto_select,
+            encrypt,
+            decrypt,
+            initial_payload,
+        }) => wrap(
+            stream,
+            initial_payload,
+            crypto_select,
+            mode,
+            encrypt,
+            decrypt,
+        ),
+    }
+}
+
+/// A completed MSE handshake, as seen by the receiver.
+struct Negotiated {
+    crypto_select: u32,
+    encrypt: Rc4,
+    decrypt: Rc4,
+    /// The initiator's IA, already decrypted.
+    initial_payload: Vec<u8>,
+}
+
+/// The receiver's half of the handshake. Returns `None` for a plaintext handshake,
+/// whose first 20 bytes have then been consumed.
+async fn responder_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    info_hashes: &[[u8; 20]],
+    mode: EncryptionMode,
+) -> Result<Option<Negotiated>> {
+    let mut remote = [0u8; KEY_LEN];
+    stream
+        .read_exact(&mut remote[..PLAIN_HANDSHAKE_PREFIX.len()])
+        .await?;
+    if remote[..PLAIN_HANDSHAKE_PREFIX.len()] == PLAIN_HANDSHAKE_PREFIX[..] {
+        if mode == EncryptionMode::Require {
+            anyhow::bail!("Plaintext connection refused (encryption required)");
+        }
+        return Ok(None);
+    }
+    if mode == EncryptionMode::Disable {
+        anyhow::bail!("Encrypted connection refused (encryption disabled)");
+    }
+
+    // 1. A -> B: Ya, PadA (the first 20 bytes of Ya are already read)
+    stream
+        .read_exact(&mut remote[PLAIN_HANDSHAKE_PREFIX.len()..])
+        .await?;
+    let private: [u8; 20] = rand::random();
+    let secret = dh_secret(&remote, &private)?;
+
+    // 2. B -> A: Yb, PadB
+    stream.write_all(&dh_public(&private)).await?;
+    stream.write_all(&random_pad()).await?;
+
+    // 3. A -> B: skip PadA by scanning for HASH('req1', S), then find the torrent
+    let req1 = sha1(&[b"req1", &secret]);
+    read_until_marker(stream, &req1, MAX_PAD + req1.len()).await?;
+    let mut obfuscated = [0u8; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req2 = xor(&obfuscated, &sha1(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .iter()
+        .find(|hash| sha1(&[b"req2", *hash]) == req2)
+        .ok_or_else(|| anyhow::anyhow!("MSE: peer asked for an unknown torrent"))?;
+
+    let mut decrypt = Rc4::new(&sha1(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::new(&sha1(&[b"keyB", &secret, info_hash]));
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        anyhow::bail!("MSE: bad verification constant");
+    }
+    let crypto_provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if pad_len > MAX_PAD {
+        anyhow::bail!("MSE: PadC too long ({} bytes)", pad_len);
+    }
+    let mut pad = vec![0u8; pad_len + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let ia_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    let mut initial_payload = vec![0u8; ia_len];
+    stream.read_exact(&mut initial_payload).await?;
+    decrypt.apply(&mut initial_payload);
+
+    // 4. B -> A: ENCRYPT(VC, crypto_select, len(PadD), PadD). RC4 wins when both work.
+    let offered = crypto_provide & mode.allowed();
+    let crypto_select = if offered & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if offered & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        anyhow::bail!(
+            "MSE: no common crypto method (peer offered {:#x})",
+            crypto_provide
+        );
+    };
+    let mut reply = Vec::with_capacity(14);
+    reply.extend_from_slice(&VC);
+    reply.extend_from_slice(&crypto_select.to_be_bytes());
+    reply.extend_from_slice(&0u16.to_be_bytes()); // len(PadD)
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    Ok(Some(Negotiated {
+        crypto_select,
+        encrypt,
+        decrypt,
+        initial_payload,
+    }))
+}
+
+/// Builds the payload stream for the negotiated method.
+fn wrap(
+    inner: TcpStream,
+    prefix: Vec<u8>,
+    crypto_select: u32,
+    mode: EncryptionMode,
+    write: Rc4,
+    read: Rc4,
+) -> Result<PeerStream> {
+    let cipher = match crypto_select {
+        CRYPTO_RC4 if mode.allowed() & CRYPTO_RC4 != 0 => Some(Cipher {
+            read,
+            write,
+            pending: Vec::new(),
+        }),
+        CRYPTO_PLAINTEXT if mode.allowed() & CRYPTO_PLAINTEXT != 0 => None,
+        other => anyhow::bail!("MSE: peer selected an unsupported method {:#x}", other),
+    };
+    Ok(PeerStream {
+        inner,
+        prefix,
+        cipher,
+    })
+}
+
+/// Reads byte by byte until the last bytes read equal `marker`, giving up after `limit`.
+async fn read_until_marker<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    marker: &[u8],
+    limit: usize,
+) -> Result<()> {
+    let mut window = Vec::with_capacity(limit);
+    while window.len() < limit {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("MSE: handshake sync marker not found")
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = rand::thread_rng().gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// Our public key: 2^private mod P, as 96 big-endian bytes.
+fn dh_public(private: &[u8]) -> [u8; KEY_LEN] {
+    let modulus = Montgomery::new(&DH_PRIME);
+    let mut two = [0u8; KEY_LEN];
+    two[KEY_LEN - 1] = 2;
+    modulus.pow(&two, private)
+}
+
+/// The shared secret: remote^private mod P. Degenerate keys (0, 1, P-1 and anything
+/// not below P) are rejected.
+fn dh_secret(remote: &[u8; KEY_LEN], private: &[u8]) -> Result<[u8; KEY_LEN]> {
+    let mut p_minus_one = DH_PRIME;
+    p_minus_one[KEY_LEN - 1] -= 1;
+    let mut two = [0u8; KEY_LEN];
+    two[KEY_LEN - 1] = 2;
+    // Big-endian arrays of equal length compare like the numbers they encode
+    if remote[..] < two[..] || remote[..] >= p_minus_one[..] {
+        anyhow::bail!("MSE: invalid public key");
+    }
+    Ok(Montgomery::new(&DH_PRIME).pow(remote, private))
+}
+
+/// RC4 keystream, with the first 1024 bytes already discarded.
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        let mut rc4 = Self { s, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; RC4_DROP]);
+        rc4
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// Number of 64-bit limbs in a 768-bit number.
+const LIMBS: usize = KEY_LEN / 8;
+
+/// Modular exponentiation with a fixed odd 768-bit modulus, using Montgomery
+/// multiplication on little-endian 64-bit limbs.
+struct Montgomery {
+    n: [u64; LIMBS],
+    /// -n^-1 mod 2^64.
+    n_inv: u64,
+    /// R^2 mod n, where R = 2^768; converts numbers into Montgomery form.
+    r2: [u64; LIMBS],
+}
+
+impl Montgomery {
+    fn new(modulus: &[u8; KEY_LEN]) -> Self {
+        let n = to_limbs(modulus);
+
+        // Newton's iteration: each step doubles the number of correct low bits
+        let mut inv = 1u64;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(n[0].wrapping_mul(inv)));
+        }
+
+        // R^2 mod n by doubling 1 (2 * 768) times
+        let mut r2 = [0u64; LIMBS];
+        r2[0] = 1;
+        for _ in 0..2 * 64 * LIMBS {
+            let carry = shl1(&mut r2);
+            if carry || !less_than(&r2, &n) {
+                sub_assign(&mut r2, &n);
+            }
+        }
+
+        Self {
+            n,
+            n_inv: inv.wrapping_neg(),
+            r2,
+        }
+    }
+
+    /// a * b * R^-1 mod n (CIOS), for a, b < n.
+    fn mul(&self, a: &[u64; LIMBS], b: &[u64; LIMBS]) -> [u64; LIMBS] {
+        let mut t = [0u64; LIMBS + 2];
+        for &b_i in b {
+            let mut carry = 0u128;
+            for (t_j, &a_j) in t.iter_mut().zip(a) {
+                let sum = *t_j as u128 + a_j as u128 * b_i as u128 + carry;
+                *t_j = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[LIMBS] as u128 + carry;
+            t[LIMBS] = sum as u64;
+            t[LIMBS + 1] = (sum >> 64) as u64;
+
+            // Add m * n so the lowest limb becomes zero, then shift down one limb
+            let m = t[0].wrapping_mul(self.n_inv);
+            let mut carry = (t[0] as u128 + m as u128 * self.n[0] as u128) >> 64;
+            for j in 1..LIMBS {
+                let sum = t[j] as u128 + m as u128 * self.n[j] as u128 + carry;
+                t[j - 1] = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[LIMBS] as u128 + carry;
+            t[LIMBS - 1] = sum as u64;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 64) as u64;
+            t[LIMBS + 1] = 0;
+        }
+
+        let mut result = [0u64; LIMBS];
+        result.copy_from_slice(&t[..LIMBS]);
+        if t[LIMBS] != 0 || !less_than(&result, &self.n) {
+            sub_assign(&mut result, &self.n);
+        }
+        result
+    }
+
+    /// base^exp mod n, with `base` (< n) and the result as big-endian bytes.
+    fn pow(&self, base: &[u8; KEY_LEN], exp: &[u8]) -> [u8; KEY_LEN] {
+        let mut one = [0u64; LIMBS];
+        one[0] = 1;
+        let base = self.mul(&to_limbs(base), &self.r2);
+        let mut acc = self.mul(&one, &self.r2);
+        for byte in exp {
+            for bit in (0..8).rev() {
+                acc = self.mul(&acc, &acc);
+                if (byte >> bit) & 1 == 1 {
+                    acc = self.mul(&acc, &base);
+                }
+            }
+        }
+        from_limbs(&self.mul(&acc, &one))
+    }
+}
+
+fn to_limbs(bytes: &[u8; KEY_LEN]) -> [u64; LIMBS] {
+    std::array::from_fn(|i| {
+        let end = KEY_LEN - i * 8;
+        u64::from_be_bytes(bytes[end - 8..end].try_into().unwrap())
+    })
+}
+
+fn from_limbs(limbs: &[u64; LIMBS]) -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, limb) in limbs.iter().enumerate() {
+        let end = KEY_LEN - i * 8;
+        bytes[end - 8..end].copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn less_than(a: &[u64; LIMBS], b: &[u64; LIMBS]) -> bool {
+    a.iter().rev().lt(b.iter().rev())
+}
+
+/// a -= b, wrapping (callers ensure a >= b, or that the borrow cancels a carry-out).
+fn sub_assign(a: &mut [u64; LIMBS], b: &[u64; LIMBS]) {
+    let mut borrow = false;
+    for (a_i, &b_i) in a.iter_mut().zip(b) {
+        let (diff, b1) = a_i.overflowing_sub(b_i);
+        let (diff, b2) = diff.overflowing_sub(borrow as u64);
+        *a_i = diff;
+        borrow = b1 || b2;
+    }
+}
+
+/// Shifts left by one bit, returning the bit shifted out.
+fn shl1(a: &mut [u64; LIMBS]) -> bool {
+    let mut carry = false;
+    for limb in a.iter_mut() {
+        let next = *limb >> 63 == 1;
+        *limb = (*limb << 1) | carry as u64;
+        carry = next;
+    }
+    carry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    /// The keystream right after the 1024-byte drop.
+    fn keystream(key: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; 16];
+        Rc4::new(key).apply(&mut out);
+        out
+    }
+
+    #[test]
+    fn rc4_matches_rfc_6229_at_offset_1024() {
+        assert_eq!(
+            keystream(&[1, 2, 3, 4, 5]),
+            unhex("30abbcc7c20b01609f23ee2d5f6bb7df")
+        );
+        let key: Vec<u8> = (1..=32).collect();
+        assert_eq!(keystream(&key), unhex("7fec5bfd9f9b89ce6548309092d7e958"));
+    }
+
+    #[test]
+    fn rc4_round_trips() {
+        let mut data = b"the quick brown fox".to_vec();
+        Rc4::new(b"key").apply(&mut data);
+        assert_ne!(&data[..], b"the quick brown fox");
+        Rc4::new(b"key").apply(&mut data);
+        assert_eq!(&data[..], b"the quick brown fox");
+    }
+
+    const PRIVATE_A: [u8; 20] = [
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+    ];
+    const PRIVATE_B: [u8; 20] = [
+        0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae,
+        0xaf, 0xb0, 0xb1, 0xb2, 0xb3,
+    ];
+
+    #[test]
+    fn dh_public_matches_known_answers() {
+        let mut two = [0u8; KEY_LEN];
+        two[KEY_LEN - 1] = 2;
+        assert_eq!(dh_public(&[1]), two);
+        assert_eq!(
+            dh_public(&PRIVATE_A).to_vec(),
+            unhex(concat!(
+                "96e112dab29e8c5272accb9b17b26887ce54a144a4e3b697c7d159b7a817e556",
+                "b0918db2b4c658e02a87f7e5fb14b18a553e084cbf3dad2d30f16596ccb982d4",
+                "06258c61b30c5c1dae2ddc60bdbd48d79896312aad63238c39e1a633821eb693",
+            ))
+        );
+        assert_eq!(
+            dh_public(&PRIVATE_B).to_vec(),
+            unhex(concat!(
+                "84b23a1e8480b595426889ed448dfc92dc2c293006e0cf39657f70c3eca33cb5",
+                "0bd62ca343a558ca8489018d6986a1d347686b3343453259367421bd5cf35c60",
+                "d834afa278f71223a4c79cdb9a1bc918e09099b4f5aabd652c226edbb75f88f7",
+            ))
+        );
+    }
+
+    #[test]
+    fn dh_secret_is_shared() {
+        let a = dh_secret(&dh_public(&PRIVATE_B), &PRIVATE_A).unwrap();
+        let b = dh_secret(&dh_public(&PRIVATE_A), &PRIVATE_B).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(
+            a.to_vec(),
+            unhex(concat!(
+                "1aea23a0431eeac96cfe444068c2674f97b4ac97054382d31445f162f8b1e576",
+                "cf94207839779de0a37f42501cf321226af0d346b0b7cdb8ff4a0123b228b38d",
+                "c7e696ead6b6f17264c28d21f1dc74536a94993f7943401a06e4b2f99febf21a",
+            ))
+        );
+
+        for _ in 0..4 {
+            let x: [u8; 20] = rand::random();
+            let y: [u8; 20] = rand::random();
+            assert_eq!(
+                dh_secret(&dh_public(&y), &x).unwrap(),
+                dh_secret(&dh_public(&x), &y).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn dh_secret_rejects_degenerate_keys() {
+        let mut p_minus_one = DH_PRIME;
+        p_minus_one[KEY_LEN - 1] -= 1;
+        let mut one = [0u8; KEY_LEN];
+        one[KEY_LEN - 1] = 1;
+        for key in [[0u8; KEY_LEN], one, p_minus_one, DH_PRIME, [0xff; KEY_LEN]] {
+            assert!(dh_secret(&key, &PRIVATE_A).is_err());
+        }
+    }
+
+    /// Runs both halves of the handshake over an in-memory pipe. Each side owns its end,
+    /// so a side that fails hangs up instead of leaving the other one waiting.
+    async fn handshake(
+        info_hash: [u8; 20],
+        known: Vec<[u8; 20]>,
+        initiator: EncryptionMode,
+        responder: EncryptionMode,
+        initial_payload: &[u8],
+    ) -> (Result<(u32, Rc4, Rc4)>, Result<Option<Negotiated>>) {
+        let (mut a, mut b) = duplex(64 * 1024);
+        tokio::join!(
+            async move { initiator_handshake(&mut a, &info_hash, initiator, initial_payload).await },
+            async move { responder_handshake(&mut b, &known, responder).await },
+        )
+    }
+
+    async fn check_handshake(initial_payload: &[u8]) {
+        let info_hash = [7u8; 20];
+        let (initiated, responded) = handshake(
+            info_hash,
+            vec![[1u8; 20], info_hash],
+            EncryptionMode::Prefer,
+            EncryptionMode::Prefer,
+            initial_payload,
+        )
+        .await;
+        let (selected, mut a_encrypt, mut a_decrypt) = initiated.unwrap();
+        let Some(Negotiated {
+            crypto_select,
+            mut encrypt,
+            mut decrypt,
+            initial_payload: received,
+        }) = responded.unwrap()
+        else {
+            panic!("expected an encrypted handshake");
+        };
+        assert_eq!(selected, CRYPTO_RC4);
+        assert_eq!(crypto_select, CRYPTO_RC4);
+        assert_eq!(received, initial_payload);
+
+        // Both keystreams line up in both directions after the handshake
+        let mut upstream = b"interested".to_vec();
+        a_encrypt.apply(&mut upstream);
+        decrypt.apply(&mut upstream);
+        assert_eq!(&upstream[..], b"interested");
+        let mut downstream = b"unchoke".to_vec();
+        encrypt.apply(&mut downstream);
+        a_decrypt.apply(&mut downstream);
+        assert_eq!(&downstream[..], b"unchoke");
+    }
+
+    #[tokio::test]
+    async fn handshake_without_initial_payload() {
+        check_handshake(&[]).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_with_initial_payload() {
+        check_handshake(b"\x13BitTorrent protocol\0\0\0\0\0\0\0\0").await;
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_for_an_unknown_torrent() {
+        let (initiated, responded) = handshake(
+            [7u8; 20],
+            vec![[1u8; 20]],
+            EncryptionMode::Prefer,
+            EncryptionMode::Prefer,
+            &[],
+        )
+        .await;
+        assert!(initiated.is_err());
+        assert!(responded.is_err());
+    }
+
+    #[tokio::test]
+    async fn responder_passes_plaintext_through_unless_required() {
+        for (mode, accepted) in [
+            (EncryptionMode::Prefer, true),
+            (EncryptionMode::Require, false),
+        ] {
+            let (mut a, mut b) = duplex(1024);
+            a.write_all(PLAIN_HANDSHAKE_PREFIX).await.unwrap();
+            let responded = responder_handshake(&mut b, &[[7u8; 20]], mode).await;
+            assert_eq!(matches!(responded, Ok(None)), accepted);
+        }
+    }
+
+    /// A peer without MSE support: it hangs up on the first connection, then reads
+    /// whatever arrives in plaintext on the next one. Returns the address, the number
+    /// of connections seen, and the bytes read from the second one.
+    async fn plaintext_only_peer() -> (
+        std::net::SocketAddr,
+        Arc<AtomicUsize>,
+        tokio::task::JoinHandle<Vec<u8>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let seen = connections.clone();
+        let peer = tokio::spawn(async move {
+            let (first, _) = listener.accept().await.unwrap();
+            seen.fetch_add(1, Ordering::SeqCst);
+            drop(first);
+            let (mut second, _) = listener.accept().await.unwrap();
+            seen.fetch_add(1, Ordering::SeqCst);
+            let mut received = vec![0u8; PLAIN_HANDSHAKE_PREFIX.len()];
+            second.read_exact(&mut received).await.unwrap();
+            received
+        });
+        (addr, connections, peer)
+    }
+
+    #[tokio::test]
+    async fn prefer_falls_back_to_plaintext() {
+        let (addr, connections, peer) = plaintext_only_peer().await;
+        let connect = || async move { Ok(TcpStream::connect(addr).await?) };
+        let mut stream = establish(
+            connect,
+            &[7u8; 20],
+            EncryptionMode::Prefer,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert!(!stream.is_encrypted());
+        stream.write_all(PLAIN_HANDSHAKE_PREFIX).await.unwrap();
+        assert_eq!(&peer.await.unwrap()[..], PLAIN_HANDSHAKE_PREFIX);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn require_does_not_fall_back() {
+        let (addr, connections, peer) = plaintext_only_peer().await;
+        let connect = || async move { Ok(TcpStream::connect(addr).await?) };
+        let established = establish(
+            connect,
+            &[7u8; 20],
+            EncryptionMode::Require,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(established.is_err());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        peer.abort();
+    }
+
+    #[tokio::test]
+    async fn peer_streams_interoperate_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = [7u8; 20];
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = accept(socket, &[info_hash], EncryptionMode::Require)
+                .await
+                .unwrap();
+            assert!(stream.is_encrypted());
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            stream.flush().await.unwrap();
+            request
+        });
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = initiate(socket, &info_hash, EncryptionMode::Require)
+            .await
+            .unwrap();
+        assert!(stream.is_encrypted());
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"pong");
+        assert_eq!(&server.await.unwrap(), b"ping");
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+/// Blocks a session keeps in flight before it has measured anything.
+const INITIAL_BLOCKS: u32 = 4;
+
+/// Never fewer blocks in flight than this, so even a slow peer has the next request
+/// queued while it sends the current one.
+const MIN_BLOCKS: u32 = 2;
+
+/// Cap on bytes in flight per peer, however fast it is.
+const MAX_WINDOW: u32 = 2 * 1024 * 1024;
+
+/// Data the peer should have queued beyond the bandwidth-delay product, so that
+/// jitter on its side doesn't leave the link idle.
+const QUEUE_TIME: Duration = Duration::from_secs(1);
+
+/// Throughput is measured over windows of this much active downloading.
+const RATE_SAMPLE: Duration = Duration::from_secs(1);
+
+/// How many request bytes a peer session keeps outstanding.
+///
+/// Fixed with `--pipeline`. Otherwise it starts at `INITIAL_BLOCKS` and follows the
+/// peer: every second of downloading feeds a smoothed throughput, and the window is
+/// set to that rate times the smallest round trip seen (the bandwidth-delay product)
+/// plus `QUEUE_TIME`. A window the peer can fill measures a higher rate and so grows;
+/// a slow peer settles at `MIN_BLOCKS`. A choke halves it.
+pub struct RequestWindow {
+    block_size: u32,
+    fixed: bool,
+    window: u32,
+    /// When each outstanding request was sent, oldest first; peers answer in order.
+    sent: VecDeque<Instant>,
+    /// Shortest request-to-block time seen: the round trip without queueing at the peer.
+    min_rtt: Option<Duration>,
+    /// Smoothed download rate from this peer, bytes per second.
+    rate: f64,
+    sample_start: Instant,
+    sample_bytes: u64,
+    /// Set while nothing is outstanding, so idle time isn't counted as slow transfer.
+    idle_since: Option<Instant>,
+}
+
+impl RequestWindow {
+    /// `fixed_depth` is the `--pipeline` block count, if given.
+    pub fn new(block_size: u32, fixed_depth: Option<u32>) -> Self {
+        let now = Instant::now();
+        Self {
+            block_size,
+            fixed: fixed_depth.is_some(),
+            window: block_size.saturating_mul(fixed_depth.unwrap_or(INITIAL_BLOCKS)),
+            sent: VecDeque::new(),
+            min_rtt: None,
+            rate: 0.0,
+            sample_start: now,
+            sample_bytes: 0,
+            idle_since: Some(now),
+        }
+    }
+
+    /// Bytes that may be outstanding at once.
+    pub fn target(&self) -> u32 {
+        self.window
+    }
+
+    pub fn on_request(&mut self) {
+        let now = Instant::now();
+        if let Some(idle_since) = self.idle_since.take() {
+            self.sample_start += now - idle_since;
+        }
+        self.sent.push_back(now);
+    }
+
+    pub fn on_block(&mut self, length: usize) {
+        let now = Instant::now();
+        if let Some(sent_at) = self.sent.pop_front() {
+            let rtt = now - sent_at;
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        }
+        if self.sent.is_empty() {
+            self.idle_since = Some(now);
+        }
+
+        self.sample_bytes += length as u64;
+        let elapsed = now.saturating_duration_since(self.sample_start);
+        if elapsed < RATE_SAMPLE {
+            return;
+        }
+        let sample = self.sample_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            self.rate * 0.75 + sample * 0.25
+        };
+        self.sample_start = now;
+        self.sample_bytes = 0;
+
+        if !self.fixed {
+            let delay = self.min_rtt.unwrap_or_default() + QUEUE_TIME;
+            self.window = (self.rate * delay.as_secs_f64()) as u32;
+            self.clamp();
+        }
+    }
+
+    /// The peer choked us and dropped our requests.
+    pub fn on_choke(&mut self) {
+        self.sent.clear();
+        self.idle_since.get_or_insert_with(Instant::now);
+        if !self.fixed {
+            self.window /= 2;
+            self.clamp();
+        }
+    }
+
+    fn clamp(&mut self) {
+        let min = self.block_size.saturating_mul(MIN_BLOCKS);
+        self.window = self.window.clamp(min, MAX_WINDOW.max(min));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u32 = 16384;
+
+    /// Downloads from a simulated peer with the given bandwidth and round trip for 20
+    /// seconds, keeping the window full, and returns where the window settled.
+    async fn settle(bytes_per_sec: f64, rtt: Duration) -> u32 {
+        let mut window = RequestWindow::new(BLOCK, None);
+        let per_block = Duration::from_secs_f64(BLOCK as f64 / bytes_per_sec);
+        let mut due: VecDeque<Instant> = VecDeque::new();
+        let mut link_free = Instant::now();
+        let end = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < end {
+            while due.len() as u32 * BLOCK < window.target() {
+                window.on_request();
+                // Blocks arrive one after another, and no sooner than a round trip
+                link_free = (Instant::now() + rtt).max(link_free + per_block);
+                due.push_back(link_free);
+            }
+            let next = due.pop_front().unwrap();
+            tokio::time::advance(next.saturating_duration_since(Instant::now())).await;
+            window.on_block(BLOCK as usize);
+        }
+        window.target()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_grows_for_a_fast_peer() {
+        let target = settle(10.0 * 1024.0 * 1024.0, Duration::from_millis(50)).await;
+        assert_eq!(target, MAX_WINDOW);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_stays_small_for_a_slow_peer() {
+        // Two or three blocks, depending on how the one-second samples fall
+        let target = settle(32.0 * 1024.0, Duration::from_millis(50)).await;
+        assert!(target < INITIAL_BLOCKS * BLOCK, "{}", target);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_depth_ignores_the_peer() {
+        let mut window = RequestWindow::new(BLOCK, Some(3));
+        window.on_request();
+        tokio::time::advance(Duration::from_secs(2)).await;
+        window.on_block(BLOCK as usize);
+        window.on_choke();
+        assert_eq!(window.target(), 3 * BLOCK);
+    }
+}
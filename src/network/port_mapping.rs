@@ -0,0 +1,450 @@
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep, timeout};
+use tracing::warn;
+
+/// NAT-PMP servers listen on this UDP port on the default gateway (RFC 6886).
+const NAT_PMP_PORT: u16 = 5351;
+
+/// Requested NAT-PMP mapping lifetime. The mapping is renewed at half this interval.
+const NAT_PMP_LIFETIME_SECS: u32 = 7200;
+
+/// SSDP multicast group used to discover UPnP devices.
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+/// How long to wait for the gateway to answer a NAT-PMP request or SSDP search.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// UPnP services that can forward ports, in order of preference.
+const UPNP_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// The mechanism that created a port mapping, with what's needed to remove it.
+enum Mapper {
+    NatPmp {
+        gateway: Ipv4Addr,
+        /// Refreshes the mapping before its lifetime runs out.
+        renewal: JoinHandle<()>,
+    },
+    Upnp {
+        control_url: String,
+        service: &'static str,
+    },
+}
+
+/// An external TCP port forwarded to our listen port by the home router.
+///
+/// Call `remove` on shutdown; routers otherwise keep (UPnP) or eventually expire
+/// (NAT-PMP) the mapping.
+pub struct PortMapping {
+    pub external_ip: Option<IpAddr>,
+    pub external_port: u16,
+    pub internal_port: u16,
+    mapper: Mapper,
+}
+
+impl PortMapping {
+    /// Asks the router to forward an external TCP port to `local_port`.
+    ///
+    /// Tries NAT-PMP first (one UDP round trip to the gateway), then UPnP IGD.
+    /// Fails if neither is available, which is common; the caller should carry on
+    /// with outbound connections only.
+    pub async fn create(local_port: u16, client: &reqwest::Client) -> anyhow::Result<Self> {
+        match Self::create_nat_pmp(local_port).await {
+            Ok(mapping) => Ok(mapping),
+            Err(nat_pmp_err) => Self::create_upnp(local_port, client)
+                .await
+                .with_context(|| format!("NAT-PMP failed ({:#})", nat_pmp_err))
+                .context("UPnP failed"),
+        }
+    }
+
+    /// Removes the mapping from the router.
+    pub async fn remove(self, client: &reqwest::Client) -> anyhow::Result<()> {
+        match self.mapper {
+            Mapper::NatPmp { gateway, renewal } => {
+                renewal.abort();
+                // A zero lifetime (and zero external port) deletes the mapping
+                nat_pmp_map(gateway, self.internal_port, 0, 0).await?;
+            }
+            Mapper::Upnp {
+                control_url,
+                service,
+            } => {
+                let args = format!(
+                    "<NewRemoteHost></NewRemoteHost>\
+                     <NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>TCP</NewProtocol>",
+                    self.external_port
+                );
+                soap_request(client, &control_url, service, "DeletePortMapping", &args).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_nat_pmp(local_port: u16) -> anyhow::Result<Self> {
+        let gateway = default_gateway()?;
+
+        // External address request: version 0, opcode 0
+        let response = nat_pmp_request(gateway, &[0, 0], 12).await?;
+        let external_ip = IpAddr::V4(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ));
+
+        let external_port =
+            nat_pmp_map(gateway, local_port, local_port, NAT_PMP_LIFETIME_SECS).await?;
+
+        let renewal = tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(NAT_PMP_LIFETIME_SECS as u64 / 2)).await;
+                if let Err(e) =
+                    nat_pmp_map(gateway, local_port, external_port, NAT_PMP_LIFETIME_SECS).await
+                {
+                    warn!("NAT-PMP renewal failed: {}", e);
+                }
+            }
+        });
+
+        Ok(Self {
+            external_ip: Some(external_ip),
+            external_port,
+            internal_port: local_port,
+            mapper: Mapper::NatPmp { gateway, renewal },
+        })
+    }
+
+    async fn create_upnp(local_port: u16, client: &reqwest::Client) -> anyhow::Result<Self> {
+        let location = ssdp_discover().await?;
+        Self::map_upnp(&location, local_port, client).await
+    }
+
+    /// Adds the mapping through the gateway whose device description is at `location`.
+    async fn map_upnp(
+        location: &str,
+        local_port: u16,
+        client: &reqwest::Client,
+    ) -> anyhow::Result<Self> {
+        let description = client
+            .get(location)
+            .send()
+            .await?
+            .text()
+            .await
+            .context("Failed to fetch gateway description")?;
+        let (service, control_path) = find_control_url(&description)
+            .ok_or_else(|| anyhow::anyhow!("Gateway offers no port-mapping service"))?;
+        let control_url = url::Url::parse(location)?.join(&control_path)?.to_string();
+
+        // The address the router knows us by is the one we'd use to reach it
+        let gateway_host = url::Url::parse(&control_url)?
+            .socket_addrs(|| Some(80))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Cannot resolve gateway address"))?;
+        let local_ip = local_ip_towards(gateway_host).await?;
+
+        let args = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>TCP</NewProtocol>\
+             <NewInternalPort>{port}</NewInternalPort>\
+             <NewInternalClient>{ip}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>p2p-file-transfer</NewPortMappingDescription>\
+             <NewLeaseDuration>0</NewLeaseDuration>",
+            port = local_port,
+            ip = local_ip
+        );
+        soap_request(client, &control_url, service, "AddPortMapping", &args).await?;
+
+        // The external address is informational; a mapping without it is still useful
+        let external_ip = soap_request(client, &control_url, service, "GetExternalIPAddress", "")
+            .await
+            .ok()
+            .and_then(|body| xml_value(&body, "NewExternalIPAddress"))
+            .and_then(|ip| ip.parse().ok());
+
+        Ok(Self {
+            external_ip,
+            external_port: local_port,
+            internal_port: local_port,
+            mapper: Mapper::Upnp {
+                control_url,
+                service,
+            },
+        })
+    }
+}
+
+/// Reads the IPv4 default gateway from the kernel routing table (Linux only).
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")
+        .context("Cannot read routing table to find the gateway")?;
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Iface, Destination, Gateway, ... ; addresses are little-endian hex
+        if fields.len() > 2
+            && fields[1] == "00000000"
+            && let Ok(gateway) = u32::from_str_radix(fields[2], 16)
+            && gateway != 0
+        {
+            return Ok(Ipv4Addr::from(gateway.swap_bytes()));
+        }
+    }
+    anyhow::bail!("No default gateway found")
+}
+
+/// Sends a NAT-PMP request and returns a validated response of at least `min_len` bytes.
+async fn nat_pmp_request(
+    gateway: Ipv4Addr,
+    request: &[u8],
+    min_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket
+        .connect(SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT))
+        .await?;
+    socket.send(request).await?;
+
+    let mut buf = [0u8; 16];
+    let len = timeout(DISCOVERY_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .context("No NAT-PMP response from gateway")??;
+    if len < min_len || buf[1] != request[1] + 128 {
+        anyhow::bail!("Malformed NAT-PMP response");
+    }
+    let result = u16::from_be_bytes([buf[2], buf[3]]);
+    if result != 0 {
+        anyhow::bail!("NAT-PMP request refused (result code {})", result);
+    }
+    Ok(buf[..len].to_vec())
+}
+
+/// Creates, renews, or (with a zero lifetime) deletes a TCP mapping.
+/// Returns the external port the gateway actually assigned.
+async fn nat_pmp_map(
+    gateway: Ipv4Addr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> anyhow::Result<u16> {
+    // Version 0, opcode 2 (TCP), 2 reserved bytes, ports, lifetime
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+
+    let response = nat_pmp_request(gateway, &request, 16).await?;
+    Ok(u16::from_be_bytes([response[10], response[11]]))
+}
+
+/// Finds an Internet Gateway Device via SSDP and returns its description URL.
+async fn ssdp_discover() -> anyhow::Result<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = "M-SEARCH * HTTP/1.1\r\n\
+                  HOST: 239.255.255.250:1900\r\n\
+                  MAN: \"ssdp:discover\"\r\n\
+                  MX: 2\r\n\
+                  ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+
+    let mut buf = [0u8; 2048];
+    let (len, _) = timeout(DISCOVERY_TIMEOUT, socket.recv_from(&mut buf))
+        .await
+        .context("No UPnP gateway answered")??;
+    let response = String::from_utf8_lossy(&buf[..len]);
+    response
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        })
+        .ok_or_else(|| anyhow::anyhow!("UPnP response has no LOCATION"))
+}
+
+/// Picks the first supported port-mapping service in a device description and
+/// returns its type and (possibly relative) control URL.
+fn find_control_url(description: &str) -> Option<(&'static str, String)> {
+    for service in UPNP_SERVICES {
+        if let Some(pos) = description.find(service) {
+            // The controlURL belongs to the same <service> block as the serviceType
+            let block_end = description[pos..]
+                .find("</service>")
+                .map_or(description.len(), |end| pos + end);
+            if let Some(url) = xml_value(&description[pos..block_end], "controlURL") {
+                return Some((service, url));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the text inside the first `<tag>...</tag>` pair.
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(xml[start..end].trim().to_string())
+}
+
+/// Invokes a UPnP action and returns the response body.
+async fn soap_request(
+    client: &reqwest::Client,
+    control_url: &str,
+    service: &str,
+    action: &str,
+    args: &str,
+) -> anyhow::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+         </s:Envelope>"
+    );
+    let response = client
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service, action))
+        .body(body)
+        .send()
+        .await
+        .with_context(|| format!("UPnP {} request failed", action))?;
+
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        let reason = xml_value(&text, "errorDescription").unwrap_or_else(|| status.to_string());
+        anyhow::bail!("UPnP {} failed: {}", action, reason);
+    }
+    Ok(text)
+}
+
+/// Returns the local address the OS would use to reach `target`.
+async fn local_ip_towards(target: SocketAddr) -> anyhow::Result<IpAddr> {
+    // Connecting a UDP socket sends nothing; it only selects the route
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(target).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const DESCRIPTION: &str = "<root><device><serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL>\
+        </service></serviceList></device></root>";
+
+    /// A UPnP gateway on loopback. Serves its device description and answers every
+    /// SOAP action, passing each request (head and body) to the returned receiver.
+    async fn mock_gateway() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let head_end = loop {
+                    if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                };
+                let head = String::from_utf8_lossy(&request[..head_end]).to_lowercase();
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |value| value.trim().parse().unwrap());
+                while request.len() < head_end + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let body = if request.starts_with("GET /rootDesc.xml") {
+                    DESCRIPTION.to_string()
+                } else if request.contains("#GetExternalIPAddress") {
+                    "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>".to_string()
+                } else {
+                    String::new()
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                let _ = requests.send(request);
+            }
+        });
+        (location, received)
+    }
+
+    #[tokio::test]
+    async fn upnp_mapping_is_requested_and_removed() {
+        let (location, mut requests) = mock_gateway().await;
+        let client = reqwest::Client::new();
+        let mapping = PortMapping::map_upnp(&location, 6881, &client)
+            .await
+            .unwrap();
+        assert_eq!(mapping.external_port, 6881);
+        assert_eq!(mapping.external_ip, Some("203.0.113.7".parse().unwrap()));
+
+        assert!(
+            requests
+                .recv()
+                .await
+                .unwrap()
+                .starts_with("GET /rootDesc.xml")
+        );
+        let add = requests.recv().await.unwrap();
+        assert!(add.starts_with("POST /ctl/IPConn"), "{}", add);
+        assert!(add.contains("WANIPConnection:1#AddPortMapping"), "{}", add);
+        assert!(add.contains("<NewExternalPort>6881</NewExternalPort>"));
+        assert!(add.contains("<NewInternalPort>6881</NewInternalPort>"));
+        assert!(add.contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        assert!(
+            requests
+                .recv()
+                .await
+                .unwrap()
+                .contains("#GetExternalIPAddress")
+        );
+
+        mapping.remove(&client).await.unwrap();
+        let delete = requests.recv().await.unwrap();
+        assert!(
+            delete.contains("WANIPConnection:1#DeletePortMapping"),
+            "{}",
+            delete
+        );
+        assert!(delete.contains("<NewExternalPort>6881</NewExternalPort>"));
+    }
+
+    #[test]
+    fn control_url_comes_from_the_matching_service() {
+        assert_eq!(
+            find_control_url(DESCRIPTION),
+            Some((UPNP_SERVICES[0], "/ctl/IPConn".to_string()))
+        );
+        assert_eq!(find_control_url("<root></root>"), None);
+    }
+}
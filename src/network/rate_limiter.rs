@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant, sleep};
+
+/// A token-bucket limiter shared by every peer session that moves payload data.
+///
+/// The bucket holds at most one second's worth of bytes. Callers may overdraw it (a
+/// 16KB block is never split), in which case they sleep until the debt is repaid, so
+/// the long-run rate stays at the configured limit no matter how many peers share it.
+pub struct RateLimiter {
+    /// Bytes per second, or 0 for unlimited. Can change at runtime (`set_rate`).
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `bytes_per_sec` (0 means unlimited).
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(bytes_per_sec),
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Changes the limit (0 means unlimited). Transfers already waiting finish on the
+    /// old schedule; the bucket never holds more than a second at the new rate.
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        self.rate.store(bytes_per_sec, Ordering::Relaxed);
+    }
+
+    /// Waits until `bytes` may be transferred under the limit.
+    pub async fn acquire(&self, bytes: usize) {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return;
+        }
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(rate as f64);
+            bucket.last_refill = now;
+
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::core::session::Session;
+use std::sync::Arc;
+use tokio::time::{Duration, interval};
+use tracing::info;
+
+/// How often the active window is re-evaluated.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Time-of-day rate limits, e.g. `22:00-08:00:unlimited,08:00-22:00:500k`.
+///
+/// Each window sets one cap for both upload and download. Windows are checked in the
+/// order given and the first that covers the current local time wins; outside every
+/// window the `--upload-limit` / `--download-limit` values apply.
+#[derive(Debug, Clone)]
+pub struct BandwidthSchedule {
+    windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone)]
+struct Window {
+    /// Minutes after local midnight; `end` is exclusive. A window whose end is not
+    /// after its start wraps past midnight (equal ends cover the whole day).
+    start: u32,
+    end: u32,
+    /// Bytes per second, 0 for unlimited.
+    limit: u64,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl BandwidthSchedule {
+    /// Parses comma-separated `HH:MM-HH:MM:<rate>` windows. A rate is `unlimited` or a
+    /// number of KiB/s with an optional `k` (KiB) or `m` (MiB) suffix.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut windows = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let invalid = || anyhow::anyhow!("Invalid --schedule window: {}", entry);
+            let (range, rate) = entry.rsplit_once(':').ok_or_else(invalid)?;
+            let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+            windows.push(Window {
+                start: parse_time(start).ok_or_else(invalid)?,
+                end: parse_time(end).ok_or_else(invalid)?,
+                limit: parse_limit(rate).ok_or_else(invalid)?,
+            });
+        }
+        if windows.is_empty() {
+            anyhow::bail!("--schedule has no windows");
+        }
+        Ok(Self { windows })
+    }
+
+    /// The cap (bytes per second, 0 for unlimited) for a time given in minutes after
+    /// local midnight, or `None` if no window covers it.
+    pub fn limit_at(&self, minute: u32) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(minute))
+            .map(|window| window.limit)
+    }
+
+    /// Applies the schedule to the session's rate limiters now and every minute after,
+    /// for the rest of the process.
+    pub fn spawn(self, session: Arc<Session>) {
+        tokio::spawn(async move {
+            let mut ticker = interval(CHECK_INTERVAL);
+            let mut active = None;
+            loop {
+                ticker.tick().await;
+                let limit = self.limit_at(local_minute_of_day());
+                if limit == active {
+                    continue;
+                }
+                active = limit;
+                let (upload, download) = match limit {
+                    Some(limit) => (limit, limit),
+                    None => (session.options.upload_limit, session.options.download_limit),
+                };
+                info!(
+                    "Schedule: upload limit {}, download limit {}",
+                    describe(upload),
+                    describe(download)
+                );
+                session.set_rate_limits(upload, download);
+            }
+        });
+    }
+}
+
+/// Parses `HH:MM` (24-hour) into minutes after midnight; `24:00` is accepted as an end.
+fn parse_time(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    let total = hours * 60 + minutes;
+    (minutes < 60 && total <= MINUTES_PER_DAY).then_some(total % MINUTES_PER_DAY)
+}
+
+/// Parses `unlimited`, `<n>`, `<n>k` (KiB/s) or `<n>m` (MiB/s) into bytes per second.
+fn parse_limit(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_lowercase();
+    if value == "unlimited" {
+        return Some(0);
+    }
+    let (number, unit) = match value.strip_suffix('m') {
+        Some(number) => (number, 1024 * 1024),
+        None => (value.strip_suffix('k').unwrap_or(&value), 1024),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .map(|n| n * unit)
+}
+
+fn describe(limit: u64) -> String {
+    if limit == 0 {
+        "unlimited".to_string()
+    } else {
+        format!("{} KiB/s", limit / 1024)
+    }
+}
+
+/// Minutes since local midnight, per the system time zone.
+#[cfg(target_os = "linux")]
+fn local_minute_of_day() -> u32 {
+    // SAFETY: `time` accepts a null pointer, and `localtime_r` only writes into `tm`
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Minutes since midnight UTC; local time zones are only read on Linux.
+#[cfg(not(target_os = "linux"))]
+fn local_minute_of_day() -> u32 {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    ((secs / 60) % MINUTES_PER_DAY as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hours: u32, minutes: u32) -> u32 {
+        hours * 60 + minutes
+    }
+
+    #[test]
+    fn active_limit_follows_the_clock() {
+        let schedule = BandwidthSchedule::parse("22:00-08:00:unlimited, 08:00-18:30:500k").unwrap();
+        assert_eq!(schedule.limit_at(at(23, 59)), Some(0));
+        assert_eq!(schedule.limit_at(at(0, 0)), Some(0));
+        assert_eq!(schedule.limit_at(at(7, 59)), Some(0));
+        assert_eq!(schedule.limit_at(at(8, 0)), Some(500 * 1024));
+        assert_eq!(schedule.limit_at(at(18, 29)), Some(500 * 1024));
+        // Not covered: the configured limits apply
+        assert_eq!(schedule.limit_at(at(18, 30)), None);
+        assert_eq!(schedule.limit_at(at(21, 59)), None);
+    }
+
+    #[test]
+    fn first_matching_window_wins() {
+        let schedule = BandwidthSchedule::parse("00:00-24:00:2m,09:00-17:00:100").unwrap();
+        assert_eq!(schedule.limit_at(at(12, 0)), Some(2 * 1024 * 1024));
+        let whole_day = BandwidthSchedule::parse("06:00-06:00:64k").unwrap();
+        assert_eq!(whole_day.limit_at(at(5, 59)), Some(64 * 1024));
+    }
+
+    #[test]
+    fn rejects_malformed_windows() {
+        for spec in [
+            "",
+            "08:00-18:00",
+            "08:60-18:00:1k",
+            "25:00-01:00:1k",
+            "08:00-18:00:0",
+        ] {
+            assert!(BandwidthSchedule::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::Context;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A SOCKS5 proxy parsed from `socks5://[user:pass@]host:port`.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Original URL, handed to reqwest for HTTP tracker requests.
+    pub url: String,
+    /// `host:port` of the proxy server.
+    pub addr: String,
+    pub auth: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    /// Parses the `--proxy` CLI value. Only SOCKS5 is supported.
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let url = url::Url::parse(value).context("Invalid proxy URL")?;
+        if url.scheme() != "socks5" && url.scheme() != "socks5h" {
+            anyhow::bail!(
+                "Unsupported proxy scheme '{}'. Expected socks5://host:port",
+                url.scheme()
+            );
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Proxy URL has no host"))?;
+        let port = url.port().unwrap_or(1080);
+        let auth = (!url.username().is_empty()).then(|| {
+            (
+                url.username().to_string(),
+                url.password().unwrap_or("").to_string(),
+            )
+        });
+
+        Ok(Self {
+            // socks5h makes reqwest resolve tracker hostnames through the proxy too
+            url: format!(
+                "socks5h://{}",
+                value.split_once("://").map_or(value, |(_, rest)| rest)
+            ),
+            addr: format!("{}:{}", host, port),
+            auth,
+        })
+    }
+
+    /// Opens a TCP connection to `target` (`host:port` or `[v6]:port`) through the proxy,
+    /// reaching the proxy from `bind` if given.
+    pub async fn connect(&self, target: &str, bind: Option<IpAddr>) -> anyhow::Result<TcpStream> {
+        let mut stream = super::tcp_connect(&self.addr, bind)
+            .await
+            .context(format!("Failed to connect to proxy {}", self.addr))?;
+
+        // 1. Greeting: offer no-auth, plus username/password if we have credentials
+        let greeting: &[u8] = if self.auth.is_some() {
+            &[5, 2, 0, 2]
+        } else {
+            &[5, 1, 0]
+        };
+        stream.write_all(greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != 5 {
+            anyhow::bail!("Proxy is not a SOCKS5 server");
+        }
+        match (choice[1], &self.auth) {
+            (0, _) => {}
+            (2, Some((user, pass))) => {
+                // RFC 1929 username/password sub-negotiation
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(pass.len() as u8);
+                request.extend_from_slice(pass.as_bytes());
+                stream.write_all(&request).await?;
+                let mut reply = [0u8; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    anyhow::bail!("Proxy rejected the credentials");
+                }
+            }
+            _ => anyhow::bail!("Proxy requires an unsupported authentication method"),
+        }
+
+        // 2. CONNECT request
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid target address: {}", target))?;
+        let port: u16 = port.parse().context("Invalid target port")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // 3. Reply: VER REP RSV ATYP BND.ADDR BND.PORT (the bound address is discarded)
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            anyhow::bail!("Proxy refused connection to {} (code {})", target, reply[1]);
+        }
+        let addr_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            other => anyhow::bail!("Proxy sent unknown address type {}", other),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A SOCKS5 server that accepts one client, checks `user:pass` if the client
+    /// offers credentials, and echoes whatever arrives after the CONNECT. Yields the
+    /// CONNECT request it received.
+    async fn mock_proxy() -> (String, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = [0u8; 2];
+            stream.read_exact(&mut head).await.unwrap();
+            let mut methods = vec![0u8; head[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            if methods.contains(&2) {
+                stream.write_all(&[5, 2]).await.unwrap();
+                let mut auth = [0u8; 1 + 1 + 4 + 1 + 4];
+                stream.read_exact(&mut auth).await.unwrap();
+                assert_eq!(&auth, b"\x01\x04user\x04pass");
+                stream.write_all(&[1, 0]).await.unwrap();
+            } else {
+                stream.write_all(&[5, 0]).await.unwrap();
+            }
+
+            let mut request = vec![0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            let addr_len = match request[3] {
+                1 => 4,
+                4 => 16,
+                _ => {
+                    let len = stream.read_u8().await.unwrap();
+                    request.push(len);
+                    len as usize
+                }
+            };
+            let mut tail = vec![0u8; addr_len + 2];
+            stream.read_exact(&mut tail).await.unwrap();
+            request.extend_from_slice(&tail);
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            stream.write_all(&ping).await.unwrap();
+            request
+        });
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn connects_to_a_hostname_through_the_proxy_with_credentials() {
+        let (addr, server) = mock_proxy().await;
+        let proxy = ProxyConfig::parse(&format!("socks5://user:pass@{}", addr)).unwrap();
+        let mut stream = proxy.connect("peer.example:6881", None).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+        assert_eq!(
+            server.await.unwrap(),
+            b"\x05\x01\x00\x03\x0cpeer.example\x1a\xe1"
+        );
+    }
+
+    #[tokio::test]
+    async fn connects_to_an_ipv6_peer_without_credentials() {
+        let (addr, server) = mock_proxy().await;
+        let proxy = ProxyConfig::parse(&format!("socks5://{}", addr)).unwrap();
+        let mut stream = proxy.connect("[::1]:6881", None).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.read_exact(&mut [0u8; 4]).await.unwrap();
+        let mut expected = vec![5, 1, 0, 4];
+        expected.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&6881u16.to_be_bytes());
+        assert_eq!(server.await.unwrap(), expected);
+    }
+}
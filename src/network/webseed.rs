@@ -0,0 +1,374 @@
+use crate::core::bitfield::Bitfield;
+use crate::core::disk::DiskWriter;
+use crate::core::manager::TorrentManager;
+use crate::network::rate_limiter::RateLimiter;
+use crate::network::socks5::ProxyConfig;
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::time::{Duration, sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Web seeds are only used while fewer peers than this are connected; a healthy swarm
+/// is faster and spares the server.
+const MAX_PEERS_FOR_WEBSEED: usize = 4;
+
+/// How often an idle web seed re-checks whether it is needed.
+const IDLE_POLL: Duration = Duration::from_secs(5);
+
+/// Wait after a failed request before trying the server again.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Time allowed for a whole piece to download; pieces can be several MB.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An HTTP server listed in the torrent's `url-list` (BEP 19, GetRight style).
+///
+/// Acts like one more peer that has every piece: pieces are picked through the
+/// manager, fetched with `Range` requests (one per file the piece spans), verified,
+/// and handed to the disk writer.
+pub struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+    manager: Arc<TorrentManager>,
+    disk: DiskWriter,
+    download_limit: Arc<RateLimiter>,
+}
+
+impl WebSeed {
+    /// Starts downloading from `url` in the background until the torrent completes or
+    /// `cancel` fires. Requests go from the local address `bind` when given, and the
+    /// body counts against the session's `download_limit` like peer traffic does.
+    pub fn spawn(
+        url: String,
+        manager: Arc<TorrentManager>,
+        disk: DiskWriter,
+        proxy: Option<&ProxyConfig>,
+        bind: Option<IpAddr>,
+        download_limit: Arc<RateLimiter>,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .local_address(bind);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(&proxy.url)?);
+        }
+        let seed = Self {
+            url,
+            client: builder.build()?,
+            manager,
+            disk,
+            download_limit,
+        };
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = seed.run() => {}
+                _ = cancel.cancelled() => {}
+            }
+        });
+        Ok(())
+    }
+
+    async fn run(&self) {
+        let mut has_all = Bitfield::new(self.manager.piece_count());
+        for index in 0..has_all.len() {
+            has_all.set(index, true);
+        }
+
+        while !self.manager.is_complete() {
+            if self.manager.is_paused() || self.manager.peer_count() >= MAX_PEERS_FOR_WEBSEED {
+                sleep(IDLE_POLL).await;
+                continue;
+            }
+            let Some(index) = self.manager.pick_next_piece(&has_all) else {
+                sleep(IDLE_POLL).await;
+                continue;
+            };
+
+            match self.fetch_piece(index).await {
+                Ok(data) => {
+                    debug!(piece = index, "Piece verified from web seed {}", self.url);
+                    self.manager.record_download(data.len());
+                    if self.disk.write(index, data).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    self.manager.reset_piece(index);
+                    warn!("Web seed {} failed: {:#}", self.url, e);
+                    sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+        info!("Web seed {} done", self.url);
+    }
+
+    /// Downloads and verifies one piece.
+    async fn fetch_piece(&self, index: usize) -> Result<Vec<u8>> {
+        let torrent = &self.manager.torrent;
+        let piece_len = torrent.calculate_piece_size(index)? as u64;
+        let start = index as u64 * torrent.info().piece_length as u64;
+        let end = start + piece_len;
+
+        let mut data = Vec::with_capacity(piece_len as usize);
+        for (file_index, file) in self.manager.storage.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            // Inclusive byte range within this file
+            let first = start.max(file.offset) - file.offset;
+            let last = end.min(file_end) - file.offset - 1;
+            let url = self.file_url(file_index)?;
+
+            let mut response = self
+                .client
+                .get(url.as_str())
+                .header(reqwest::header::RANGE, format!("bytes={}-{}", first, last))
+                .send()
+                .await
+                .context(format!("Request for {} failed", url))?;
+            let status = response.status();
+            let wanted = last - first + 1;
+            // Most bytes worth reading: the range, or the whole file if the server
+            // ignored the Range header, which is only acceptable for a small file.
+            let limit = match status.as_u16() {
+                206 => wanted,
+                200 if file.length <= piece_len => file.length,
+                200 => anyhow::bail!("{} ignored the Range request", url),
+                _ => anyhow::bail!("{} answered HTTP {}", url, status),
+            };
+            if let Some(length) = response.content_length()
+                && length > limit
+            {
+                anyhow::bail!("{} announced {} bytes, expected {}", url, length, limit);
+            }
+
+            let mut body = Vec::with_capacity(limit as usize);
+            while let Some(bytes) = response.chunk().await? {
+                if (body.len() + bytes.len()) as u64 > limit {
+                    anyhow::bail!("{} sent more than {} bytes", url, limit);
+                }
+                self.download_limit.acquire(bytes.len()).await;
+                body.extend_from_slice(&bytes);
+            }
+            let chunk = match status.as_u16() {
+                206 => &body[..],
+                _ if body.len() as u64 > last => &body[first as usize..=last as usize],
+                _ => &[][..],
+            };
+            if chunk.len() as u64 != wanted {
+                anyhow::bail!(
+                    "{} returned {} bytes, expected {}",
+                    url,
+                    chunk.len(),
+                    wanted
+                );
+            }
+            data.extend_from_slice(chunk);
+        }
+
+        let actual: [u8; 20] = Sha1::digest(&data).into();
+        if actual != torrent.get_piece_hash(index)? {
+            anyhow::bail!("Hash mismatch on piece {}", index);
+        }
+        Ok(data)
+    }
+
+    /// URL of one file per BEP 19: multi-file torrents map to
+    /// `<url>/<name>/<path...>`; a single-file URL ending in `/` gets the name appended,
+    /// otherwise it names the file itself.
+    fn file_url(&self, file_index: usize) -> Result<url::Url> {
+        let mut url = url::Url::parse(&self.url)?;
+        let info = &self.manager.torrent.info();
+        let components: Vec<&str> = match &info.files {
+            Some(files) => std::iter::once(info.name.as_str())
+                .chain(files[file_index].path.iter().map(String::as_str))
+                .collect(),
+            None if self.url.ends_with('/') => vec![info.name.as_str()],
+            None => return Ok(url),
+        };
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Web seed URL {} can't have a path", self.url))?
+            .pop_if_empty()
+            .extend(components);
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::manager::TorrentEvent;
+    use crate::core::test_util::manager_for;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    const PIECE: usize = 16384;
+
+    /// Serves `body` at any path, honouring `Range: bytes=a-b`. Returns the base URL.
+    async fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let (first, last) = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.trim().split_once('-'))
+                    .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()))
+                    .unwrap();
+                let chunk = &body[first..=last];
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    chunk.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(chunk).await.unwrap();
+            }
+        });
+        url
+    }
+
+    /// Answers every request with the raw bytes `response`. Returns the base URL.
+    async fn serve_raw(response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(&response).await;
+            }
+        });
+        url
+    }
+
+    /// A two-piece torrent of `data` downloading into a fresh directory.
+    fn manager(data: &[u8]) -> (TempDir, Arc<TorrentManager>) {
+        let dir = TempDir::new().unwrap();
+        let manager = manager_for(dir.path(), data, PIECE);
+        (dir, manager)
+    }
+
+    fn content() -> Vec<u8> {
+        (0..2 * PIECE).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn fetched_pieces_are_verified_and_completed() {
+        let data = content();
+        let (_dir, manager) = manager(&data);
+        let mut events = manager.subscribe();
+        let url = serve(data.clone()).await;
+        let cancel = CancellationToken::new();
+        let disk = DiskWriter::spawn(manager.clone());
+        let limit = Arc::new(RateLimiter::new(0));
+        WebSeed::spawn(
+            url,
+            manager.clone(),
+            disk,
+            None,
+            None,
+            limit,
+            cancel.clone(),
+        )
+        .unwrap();
+
+        let mut completed = Vec::new();
+        while completed.len() < 2 {
+            match timeout(Duration::from_secs(10), events.recv())
+                .await
+                .unwrap()
+            {
+                Ok(TorrentEvent::PieceCompleted { index }) => completed.push(index),
+                Ok(_) => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        completed.sort();
+        assert_eq!(completed, vec![0, 1]);
+        assert!(manager.is_complete());
+        assert_eq!(
+            manager.read_piece_from_disk(1, PIECE as u64).unwrap(),
+            data[PIECE..]
+        );
+        cancel.cancel();
+    }
+
+    #[tokio::test]
+    async fn corrupt_piece_is_rejected() {
+        let data = content();
+        let (_dir, manager) = manager(&data);
+        let mut served = data.clone();
+        served[PIECE + 7] ^= 0xff;
+        let seed = WebSeed {
+            url: serve(served).await,
+            client: reqwest::Client::new(),
+            manager: manager.clone(),
+            disk: DiskWriter::spawn(manager.clone()),
+            download_limit: Arc::new(RateLimiter::new(0)),
+        };
+        assert_eq!(seed.fetch_piece(0).await.unwrap(), data[..PIECE]);
+        let error = seed.fetch_piece(1).await.unwrap_err();
+        assert_eq!(error.to_string(), "Hash mismatch on piece 1");
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_refused() {
+        let data = content();
+        let (_dir, manager) = manager(&data);
+        let seed = |url| WebSeed {
+            url,
+            client: reqwest::Client::new(),
+            manager: manager.clone(),
+            disk: DiskWriter::spawn(manager.clone()),
+            download_limit: Arc::new(RateLimiter::new(0)),
+        };
+
+        let head = format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+            1 << 30
+        );
+        let error = seed(serve_raw(head.into_bytes()).await)
+            .fetch_piece(0)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("announced 1073741824 bytes"),
+            "{}",
+            error
+        );
+
+        // The file spans two pieces, so a full 200 answer is never read
+        let mut whole =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len()).into_bytes();
+        whole.extend_from_slice(&data);
+        let error = seed(serve_raw(whole).await)
+            .fetch_piece(0)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("ignored the Range request"),
+            "{}",
+            error
+        );
+    }
+}
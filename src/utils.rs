@@ -1,30 +1,153 @@
 use rand::Rng;
+use std::time::Duration;
 use url::form_urlencoded;
 
+/// Our own Azureus-style client prefix: client ID 'RT', version '0100'.
+pub const DEFAULT_PEER_ID_PREFIX: [u8; 8] = *b"-RT0100-";
+
 /// Generates a unique 20-byte Peer ID for this client instance.
 ///
 /// Following the Azureus-style convention:
-/// - First 8 bytes: `-RT0100-` (Client ID 'RT' and Version '0100').
+/// - First 8 bytes: `prefix`, normally `DEFAULT_PEER_ID_PREFIX`.
 /// - Last 12 bytes: Random alphanumeric characters to ensure uniqueness in the swarm.
-pub fn generate_peer_id() -> [u8; 20] {
-    const PREFIX: &[u8; 8] = b"-RT0100-";
+pub fn generate_peer_id(prefix: &[u8; 8]) -> [u8; 20] {
     const CHARSET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
 
     let mut rng = rand::thread_rng();
     let mut peer_id = [0u8; 20];
 
     // Apply the client version prefix
-    peer_id[..8].copy_from_slice(PREFIX);
+    peer_id[..8].copy_from_slice(prefix);
 
     // Fill the remaining 12 bytes with random characters
-    for i in 8..20 {
-        let idx = rng.gen_range(0..CHARSET.len());
-        peer_id[i] = CHARSET[idx];
+    for byte in &mut peer_id[8..] {
+        *byte = CHARSET[rng.gen_range(0..CHARSET.len())];
     }
 
     peer_id
 }
 
+/// Parses a `--peer-id-prefix` value: exactly 8 ASCII characters in Azureus style,
+/// `-` + two-letter client ID + four version characters + `-` (e.g. `-qB4620-`).
+pub fn parse_peer_id_prefix(value: &str) -> anyhow::Result<[u8; 8]> {
+    let prefix: [u8; 8] = value.as_bytes().try_into().map_err(|_| {
+        anyhow::anyhow!(
+            "Peer ID prefix must be exactly 8 characters, got {}: {}",
+            value.len(),
+            value
+        )
+    })?;
+    if prefix[0] != b'-' || prefix[7] != b'-' || !prefix[1..7].iter().all(u8::is_ascii_alphanumeric)
+    {
+        anyhow::bail!(
+            "Peer ID prefix must look like -XX0000- (letters/digits between dashes): {}",
+            value
+        );
+    }
+    Ok(prefix)
+}
+
+/// Two-letter client codes used in Azureus-style peer IDs (`-qB4620-...`).
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("qB", "qBittorrent"),
+    ("RT", "P2P-File-Transfer"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// First characters of Shadow-style peer IDs (`T03I--...`).
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Decodes the client name and version from a peer ID, e.g. `qBittorrent 4.6.2`.
+///
+/// Understands Azureus style (`-XXvvvv-`, unknown codes shown as-is), Shadow style
+/// (a letter followed by base-64 version digits) and Mainline (`M4-3-6--`). Anything
+/// else is `Unknown`.
+pub fn client_name(peer_id: &[u8; 20]) -> String {
+    // Azureus: -XX1234-
+    if peer_id[0] == b'-'
+        && peer_id[7] == b'-'
+        && peer_id[1..7].iter().all(u8::is_ascii_alphanumeric)
+    {
+        let code = String::from_utf8_lossy(&peer_id[1..3]);
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map_or(code.to_string(), |(_, name)| name.to_string());
+        // Version digits, stopping at the first build letter (µTorrent's `-UT355W-`)
+        let mut parts: Vec<String> = peer_id[3..7]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .map(|c| (c - b'0').to_string())
+            .collect();
+        while parts.len() > 2 && parts.last().is_some_and(|p| p == "0") {
+            parts.pop();
+        }
+        return format!("{} {}", name, parts.join("."))
+            .trim_end()
+            .to_string();
+    }
+
+    // Mainline: M4-3-6-- (major, minor and patch digits between dashes)
+    if peer_id[0] == b'M' {
+        let text = String::from_utf8_lossy(&peer_id[1..8]);
+        let parts: Vec<&str> = text.trim_end_matches('-').split('-').collect();
+        if parts
+            .iter()
+            .all(|p| !p.is_empty() && p.bytes().all(|c| c.is_ascii_digit()))
+        {
+            return format!("BitTorrent {}", parts.join("."));
+        }
+    }
+
+    // Shadow: T03I-- (each version character is a base-64 digit)
+    if let Some((_, name)) = SHADOW_CLIENTS.iter().find(|(code, _)| *code == peer_id[0]) {
+        let digits: Option<Vec<String>> = peer_id[1..6]
+            .iter()
+            .take_while(|&&c| c != b'-')
+            .map(|&c| shadow_digit(c).map(|d| d.to_string()))
+            .collect();
+        if let Some(digits) = digits.filter(|d| !d.is_empty()) {
+            return format!("{} {}", name, digits.join("."));
+        }
+    }
+
+    "Unknown".to_string()
+}
+
+/// Value of one Shadow-style version character: `0-9`, `A-Z`, `a-z`, `.` then `-`.
+fn shadow_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
 /// URL-encodes a byte slice into a string suitable for HTTP query parameters.
 ///
 /// This is primarily used for encoding the `info_hash` and `peer_id` when
@@ -32,3 +155,177 @@ pub fn generate_peer_id() -> [u8; 20] {
 pub fn url_encode(data: &[u8]) -> String {
     form_urlencoded::byte_serialize(data).collect()
 }
+
+/// Formats a byte count with binary units, e.g. `1536` -> `"1.5 KiB"`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Time left to transfer `remaining` bytes at `rate` bytes per second, rounded up to
+/// whole seconds. `None` when nothing is moving, since no estimate can be made.
+pub fn eta(remaining: u64, rate: u64) -> Option<Duration> {
+    if remaining == 0 {
+        return Some(Duration::ZERO);
+    }
+    (rate > 0).then(|| Duration::from_secs(remaining.div_ceil(rate)))
+}
+
+/// Formats a duration compactly, e.g. `45s`, `3m07s` or `2h05m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Parses a list of indices and inclusive ranges, e.g. `"1,3-5"` -> `[1, 3, 4, 5]`.
+///
+/// Used by CLI options that select files or pieces.
+pub fn parse_index_list(spec: &str) -> anyhow::Result<Vec<usize>> {
+    let mut indices = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let invalid = || anyhow::anyhow!("Invalid index or range: '{}'", part);
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse().map_err(|_| invalid())?;
+                let end: usize = end.trim().parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                indices.extend(start..=end);
+            }
+            None => indices.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(indices)
+}
+
+/// Matches `text` against a simple glob pattern.
+///
+/// `*` matches any run of characters (including `/`, so `.git/*` covers everything
+/// below `.git`) and `?` matches exactly one character. Everything else is literal.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_indices_and_ranges() {
+        assert_eq!(parse_index_list("3").unwrap(), vec![3]);
+        assert_eq!(parse_index_list("1,3-5").unwrap(), vec![1, 3, 4, 5]);
+        assert_eq!(parse_index_list(" 0 - 2 , 7 ,").unwrap(), vec![0, 1, 2, 7]);
+        assert_eq!(parse_index_list("4-4").unwrap(), vec![4]);
+        assert!(parse_index_list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_indices_and_ranges() {
+        for spec in ["a", "5-3", "1-", "-2", "1-2-3", "1.5", "-1"] {
+            assert!(parse_index_list(spec).is_err(), "{}", spec);
+        }
+    }
+
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut peer_id = [b'x'; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    #[test]
+    fn decodes_known_clients() {
+        assert_eq!(client_name(&id(b"-qB4620-")), "qBittorrent 4.6.2");
+        assert_eq!(client_name(&id(b"-TR3000-")), "Transmission 3.0");
+        assert_eq!(client_name(&id(b"-UT355W-")), "µTorrent 3.5.5");
+        assert_eq!(client_name(&id(b"-ZZ1200-")), "ZZ 1.2");
+        assert_eq!(client_name(&id(b"M4-3-6--")), "BitTorrent 4.3.6");
+        assert_eq!(client_name(&id(b"M7-10-2-")), "BitTorrent 7.10.2");
+        assert_eq!(client_name(&id(b"T03I--")), "BitTornado 0.3.18");
+        assert_eq!(client_name(&id(b"S58B-----")), "Shadow 5.8.11");
+    }
+
+    #[test]
+    fn unrecognised_ids_are_unknown() {
+        assert_eq!(client_name(&[0; 20]), "Unknown");
+        assert_eq!(client_name(&id(b"-qB46 0-")), "Unknown");
+        assert_eq!(client_name(&id(b"Mx-y-z--")), "Unknown");
+    }
+
+    #[test]
+    fn peer_id_keeps_the_prefix_and_randomizes_the_rest() {
+        let prefix = parse_peer_id_prefix("-qB4620-").unwrap();
+        let (first, second) = (generate_peer_id(&prefix), generate_peer_id(&prefix));
+        assert_eq!(&first[..8], b"-qB4620-");
+        assert_eq!(&second[..8], b"-qB4620-");
+        assert!(first[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(first[8..], second[8..]);
+    }
+
+    #[test]
+    fn rejects_malformed_peer_id_prefixes() {
+        // The last one is 8 bytes, but not 8 ASCII characters
+        for value in [
+            "",
+            "-RT010-",
+            "-RT01000-",
+            "RT0100--",
+            "-RT 100-",
+            "-RT01é-",
+        ] {
+            assert!(parse_peer_id_prefix(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn eta_rounds_up_and_needs_a_rate() {
+        assert_eq!(eta(0, 0), Some(Duration::ZERO));
+        assert_eq!(eta(100, 0), None);
+        assert_eq!(eta(100, 10), Some(Duration::from_secs(10)));
+        assert_eq!(eta(101, 10), Some(Duration::from_secs(11)));
+        assert_eq!(eta(1, u64::MAX), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn formats_durations_compactly() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(187)), "3m07s");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h05m");
+    }
+}